@@ -0,0 +1,219 @@
+use std::io::{self, Read, Write};
+use std::result;
+use decoder::{Decoder, DecoderErrorKind};
+
+///Samples per channel of the longest packet at 48 kHz, 120 ms
+const MAX_PACKET_SIZE: usize = 5760;
+
+#[derive(Debug)]
+pub enum BitstreamErrorKind {
+    Io(io::Error),
+    ///The data ends inside a record
+    Truncated,
+    ///The decoder's final range after a packet isn't the one the encoder stored, so the packet decoded differently.
+    ///Packets are counted from 0
+    FinalRangeMismatch { packet: usize, expected: u32, found: u32 },
+    Decoder(DecoderErrorKind),
+}
+
+impl From<io::Error> for BitstreamErrorKind {
+    fn from(err: io::Error) -> Self {
+        BitstreamErrorKind::Io(err)
+    }
+}
+
+impl From<DecoderErrorKind> for BitstreamErrorKind {
+    fn from(err: DecoderErrorKind) -> Self {
+        BitstreamErrorKind::Decoder(err)
+    }
+}
+
+pub type Result<T> = result::Result<T, BitstreamErrorKind>;
+
+///A record of the bitstream files the reference `opus_demo` tool writes and the RFC 6716 and RFC 8251 test vectors
+///come as: the packet's length and the encoder's final range as big-endian 32 bit values, then the packet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BitPacket {
+    ///Empty for a lost packet
+    pub data: Vec<u8>,
+    ///Final range of the encoder's range coder after the packet
+    pub final_range: u32,
+}
+
+///Reads the records of an `opus_demo` bitstream file
+pub struct Reader<R> {
+    reader: R,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    ///Returns the next record, or `None` at the end of the data
+    pub fn read_packet(&mut self) -> Result<Option<BitPacket>> {
+        let mut header = [0; 8];
+        let mut filled = 0;
+        while filled < header.len() {
+            match self.reader.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(BitstreamErrorKind::Truncated),
+                Ok(n) => filled += n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+                Err(err) => return Err(err.into()),
+            }
+        }
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let final_range = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        //A length beyond what's there shouldn't allocate it all up front
+        let mut data = Vec::new();
+        (&mut self.reader).take(u64::from(len)).read_to_end(&mut data)?;
+        if data.len() < len as usize {
+            return Err(BitstreamErrorKind::Truncated);
+        }
+        Ok(Some(BitPacket { data, final_range }))
+    }
+}
+
+///Writes records of an `opus_demo` bitstream file
+pub struct Writer<W> {
+    writer: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    ///Writes a packet with the final range the encoder reported for it, an empty packet marks a lost one
+    pub fn write_packet(&mut self, data: &[u8], final_range: u32) -> Result<()> {
+        self.writer.write_all(&(data.len() as u32).to_be_bytes())?;
+        self.writer.write_all(&final_range.to_be_bytes())?;
+        self.writer.write_all(data)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+///Decodes all packets of a bitstream file with `decoder` and returns the output interleaved, as `opus_demo -d` does.
+///After every packet the decoder's final range has to match the stored one, except for lost packets and the packet
+///after one. Lost packets are concealed for the duration of the packet before them
+pub fn decode<R: Read>(reader: R, decoder: &mut Decoder) -> Result<Vec<i16>> {
+    let mut reader = Reader::new(reader);
+    let channels = decoder.channels() as usize;
+    let mut buf = vec![0; MAX_PACKET_SIZE * channels];
+    let mut pcm = Vec::new();
+    let mut lost_prev = false;
+    let mut packet = 0;
+    while let Some(record) = reader.read_packet()? {
+        let lost = record.data.is_empty();
+        let n = if lost {
+            decoder.decode_lost(decoder.last_packet_duration(), &mut buf)?
+        } else {
+            decoder.decode(&record.data, &mut buf)?
+        };
+        pcm.extend_from_slice(&buf[..n * channels]);
+        if !lost && !lost_prev && decoder.final_range() != record.final_range {
+            return Err(BitstreamErrorKind::FinalRangeMismatch { packet, expected: record.final_range, found: decoder.final_range() });
+        }
+        lost_prev = lost;
+        packet += 1;
+    }
+    Ok(pcm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use common::types::{Channels, SampleRate};
+
+    ///20 ms CELT packets of made up data, and every fourth one lost
+    fn packets() -> Vec<Vec<u8>> {
+        (0..12u8).map(|i| if i % 4 == 2 {
+            Vec::new()
+        } else {
+            let mut data = vec![0xfc];
+            data.extend((0..40u8).map(|j| j.wrapping_mul(37).wrapping_add(i.wrapping_mul(91))));
+            data
+        }).collect()
+    }
+
+    ///Writes the packets with the final ranges a decoder ends up with, as an encoder would have reported them
+    fn bitstream(packets: &[Vec<u8>]) -> Vec<BitPacket> {
+        let mut decoder = Decoder::new(SampleRate::Khz48, Channels::Stereo);
+        let mut buf = vec![0; MAX_PACKET_SIZE * 2];
+        packets.iter().map(|data| {
+            if data.is_empty() {
+                decoder.decode_lost(decoder.last_packet_duration(), &mut buf).unwrap();
+            } else {
+                decoder.decode(data, &mut buf).unwrap();
+            }
+            BitPacket { data: data.clone(), final_range: decoder.final_range() }
+        }).collect()
+    }
+
+    fn write(records: &[BitPacket]) -> Vec<u8> {
+        let mut writer = Writer::new(Vec::new());
+        for record in records {
+            writer.write_packet(&record.data, record.final_range).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let records = bitstream(&packets());
+        let data = write(&records);
+        let mut reader = Reader::new(Cursor::new(&data));
+        for record in &records {
+            assert_eq!(reader.read_packet().unwrap().as_ref(), Some(record));
+        }
+        assert!(reader.read_packet().unwrap().is_none());
+        //The lost packet is only a header with a length of 0
+        assert_eq!(data.len(), 12 * 8 + 9 * 41);
+        assert_eq!(&data[2 * (8 + 41)..][..4], [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn truncated() {
+        let data = write(&bitstream(&packets()[..1]));
+        for len in &[3, data.len() - 1] {
+            match Reader::new(Cursor::new(&data[..*len])).read_packet() {
+                Err(BitstreamErrorKind::Truncated) => {},
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn final_range() {
+        let mut records = bitstream(&packets());
+        let mut decoder = Decoder::new(SampleRate::Khz48, Channels::Stereo);
+        assert_eq!(decode(Cursor::new(write(&records)), &mut decoder).unwrap().len(), 12 * 960 * 2);
+
+        //Lost packets and the packets after them aren't checked
+        records[2].final_range ^= 1;
+        records[3].final_range ^= 1;
+        let mut decoder = Decoder::new(SampleRate::Khz48, Channels::Stereo);
+        decode(Cursor::new(write(&records)), &mut decoder).unwrap();
+
+        let expected = records[4].final_range ^ 1;
+        records[4].final_range = expected;
+        let mut decoder = Decoder::new(SampleRate::Khz48, Channels::Stereo);
+        match decode(Cursor::new(write(&records)), &mut decoder) {
+            Err(BitstreamErrorKind::FinalRangeMismatch { packet: 4, expected: e, found }) => {
+                assert_eq!((e, found), (expected, expected ^ 1));
+            },
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,784 @@
+use std::cmp::{max, min};
+use std::f32::consts::SQRT_2;
+use std::f64::consts::LN_2;
+use range;
+use super::rate::{self, Allocation, BITRES};
+use super::tables;
+use super::vq::{self, SPREAD_AGGRESSIVE};
+use super::{Frame, NB_BANDS};
+
+const QTHETA_OFFSET: i32 = 4;
+const QTHETA_OFFSET_TWOPHASE: i32 = 16;
+///Widest band, the last one of a 20ms frame
+const MAX_BAND_SIZE: usize = 176;
+
+static BIT_INTERLEAVE: [u32; 16] = [0, 1, 1, 1, 2, 3, 3, 3, 2, 3, 3, 3, 2, 3, 3, 3];
+static BIT_DEINTERLEAVE: [u32; 16] = [
+    0x00, 0x03, 0x0C, 0x0F, 0x30, 0x33, 0x3C, 0x3F,
+    0xC0, 0xC3, 0xCC, 0xCF, 0xF0, 0xF3, 0xFC, 0xFF,
+];
+
+///Generator for the noise used by folding and anti-collapse
+pub fn lcg_rand(seed: u32) -> u32 {
+    seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223)
+}
+
+///Cosine approximation that is bit exact on every platform, since it feeds the bit allocation
+fn bitexact_cos(x: i32) -> i32 {
+    let tmp = (4096 + x * x)>>13;
+    let x2 = tmp;
+    let x2 = (32767 - x2) + frac_mul16(x2, -7651 + frac_mul16(x2, 8277 + frac_mul16(-626, x2)));
+    1 + x2
+}
+
+fn bitexact_log2tan(isin: i32, icos: i32) -> i32 {
+    let lc = ilog(icos as u32);
+    let ls = ilog(isin as u32);
+    let icos = icos<<(15 - lc);
+    let isin = isin<<(15 - ls);
+    (ls - lc) * (1<<11)
+        + frac_mul16(isin, frac_mul16(isin, -2597) + 7932)
+        - frac_mul16(icos, frac_mul16(icos, -2597) + 7932)
+}
+
+fn frac_mul16(a: i32, b: i32) -> i32 {
+    (16384 + i32::from(a as i16) * i32::from(b as i16))>>15
+}
+
+fn ilog(x: u32) -> i32 {
+    32 - x.leading_zeros() as i32
+}
+
+///Integer square root, rounded down
+fn isqrt32(mut val: u32) -> u32 {
+    let mut g = 0;
+    let mut bshift = (ilog(val) - 1)>>1;
+    let mut b = 1<<bshift;
+    while bshift >= 0 {
+        let t = ((g<<1) + b)<<bshift;
+        if t <= val {
+            g += b;
+            val -= t;
+        }
+        b >>= 1;
+        bshift -= 1;
+    }
+    g
+}
+
+fn haar1(x: &mut [f32], n0: usize, stride: usize) {
+    const SCALE: f32 = 0.707_106_77;
+    for i in 0..stride {
+        for j in 0..n0>>1 {
+            let tmp1 = SCALE * x[stride * 2 * j + i];
+            let tmp2 = SCALE * x[stride * (2 * j + 1) + i];
+            x[stride * 2 * j + i] = tmp1 + tmp2;
+            x[stride * (2 * j + 1) + i] = tmp1 - tmp2;
+        }
+    }
+}
+
+///Reorders interleaved short blocks so that every block is contiguous
+fn deinterleave_hadamard(x: &mut [f32], n0: usize, stride: usize, hadamard: bool) {
+    let n = n0 * stride;
+    let mut tmp = [0.0; MAX_BAND_SIZE];
+    for i in 0..stride {
+        let to = if hadamard { tables::ORDERY[stride - 2 + i] } else { i };
+        for j in 0..n0 {
+            tmp[to * n0 + j] = x[j * stride + i];
+        }
+    }
+    x[..n].copy_from_slice(&tmp[..n]);
+}
+
+fn interleave_hadamard(x: &mut [f32], n0: usize, stride: usize, hadamard: bool) {
+    let n = n0 * stride;
+    let mut tmp = [0.0; MAX_BAND_SIZE];
+    for i in 0..stride {
+        let from = if hadamard { tables::ORDERY[stride - 2 + i] } else { i };
+        for j in 0..n0 {
+            tmp[j * stride + i] = x[from * n0 + j];
+        }
+    }
+    x[..n].copy_from_slice(&tmp[..n]);
+}
+
+///Resolution of the split angle
+fn compute_qn(n: usize, b: i32, offset: i32, pulse_cap: i32, stereo: bool) -> i32 {
+    const EXP2_TABLE8: [i32; 8] = [16384, 17866, 19483, 21247, 23170, 25267, 27554, 30048];
+    let mut n2 = 2 * n as i32 - 1;
+    if stereo && n == 2 {
+        n2 -= 1;
+    }
+    //The upper limit ensures that in a stereo split with itheta==16384, we'll always have enough
+    //bits left over to code at least one pulse in the side
+    let qb = (b + n2 * offset) / n2;
+    let qb = min(b - pulse_cap - (4<<BITRES), qb);
+    let qb = min(8<<BITRES, qb);
+    if qb < (1<<BITRES>>1) {
+        1
+    } else {
+        let qn = EXP2_TABLE8[(qb & 0x7) as usize]>>(14 - (qb>>BITRES));
+        (qn + 1)>>1<<1
+    }
+}
+
+///Turns a decoded mid and side pair back into left and right
+fn stereo_merge(x: &mut [f32], y: &mut [f32], mid: f32) {
+    let (mut xp, mut side) = (0.0f32, 0.0f32);
+    for (&x, &y) in x.iter().zip(y.iter()) {
+        xp += y * x;
+        side += y * y;
+    }
+    //Compensating for the mid normalization
+    let xp = mid * xp;
+    let el = mid * mid + side - 2.0 * xp;
+    let er = mid * mid + side + 2.0 * xp;
+    if er < 6e-4 || el < 6e-4 {
+        y.copy_from_slice(x);
+        return;
+    }
+    let lgain = 1.0 / el.sqrt();
+    let rgain = 1.0 / er.sqrt();
+    for (x, y) in x.iter_mut().zip(y.iter_mut()) {
+        //Apply mid scaling, side is already scaled
+        let l = mid * *x;
+        let r = *y;
+        *x = lgain * (l - r);
+        *y = rgain * (l + r);
+    }
+}
+
+///Parameters of a band split in two halves, or in mid and side
+struct Split {
+    inv: bool,
+    imid: i32,
+    iside: i32,
+    delta: i32,
+    itheta: i32,
+    qalloc: i32,
+}
+
+struct BandCtx<'a, 'b: 'a> {
+    rc: &'a mut range::Decoder<'b>,
+    band: usize,
+    intensity: usize,
+    spread: usize,
+    disable_inv: bool,
+    tf_change: i32,
+    remaining_bits: i32,
+    seed: u32,
+}
+
+//The band decoding follows the reference implementation closely, parameters included
+#[allow(clippy::too_many_arguments)]
+impl<'a, 'b> BandCtx<'a, 'b> {
+    ///Decodes the angle between the two halves of a band
+    fn compute_theta(&mut self, n: usize, b: &mut i32, blocks: usize, blocks0: usize, lm: i32, stereo: bool, fill: &mut u32) -> Split {
+        let i = self.band;
+        //Decide on the resolution to give to the split parameter theta
+        let pulse_cap = tables::LOG_N[i] + lm * (1<<BITRES);
+        let offset = (pulse_cap>>1) - if stereo && n == 2 { QTHETA_OFFSET_TWOPHASE } else { QTHETA_OFFSET };
+        let mut qn = compute_qn(n, *b, offset, pulse_cap, stereo);
+        if stereo && i >= self.intensity {
+            qn = 1;
+        }
+        let tell = self.rc.tell_frac() as i32;
+        let mut itheta = 0;
+        let mut inv = false;
+        if qn != 1 {
+            if stereo && n > 2 {
+                //Step pdf, a probability of p0 up to itheta=8192 and 1 after
+                let p0 = 3;
+                let x0 = qn / 2;
+                let ft = p0 * (x0 + 1) + x0;
+                let fs = self.rc.decode(ft as u32) as i32;
+                let x = if fs < (x0 + 1) * p0 {
+                    fs / p0
+                } else {
+                    x0 + 1 + (fs - (x0 + 1) * p0)
+                };
+                let (fl, fh) = if x <= x0 {
+                    (p0 * x, p0 * (x + 1))
+                } else {
+                    ((x - 1 - x0) + (x0 + 1) * p0, (x - x0) + (x0 + 1) * p0)
+                };
+                self.rc.update(fl as u16, fh as u16, ft as u16);
+                itheta = x;
+            } else if blocks0 > 1 || stereo {
+                //Uniform pdf
+                itheta = self.rc.decode_uniform(qn as u32 + 1) as i32;
+            } else {
+                //Triangular pdf
+                let ft = ((qn>>1) + 1) * ((qn>>1) + 1);
+                let fm = self.rc.decode(ft as u32) as i32;
+                let (fl, fs);
+                if fm < (((qn>>1) * ((qn>>1) + 1))>>1) {
+                    itheta = (isqrt32(8 * fm as u32 + 1) as i32 - 1)>>1;
+                    fs = itheta + 1;
+                    fl = (itheta * (itheta + 1))>>1;
+                } else {
+                    itheta = (2 * (qn + 1) - isqrt32(8 * (ft - fm - 1) as u32 + 1) as i32)>>1;
+                    fs = qn + 1 - itheta;
+                    fl = ft - (((qn + 1 - itheta) * (qn + 2 - itheta))>>1);
+                }
+                self.rc.update(fl as u16, (fl + fs) as u16, ft as u16);
+            }
+            itheta = itheta * 16384 / qn;
+        } else if stereo {
+            inv = *b > 2<<BITRES && self.remaining_bits > 2<<BITRES && self.rc.decode_bit_logp(2);
+            //Inverted channels cancel out when downmixing
+            inv &= !self.disable_inv;
+        }
+        let qalloc = self.rc.tell_frac() as i32 - tell;
+        *b -= qalloc;
+
+        let (imid, iside, delta) = if itheta == 0 {
+            *fill &= (1<<blocks) - 1;
+            (32767, 0, -16384)
+        } else if itheta == 16384 {
+            *fill &= ((1<<blocks) - 1)<<blocks;
+            (0, 32767, 16384)
+        } else {
+            let imid = bitexact_cos(itheta);
+            let iside = bitexact_cos(16384 - itheta);
+            //The mid vs side allocation that minimizes squared error in that band
+            (imid, iside, frac_mul16((n as i32 - 1)<<7, bitexact_log2tan(iside, imid)))
+        };
+
+        Split {
+            inv,
+            imid,
+            iside,
+            delta,
+            itheta,
+            qalloc,
+        }
+    }
+
+    ///Bands of a single sample only code a sign
+    fn quant_band_n1(&mut self, x: &mut [f32], y: Option<&mut [f32]>, lowband_out: Option<&mut [f32]>) -> u32 {
+        let mut decode_sign = |x: &mut [f32]| {
+            let mut sign = 0;
+            if self.remaining_bits >= 1<<BITRES {
+                sign = self.rc.decode_bits(1);
+                self.remaining_bits -= 1<<BITRES;
+            }
+            x[0] = if sign != 0 { -1.0 } else { 1.0 };
+        };
+        decode_sign(x);
+        if let Some(y) = y {
+            decode_sign(y);
+        }
+        if let Some(lowband_out) = lowband_out {
+            lowband_out[0] = x[0];
+        }
+        1
+    }
+
+    ///Decodes a mono partition. It can split the band in two and code the energy difference between
+    ///the halves, recursively, so bands can end up being split in 8 parts
+    fn quant_partition(&mut self, x: &mut [f32], n: usize, mut b: i32, mut blocks: usize, lowband: Option<&[f32]>, mut lm: i32, gain: f32, mut fill: u32) -> u32 {
+        let blocks0 = blocks;
+        let i = self.band;
+
+        //If we need 1.5 more bit than we can produce, split the band in two
+        if rate::should_split(i, lm, b, n) {
+            let n = n>>1;
+            let (x, y) = x.split_at_mut(n);
+            lm -= 1;
+            if blocks == 1 {
+                fill = (fill & 1) | (fill<<1);
+            }
+            blocks = (blocks + 1)>>1;
+
+            let split = self.compute_theta(n, &mut b, blocks, blocks0, lm, false, &mut fill);
+            let mid = (1.0 / 32768.0) * split.imid as f32;
+            let side = (1.0 / 32768.0) * split.iside as f32;
+            let itheta = split.itheta;
+            let mut delta = split.delta;
+
+            //Give more bits to low-energy mdcts than they would otherwise deserve
+            if blocks0 > 1 && itheta & 0x3fff != 0 {
+                if itheta > 8192 {
+                    //Rough approximation for pre-echo masking
+                    delta -= delta>>(4 - lm);
+                } else {
+                    //Corresponds to a forward-masking slope of 1.5 dB per 10 ms
+                    delta = min(0, delta + ((n as i32)<<BITRES>>(5 - lm)));
+                }
+            }
+            let mut mbits = max(0, min(b, (b - delta) / 2));
+            let mut sbits = b - mbits;
+            self.remaining_bits -= split.qalloc;
+
+            let next_lowband2 = lowband.map(|lowband| &lowband[n..]);
+
+            let mut rebalance = self.remaining_bits;
+            let mut cm;
+            if mbits >= sbits {
+                cm = self.quant_partition(x, n, mbits, blocks, lowband, lm, gain * mid, fill);
+                rebalance = mbits - (rebalance - self.remaining_bits);
+                if rebalance > 3<<BITRES && itheta != 0 {
+                    sbits += rebalance - (3<<BITRES);
+                }
+                cm |= self.quant_partition(y, n, sbits, blocks, next_lowband2, lm, gain * side, fill>>blocks)<<(blocks0>>1);
+            } else {
+                cm = self.quant_partition(y, n, sbits, blocks, next_lowband2, lm, gain * side, fill>>blocks)<<(blocks0>>1);
+                rebalance = sbits - (rebalance - self.remaining_bits);
+                if rebalance > 3<<BITRES && itheta != 16384 {
+                    mbits += rebalance - (3<<BITRES);
+                }
+                cm |= self.quant_partition(x, n, mbits, blocks, lowband, lm, gain * mid, fill);
+            }
+            return cm;
+        }
+
+        //This is the basic no-split case
+        let mut q = rate::bits2pulses(i, lm, b);
+        let mut curr_bits = rate::pulses2bits(i, lm, q);
+        self.remaining_bits -= curr_bits;
+        //Ensures we can never bust the budget
+        while self.remaining_bits < 0 && q > 0 {
+            self.remaining_bits += curr_bits;
+            q -= 1;
+            curr_bits = rate::pulses2bits(i, lm, q);
+            self.remaining_bits -= curr_bits;
+        }
+
+        if q != 0 {
+            let k = rate::get_pulses(q) as usize;
+            return vq::alg_unquant(self.rc, x, n, k, self.spread, blocks, gain);
+        }
+
+        //If there's no pulse, fill the band anyway
+        let cm_mask = (1u32<<blocks) - 1;
+        fill &= cm_mask;
+        let x = &mut x[..n];
+        if fill == 0 {
+            for x in x.iter_mut() {
+                *x = 0.0;
+            }
+            return 0;
+        }
+        let cm = match lowband {
+            None => {
+                //Noise
+                for x in x.iter_mut() {
+                    self.seed = lcg_rand(self.seed);
+                    *x = (self.seed as i32>>20) as f32;
+                }
+                cm_mask
+            },
+            Some(lowband) => {
+                //Folded spectrum, with noise about 48 dB below the folding level
+                for (x, &l) in x.iter_mut().zip(lowband) {
+                    self.seed = lcg_rand(self.seed);
+                    let tmp = if self.seed & 0x8000 != 0 { 1.0 / 256.0 } else { -1.0 / 256.0 };
+                    *x = l + tmp;
+                }
+                fill
+            },
+        };
+        vq::renormalise_vector(x, gain);
+        cm
+    }
+
+    ///Decodes a band of one channel. `lowband` is the folding source and is used as scratch space,
+    ///`lowband_out` receives the normalised band for folding into later bands
+    fn quant_band(&mut self, x: &mut [f32], n: usize, b: i32, mut blocks: usize, mut lowband: Option<&mut [f32]>, lm: i32, lowband_out: Option<&mut [f32]>, gain: f32, mut fill: u32) -> u32 {
+        let n0 = n;
+        let blocks0 = blocks;
+        let long_blocks = blocks0 == 1;
+        let mut n_b = n / blocks;
+        let mut tf_change = self.tf_change;
+
+        //Special case for one sample
+        if n == 1 {
+            return self.quant_band_n1(x, None, lowband_out);
+        }
+
+        //Band recombining to increase frequency resolution
+        let recombine = max(tf_change, 0) as usize;
+        for k in 0..recombine {
+            if let Some(lowband) = lowband.as_mut() {
+                haar1(lowband, n>>k, 1<<k);
+            }
+            fill = BIT_INTERLEAVE[(fill & 0xF) as usize] | BIT_INTERLEAVE[(fill>>4) as usize]<<2;
+        }
+        blocks >>= recombine;
+        n_b <<= recombine;
+
+        //Increasing the time resolution
+        let mut time_divide = 0;
+        while n_b & 1 == 0 && tf_change < 0 {
+            if let Some(lowband) = lowband.as_mut() {
+                haar1(lowband, n_b, blocks);
+            }
+            fill |= fill<<blocks;
+            blocks <<= 1;
+            n_b >>= 1;
+            time_divide += 1;
+            tf_change += 1;
+        }
+        let blocks0 = blocks;
+        let n_b0 = n_b;
+
+        //Reorganize the samples in time order instead of frequency order
+        if blocks0 > 1 {
+            if let Some(lowband) = lowband.as_mut() {
+                deinterleave_hadamard(lowband, n_b>>recombine, blocks0<<recombine, long_blocks);
+            }
+        }
+
+        let mut cm = self.quant_partition(x, n, b, blocks, lowband.map(|lowband| &*lowband), lm, gain, fill);
+
+        //Undo the sample reorganization going from time order to frequency order
+        if blocks0 > 1 {
+            interleave_hadamard(x, n_b>>recombine, blocks0<<recombine, long_blocks);
+        }
+
+        //Undo time-freq changes that we did earlier
+        n_b = n_b0;
+        blocks = blocks0;
+        for _ in 0..time_divide {
+            blocks >>= 1;
+            n_b <<= 1;
+            cm |= cm>>blocks;
+            haar1(x, n_b, blocks);
+        }
+
+        for k in 0..recombine {
+            cm = BIT_DEINTERLEAVE[cm as usize];
+            haar1(x, n0>>k, 1<<k);
+        }
+        blocks <<= recombine;
+
+        //Scale output for later folding
+        if let Some(lowband_out) = lowband_out {
+            let scale = (n0 as f32).sqrt();
+            for (out, &x) in lowband_out.iter_mut().zip(&x[..n0]) {
+                *out = scale * x;
+            }
+        }
+        cm & ((1<<blocks) - 1)
+    }
+
+    ///Decodes a band of both channels as mid and side
+    fn quant_band_stereo(&mut self, x: &mut [f32], y: &mut [f32], n: usize, mut b: i32, blocks: usize, lowband: Option<&mut [f32]>, lm: i32, lowband_out: Option<&mut [f32]>, mut fill: u32) -> u32 {
+        //Special case for one sample
+        if n == 1 {
+            return self.quant_band_n1(x, Some(y), lowband_out);
+        }
+
+        let orig_fill = fill;
+        let split = self.compute_theta(n, &mut b, blocks, blocks, lm, true, &mut fill);
+        let mid = (1.0 / 32768.0) * split.imid as f32;
+        let side = (1.0 / 32768.0) * split.iside as f32;
+        let itheta = split.itheta;
+
+        let cm;
+        if n == 2 {
+            //Mid and side are orthogonal, so the side only needs a sign bit
+            let mut mbits = b;
+            let mut sbits = 0;
+            if itheta != 0 && itheta != 16384 {
+                sbits = 1<<BITRES;
+            }
+            mbits -= sbits;
+            let c = itheta > 8192;
+            self.remaining_bits -= split.qalloc + sbits;
+
+            let mut sign = 0;
+            if sbits != 0 {
+                sign = self.rc.decode_bits(1);
+            }
+            let sign = 1.0 - 2.0 * sign as f32;
+            {
+                let (x2, y2) = if c { (&mut *y, &mut *x) } else { (&mut *x, &mut *y) };
+                //We use orig_fill here because we want to fold the side, but if itheta==16384 we'll have
+                //cleared the low bits of fill
+                cm = self.quant_band(x2, n, mbits, blocks, lowband, lm, lowband_out, 1.0, orig_fill);
+                y2[0] = -sign * x2[1];
+                y2[1] = sign * x2[0];
+            }
+            x[0] *= mid;
+            x[1] *= mid;
+            y[0] *= side;
+            y[1] *= side;
+            for j in 0..2 {
+                let tmp = x[j];
+                x[j] = tmp - y[j];
+                y[j] += tmp;
+            }
+        } else {
+            let mut mbits = max(0, min(b, (b - split.delta) / 2));
+            let mut sbits = b - mbits;
+            self.remaining_bits -= split.qalloc;
+
+            //The mid is not scaled since we need the normalised mid for folding later.
+            //For a stereo split, the high bits of fill are always zero, so no folding will be done to the side
+            let mut rebalance = self.remaining_bits;
+            if mbits >= sbits {
+                let mut mid_cm = self.quant_band(x, n, mbits, blocks, lowband, lm, lowband_out, 1.0, fill);
+                rebalance = mbits - (rebalance - self.remaining_bits);
+                if rebalance > 3<<BITRES && itheta != 0 {
+                    sbits += rebalance - (3<<BITRES);
+                }
+                mid_cm |= self.quant_band(y, n, sbits, blocks, None, lm, None, side, fill>>blocks);
+                cm = mid_cm;
+            } else {
+                let mut side_cm = self.quant_band(y, n, sbits, blocks, None, lm, None, side, fill>>blocks);
+                rebalance = sbits - (rebalance - self.remaining_bits);
+                if rebalance > 3<<BITRES && itheta != 16384 {
+                    mbits += rebalance - (3<<BITRES);
+                }
+                side_cm |= self.quant_band(x, n, mbits, blocks, lowband, lm, lowband_out, 1.0, fill);
+                cm = side_cm;
+            }
+            stereo_merge(&mut x[..n], &mut y[..n], mid);
+        }
+
+        if split.inv {
+            for y in y[..n].iter_mut() {
+                *y = -*y;
+            }
+        }
+        cm
+    }
+}
+
+///Settings of the band shape decoding that are signalled once per frame
+pub struct Shape<'a> {
+    pub short_blocks: bool,
+    pub spread: usize,
+    ///Ignores the phase inversion of intensity coded bands
+    pub disable_inv: bool,
+    pub tf_res: &'a [i32; NB_BANDS],
+    ///Total budget in 1/8 bits
+    pub total_bits: i32,
+}
+
+///Decodes the normalised shape of every band into `x`, one channel after another.
+///Returns the collapse masks of the short blocks per band and channel
+pub fn quant_all_bands(rc: &mut range::Decoder, frame: &Frame, x: &mut [f32], alloc: &Allocation, shape: &Shape, seed: &mut u32) -> [u8; 2 * NB_BANDS] {
+    let Frame { start, end, lm, channels } = *frame;
+    let ebands = |i: usize| tables::EBANDS[i]<<lm;
+    let m = 1<<lm;
+    let blocks = if shape.short_blocks { m } else { 1 };
+    let norm_offset = ebands(start);
+    let frame_len = x.len() / channels;
+    let (x, y) = x.split_at_mut(frame_len);
+    let mut y = if channels == 2 { Some(y) } else { None };
+
+    //No need to keep the normalised shape of the last band since nothing folds from it
+    let norm_len = ebands(NB_BANDS - 1) - norm_offset;
+    let mut norm_buf = vec![0.0; 2 * norm_len];
+    let (norm, norm2) = norm_buf.split_at_mut(norm_len);
+
+    let mut ctx = BandCtx {
+        rc,
+        band: start,
+        intensity: alloc.intensity,
+        spread: shape.spread,
+        disable_inv: shape.disable_inv,
+        tf_change: 0,
+        remaining_bits: 0,
+        seed: *seed,
+    };
+    let mut collapse_masks = [0u8; 2 * NB_BANDS];
+    let mut balance = alloc.balance;
+    let mut dual_stereo = alloc.dual_stereo;
+    let mut lowband_offset = 0;
+    let mut update_lowband = true;
+    let coded_bands = alloc.coded_bands as i32;
+
+    for i in start..end {
+        ctx.band = i;
+        let last = i == end - 1;
+        let band_start = ebands(i);
+        let n = ebands(i + 1) - band_start;
+        let tell = ctx.rc.tell_frac() as i32;
+
+        //Compute how many bits we want to allocate to this band
+        if i != start {
+            balance -= tell;
+        }
+        let remaining_bits = shape.total_bits - tell - 1;
+        ctx.remaining_bits = remaining_bits;
+        let b = if (i as i32) < coded_bands {
+            let curr_balance = balance / min(3, coded_bands - i as i32);
+            min(remaining_bits + 1, alloc.pulses[i] + curr_balance).clamp(0, 16383)
+        } else {
+            0
+        };
+
+        if (band_start as i32 - n as i32 >= ebands(start) as i32 || i == start + 1) && (update_lowband || lowband_offset == 0) {
+            lowband_offset = i;
+        }
+        if i == start + 1 {
+            special_hybrid_folding(norm, norm2, start, lm, dual_stereo);
+        }
+
+        let tf_change = shape.tf_res[i];
+        ctx.tf_change = tf_change;
+
+        //Get a conservative estimate of the collapse masks of the bands we're going to be folding from
+        let mut effective_lowband = None;
+        let (mut x_cm, mut y_cm);
+        if lowband_offset != 0 && (shape.spread != SPREAD_AGGRESSIVE || blocks > 1 || tf_change < 0) {
+            //This ensures we never repeat spectral content within one band
+            let lowband = (ebands(lowband_offset) as i32 - norm_offset as i32 - n as i32).max(0) as usize;
+            let mut fold_start = lowband_offset - 1;
+            while ebands(fold_start) > lowband + norm_offset {
+                fold_start -= 1;
+            }
+            let mut fold_end = lowband_offset;
+            while fold_end < i && ebands(fold_end) < lowband + norm_offset + n {
+                fold_end += 1;
+            }
+            x_cm = 0;
+            y_cm = 0;
+            for fold_i in fold_start..max(fold_end, fold_start + 1) {
+                x_cm |= u32::from(collapse_masks[fold_i * channels]);
+                y_cm |= u32::from(collapse_masks[fold_i * channels + channels - 1]);
+            }
+            effective_lowband = Some(lowband);
+        } else {
+            //Otherwise, we'll be using the lcg to fold, so all blocks will (almost always) be non-zero
+            x_cm = (1<<blocks) - 1;
+            y_cm = x_cm;
+        }
+
+        if dual_stereo && i == alloc.intensity {
+            //Switch off dual stereo to do intensity
+            dual_stereo = false;
+            for (n1, &n2) in norm[..band_start - norm_offset].iter_mut().zip(norm2.iter()) {
+                *n1 = 0.5 * (*n1 + n2);
+            }
+        }
+
+        let x = &mut x[band_start..band_start + n];
+        let out = band_start - norm_offset;
+        let fold_source = |norm: &[f32]| effective_lowband.map(|lowband| {
+            let mut buf = [0.0; MAX_BAND_SIZE];
+            buf[..n].copy_from_slice(&norm[lowband..lowband + n]);
+            buf
+        });
+        match y.as_mut() {
+            Some(y) if dual_stereo => {
+                let y = &mut y[band_start..band_start + n];
+                let mut lowband = fold_source(norm);
+                x_cm = ctx.quant_band(x, n, b / 2, blocks, lowband.as_mut().map(|l| &mut l[..n]), lm as i32, if last { None } else { Some(&mut norm[out..]) }, 1.0, x_cm);
+                let mut lowband = fold_source(norm2);
+                y_cm = ctx.quant_band(y, n, b / 2, blocks, lowband.as_mut().map(|l| &mut l[..n]), lm as i32, if last { None } else { Some(&mut norm2[out..]) }, 1.0, y_cm);
+            },
+            Some(y) => {
+                let y = &mut y[band_start..band_start + n];
+                let mut lowband = fold_source(norm);
+                x_cm = ctx.quant_band_stereo(x, y, n, b, blocks, lowband.as_mut().map(|l| &mut l[..n]), lm as i32, if last { None } else { Some(&mut norm[out..]) }, x_cm | y_cm);
+                y_cm = x_cm;
+            },
+            None => {
+                let mut lowband = fold_source(norm);
+                x_cm = ctx.quant_band(x, n, b, blocks, lowband.as_mut().map(|l| &mut l[..n]), lm as i32, if last { None } else { Some(&mut norm[out..]) }, 1.0, x_cm | y_cm);
+                y_cm = x_cm;
+            },
+        }
+        collapse_masks[i * channels] = x_cm as u8;
+        collapse_masks[i * channels + channels - 1] = y_cm as u8;
+        balance += alloc.pulses[i] + tell;
+
+        //Update the folding position only as long as we have 1 bit/sample depth
+        update_lowband = b > (n as i32)<<BITRES;
+    }
+    *seed = ctx.seed;
+    collapse_masks
+}
+
+///Duplicates enough of the first band's folding data to be able to fold the second band.
+///Copies no data for celt-only frames
+fn special_hybrid_folding(norm: &mut [f32], norm2: &mut [f32], start: usize, lm: usize, dual_stereo: bool) {
+    let n1 = (tables::EBANDS[start + 1] - tables::EBANDS[start])<<lm;
+    let n2 = (tables::EBANDS[start + 2] - tables::EBANDS[start + 1])<<lm;
+    if n2 > n1 {
+        norm.copy_within(2 * n1 - n2..n1, n1);
+        if dual_stereo {
+            norm2.copy_within(2 * n1 - n2..n1, n1);
+        }
+    }
+}
+
+///Fills short blocks that received no pulses with noise, so transients don't leave holes in the spectrum
+#[allow(clippy::too_many_arguments)]
+pub fn anti_collapse(x: &mut [f32], collapse_masks: &[u8], frame: &Frame, log_e: &[f32], prev1_log_e: &[f32], prev2_log_e: &[f32], pulses: &[i32], mut seed: u32) {
+    let Frame { start, end, lm, channels } = *frame;
+    let frame_len = x.len() / channels;
+    for i in start..end {
+        let n0 = tables::EBANDS[i + 1] - tables::EBANDS[i];
+        //Depth in 1/8 bits
+        let depth = ((1 + pulses[i]) as usize / n0)>>lm;
+        let thresh = 0.5 * exp2(-0.125 * depth as f32);
+        let sqrt_1 = 1.0 / ((n0<<lm) as f32).sqrt();
+
+        for c in 0..channels {
+            let mut prev1 = prev1_log_e[c * NB_BANDS + i];
+            let mut prev2 = prev2_log_e[c * NB_BANDS + i];
+            if channels == 1 {
+                prev1 = prev1.max(prev1_log_e[NB_BANDS + i]);
+                prev2 = prev2.max(prev2_log_e[NB_BANDS + i]);
+            }
+            let ediff = (log_e[c * NB_BANDS + i] - prev1.min(prev2)).max(0.0);
+
+            //r needs to be multiplied by 2 or 2*sqrt(2) depending on lm because short blocks don't
+            //have the same energy as long
+            let mut r = 2.0 * exp2(-ediff);
+            if lm == 3 {
+                r *= SQRT_2;
+            }
+            let r = r.min(thresh) * sqrt_1;
+
+            let band = &mut x[c * frame_len + (tables::EBANDS[i]<<lm)..c * frame_len + (tables::EBANDS[i + 1]<<lm)];
+            let mut renormalize = false;
+            for k in 0..1<<lm {
+                //Detect collapse
+                if collapse_masks[i * channels + c] & 1<<k == 0 {
+                    //Fill with noise
+                    for j in 0..n0 {
+                        seed = lcg_rand(seed);
+                        band[(j<<lm) + k] = if seed & 0x8000 != 0 { r } else { -r };
+                    }
+                    renormalize = true;
+                }
+            }
+            //We just added some energy, so we need to renormalise
+            if renormalize {
+                vq::renormalise_vector(band, 1.0);
+            }
+        }
+    }
+}
+
+///Scales the normalised bands of one channel by their energy, zeroing everything outside `start..end`
+///and above the Nyquist frequency of the output decimated by `downsample`
+pub fn denormalise_bands(x: &[f32], freq: &mut [f32], band_log_e: &[f32], start: usize, end: usize, lm: usize, downsample: usize) {
+    let ebands = |i: usize| tables::EBANDS[i]<<lm;
+    for f in freq[..ebands(start)].iter_mut() {
+        *f = 0.0;
+    }
+    for i in start..end {
+        let lg = band_log_e[i] + tables::E_MEANS[i];
+        let g = exp2(lg.min(32.0));
+        for (f, &x) in freq[ebands(i)..ebands(i + 1)].iter_mut().zip(&x[ebands(i)..]) {
+            *f = x * g;
+        }
+    }
+    let bound = ebands(end).min(freq.len() / downsample);
+    for f in freq[bound..].iter_mut() {
+        *f = 0.0;
+    }
+}
+
+pub fn exp2(x: f32) -> f32 {
+    (LN_2 * f64::from(x)).exp() as f32
+}
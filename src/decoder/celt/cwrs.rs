@@ -0,0 +1,69 @@
+use range;
+
+///Decodes the index of a pulse vector with `k` pulses in `n` dimensions and returns the vector in `y`
+///and its squared norm
+pub fn decode_pulses(rc: &mut range::Decoder, y: &mut [i32], n: usize, k: usize) -> f32 {
+    debug_assert!(k > 0 && n > 1);
+    let mut u = vec![0u32; k + 2];
+    let total = ncwrs_urow(n, k, &mut u);
+    let i = rc.decode_uniform(total);
+    cwrsi(n, k, i, y, &mut u)
+}
+
+///Computes V(n, k) and leaves U(n, 0..k + 1) in `u`
+fn ncwrs_urow(n: usize, k: usize, u: &mut [u32]) -> u32 {
+    u[0] = 0;
+    u[1] = 1;
+    for (k, u) in u.iter_mut().enumerate().skip(2) {
+        *u = ((k as u32)<<1) - 1;
+    }
+    for _ in 2..n {
+        unext(&mut u[1..], 1);
+    }
+    u[k].wrapping_add(u[k + 1])
+}
+
+///Next row of U, obeying u[i][j] = u[i-1][j] + u[i][j-1] + u[i-1][j-1]
+fn unext(u: &mut [u32], mut u0: u32) {
+    for j in 1..u.len() {
+        let u1 = u[j].wrapping_add(u[j - 1]).wrapping_add(u0);
+        u[j - 1] = u0;
+        u0 = u1;
+    }
+    let last = u.len() - 1;
+    u[last] = u0;
+}
+
+///Previous row of U, the inverse of `unext`
+fn uprev(u: &mut [u32], mut u0: u32) {
+    for j in 1..u.len() {
+        let u1 = u[j].wrapping_sub(u[j - 1]).wrapping_sub(u0);
+        u[j - 1] = u0;
+        u0 = u1;
+    }
+    let last = u.len() - 1;
+    u[last] = u0;
+}
+
+fn cwrsi(n: usize, mut k: usize, mut i: u32, y: &mut [i32], u: &mut [u32]) -> f32 {
+    let mut yy = 0.0;
+    for y in y.iter_mut().take(n) {
+        let p = u[k + 1];
+        let negative = i >= p;
+        if negative {
+            i -= p;
+        }
+        let k0 = k;
+        let mut p = u[k];
+        while p > i {
+            k -= 1;
+            p = u[k];
+        }
+        i -= p;
+        let val = (k0 - k) as i32;
+        *y = if negative { -val } else { val };
+        yy += (val * val) as f32;
+        uprev(&mut u[..k + 2], 0);
+    }
+    yy
+}
@@ -0,0 +1,116 @@
+use range;
+use super::rate::Allocation;
+use super::tables;
+use super::{Frame, NB_BANDS};
+
+///Largest number of fine energy bits per band
+pub const MAX_FINE_BITS: i32 = 8;
+
+///Decodes the coarse band energies as laplace distributed prediction residuals.
+///`old_e` holds the energies of the previous frame and is updated in place
+pub fn unquant_coarse(rc: &mut range::Decoder, old_e: &mut [f32; 2 * NB_BANDS], frame: &Frame, intra: bool) {
+    let Frame { start, end, lm, channels } = *frame;
+    let prob_model = &tables::E_PROB_MODEL[lm][intra as usize];
+    let (coef, beta) = if intra {
+        (0.0, tables::BETA_INTRA)
+    } else {
+        (tables::PRED_COEF[lm], tables::BETA_COEF[lm])
+    };
+    let budget = rc.storage_bits();
+    let mut prev = [0.0f32; 2];
+
+    for i in start..end {
+        for c in 0..channels {
+            let tell = rc.tell();
+            let qi = if budget >= tell + 15 {
+                let pi = 2 * i.min(20);
+                laplace_decode(rc, u32::from(prob_model[pi])<<7, u32::from(prob_model[pi + 1])<<6)
+            } else if budget >= tell + 2 {
+                let qi = rc.decode_icdf(&tables::SMALL_ENERGY_ICDF, 2) as i32;
+                (qi>>1) ^ -(qi & 1)
+            } else if budget > tell {
+                -(rc.decode_bit_logp(1) as i32)
+            } else {
+                -1
+            };
+            let q = qi as f32;
+
+            let e = &mut old_e[i + c * NB_BANDS];
+            *e = e.max(-9.0);
+            *e = coef * *e + prev[c] + q;
+            prev[c] = prev[c] + q - beta * q;
+        }
+    }
+}
+
+///Adds the fine energy refinement of `fine_quant[i]` bits per band
+pub fn unquant_fine(rc: &mut range::Decoder, old_e: &mut [f32; 2 * NB_BANDS], frame: &Frame, fine_quant: &[i32]) {
+    for i in frame.start..frame.end {
+        if fine_quant[i] <= 0 {
+            continue;
+        }
+        for c in 0..frame.channels {
+            let q2 = rc.decode_bits(fine_quant[i] as usize);
+            let offset = (q2 as f32 + 0.5) * (1<<(14 - fine_quant[i])) as f32 * (1.0 / 16384.0) - 0.5;
+            old_e[i + c * NB_BANDS] += offset;
+        }
+    }
+}
+
+///Spends the bits left at the end of the frame on one more bit of fine energy, in order of priority
+pub fn unquant_finalise(rc: &mut range::Decoder, old_e: &mut [f32; 2 * NB_BANDS], frame: &Frame, alloc: &Allocation, mut bits_left: i32) {
+    let Frame { start, end, channels, .. } = *frame;
+    let fine_quant = &alloc.fine_quant;
+    for &prio in &[false, true] {
+        for i in start..end {
+            if bits_left < channels as i32 {
+                break;
+            }
+            if fine_quant[i] >= MAX_FINE_BITS || alloc.fine_priority[i] != prio {
+                continue;
+            }
+            for c in 0..channels {
+                let q2 = rc.decode_bits(1);
+                let offset = (q2 as f32 - 0.5) * (1<<(14 - fine_quant[i] - 1)) as f32 * (1.0 / 16384.0);
+                old_e[i + c * NB_BANDS] += offset;
+                bits_left -= 1;
+            }
+        }
+    }
+}
+
+///Decodes a laplace distributed value where `fs` is the probability of zero and `decay` the decay
+///of the probability of larger values, both in Q15
+fn laplace_decode(rc: &mut range::Decoder, mut fs: u32, decay: u32) -> i32 {
+    const MIN_P: u32 = 1;
+    const N_MIN: u32 = 16;
+
+    let mut val = 0;
+    let fm = rc.decode_bin(15);
+    let mut fl = 0;
+    if fm >= fs {
+        val += 1;
+        fl = fs;
+        fs = (((32768 - MIN_P * 2 * N_MIN - fs) * (16384 - decay))>>15) + MIN_P;
+        //Search the decaying part of the pdf
+        while fs > MIN_P && fm >= fl + 2 * fs {
+            fs *= 2;
+            fl += fs;
+            fs = (((fs - 2 * MIN_P) * decay)>>15) + MIN_P;
+            val += 1;
+        }
+        //Everything beyond that has probability MIN_P
+        if fs <= MIN_P {
+            let di = (fm - fl)>>1;
+            val += di as i32;
+            fl += 2 * di * MIN_P;
+        }
+        if fm < fl + fs {
+            val = -val;
+        } else {
+            fl += fs;
+        }
+    }
+    rc.update(fl as u16, (fl + fs).min(32768) as u16, 32768);
+    val
+}
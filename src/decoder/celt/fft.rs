@@ -0,0 +1,299 @@
+use std::f64::consts::PI;
+use std::ops::{Add, Sub, Mul};
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Complex {
+    pub r: f32,
+    pub i: f32,
+}
+
+impl Complex {
+    pub fn new(r: f32, i: f32) -> Self {
+        Self { r, i }
+    }
+}
+
+impl Add for Complex {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self::new(self.r + rhs.r, self.i + rhs.i)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self::new(self.r - rhs.r, self.i - rhs.i)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self {
+        Self::new(self.r * rhs.r - self.i * rhs.i, self.r * rhs.i + self.i * rhs.r)
+    }
+}
+
+impl Mul<f32> for Complex {
+    type Output = Self;
+
+    fn mul(self, rhs: f32) -> Self {
+        Self::new(self.r * rhs, self.i * rhs)
+    }
+}
+
+///A fixed size complex fft.
+///The mdct only goes through this trait so a faster backend can replace `KissFft`
+pub trait Fft {
+    fn size(&self) -> usize;
+
+    ///Unscaled forward transform of `input` into `output`
+    fn process(&self, input: &[Complex], output: &mut [Complex]);
+}
+
+///Mixed radix (2, 3, 4, 5) fft in the style of KISS FFT
+pub struct KissFft {
+    nfft: usize,
+    ///(radix, remaining length) for every stage
+    factors: Vec<(usize, usize)>,
+    bitrev: Vec<usize>,
+    twiddles: Vec<Complex>,
+}
+
+impl KissFft {
+    ///Returns `None` if `nfft` has a prime factor larger than 5
+    pub fn new(nfft: usize) -> Option<Self> {
+        let factors = factor(nfft)?;
+        let twiddles = (0..nfft).map(|i| {
+            let phase = (-2.0 * PI / nfft as f64) * i as f64;
+            Complex::new(phase.cos() as f32, phase.sin() as f32)
+        }).collect();
+        let mut bitrev = vec![0; nfft];
+        compute_bitrev(0, &mut bitrev, 0, 1, &factors);
+        Some(Self {
+            nfft,
+            factors,
+            bitrev,
+            twiddles,
+        })
+    }
+
+    fn process_inplace(&self, fout: &mut [Complex]) {
+        let mut fstride = [1; 9];
+        let mut stages = 0;
+        loop {
+            let (p, m) = self.factors[stages];
+            fstride[stages + 1] = fstride[stages] * p;
+            stages += 1;
+            if m == 1 { break }
+        }
+        let mut m = self.factors[stages - 1].1;
+        for i in (0..stages).rev() {
+            let mm = if i != 0 { self.factors[i - 1].1 } else { 1 };
+            match self.factors[i].0 {
+                2 => self.butterfly2(fout, fstride[i], m, fstride[i], mm),
+                3 => self.butterfly3(fout, fstride[i], m, fstride[i], mm),
+                4 => self.butterfly4(fout, fstride[i], m, fstride[i], mm),
+                5 => self.butterfly5(fout, fstride[i], m, fstride[i], mm),
+                _ => unreachable!(),
+            }
+            m = mm;
+        }
+    }
+
+    fn butterfly2(&self, fout: &mut [Complex], fstride: usize, m: usize, n: usize, mm: usize) {
+        for i in 0..n {
+            let base = i * mm;
+            for j in 0..m {
+                let t = fout[base + j + m] * self.twiddles[j * fstride];
+                fout[base + j + m] = fout[base + j] - t;
+                fout[base + j] = fout[base + j] + t;
+            }
+        }
+    }
+
+    fn butterfly3(&self, fout: &mut [Complex], fstride: usize, m: usize, n: usize, mm: usize) {
+        let m2 = 2 * m;
+        let epi3 = self.twiddles[fstride * m];
+        for i in 0..n {
+            let base = i * mm;
+            for k in 0..m {
+                let f = base + k;
+                let s1 = fout[f + m] * self.twiddles[k * fstride];
+                let s2 = fout[f + m2] * self.twiddles[2 * k * fstride];
+                let s3 = s1 + s2;
+                let s0 = (s1 - s2) * epi3.i;
+
+                fout[f + m] = fout[f] - s3 * 0.5;
+                fout[f] = fout[f] + s3;
+                fout[f + m2] = Complex::new(fout[f + m].r + s0.i, fout[f + m].i - s0.r);
+                fout[f + m] = Complex::new(fout[f + m].r - s0.i, fout[f + m].i + s0.r);
+            }
+        }
+    }
+
+    fn butterfly4(&self, fout: &mut [Complex], fstride: usize, m: usize, n: usize, mm: usize) {
+        let m2 = 2 * m;
+        let m3 = 3 * m;
+        for i in 0..n {
+            let base = i * mm;
+            for j in 0..m {
+                let f = base + j;
+                let s0 = fout[f + m] * self.twiddles[j * fstride];
+                let s1 = fout[f + m2] * self.twiddles[2 * j * fstride];
+                let s2 = fout[f + m3] * self.twiddles[3 * j * fstride];
+
+                let s5 = fout[f] - s1;
+                fout[f] = fout[f] + s1;
+                let s3 = s0 + s2;
+                let s4 = s0 - s2;
+                fout[f + m2] = fout[f] - s3;
+                fout[f] = fout[f] + s3;
+                fout[f + m] = Complex::new(s5.r + s4.i, s5.i - s4.r);
+                fout[f + m3] = Complex::new(s5.r - s4.i, s5.i + s4.r);
+            }
+        }
+    }
+
+    fn butterfly5(&self, fout: &mut [Complex], fstride: usize, m: usize, n: usize, mm: usize) {
+        let ya = self.twiddles[fstride * m];
+        let yb = self.twiddles[fstride * 2 * m];
+        let tw = &self.twiddles;
+        for i in 0..n {
+            let base = i * mm;
+            for u in 0..m {
+                let f0 = base + u;
+                let (f1, f2, f3, f4) = (f0 + m, f0 + 2 * m, f0 + 3 * m, f0 + 4 * m);
+                let s0 = fout[f0];
+                let s1 = fout[f1] * tw[u * fstride];
+                let s2 = fout[f2] * tw[2 * u * fstride];
+                let s3 = fout[f3] * tw[3 * u * fstride];
+                let s4 = fout[f4] * tw[4 * u * fstride];
+
+                let s7 = s1 + s4;
+                let s10 = s1 - s4;
+                let s8 = s2 + s3;
+                let s9 = s2 - s3;
+
+                fout[f0] = fout[f0] + (s7 + s8);
+
+                let s5 = Complex::new(
+                    s0.r + (s7.r * ya.r + s8.r * yb.r),
+                    s0.i + (s7.i * ya.r + s8.i * yb.r),
+                );
+                let s6 = Complex::new(
+                    s10.i * ya.i + s9.i * yb.i,
+                    -(s10.r * ya.i + s9.r * yb.i),
+                );
+                fout[f1] = s5 - s6;
+                fout[f4] = s5 + s6;
+
+                let s11 = Complex::new(
+                    s0.r + (s7.r * yb.r + s8.r * ya.r),
+                    s0.i + (s7.i * yb.r + s8.i * ya.r),
+                );
+                let s12 = Complex::new(
+                    s9.i * ya.i - s10.i * yb.i,
+                    s10.r * yb.i - s9.r * ya.i,
+                );
+                fout[f2] = s11 + s12;
+                fout[f3] = s11 - s12;
+            }
+        }
+    }
+}
+
+impl Fft for KissFft {
+    fn size(&self) -> usize {
+        self.nfft
+    }
+
+    fn process(&self, input: &[Complex], output: &mut [Complex]) {
+        debug_assert!(input.len() >= self.nfft && output.len() >= self.nfft);
+        for (&x, &rev) in input.iter().zip(&self.bitrev) {
+            output[rev] = x;
+        }
+        self.process_inplace(output);
+    }
+}
+
+///Splits `n` into radix 4, 2, 3 and 5 stages, largest radices last
+fn factor(n: usize) -> Option<Vec<(usize, usize)>> {
+    let mut radices = Vec::with_capacity(8);
+    let mut p = 4;
+    let mut rem = n;
+    loop {
+        while !rem.is_multiple_of(p) {
+            p = match p {
+                4 => 2,
+                2 => 3,
+                _ => p + 2,
+            };
+            if p * p > rem {
+                p = rem;
+            }
+        }
+        rem /= p;
+        if p > 5 {
+            return None;
+        }
+        radices.push(p);
+        let stages = radices.len() - 1;
+        if p == 2 && stages > 1 {
+            radices[stages] = 4;
+            radices[1] = 2;
+        }
+        if rem <= 1 { break }
+    }
+    radices.reverse();
+    let mut rem = n;
+    Some(radices.into_iter().map(|p| {
+        rem /= p;
+        (p, rem)
+    }).collect())
+}
+
+fn compute_bitrev(mut fout: usize, bitrev: &mut [usize], mut pos: usize, fstride: usize, factors: &[(usize, usize)]) {
+    let (p, m) = factors[0];
+    if m == 1 {
+        for j in 0..p {
+            bitrev[pos] = fout + j;
+            pos += fstride;
+        }
+    } else {
+        for _ in 0..p {
+            compute_bitrev(fout, bitrev, pos, fstride * p, &factors[1..]);
+            pos += fstride;
+            fout += m;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_dft() {
+        for &n in &[60, 120, 240, 480] {
+            let fft = KissFft::new(n).unwrap();
+            let input: Vec<_> = (0..n).map(|i| {
+                Complex::new(((i * 7) % 13) as f32 - 6.0, ((i * 3) % 5) as f32 - 2.0)
+            }).collect();
+            let mut output = vec![Complex::default(); n];
+            fft.process(&input, &mut output);
+            for (k, out) in output.iter().enumerate() {
+                let (mut r, mut i) = (0.0f64, 0.0f64);
+                for (j, x) in input.iter().enumerate() {
+                    let phase = -2.0 * PI * (j * k % n) as f64 / n as f64;
+                    r += x.r as f64 * phase.cos() - x.i as f64 * phase.sin();
+                    i += x.r as f64 * phase.sin() + x.i as f64 * phase.cos();
+                }
+                assert!((out.r as f64 - r).abs() < 1e-2 && (out.i as f64 - i).abs() < 1e-2, "n: {}, k: {}", n, k);
+            }
+        }
+    }
+}
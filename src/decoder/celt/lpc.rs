@@ -0,0 +1,92 @@
+///Order of the filter used to extend lost frames
+pub const LPC_ORDER: usize = 24;
+
+///Computes the autocorrelation of `x` for lags `0..ac.len()`, with `window` applied to both ends of `x`
+pub fn autocorr(x: &[f32], ac: &mut [f32], window: Option<&[f32]>) {
+    let n = x.len();
+    let lag = ac.len() - 1;
+    let mut xx = x.to_vec();
+    if let Some(window) = window {
+        for (i, &w) in window.iter().enumerate() {
+            xx[i] = x[i] * w;
+            xx[n - i - 1] = x[n - i - 1] * w;
+        }
+    }
+    let fast_n = n - lag;
+    for (k, ac) in ac.iter_mut().enumerate() {
+        *ac = xx[..fast_n].iter().zip(&xx[k..]).fold(0.0, |sum, (&a, &b)| sum + a * b);
+        let d = (k + fast_n..n).fold(0.0, |d, i| d + xx[i] * xx[i - k]);
+        *ac += d;
+    }
+}
+
+///Derives prediction coefficients from the autocorrelation with the Levinson-Durbin recursion
+pub fn lpc(lpc: &mut [f32], ac: &[f32]) {
+    for val in lpc.iter_mut() {
+        *val = 0.0;
+    }
+    let mut error = ac[0];
+    if error == 0.0 {
+        return;
+    }
+    for i in 0..lpc.len() {
+        //Sum up this iteration's reflection coefficient
+        let mut rr = 0.0;
+        for j in 0..i {
+            rr += lpc[j] * ac[i - j];
+        }
+        rr += ac[i + 1];
+        let r = -(rr / error);
+        //Update the coefficients and the total error
+        lpc[i] = r;
+        for j in 0..(i + 1)>>1 {
+            let tmp1 = lpc[j];
+            let tmp2 = lpc[i - 1 - j];
+            lpc[j] = tmp1 + r * tmp2;
+            lpc[i - 1 - j] = tmp2 + r * tmp1;
+        }
+        error -= r * r * error;
+        //Bail out once we get 30 dB gain
+        if error < 0.001 * ac[0] {
+            break;
+        }
+    }
+}
+
+///Filters `x[LPC_ORDER..]` with the prediction error filter `num` into `y`,
+///the first `LPC_ORDER` samples of `x` are the filter history
+pub fn fir(x: &[f32], num: &[f32; LPC_ORDER], y: &mut [f32]) {
+    for (i, y) in y.iter_mut().enumerate() {
+        let mut sum = x[i + LPC_ORDER];
+        for j in 0..LPC_ORDER {
+            sum += num[LPC_ORDER - j - 1] * x[i + j];
+        }
+        *y = sum;
+    }
+}
+
+///Runs the synthesis filter `den` in place over `x`, `mem` holds the last outputs with the most recent first
+pub fn iir(x: &mut [f32], den: &[f32; LPC_ORDER], mem: &[f32; LPC_ORDER]) {
+    debug_assert!(x.len() & 3 == 0);
+    //Negated outputs with the filter history in front, the accumulation order follows the
+    //reference implementation, which unrolls the filter by four
+    let mut y = vec![0.0; x.len() + LPC_ORDER];
+    for i in 0..LPC_ORDER {
+        y[i] = -mem[LPC_ORDER - i - 1];
+    }
+    for i in (0..x.len()).step_by(4) {
+        let mut sum = [x[i], x[i + 1], x[i + 2], x[i + 3]];
+        for (k, sum) in sum.iter_mut().enumerate() {
+            for j in 0..LPC_ORDER {
+                *sum += den[LPC_ORDER - j - 1] * y[i + k + j];
+            }
+        }
+        for k in 0..4 {
+            for j in 0..k {
+                sum[k] += y[i + LPC_ORDER + k - j - 1] * den[j];
+            }
+            y[i + LPC_ORDER + k] = -sum[k];
+            x[i + k] = sum[k];
+        }
+    }
+}
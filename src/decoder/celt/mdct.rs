@@ -0,0 +1,145 @@
+use std::f64::consts::PI;
+use super::fft::{Complex, Fft, KissFft};
+
+///Mdct of size `n` that can also run at `n>>1` ... `n>>max_shift`
+pub struct Mdct<F = KissFft> {
+    n: usize,
+    ffts: Vec<F>,
+    trig: Vec<f32>,
+    scratch: Vec<Complex>,
+    buffer: Vec<Complex>,
+}
+
+impl Mdct<KissFft> {
+    pub fn new(n: usize, max_shift: usize) -> Self {
+        Self::with_backend(n, max_shift, |len| KissFft::new(len).expect("mdct size must only have factors 2, 3 and 5"))
+    }
+}
+
+impl<F: Fft> Mdct<F> {
+    ///Creates an mdct that runs its ffts on the backend returned by `backend` for every size
+    pub fn with_backend<B: FnMut(usize) -> F>(n: usize, max_shift: usize, mut backend: B) -> Self {
+        let ffts: Vec<F> = (0..max_shift + 1).map(|shift| backend(n>>2>>shift)).collect();
+        debug_assert!(ffts.iter().enumerate().all(|(shift, fft)| fft.size() == n>>2>>shift));
+        let mut trig = Vec::with_capacity(n);
+        for shift in 0..max_shift + 1 {
+            let size = n>>shift;
+            trig.extend((0..size>>1).map(|i| (2.0 * PI * (i as f64 + 0.125) / size as f64).cos() as f32));
+        }
+        Self {
+            n,
+            ffts,
+            trig,
+            scratch: vec![Complex::default(); n>>2],
+            buffer: vec![Complex::default(); n>>2],
+        }
+    }
+
+    ///Inverse mdct of every `stride`th value of `input`.
+    ///The windowed output is overlap-added in place with the previous block, which has to be
+    ///in the first `overlap/2` samples of `out`. The first `(n>>shift)/2 + overlap/2` samples of `out` are written.
+    pub fn backward(&mut self, input: &[f32], stride: usize, out: &mut [f32], window: &[f32], overlap: usize, shift: usize) {
+        let mut n = self.n;
+        let mut trig = &self.trig[..];
+        for _ in 0..shift {
+            n >>= 1;
+            trig = &trig[n..];
+        }
+        let n2 = n>>1;
+        let n4 = n>>2;
+
+        //Pre-rotate, real and imaginary parts are swapped since we use a forward fft
+        for i in 0..n4 {
+            let x1 = input[2 * stride * i];
+            let x2 = input[stride * (n2 - 1 - 2 * i)];
+            let yr = x2 * trig[i] + x1 * trig[n4 + i];
+            let yi = x1 * trig[i] - x2 * trig[n4 + i];
+            self.scratch[i] = Complex::new(yi, yr);
+        }
+
+        self.ffts[shift].process(&self.scratch[..n4], &mut self.buffer[..n4]);
+
+        //Post-rotate from both ends at once
+        let buffer = &mut self.buffer[..n4];
+        for i in 0..(n4 + 1)>>1 {
+            let front = buffer[i];
+            let back = buffer[n4 - 1 - i];
+
+            let (re, im) = (front.i, front.r);
+            let (t0, t1) = (trig[i], trig[n4 + i]);
+            let yr0 = re * t0 + im * t1;
+            let yi0 = re * t1 - im * t0;
+
+            let (re, im) = (back.i, back.r);
+            let (t0, t1) = (trig[n4 - i - 1], trig[n2 - i - 1]);
+            let yr1 = re * t0 + im * t1;
+            let yi1 = re * t1 - im * t0;
+
+            buffer[n4 - 1 - i] = Complex::new(yr1, yi0);
+            buffer[i] = Complex::new(yr0, yi1);
+        }
+        for (pair, val) in out[overlap / 2..overlap / 2 + n2].chunks_mut(2).zip(buffer.iter()) {
+            pair[0] = val.r;
+            pair[1] = val.i;
+        }
+
+        //Mirror on both sides for TDAC
+        for i in 0..overlap / 2 {
+            let x1 = out[overlap - 1 - i];
+            let x2 = out[i];
+            let w1 = window[i];
+            let w2 = window[overlap - 1 - i];
+            out[i] = w2 * x2 - w1 * x1;
+            out[overlap - 1 - i] = w1 * x2 + w2 * x1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tables::WINDOW;
+
+    ///Sample `t` of the inverse mdct of `x`, straight from the definition
+    fn imdct(x: &[f32], t: usize) -> f64 {
+        let n = 2 * x.len();
+        x.iter().enumerate().map(|(k, &x)| {
+            f64::from(x) * (2.0 * PI / n as f64 * (t as f64 + 0.5 + n as f64 / 4.0) * (k as f64 + 0.5)).cos()
+        }).sum()
+    }
+
+    #[test]
+    fn matches_direct_overlap_add() {
+        let overlap = WINDOW.len();
+        let mut mdct = Mdct::new(1920, 3);
+        for shift in 0..4 {
+            let n = 1920>>shift;
+            let blocks: Vec<Vec<f32>> = (0..2).map(|b| {
+                (0..n / 2).map(|k| ((k * 7 + b * 3) % 11) as f32 / 5.0 - 1.0).collect()
+            }).collect();
+            let mut out = vec![0.0; n + overlap / 2];
+            for (b, block) in blocks.iter().enumerate() {
+                mdct.backward(block, 1, &mut out[b * n / 2..], &WINDOW, overlap, shift);
+            }
+
+            //Every block covers n/2 + overlap samples, windowed at both ends
+            let mut expected = vec![0.0; n + overlap];
+            for (b, block) in blocks.iter().enumerate() {
+                for j in 0..n / 2 + overlap {
+                    let window = if j < overlap {
+                        WINDOW[j]
+                    } else if j >= n / 2 {
+                        WINDOW[n / 2 + overlap - 1 - j]
+                    } else {
+                        1.0
+                    };
+                    expected[b * n / 2 + j] += f64::from(window) * imdct(block, j + n / 4 - overlap / 2);
+                }
+            }
+            //Past the overlap of the two blocks the second one still waits for the next
+            for (j, (&out, &expected)) in out.iter().zip(expected.iter()).take(n / 2 + overlap).enumerate() {
+                assert!((f64::from(out) - expected).abs() < 1e-3, "shift: {}, sample: {}", shift, j);
+            }
+        }
+    }
+}
@@ -0,0 +1,514 @@
+mod bands;
+mod cwrs;
+mod energy;
+pub mod fft;
+mod lpc;
+pub mod mdct;
+mod pitch;
+mod plc;
+pub mod postfilter;
+mod rate;
+mod tables;
+mod vq;
+
+use std::cmp::max;
+use common::types::{Bandwidth, Channels, FrameSize, SampleRate};
+use range;
+use self::bands::Shape;
+use self::lpc::LPC_ORDER;
+use self::mdct::Mdct;
+use self::postfilter::PostFilter;
+use self::rate::BITRES;
+pub use self::tables::WINDOW;
+
+///Samples of history kept per channel
+const DECODE_BUFFER_SIZE: usize = 2048;
+const OVERLAP: usize = 120;
+const SHORT_MDCT_SIZE: usize = 120;
+const MAX_LM: usize = 3;
+const NB_BANDS: usize = 21;
+///First band coded by CELT in hybrid frames, the ones below are up to 8 kHz
+pub const HYBRID_START_BAND: usize = 17;
+const PREEMPHASIS: f32 = 0.850_006_1;
+///Internal signals are kept at 16 bit scale
+const SIG_SCALE: f32 = 32768.0;
+///Keeps the de-emphasis filter out of denormals
+const VERY_SMALL: f32 = 1e-30;
+
+///Bands and channels coded in a frame
+#[derive(Copy, Clone, Debug)]
+struct Frame {
+    start: usize,
+    end: usize,
+    lm: usize,
+    channels: usize,
+}
+
+pub struct Decoder {
+    channels: Channels,
+    ///Frames are synthesized at 48 kHz and decimated by this factor for the output
+    downsample: usize,
+    mdct: Mdct,
+    ///Per channel synthesis history, the last `OVERLAP/2` samples hold the unwindowed tail of the previous frame
+    decode_mem: [Vec<f32>; 2],
+    postfilter: PostFilter,
+    postfilter_old: PostFilter,
+    preemph_mem: [f32; 2],
+    ///Keeps intensity stereo from inverting the side channel, so a downmix doesn't cancel out
+    disable_inv: bool,
+    ///Seed of the folding noise, the final range of the last frame
+    rng: u32,
+    ///Bands coded in the last frame, concealment keeps using them
+    start: usize,
+    end: usize,
+    ///Number of frames concealed in a row
+    loss_count: usize,
+    ///Makes the concealment fall back to noise until two frames were decoded in a row
+    skip_plc: bool,
+    last_pitch: usize,
+    ///Prediction filter of the signal before the first lost frame, per channel
+    lpc: [[f32; LPC_ORDER]; 2],
+    ///Band energies of the last frame, per channel
+    old_band_e: [f32; 2 * NB_BANDS],
+    ///Band energies of the last two non-transient frames
+    old_log_e: [f32; 2 * NB_BANDS],
+    old_log_e2: [f32; 2 * NB_BANDS],
+    ///Slowly rising estimate of the noise floor
+    background_log_e: [f32; 2 * NB_BANDS],
+}
+
+impl Decoder {
+    pub fn new(rate: SampleRate, channels: Channels) -> Self {
+        let downsample = match rate {
+            SampleRate::Khz8 => 6,
+            SampleRate::Khz12 => 4,
+            SampleRate::Khz16 => 3,
+            SampleRate::Khz24 => 2,
+            SampleRate::Khz48 => 1,
+        };
+        Self {
+            channels,
+            downsample,
+            mdct: Mdct::new((2 * SHORT_MDCT_SIZE)<<MAX_LM, MAX_LM),
+            decode_mem: [
+                vec![0.0; DECODE_BUFFER_SIZE + OVERLAP],
+                vec![0.0; DECODE_BUFFER_SIZE + OVERLAP],
+            ],
+            postfilter: PostFilter::default(),
+            postfilter_old: PostFilter::default(),
+            preemph_mem: [0.0; 2],
+            disable_inv: channels == Channels::Mono,
+            rng: 0,
+            start: 0,
+            end: NB_BANDS,
+            loss_count: 0,
+            skip_plc: true,
+            last_pitch: 0,
+            lpc: [[0.0; LPC_ORDER]; 2],
+            old_band_e: [0.0; 2 * NB_BANDS],
+            old_log_e: [-28.0; 2 * NB_BANDS],
+            old_log_e2: [-28.0; 2 * NB_BANDS],
+            background_log_e: [0.0; 2 * NB_BANDS],
+        }
+    }
+
+    pub fn channels(&self) -> Channels {
+        self.channels
+    }
+
+    pub fn set_phase_inversion_disabled(&mut self, disabled: bool) {
+        self.disable_inv = disabled;
+    }
+
+    pub fn phase_inversion_disabled(&self) -> bool {
+        self.disable_inv
+    }
+
+    ///Period of the post-filter of the last frame at 48 kHz
+    pub fn pitch(&self) -> usize {
+        self.postfilter.period
+    }
+
+    pub fn reset(&mut self) {
+        for mem in &mut self.decode_mem {
+            for val in mem.iter_mut() {
+                *val = 0.0;
+            }
+        }
+        self.postfilter = PostFilter::default();
+        self.postfilter_old = PostFilter::default();
+        self.preemph_mem = [0.0; 2];
+        self.rng = 0;
+        self.loss_count = 0;
+        self.skip_plc = true;
+        self.last_pitch = 0;
+        self.lpc = [[0.0; LPC_ORDER]; 2];
+        self.old_band_e = [0.0; 2 * NB_BANDS];
+        self.old_log_e = [-28.0; 2 * NB_BANDS];
+        self.old_log_e2 = [-28.0; 2 * NB_BANDS];
+        self.background_log_e = [0.0; 2 * NB_BANDS];
+    }
+
+    ///Decodes a frame coded with `stream_channels` channels and writes it interleaved into `pcm`
+    ///with the decoder's channel count. A mono stream is copied to both channels and a stereo stream
+    ///is downmixed for a mono decoder. Bands below `start` are left to SILK in hybrid frames
+    pub fn decode(&mut self, rc: &mut range::Decoder, frame_size: FrameSize, bandwidth: Bandwidth, stream_channels: Channels, start: usize, pcm: &mut [f32]) {
+        let lm = lm(frame_size);
+        let frame = Frame {
+            start,
+            end: end_band(bandwidth),
+            lm,
+            channels: stream_channels as usize,
+        };
+        let n = SHORT_MDCT_SIZE<<lm;
+        let c = frame.channels;
+        self.start = frame.start;
+        self.end = frame.end;
+        //Only conceal with the pitch of the signal once two frames were decoded in a row
+        self.skip_plc = self.loss_count != 0;
+
+        //A mono stream keeps the louder energy of both channels, so switching back to stereo is smooth
+        if c == 1 {
+            for i in 0..NB_BANDS {
+                self.old_band_e[i] = self.old_band_e[i].max(self.old_band_e[NB_BANDS + i]);
+            }
+        }
+
+        let total_bits = rc.storage_bits();
+        let mut tell = rc.tell();
+        let silence = if tell >= total_bits {
+            true
+        } else if tell == 1 {
+            rc.decode_bit_logp(15)
+        } else {
+            false
+        };
+        if silence {
+            //Pretend we've read all the remaining bits
+            rc.skip_to_end();
+            tell = total_bits;
+        }
+
+        let mut postfilter = PostFilter::default();
+        if frame.start == 0 && tell + 16 <= total_bits {
+            postfilter = PostFilter::decode(rc, total_bits);
+            tell = rc.tell();
+        }
+
+        let mut transient = false;
+        if lm > 0 && tell + 3 <= total_bits {
+            transient = rc.decode_bit_logp(3);
+            tell = rc.tell();
+        }
+        let intra = tell + 3 <= total_bits && rc.decode_bit_logp(3);
+
+        energy::unquant_coarse(rc, &mut self.old_band_e, &frame, intra);
+        let tf_res = tf_decode(rc, &frame, transient);
+
+        let spread = if rc.tell() + 4 <= total_bits {
+            rc.decode_icdf(&tables::SPREAD_ICDF, 5)
+        } else {
+            vq::SPREAD_NORMAL
+        };
+
+        //Dynamic allocation boosts of single bands
+        let cap = rate::init_caps(lm, c);
+        let mut offsets = [0; NB_BANDS];
+        let mut dynalloc_logp = 6;
+        let mut total_frac = (total_bits as i32)<<BITRES;
+        let mut tell_frac = rc.tell_frac() as i32;
+        for i in frame.start..frame.end {
+            let width = ((c * (tables::EBANDS[i + 1] - tables::EBANDS[i]))<<lm) as i32;
+            //Quanta is 6 bits, but no more than 1 bit/sample and no less than 1/8 bit/sample
+            let quanta = (width<<BITRES).min(max(6<<BITRES, width));
+            let mut loop_logp = dynalloc_logp;
+            let mut boost = 0;
+            while tell_frac + (loop_logp<<BITRES) < total_frac && boost < cap[i] {
+                let flag = rc.decode_bit_logp(loop_logp as u16);
+                tell_frac = rc.tell_frac() as i32;
+                if !flag {
+                    break;
+                }
+                boost += quanta;
+                total_frac -= quanta;
+                loop_logp = 1;
+            }
+            offsets[i] = boost;
+            //Making dynalloc more likely
+            if boost > 0 {
+                dynalloc_logp = max(2, dynalloc_logp - 1);
+            }
+        }
+
+        let alloc_trim = if tell_frac + (6<<BITRES) <= total_frac {
+            rc.decode_icdf(&tables::TRIM_ICDF, 7) as i32
+        } else {
+            5
+        };
+
+        let mut bits = ((total_bits as i32)<<BITRES) - rc.tell_frac() as i32 - 1;
+        let anti_collapse_rsv = if transient && lm >= 2 && bits >= (lm as i32 + 2)<<BITRES {
+            1<<BITRES
+        } else {
+            0
+        };
+        bits -= anti_collapse_rsv;
+        let alloc = rate::compute_allocation(rc, &frame, &offsets, &cap, alloc_trim, bits);
+        energy::unquant_fine(rc, &mut self.old_band_e, &frame, &alloc.fine_quant);
+
+        //Decode the normalised band shapes
+        let mut x = vec![0.0; c * n];
+        let shape = Shape {
+            short_blocks: transient,
+            spread,
+            disable_inv: self.disable_inv,
+            tf_res: &tf_res,
+            total_bits: ((total_bits as i32)<<BITRES) - anti_collapse_rsv,
+        };
+        let collapse_masks = bands::quant_all_bands(rc, &frame, &mut x, &alloc, &shape, &mut self.rng);
+
+        let anti_collapse = anti_collapse_rsv > 0 && rc.decode_bits(1) != 0;
+        let bits_left = total_bits as i32 - rc.tell() as i32;
+        energy::unquant_finalise(rc, &mut self.old_band_e, &frame, &alloc, bits_left);
+        if anti_collapse {
+            bands::anti_collapse(&mut x, &collapse_masks, &frame, &self.old_band_e, &self.old_log_e, &self.old_log_e2, &alloc.pulses, self.rng);
+        }
+
+        if silence {
+            self.old_band_e = [-28.0; 2 * NB_BANDS];
+        }
+
+        self.synthesis(&x, &frame, transient, silence);
+        self.postfilter(frame_size, postfilter);
+
+        if c == 1 {
+            let (first, second) = self.old_band_e.split_at_mut(NB_BANDS);
+            second.copy_from_slice(first);
+        }
+
+        if transient {
+            for (log_e, &band_e) in self.old_log_e.iter_mut().zip(self.old_band_e.iter()) {
+                *log_e = log_e.min(band_e);
+            }
+        } else {
+            self.old_log_e2 = self.old_log_e;
+            self.old_log_e = self.old_band_e;
+            //The noise floor may only rise by 2.4 dB/second, but by 6 dB per update after a long loss
+            let max_background_increase = if self.loss_count < 10 { (1<<lm) as f32 * 0.001 } else { 1.0 };
+            for (background, &band_e) in self.background_log_e.iter_mut().zip(self.old_band_e.iter()) {
+                *background = (*background + max_background_increase).min(band_e);
+            }
+        }
+
+        //Clear the bands that weren't coded, in case start or end change
+        for c in 0..2 {
+            for i in (0..frame.start).chain(frame.end..NB_BANDS) {
+                self.old_band_e[c * NB_BANDS + i] = 0.0;
+                self.old_log_e[c * NB_BANDS + i] = -28.0;
+                self.old_log_e2[c * NB_BANDS + i] = -28.0;
+            }
+        }
+
+        self.rng = rc.final_range();
+        self.deemphasis(frame_size, pcm);
+        self.loss_count = 0;
+    }
+
+    ///Scales the normalised bands by their energies, inverse transforms them and overlap-adds the result
+    ///with the previous frame. Transient frames hold `1<<lm` interleaved short blocks
+    fn synthesis(&mut self, x: &[f32], frame: &Frame, transient: bool, silence: bool) {
+        let lm = frame.lm;
+        let n = SHORT_MDCT_SIZE<<lm;
+        let (blocks, block_size, shift) = if transient {
+            (1<<lm, SHORT_MDCT_SIZE, MAX_LM)
+        } else {
+            (1, n, MAX_LM - lm)
+        };
+        let (start, end) = if silence { (0, 0) } else { (frame.start, frame.end) };
+
+        let channels = self.channels as usize;
+        let mut freq = vec![0.0; 2 * n];
+        {
+            let (freq0, freq1) = freq.split_at_mut(n);
+            let (band_e0, band_e1) = self.old_band_e.split_at(NB_BANDS);
+            match (channels, frame.channels) {
+                (2, 1) => {
+                    //Copying a mono stream to both channels
+                    bands::denormalise_bands(x, freq0, band_e0, start, end, lm, self.downsample);
+                    freq1.copy_from_slice(freq0);
+                },
+                (1, 2) => {
+                    //Downmixing a stereo stream to mono
+                    bands::denormalise_bands(&x[..n], freq0, band_e0, start, end, lm, self.downsample);
+                    bands::denormalise_bands(&x[n..], freq1, band_e1, start, end, lm, self.downsample);
+                    for (f0, &f1) in freq0.iter_mut().zip(freq1.iter()) {
+                        *f0 = 0.5 * *f0 + 0.5 * f1;
+                    }
+                },
+                _ => {
+                    bands::denormalise_bands(&x[..n], freq0, band_e0, start, end, lm, self.downsample);
+                    if channels == 2 {
+                        bands::denormalise_bands(&x[n..], freq1, band_e1, start, end, lm, self.downsample);
+                    }
+                },
+            }
+        }
+
+        for (c, mem) in self.decode_mem.iter_mut().take(channels).enumerate() {
+            //Drop the oldest frame while keeping the tail needed for the overlap-add
+            let len = mem.len();
+            mem.copy_within(n..len - OVERLAP / 2, 0);
+            let out_syn = &mut mem[DECODE_BUFFER_SIZE - n..];
+            let freq = &freq[c * n..(c + 1) * n];
+            for b in 0..blocks {
+                self.mdct.backward(&freq[b..], blocks, &mut out_syn[block_size * b..], &tables::WINDOW, OVERLAP, shift);
+            }
+        }
+    }
+
+    ///Runs the pitch post-filter over the last synthesized frame.
+    ///The first short block cross-fades from the previous frame's filter, the rest moves on to `postfilter`
+    fn postfilter(&mut self, frame_size: FrameSize, postfilter: PostFilter) {
+        let lm = lm(frame_size);
+        let n = SHORT_MDCT_SIZE<<lm;
+        let start = DECODE_BUFFER_SIZE - n;
+        self.postfilter.period = self.postfilter.period.max(postfilter::MIN_PERIOD);
+        self.postfilter_old.period = self.postfilter_old.period.max(postfilter::MIN_PERIOD);
+        for mem in self.decode_mem.iter_mut().take(self.channels as usize) {
+            postfilter::comb_filter(mem, start, SHORT_MDCT_SIZE, self.postfilter_old, self.postfilter, &tables::WINDOW, OVERLAP);
+            if lm != 0 {
+                postfilter::comb_filter(mem, start + SHORT_MDCT_SIZE, n - SHORT_MDCT_SIZE, self.postfilter, postfilter, &tables::WINDOW, OVERLAP);
+            }
+        }
+        self.postfilter_old = if lm != 0 { postfilter } else { self.postfilter };
+        self.postfilter = postfilter;
+    }
+
+    ///Applies the de-emphasis filter to the last synthesized frame and writes it interleaved into `pcm`,
+    ///keeping every `downsample`th sample
+    fn deemphasis(&mut self, frame_size: FrameSize, pcm: &mut [f32]) {
+        let n = SHORT_MDCT_SIZE<<lm(frame_size);
+        let channels = self.channels as usize;
+        let downsample = self.downsample;
+        debug_assert!(pcm.len() >= n / downsample * channels);
+        for (c, mem) in self.decode_mem.iter().take(channels).enumerate() {
+            let mut m = self.preemph_mem[c];
+            for (j, &x) in mem[DECODE_BUFFER_SIZE - n..DECODE_BUFFER_SIZE].iter().enumerate() {
+                let tmp = x + VERY_SMALL + m;
+                m = PREEMPHASIS * tmp;
+                if j % downsample == 0 {
+                    pcm[j / downsample * channels + c] = tmp * (1.0 / SIG_SCALE);
+                }
+            }
+            self.preemph_mem[c] = m;
+        }
+    }
+}
+
+///Decodes the per band time-frequency resolution changes
+fn tf_decode(rc: &mut range::Decoder, frame: &Frame, transient: bool) -> [i32; NB_BANDS] {
+    let mut budget = rc.storage_bits();
+    let mut tell = rc.tell();
+    let mut logp = if transient { 2 } else { 4 };
+    let tf_select_rsv = frame.lm > 0 && tell + logp < budget;
+    budget -= tf_select_rsv as usize;
+
+    let mut tf_res = [0; NB_BANDS];
+    let mut tf_changed = 0;
+    let mut curr = 0;
+    for tf in tf_res[frame.start..frame.end].iter_mut() {
+        if tell + logp <= budget {
+            curr ^= rc.decode_bit_logp(logp as u16) as usize;
+            tell = rc.tell();
+            tf_changed |= curr;
+        }
+        *tf = curr as i32;
+        logp = if transient { 4 } else { 5 };
+    }
+
+    let select = &tables::TF_SELECT[frame.lm][4 * transient as usize..];
+    let tf_select = if tf_select_rsv && select[tf_changed] != select[2 + tf_changed] {
+        rc.decode_bit_logp(1) as usize
+    } else {
+        0
+    };
+    for tf in tf_res[frame.start..frame.end].iter_mut() {
+        *tf = i32::from(select[2 * tf_select + *tf as usize]);
+    }
+    tf_res
+}
+
+///Log2 of the number of short mdcts in a frame
+fn lm(frame_size: FrameSize) -> usize {
+    match frame_size {
+        FrameSize::Ms2_5 => 0,
+        FrameSize::Ms5 => 1,
+        FrameSize::Ms10 => 2,
+        FrameSize::Ms20 => 3,
+        _ => panic!("celt does not support frame size {:?}", frame_size),
+    }
+}
+
+///First band that isn't coded at a given bandwidth
+fn end_band(bandwidth: Bandwidth) -> usize {
+    match bandwidth {
+        Bandwidth::Narrow => 13,
+        Bandwidth::Medium | Bandwidth::Wide => 17,
+        Bandwidth::SuperWide => 19,
+        Bandwidth::Full => 21,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitstream::{BitPacket, Reader};
+
+    ///Fullband stereo CELT packets of 20 ms with intensity stereo from band 10, the last five with dual stereo below it.
+    ///The channels are hardly correlated
+    pub static STEREO: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/celt_stereo.bit"));
+
+    pub fn packets(data: &[u8]) -> Vec<BitPacket> {
+        let mut reader = Reader::new(data);
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.read_packet().unwrap() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    ///Decodes the packets, which have to be CELT only with a single frame, and checks their final ranges
+    pub fn decode(decoder: &mut Decoder, packets: &[BitPacket]) -> Vec<f32> {
+        let channels = decoder.channels() as usize;
+        let mut pcm = vec![0.0; packets.len() * 960 * channels];
+        for (packet, pcm) in packets.iter().zip(pcm.chunks_mut(960 * channels)) {
+            let stereo = if packet.data[0] & 0x4 != 0 { Channels::Stereo } else { Channels::Mono };
+            let mut rc = range::Decoder::new(&packet.data[1..]);
+            decoder.decode(&mut rc, FrameSize::Ms20, Bandwidth::Full, stereo, 0, pcm);
+            assert_eq!(rc.final_range(), packet.final_range);
+        }
+        pcm
+    }
+
+    fn rms(x: &[f32]) -> f32 {
+        (x.iter().map(|x| x * x).sum::<f32>() / x.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn stereo() {
+        let mut decoder = Decoder::new(SampleRate::Khz48, Channels::Stereo);
+        let pcm = decode(&mut decoder, &packets(STEREO));
+        let left: Vec<f32> = pcm.iter().step_by(2).cloned().collect();
+        let right: Vec<f32> = pcm.iter().skip(1).step_by(2).cloned().collect();
+        //Levels of the reference decoder's output
+        assert!((rms(&left) - 0.100_983).abs() < 1e-4);
+        assert!((rms(&right) - 0.098_678).abs() < 1e-4);
+        let correlation = left.iter().zip(right.iter()).map(|(l, r)| l * r).sum::<f32>() / (left.len() as f32 * rms(&left) * rms(&right));
+        assert!((correlation - 0.1077).abs() < 1e-3);
+
+        //A mono decoder mixes the channels before the synthesis
+        let mut decoder = Decoder::new(SampleRate::Khz48, Channels::Mono);
+        let mono = decode(&mut decoder, &packets(STEREO));
+        assert!((rms(&mono) - 0.074_307).abs() < 1e-4);
+    }
+}
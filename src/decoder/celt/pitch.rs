@@ -0,0 +1,124 @@
+use super::lpc;
+
+///Low-passes and decimates the channels by two, then whitens the result with a fifth order filter
+pub fn downsample(x: &[&[f32]], x_lp: &mut [f32]) {
+    for (c, x) in x.iter().enumerate() {
+        for (i, lp) in x_lp.iter_mut().enumerate() {
+            let val = if i == 0 {
+                0.5 * (0.5 * x[1] + x[0])
+            } else {
+                0.5 * (0.5 * (x[2 * i - 1] + x[2 * i + 1]) + x[2 * i])
+            };
+            *lp = if c == 0 { val } else { *lp + val };
+        }
+    }
+
+    let mut ac = [0.0; 5];
+    lpc::autocorr(x_lp, &mut ac, None);
+    //Noise floor -40 dB
+    ac[0] *= 1.0001;
+    //Lag windowing
+    for (i, ac) in ac.iter_mut().enumerate().skip(1) {
+        *ac -= *ac * (0.008 * i as f32) * (0.008 * i as f32);
+    }
+
+    let mut coef = [0.0; 4];
+    lpc::lpc(&mut coef, &ac);
+    let mut tmp = 1.0;
+    for coef in coef.iter_mut() {
+        tmp *= 0.9;
+        *coef *= tmp;
+    }
+    //Add a zero
+    let c1 = 0.8;
+    let num = [
+        coef[0] + 0.8,
+        coef[1] + c1 * coef[0],
+        coef[2] + c1 * coef[1],
+        coef[3] + c1 * coef[2],
+        c1 * coef[3],
+    ];
+
+    let mut mem = [0.0; 5];
+    for x in x_lp.iter_mut() {
+        let sum = *x + num[0] * mem[0] + num[1] * mem[1] + num[2] * mem[2] + num[3] * mem[3] + num[4] * mem[4];
+        mem = [*x, mem[0], mem[1], mem[2], mem[3]];
+        *x = sum;
+    }
+}
+
+///Searches the lag in `0..max_pitch` that best correlates `x_lp` with `y`, both decimated by two.
+///Runs a coarse search at a further decimation by two first
+pub fn search(x_lp: &[f32], y: &[f32], len: usize, max_pitch: usize) -> usize {
+    let lag = len + max_pitch;
+
+    //Coarse search with 4x decimation
+    let x_lp4: Vec<f32> = x_lp.iter().step_by(2).take(len>>2).cloned().collect();
+    let y_lp4: Vec<f32> = y.iter().step_by(2).take(lag>>2).cloned().collect();
+    let mut xcorr = vec![0.0; max_pitch>>1];
+    for (i, xcorr) in xcorr[..max_pitch>>2].iter_mut().enumerate() {
+        *xcorr = inner_prod(&x_lp4, &y_lp4[i..]);
+    }
+    let best_pitch = find_best_pitch(&xcorr, &y_lp4, len>>2, max_pitch>>2);
+
+    //Finer search with 2x decimation around the two best candidates
+    for (i, xcorr) in xcorr.iter_mut().enumerate() {
+        let near = |pitch: usize| (i as i32 - 2 * pitch as i32).abs() <= 2;
+        *xcorr = if near(best_pitch[0]) || near(best_pitch[1]) {
+            inner_prod(&x_lp[..len>>1], &y[i..]).max(-1.0)
+        } else {
+            0.0
+        };
+    }
+    let best_pitch = find_best_pitch(&xcorr, y, len>>1, max_pitch>>1);
+
+    //Refine by pseudo-interpolation
+    let mut pitch = 2 * best_pitch[0];
+    if best_pitch[0] > 0 && best_pitch[0] < (max_pitch>>1) - 1 {
+        let a = xcorr[best_pitch[0] - 1];
+        let b = xcorr[best_pitch[0]];
+        let c = xcorr[best_pitch[0] + 1];
+        if c - a > 0.7 * (b - a) {
+            pitch -= 1;
+        } else if a - c > 0.7 * (b - c) {
+            pitch += 1;
+        }
+    }
+    pitch
+}
+
+fn inner_prod(x: &[f32], y: &[f32]) -> f32 {
+    x.iter().zip(y).fold(0.0, |sum, (&x, &y)| sum + x * y)
+}
+
+///Finds the two lags with the highest normalised correlation
+fn find_best_pitch(xcorr: &[f32], y: &[f32], len: usize, max_pitch: usize) -> [usize; 2] {
+    let mut syy = y[..len].iter().fold(1.0, |syy, &y| syy + y * y);
+    let mut best_num = [-1.0; 2];
+    let mut best_den = [0.0; 2];
+    let mut best_pitch = [0, 1];
+    for (i, &xcorr) in xcorr[..max_pitch].iter().enumerate() {
+        if xcorr > 0.0 {
+            //Keeps the square of the correlation clear of overflows
+            let xcorr16 = xcorr * 1e-12;
+            let num = xcorr16 * xcorr16;
+            if num * best_den[1] > best_num[1] * syy {
+                if num * best_den[0] > best_num[0] * syy {
+                    best_num[1] = best_num[0];
+                    best_den[1] = best_den[0];
+                    best_pitch[1] = best_pitch[0];
+                    best_num[0] = num;
+                    best_den[0] = syy;
+                    best_pitch[0] = i;
+                } else {
+                    best_num[1] = num;
+                    best_den[1] = syy;
+                    best_pitch[1] = i;
+                }
+            }
+        }
+        syy += y[i + len] * y[i + len] - y[i] * y[i];
+        syy = syy.max(1.0);
+    }
+    best_pitch
+}
@@ -0,0 +1,243 @@
+use common::types::{Bandwidth, FrameSize};
+use super::bands;
+use super::lpc::{self, LPC_ORDER};
+use super::pitch;
+use super::postfilter::{self, PostFilter, MAX_PERIOD};
+use super::tables;
+use super::vq;
+use super::{end_band, lm, Decoder, Frame, DECODE_BUFFER_SIZE, NB_BANDS, OVERLAP, SHORT_MDCT_SIZE};
+
+///Range of pitch periods the concealment looks for
+const PITCH_LAG_MAX: usize = 720;
+const PITCH_LAG_MIN: usize = 100;
+
+impl Decoder {
+    ///Conceals a lost frame and writes it interleaved into `pcm`.
+    ///Repeats the last pitch period with a decaying amplitude, and falls back to noise at the
+    ///previous band energies once several frames in a row were lost. Hybrid frames, starting at
+    ///band `start`, are always concealed with noise. The bands up to `bandwidth` are concealed,
+    ///or the ones of the last frame if it isn't known
+    pub fn decode_lost(&mut self, frame_size: FrameSize, bandwidth: Option<Bandwidth>, start: usize, pcm: &mut [f32]) {
+        let lm = lm(frame_size);
+        self.start = start;
+        if let Some(bandwidth) = bandwidth {
+            self.end = end_band(bandwidth);
+        }
+        if self.loss_count >= 5 || self.start != 0 || self.skip_plc {
+            self.conceal_noise(lm);
+        } else {
+            self.conceal_pitch(lm);
+        }
+        self.loss_count += 1;
+        self.deemphasis(frame_size, pcm);
+    }
+
+    ///Synthesizes noise shaped by the band energies, which decay down to the background noise
+    fn conceal_noise(&mut self, lm: usize) {
+        let channels = self.channels as usize;
+        let n = SHORT_MDCT_SIZE<<lm;
+        let frame = Frame {
+            start: self.start,
+            end: self.end.min(NB_BANDS).max(self.start),
+            lm,
+            channels,
+        };
+
+        //Energy decay
+        let decay = if self.loss_count == 0 { 1.5 } else { 0.5 };
+        for c in 0..channels {
+            for i in self.start..self.end {
+                let e = &mut self.old_band_e[c * NB_BANDS + i];
+                *e = self.background_log_e[c * NB_BANDS + i].max(*e - decay);
+            }
+        }
+
+        let mut x = vec![0.0; channels * n];
+        let mut seed = self.rng;
+        for c in 0..channels {
+            for i in frame.start..frame.end {
+                let band = &mut x[c * n + (tables::EBANDS[i]<<lm)..c * n + (tables::EBANDS[i + 1]<<lm)];
+                for x in band.iter_mut() {
+                    seed = bands::lcg_rand(seed);
+                    *x = (seed as i32>>20) as f32;
+                }
+                vq::renormalise_vector(band, 1.0);
+            }
+        }
+        self.rng = seed;
+
+        self.synthesis(&x, &frame, false, false);
+    }
+
+    ///Extends the signal periodically with the pitch of the last frames. The extension runs through the
+    ///inverse of the signal's prediction filter, so only the excitation is repeated
+    fn conceal_pitch(&mut self, lm: usize) {
+        let channels = self.channels as usize;
+        let n = SHORT_MDCT_SIZE<<lm;
+        let window = &tables::WINDOW;
+
+        let mut fade = 1.0;
+        if self.loss_count == 0 {
+            self.last_pitch = self.pitch_search();
+        } else {
+            fade = 0.8;
+        }
+        let pitch = self.last_pitch;
+
+        //We want the excitation for 2 pitch periods in order to look for a decaying signal,
+        //but we can't get more than MAX_PERIOD
+        let exc_length = (2 * pitch).min(MAX_PERIOD);
+        let extrapolation_len = n + OVERLAP;
+
+        for (c, buf) in self.decode_mem.iter_mut().take(channels).enumerate() {
+            //The last MAX_PERIOD samples with LPC_ORDER samples of history in front
+            let mut exc = buf[DECODE_BUFFER_SIZE - MAX_PERIOD - LPC_ORDER..DECODE_BUFFER_SIZE].to_vec();
+            let lpc = &mut self.lpc[c];
+
+            if self.loss_count == 0 {
+                //Compute the prediction filter for the last MAX_PERIOD samples before the first loss,
+                //so we can work in the excitation domain
+                let mut ac = [0.0; LPC_ORDER + 1];
+                lpc::autocorr(&exc[LPC_ORDER..], &mut ac, Some(window));
+                //Add a noise floor of -40 dB
+                ac[0] *= 1.0001;
+                //Use lag windowing to stabilize the Levinson-Durbin recursion
+                for (i, ac) in ac.iter_mut().enumerate().skip(1) {
+                    *ac -= *ac * (0.008 * 0.008) * i as f32 * i as f32;
+                }
+                lpc::lpc(lpc, &ac);
+            }
+
+            //Compute the excitation for exc_length samples before the loss
+            {
+                let start = LPC_ORDER + MAX_PERIOD - exc_length;
+                let mut fir_tmp = vec![0.0; exc_length];
+                lpc::fir(&exc[start - LPC_ORDER..], lpc, &mut fir_tmp);
+                exc[start..start + exc_length].copy_from_slice(&fir_tmp);
+            }
+            let exc = &exc[LPC_ORDER..];
+
+            //Check if the waveform is decaying, and if so how fast. We do this to avoid adding energy
+            //when concealing in a segment with decaying energy
+            let decay = {
+                let decay_length = exc_length>>1;
+                let mut e1 = 1.0f32;
+                let mut e2 = 1.0f32;
+                for i in 0..decay_length {
+                    let e = exc[MAX_PERIOD - decay_length + i];
+                    e1 += e * e;
+                    let e = exc[MAX_PERIOD - 2 * decay_length + i];
+                    e2 += e * e;
+                }
+                (e1.min(e2) / e2).sqrt()
+            };
+
+            //Move the memory one frame to the left, ignoring the overlap past the end of the buffer
+            buf.copy_within(n..DECODE_BUFFER_SIZE, 0);
+
+            //Extrapolate from the end of the excitation with a period of pitch, scaling down each period
+            //by another factor of decay. Covers a complete mdct window with overlap/2 samples on both sides
+            let extrapolation_offset = MAX_PERIOD - pitch;
+            let mut attenuation = fade * decay;
+            let mut s1 = 0.0;
+            let mut j = 0;
+            for i in 0..extrapolation_len {
+                if j >= pitch {
+                    j -= pitch;
+                    attenuation *= decay;
+                }
+                buf[DECODE_BUFFER_SIZE - n + i] = attenuation * exc[extrapolation_offset + j];
+                //Energy of the previously decoded signal whose excitation we're copying
+                let tmp = buf[DECODE_BUFFER_SIZE - MAX_PERIOD - n + extrapolation_offset + j];
+                s1 += tmp * tmp;
+                j += 1;
+            }
+
+            //Apply the synthesis filter, continuing from the last decoded samples before the overlap
+            let mut lpc_mem = [0.0; LPC_ORDER];
+            for (i, mem) in lpc_mem.iter_mut().enumerate() {
+                *mem = buf[DECODE_BUFFER_SIZE - n - 1 - i];
+            }
+            let out = &mut buf[DECODE_BUFFER_SIZE - n..DECODE_BUFFER_SIZE - n + extrapolation_len];
+            lpc::iir(out, lpc, &lpc_mem);
+
+            //Attenuate if the synthesis has more energy than expected, which happens when the signal
+            //changes during our window. NaNs out of the filter silence the frame as well
+            let s2 = out.iter().fold(0.0, |s2, &x| s2 + x * x);
+            if s1 <= 0.2 * s2 || s2.is_nan() {
+                for x in out.iter_mut() {
+                    *x = 0.0;
+                }
+            } else if s1 < s2 {
+                let ratio = ((s1 + 1.0) / (s2 + 1.0)).sqrt();
+                for (x, &w) in out.iter_mut().zip(window.iter()) {
+                    *x *= 1.0 - w * (1.0 - ratio);
+                }
+                for x in out[OVERLAP..].iter_mut() {
+                    *x *= ratio;
+                }
+            }
+
+            //Apply the pre-filter to the mdct overlap of the next frame, since the post-filter
+            //is applied again after the next frame's overlap-add
+            let mut etmp = [0.0; OVERLAP];
+            let prefilter = PostFilter {
+                gain: -self.postfilter.gain,
+                ..self.postfilter
+            };
+            postfilter::comb_filter_const(&mut etmp, buf, DECODE_BUFFER_SIZE, prefilter);
+
+            //Simulate TDAC on the concealed audio so that it blends with the mdct of the next frame
+            for i in 0..OVERLAP / 2 {
+                buf[DECODE_BUFFER_SIZE + i] = window[i] * etmp[OVERLAP - 1 - i] + window[OVERLAP - i - 1] * etmp[i];
+            }
+        }
+    }
+
+    ///Finds the pitch period of the last decoded samples
+    fn pitch_search(&self) -> usize {
+        let channels: Vec<&[f32]> = self.decode_mem.iter()
+            .take(self.channels as usize)
+            .map(|mem| &mem[..DECODE_BUFFER_SIZE])
+            .collect();
+        let mut lp_pitch_buf = [0.0; DECODE_BUFFER_SIZE>>1];
+        pitch::downsample(&channels, &mut lp_pitch_buf);
+        let pitch = pitch::search(
+            &lp_pitch_buf[PITCH_LAG_MAX>>1..],
+            &lp_pitch_buf,
+            DECODE_BUFFER_SIZE - PITCH_LAG_MAX,
+            PITCH_LAG_MAX - PITCH_LAG_MIN,
+        );
+        PITCH_LAG_MAX - pitch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::types::{Channels, SampleRate};
+    use super::*;
+    use super::super::tests::{decode, packets, STEREO};
+
+    fn rms(x: &[f32]) -> f32 {
+        (x.iter().map(|x| x * x).sum::<f32>() / x.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn decays() {
+        let mut decoder = Decoder::new(SampleRate::Khz48, Channels::Stereo);
+        let pcm = decode(&mut decoder, &packets(STEREO));
+        let last = rms(&pcm[pcm.len() - 1920..]);
+
+        let mut levels = Vec::new();
+        let mut lost = [0.0; 1920];
+        for _ in 0..8 {
+            decoder.decode_lost(FrameSize::Ms20, None, 0, &mut lost);
+            assert!(lost.iter().all(|x| x.is_finite()));
+            levels.push(rms(&lost));
+        }
+        //The pitch repetition keeps the level at first, then it fades and turns into decaying noise
+        assert!(levels[0] > 0.5 * last);
+        assert!(levels[1..].windows(2).all(|pair| pair[1] < pair[0]));
+        assert!(levels[7] < 0.25 * last);
+    }
+}
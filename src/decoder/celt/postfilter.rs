@@ -0,0 +1,191 @@
+use range;
+
+///Shortest period the comb filter runs at
+pub const MIN_PERIOD: usize = 15;
+///Longest period the comb filter can be signalled with
+pub const MAX_PERIOD: usize = 1024;
+
+static TAPSET_ICDF: [u8; 3] = [2, 1, 0];
+
+static TAPS: [[f32; 3]; 3] = [
+    [0.306_640_63, 0.217_041_02, 0.129_638_67],
+    [0.463_867_2, 0.268_066_4, 0.0],
+    [0.799_804_7, 0.100_097_656, 0.0],
+];
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+///Pitch post-filter parameters of a frame
+pub struct PostFilter {
+    pub period: usize,
+    pub gain: f32,
+    pub tapset: usize,
+}
+
+impl PostFilter {
+    ///Decodes the post-filter flag and, if set, the filter parameters
+    pub fn decode(rc: &mut range::Decoder, total_bits: usize) -> Self {
+        if !rc.decode_bit_logp(1) {
+            return Self::default();
+        }
+        let octave = rc.decode_uniform(6) as usize;
+        let period = (16<<octave) + rc.decode_bits(4 + octave) as usize - 1;
+        let gain = 0.09375 * (rc.decode_bits(3) + 1) as f32;
+        let tapset = if rc.tell() + 2 <= total_bits {
+            rc.decode_icdf(&TAPSET_ICDF, 2)
+        } else {
+            0
+        };
+        Self {
+            period,
+            gain,
+            tapset,
+        }
+    }
+
+    fn taps(&self) -> [f32; 3] {
+        let taps = TAPS[self.tapset];
+        [self.gain * taps[0], self.gain * taps[1], self.gain * taps[2]]
+    }
+}
+
+///Runs the comb filter in place over `buf[start..start + n]`, cross-fading from `old` to `new`
+///over the first `overlap` samples. `buf` needs `MAX_PERIOD + 2` samples of history before `start`
+pub fn comb_filter(buf: &mut [f32], start: usize, n: usize, old: PostFilter, new: PostFilter, window: &[f32], overlap: usize) {
+    if old.gain == 0.0 && new.gain == 0.0 {
+        return;
+    }
+    let t0 = old.period.max(MIN_PERIOD);
+    let t1 = new.period.max(MIN_PERIOD);
+    let g0 = old.taps();
+    let g1 = new.taps();
+
+    //If the filter didn't change we don't need the cross-fade
+    let overlap = if old.gain == new.gain && t0 == t1 && old.tapset == new.tapset {
+        0
+    } else {
+        overlap
+    };
+
+    let mut x1 = buf[start + 1 - t1];
+    let mut x2 = buf[start - t1];
+    let mut x3 = buf[start - t1 - 1];
+    let mut x4 = buf[start - t1 - 2];
+    for (i, &w) in window[..overlap].iter().enumerate() {
+        let s = start + i;
+        let x0 = buf[s + 2 - t1];
+        let f = w * w;
+        buf[s] = buf[s]
+            + ((1.0 - f) * g0[0]) * buf[s - t0]
+            + ((1.0 - f) * g0[1]) * (buf[s + 1 - t0] + buf[s - t0 - 1])
+            + ((1.0 - f) * g0[2]) * (buf[s + 2 - t0] + buf[s - t0 - 2])
+            + (f * g1[0]) * x2
+            + (f * g1[1]) * (x1 + x3)
+            + (f * g1[2]) * (x0 + x4);
+        x4 = x3;
+        x3 = x2;
+        x2 = x1;
+        x1 = x0;
+    }
+
+    if new.gain == 0.0 {
+        return;
+    }
+
+    for s in start + overlap..start + n {
+        let x0 = buf[s + 2 - t1];
+        buf[s] = buf[s]
+            + g1[0] * x2
+            + g1[1] * (x1 + x3)
+            + g1[2] * (x0 + x4);
+        x4 = x3;
+        x3 = x2;
+        x2 = x1;
+        x1 = x0;
+    }
+}
+
+///Runs the comb filter with fixed parameters over `buf[start..start + out.len()]` and writes the result to `out`
+pub fn comb_filter_const(out: &mut [f32], buf: &[f32], start: usize, filter: PostFilter) {
+    let t = filter.period;
+    let g = filter.taps();
+    for (i, out) in out.iter_mut().enumerate() {
+        let s = start + i;
+        *out = if filter.gain == 0.0 {
+            buf[s]
+        } else {
+            buf[s]
+                + g[0] * buf[s - t]
+                + g[1] * (buf[s + 1 - t] + buf[s - t - 1])
+                + g[2] * (buf[s + 2 - t] + buf[s - t - 2])
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tables::WINDOW;
+
+    const HISTORY: usize = MAX_PERIOD + 2;
+
+    fn filter(period: usize, tapset: usize) -> PostFilter {
+        PostFilter { period, gain: 0.375, tapset }
+    }
+
+    #[test]
+    fn impulse_response() {
+        let pf = filter(100, 1);
+        let g = pf.taps();
+        let mut buf = vec![0.0; HISTORY + 300];
+        buf[HISTORY] = 1.0;
+        comb_filter(&mut buf, HISTORY, 300, pf, pf, &WINDOW, WINDOW.len());
+        let out = &buf[HISTORY..];
+
+        //The first echo comes a period later through the three centre taps, the second one feeds back on it
+        assert_eq!(out[0], 1.0);
+        assert!(out[1..99].iter().all(|&x| x == 0.0));
+        assert_eq!((out[99], out[100], out[101]), (g[1], g[0], g[1]));
+        assert!(out[102..198].iter().all(|&x| x == 0.0));
+        assert!((out[200] - (g[0] * g[0] + 2.0 * g[1] * g[1])).abs() < 1e-6);
+        assert!((out[199] - 2.0 * g[0] * g[1]).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cross_fade() {
+        //The filter switches on over the overlap, an echo there is weighted by the squared window
+        let pf = filter(200, 0);
+        let g = pf.taps();
+        let mut buf = vec![0.0; HISTORY + 300];
+        buf[HISTORY - 190] = 1.0;
+        buf[HISTORY - 50] = 1.0;
+        comb_filter(&mut buf, HISTORY, 300, PostFilter::default(), pf, &WINDOW, WINDOW.len());
+        let out = &buf[HISTORY..];
+        let f = |i: usize| WINDOW[i] * WINDOW[i];
+        assert!((out[10] - f(10) * g[0]).abs() < 1e-6);
+        assert!((out[11] - f(11) * g[1]).abs() < 1e-6);
+        assert!((out[12] - f(12) * g[2]).abs() < 1e-6);
+        //Past the overlap the filter runs at full gain
+        assert_eq!((out[149], out[150], out[151], out[152]), (g[1], g[0], g[1], g[2]));
+
+        //Off on both sides the buffer isn't touched
+        let before = buf.clone();
+        comb_filter(&mut buf, HISTORY, 300, PostFilter::default(), PostFilter::default(), &WINDOW, WINDOW.len());
+        assert_eq!(buf, before);
+    }
+
+    #[test]
+    fn constant() {
+        //Reads the history unfiltered, so every input sample gives a single echo
+        let pf = filter(100, 2);
+        let g = pf.taps();
+        let mut buf = vec![0.0; HISTORY + 300];
+        buf[HISTORY] = 1.0;
+        let mut out = vec![0.0; 300];
+        comb_filter_const(&mut out, &buf, HISTORY, pf);
+        assert_eq!((out[0], out[99], out[100], out[101]), (1.0, g[1], g[0], g[1]));
+        assert!(out[102..].iter().all(|&x| x == 0.0));
+
+        comb_filter_const(&mut out, &buf, HISTORY, PostFilter::default());
+        assert_eq!(out[..], buf[HISTORY..]);
+    }
+}
@@ -0,0 +1,404 @@
+use std::cmp::{max, min};
+use range;
+use super::tables;
+use super::energy::MAX_FINE_BITS;
+use super::{Frame, NB_BANDS};
+
+///Fractional bits used by the allocation, everything is in 1/8 bits
+pub const BITRES: i32 = 3;
+const FINE_OFFSET: i32 = 21;
+const ALLOC_STEPS: i32 = 6;
+const LOG_MAX_PSEUDO: usize = 6;
+
+///Result of the bit allocation of a frame
+pub struct Allocation {
+    ///Bits for the shape of every band
+    pub pulses: [i32; NB_BANDS],
+    ///Fine energy bits per band and channel
+    pub fine_quant: [i32; NB_BANDS],
+    ///Whether a band gets its final fine energy bit in the first or second pass
+    pub fine_priority: [bool; NB_BANDS],
+    ///Bands above this one get no shape bits and are folded
+    pub coded_bands: usize,
+    ///First band coded with intensity stereo
+    pub intensity: usize,
+    pub dual_stereo: bool,
+    ///Bits left over the caps, handed on to the shape quantisation
+    pub balance: i32,
+}
+
+fn cache(band: usize, lm: i32) -> &'static [u8] {
+    let index = tables::CACHE_INDEX[(lm + 1) as usize * NB_BANDS + band];
+    &tables::CACHE_BITS[index as usize..]
+}
+
+///Number of pulses for a pseudo pulse count
+pub fn get_pulses(i: i32) -> i32 {
+    if i < 8 {
+        i
+    } else {
+        (8 + (i & 7))<<((i>>3) - 1)
+    }
+}
+
+///Largest pseudo pulse count that fits in `bits`
+pub fn bits2pulses(band: usize, lm: i32, bits: i32) -> i32 {
+    let cache = cache(band, lm);
+    let bits = bits - 1;
+    let mut lo = 0;
+    let mut hi = cache[0] as usize;
+    for _ in 0..LOG_MAX_PSEUDO {
+        let mid = (lo + hi + 1)>>1;
+        if i32::from(cache[mid]) >= bits {
+            hi = mid;
+        } else {
+            lo = mid;
+        }
+    }
+    let lo_bits = if lo == 0 { -1 } else { i32::from(cache[lo]) };
+    if bits - lo_bits <= i32::from(cache[hi]) - bits {
+        lo as i32
+    } else {
+        hi as i32
+    }
+}
+
+pub fn pulses2bits(band: usize, lm: i32, pulses: i32) -> i32 {
+    if pulses == 0 {
+        0
+    } else {
+        i32::from(cache(band, lm)[pulses as usize]) + 1
+    }
+}
+
+///Whether a band of `n` samples at `b` bits gets split in two halves
+pub fn should_split(band: usize, lm: i32, b: i32, n: usize) -> bool {
+    if lm == -1 || n <= 2 {
+        return false;
+    }
+    let cache = cache(band, lm);
+    b > i32::from(cache[cache[0] as usize]) + 12
+}
+
+///Maximum useful bits per band
+pub fn init_caps(lm: usize, channels: usize) -> [i32; NB_BANDS] {
+    let mut cap = [0; NB_BANDS];
+    for (i, cap) in cap.iter_mut().enumerate() {
+        let n = ((tables::EBANDS[i + 1] - tables::EBANDS[i])<<lm) as i32;
+        let caps = i32::from(tables::CACHE_CAPS[NB_BANDS * (2 * lm + channels - 1) + i]);
+        *cap = ((caps + 64) * channels as i32 * n)>>2;
+    }
+    cap
+}
+
+///Interpolation between two rows of the static allocation
+struct Interpolation<'a> {
+    bits1: [i32; NB_BANDS],
+    bits2: [i32; NB_BANDS],
+    thresh: [i32; NB_BANDS],
+    cap: &'a [i32],
+    skip_start: usize,
+    skip_rsv: i32,
+    intensity_rsv: i32,
+    dual_stereo_rsv: i32,
+}
+
+///Splits `total` bits between the bands of a frame, decoding the band skipping, intensity and
+///dual stereo parameters on the way
+pub fn compute_allocation(rc: &mut range::Decoder, frame: &Frame, offsets: &[i32], cap: &[i32], alloc_trim: i32, total: i32) -> Allocation {
+    let Frame { start, end, lm, channels } = *frame;
+    let c = channels as i32;
+    let ebands = |i: usize| tables::EBANDS[i] as i32;
+    let mut total = max(total, 0);
+    let mut skip_start = start;
+    //Reserve a bit to signal the end of manually skipped bands
+    let skip_rsv = if total >= 1<<BITRES { 1<<BITRES } else { 0 };
+    total -= skip_rsv;
+    //Reserve bits for the intensity and dual stereo parameters
+    let mut intensity_rsv = 0;
+    let mut dual_stereo_rsv = 0;
+    if channels == 2 {
+        intensity_rsv = tables::LOG2_FRAC[end - start];
+        if intensity_rsv > total {
+            intensity_rsv = 0;
+        } else {
+            total -= intensity_rsv;
+            dual_stereo_rsv = if total >= 1<<BITRES { 1<<BITRES } else { 0 };
+            total -= dual_stereo_rsv;
+        }
+    }
+
+    let mut bits1 = [0; NB_BANDS];
+    let mut bits2 = [0; NB_BANDS];
+    let mut thresh = [0; NB_BANDS];
+    let mut trim_offset = [0; NB_BANDS];
+    for j in start..end {
+        let width = ebands(j + 1) - ebands(j);
+        //Below this threshold, we're sure not to allocate any pvq bits
+        thresh[j] = max(c<<BITRES, ((3 * width)<<lm<<BITRES)>>4);
+        //Tilt of the allocation curve
+        trim_offset[j] = (c * width * (alloc_trim - 5 - lm as i32) * (end - j - 1) as i32 * (1<<(lm as i32 + BITRES)))>>6;
+        //Single coefficient bands benefit more from a coarse value per coefficient
+        if width<<lm == 1 {
+            trim_offset[j] -= c<<BITRES;
+        }
+    }
+
+    let alloc = |vector: usize, j: usize| (c * (ebands(j + 1) - ebands(j)) * i32::from(tables::BAND_ALLOCATION[vector][j]))<<lm>>2;
+    let mut lo = 1;
+    let mut hi = tables::BAND_ALLOCATION.len() - 1;
+    while lo <= hi {
+        let mut done = false;
+        let mut psum = 0;
+        let mid = (lo + hi)>>1;
+        for j in (start..end).rev() {
+            let mut bitsj = alloc(mid, j);
+            if bitsj > 0 {
+                bitsj = max(0, bitsj + trim_offset[j]);
+            }
+            bitsj += offsets[j];
+            if bitsj >= thresh[j] || done {
+                done = true;
+                //Don't allocate more than we can actually use
+                psum += min(bitsj, cap[j]);
+            } else if bitsj >= c<<BITRES {
+                psum += c<<BITRES;
+            }
+        }
+        if psum > total {
+            hi = mid - 1;
+        } else {
+            lo = mid + 1;
+        }
+    }
+    let hi = lo;
+    let lo = lo - 1;
+    for j in start..end {
+        let mut bits1j = alloc(lo, j);
+        let mut bits2j = if hi >= tables::BAND_ALLOCATION.len() { cap[j] } else { alloc(hi, j) };
+        if bits1j > 0 {
+            bits1j = max(0, bits1j + trim_offset[j]);
+        }
+        if bits2j > 0 {
+            bits2j = max(0, bits2j + trim_offset[j]);
+        }
+        if lo > 0 {
+            bits1j += offsets[j];
+        }
+        bits2j += offsets[j];
+        if offsets[j] > 0 {
+            skip_start = j;
+        }
+        bits1[j] = bits1j;
+        bits2[j] = max(0, bits2j - bits1j);
+    }
+
+    let interp = Interpolation {
+        bits1,
+        bits2,
+        thresh,
+        cap,
+        skip_start,
+        skip_rsv,
+        intensity_rsv,
+        dual_stereo_rsv,
+    };
+    interp.bits2pulses(rc, frame, total)
+}
+
+impl<'a> Interpolation<'a> {
+    fn bits2pulses(self, rc: &mut range::Decoder, frame: &Frame, mut total: i32) -> Allocation {
+        let Interpolation { bits1, bits2, thresh, cap, skip_start, skip_rsv, mut intensity_rsv, mut dual_stereo_rsv } = self;
+        let Frame { start, end, lm, channels } = *frame;
+        let c = channels as i32;
+        let stereo = (channels > 1) as i32;
+        let ebands = |i: usize| tables::EBANDS[i] as i32;
+        let alloc_floor = c<<BITRES;
+        let log_m = (lm as i32)<<BITRES;
+
+        let mut lo = 0;
+        let mut hi = 1<<ALLOC_STEPS;
+        for _ in 0..ALLOC_STEPS {
+            let mid = (lo + hi)>>1;
+            let mut psum = 0;
+            let mut done = false;
+            for j in (start..end).rev() {
+                let tmp = bits1[j] + ((mid * bits2[j])>>ALLOC_STEPS);
+                if tmp >= thresh[j] || done {
+                    done = true;
+                    //Don't allocate more than we can actually use
+                    psum += min(tmp, cap[j]);
+                } else if tmp >= alloc_floor {
+                    psum += alloc_floor;
+                }
+            }
+            if psum > total {
+                hi = mid;
+            } else {
+                lo = mid;
+            }
+        }
+
+        let mut bits = [0; NB_BANDS];
+        let mut psum = 0;
+        let mut done = false;
+        for j in (start..end).rev() {
+            let mut tmp = bits1[j] + ((lo * bits2[j])>>ALLOC_STEPS);
+            if tmp < thresh[j] && !done {
+                tmp = if tmp >= alloc_floor { alloc_floor } else { 0 };
+            } else {
+                done = true;
+            }
+            //Don't allocate more than we can actually use
+            tmp = min(tmp, cap[j]);
+            bits[j] = tmp;
+            psum += tmp;
+        }
+
+        //Decide which bands to skip, working backwards from the end
+        let mut coded_bands = end;
+        loop {
+            let j = coded_bands - 1;
+            //Never skip the first band, nor a band that has been boosted by dynalloc
+            if j <= skip_start {
+                //Give the bit we reserved to end skipping back
+                total += skip_rsv;
+                break;
+            }
+            //Figure out how many left-over bits we would be adding to this band
+            let mut left = total - psum;
+            let percoeff = left / (ebands(coded_bands) - ebands(start));
+            left -= (ebands(coded_bands) - ebands(start)) * percoeff;
+            let rem = max(left - (ebands(j) - ebands(start)), 0);
+            let band_width = ebands(coded_bands) - ebands(j);
+            let mut band_bits = bits[j] + percoeff * band_width + rem;
+            //Only code a skip decision if we're above the threshold for this band, otherwise it is force-skipped
+            if band_bits >= max(thresh[j], alloc_floor + (1<<BITRES)) {
+                if rc.decode_bit_logp(1) {
+                    break;
+                }
+                //We used a bit to skip this band
+                psum += 1<<BITRES;
+                band_bits -= 1<<BITRES;
+            }
+            //Reclaim the bits originally allocated to this band
+            psum -= bits[j] + intensity_rsv;
+            if intensity_rsv > 0 {
+                intensity_rsv = tables::LOG2_FRAC[j - start];
+            }
+            psum += intensity_rsv;
+            if band_bits >= alloc_floor {
+                //If we have enough for a fine energy bit per channel, use it
+                psum += alloc_floor;
+                bits[j] = alloc_floor;
+            } else {
+                //Otherwise this band gets nothing at all
+                bits[j] = 0;
+            }
+            coded_bands -= 1;
+        }
+
+        let intensity = if intensity_rsv > 0 {
+            start + rc.decode_uniform((coded_bands + 1 - start) as u32) as usize
+        } else {
+            0
+        };
+        if intensity <= start {
+            total += dual_stereo_rsv;
+            dual_stereo_rsv = 0;
+        }
+        let dual_stereo = dual_stereo_rsv > 0 && rc.decode_bit_logp(1);
+
+        //Allocate the remaining bits
+        let mut left = total - psum;
+        let percoeff = left / (ebands(coded_bands) - ebands(start));
+        left -= (ebands(coded_bands) - ebands(start)) * percoeff;
+        for (j, bits) in bits.iter_mut().enumerate().take(coded_bands).skip(start) {
+            *bits += percoeff * (ebands(j + 1) - ebands(j));
+        }
+        for (j, bits) in bits.iter_mut().enumerate().take(coded_bands).skip(start) {
+            let tmp = min(left, ebands(j + 1) - ebands(j));
+            *bits += tmp;
+            left -= tmp;
+        }
+
+        let mut fine_quant = [0; NB_BANDS];
+        let mut fine_priority = [false; NB_BANDS];
+        let mut balance = 0;
+        for j in start..coded_bands {
+            let n0 = ebands(j + 1) - ebands(j);
+            let n = n0<<lm;
+            let bit = bits[j] + balance;
+            let mut excess;
+
+            if n > 1 {
+                excess = max(bit - cap[j], 0);
+                bits[j] = bit - excess;
+
+                //Compensate for the extra degree of freedom in stereo
+                let den = c * n + (channels == 2 && n > 2 && !dual_stereo && j < intensity) as i32;
+                let nclogn = den * (tables::LOG_N[j] + log_m);
+
+                //Offset for the number of fine bits by log2(N)/2 + FINE_OFFSET compared to their "fair share" of total/N
+                let mut offset = (nclogn>>1) - den * FINE_OFFSET;
+                //N=2 is the only point that doesn't match the curve
+                if n == 2 {
+                    offset += den<<BITRES>>2;
+                }
+                //Changing the offset for allocating the second and third fine energy bit
+                if bits[j] + offset < (den * 2)<<BITRES {
+                    offset += nclogn>>2;
+                } else if bits[j] + offset < (den * 3)<<BITRES {
+                    offset += nclogn>>3;
+                }
+
+                //Divide with rounding
+                fine_quant[j] = max(0, bits[j] + offset + (den<<(BITRES - 1)));
+                fine_quant[j] = (fine_quant[j] / den)>>BITRES;
+                //Make sure not to bust
+                if c * fine_quant[j] > bits[j]>>BITRES {
+                    fine_quant[j] = bits[j]>>stereo>>BITRES;
+                }
+                //More than that is useless because that's about as far as pvq can go
+                fine_quant[j] = min(fine_quant[j], MAX_FINE_BITS);
+                //If we rounded down or capped this band, make it a candidate for the final fine energy pass
+                fine_priority[j] = fine_quant[j] * (den<<BITRES) >= bits[j] + offset;
+                //Remove the allocated fine bits, the rest are assigned to pvq
+                bits[j] -= (c * fine_quant[j])<<BITRES;
+            } else {
+                //For N=1, all bits go to fine energy except for a single sign bit
+                excess = max(0, bit - (c<<BITRES));
+                bits[j] = bit - excess;
+                fine_quant[j] = 0;
+                fine_priority[j] = true;
+            }
+
+            //Fine energy can't take advantage of the re-balancing in the band decoding, so do it here
+            if excess > 0 {
+                let extra_fine = min(excess>>(stereo + BITRES), MAX_FINE_BITS - fine_quant[j]);
+                fine_quant[j] += extra_fine;
+                let extra_bits = (extra_fine * c)<<BITRES;
+                fine_priority[j] = extra_bits >= excess - balance;
+                excess -= extra_bits;
+            }
+            balance = excess;
+        }
+
+        //The skipped bands use all their bits for fine energy
+        for j in coded_bands..end {
+            fine_quant[j] = bits[j]>>stereo>>BITRES;
+            bits[j] = 0;
+            fine_priority[j] = fine_quant[j] < 1;
+        }
+
+        Allocation {
+            pulses: bits,
+            fine_quant,
+            fine_priority,
+            coded_bands,
+            intensity,
+            dual_stereo,
+            balance,
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::f32::consts::PI;
+use range;
+use super::cwrs;
+
+pub const SPREAD_NONE: usize = 0;
+pub const SPREAD_NORMAL: usize = 2;
+pub const SPREAD_AGGRESSIVE: usize = 3;
+
+///Decodes a pulse vector of `k` pulses, normalises it to `gain` and undoes the spreading rotation.
+///Returns the mask of short blocks that received pulses
+pub fn alg_unquant(rc: &mut range::Decoder, x: &mut [f32], n: usize, k: usize, spread: usize, blocks: usize, gain: f32) -> u32 {
+    let mut iy = vec![0; n];
+    let ryy = cwrs::decode_pulses(rc, &mut iy, n, k);
+    let g = gain * (1.0 / ryy.sqrt());
+    for (x, &y) in x.iter_mut().zip(&iy) {
+        *x = g * y as f32;
+    }
+    exp_rotation(&mut x[..n], -1, blocks, k, spread);
+    collapse_mask(&iy, blocks)
+}
+
+///Scales `x` to a norm of `gain`
+pub fn renormalise_vector(x: &mut [f32], gain: f32) {
+    let e = 1e-15 + x.iter().map(|x| x * x).sum::<f32>();
+    let g = gain * (1.0 / e.sqrt());
+    for x in x.iter_mut() {
+        *x *= g;
+    }
+}
+
+fn collapse_mask(iy: &[i32], blocks: usize) -> u32 {
+    if blocks <= 1 {
+        return 1;
+    }
+    let n0 = iy.len() / blocks;
+    iy.chunks(n0).enumerate().fold(0, |mask, (i, block)| {
+        mask | ((block.iter().any(|&y| y != 0) as u32)<<i)
+    })
+}
+
+///Spreads the energy of sparse pulse vectors over neighbouring bins with a series of givens rotations
+fn exp_rotation(x: &mut [f32], dir: i32, stride: usize, k: usize, spread: usize) {
+    const SPREAD_FACTOR: [usize; 3] = [15, 10, 5];
+    let len = x.len();
+    if 2 * k >= len || spread == SPREAD_NONE {
+        return;
+    }
+    let factor = SPREAD_FACTOR[spread - 1];
+
+    let gain = len as f32 / (len + factor * k) as f32;
+    let theta = 0.5 * (gain * gain);
+    let c = cos_norm(theta);
+    let s = cos_norm(1.0 - theta);
+
+    let mut stride2 = 0;
+    if len >= 8 * stride {
+        stride2 = 1;
+        //Equivalent to sqrt(len/stride) with rounding
+        while (stride2 * stride2 + stride2) * stride + (stride>>2) < len {
+            stride2 += 1;
+        }
+    }
+    let len = len / stride;
+    for block in x.chunks_mut(len).take(stride) {
+        if dir < 0 {
+            if stride2 != 0 {
+                exp_rotation1(block, stride2, s, c);
+            }
+            exp_rotation1(block, 1, c, s);
+        } else {
+            exp_rotation1(block, 1, c, -s);
+            if stride2 != 0 {
+                exp_rotation1(block, stride2, s, -c);
+            }
+        }
+    }
+}
+
+fn exp_rotation1(x: &mut [f32], stride: usize, c: f32, s: f32) {
+    let len = x.len();
+    for i in 0..len - stride {
+        let x1 = x[i];
+        let x2 = x[i + stride];
+        x[i + stride] = c * x2 + s * x1;
+        x[i] = c * x1 - s * x2;
+    }
+    if len > 2 * stride {
+        for i in (0..len - 2 * stride).rev() {
+            let x1 = x[i];
+            let x2 = x[i + stride];
+            x[i + stride] = c * x2 + s * x1;
+            x[i] = c * x1 - s * x2;
+        }
+    }
+}
+
+///cos(pi/2 * x)
+fn cos_norm(x: f32) -> f32 {
+    f64::from(0.5 * PI * x).cos() as f32
+}
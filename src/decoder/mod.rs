@@ -1 +1,746 @@
+pub mod celt;
+pub mod multistream;
 pub mod silk;
+
+use std::f64::consts::LN_2;
+use std::result;
+use common::types::{Bandwidth, Channels, FrameSize, SampleRate};
+use packet::{Mode, Packet, PacketErrorKind};
+use range;
+use self::celt::{HYBRID_START_BAND, WINDOW};
+use self::silk::FrameKind;
+
+///Sampling rate CELT runs at, lower output rates are decimated from it
+const MAX_FS_HZ: usize = 48000;
+///Samples per channel of the longest frame at 48 kHz, 60 ms
+const MAX_FRAME_SIZE: usize = 2880;
+///Samples per channel of the longest packet at 48 kHz, 120 ms
+const MAX_PACKET_SIZE: usize = 2 * MAX_FRAME_SIZE;
+///Samples per channel of a 5 ms frame at 48 kHz, the longest mode transition
+const MAX_F5: usize = MAX_FS_HZ / 200;
+
+#[derive(Debug)]
+pub enum DecoderErrorKind {
+    InvalidPacket(PacketErrorKind),
+    ///The output buffer can't hold all frames of the packet
+    BufferTooSmall,
+    ///Lost audio can only be recovered in multiples of 2.5 ms
+    InvalidDuration,
+    ///The streams and channel mapping of a multistream decoder don't fit together
+    InvalidChannelMapping,
+}
+
+impl From<PacketErrorKind> for DecoderErrorKind {
+    fn from(err: PacketErrorKind) -> Self {
+        DecoderErrorKind::InvalidPacket(err)
+    }
+}
+
+pub type Result<T> = result::Result<T, DecoderErrorKind>;
+
+///A CELT frame coded after the SILK data of a packet that switches modes.
+///It covers the 5 ms the SILK frame can't make a smooth transition over
+struct Redundancy<'a> {
+    ///Whether the frame continues a previous CELT frame, at the start of the packet, or leads into the next one
+    celt_to_silk: bool,
+    data: &'a [u8],
+}
+
+///Decodes Opus packets of any mode, keeping the SILK and CELT decoders in step across packets and mode switches
+pub struct Decoder {
+    silk: silk::Decoder,
+    celt: celt::Decoder,
+    fs_hz: usize,
+    channels: Channels,
+    ///Configuration of the current packet
+    mode: Mode,
+    bandwidth: Bandwidth,
+    frame_size: FrameSize,
+    stream_channels: Channels,
+    ///Mode of the last decoded frame, `None` until the first packet
+    prev_mode: Option<Mode>,
+    ///Whether the last frame ended with a redundant CELT frame, which leaves CELT ready for the next one
+    prev_redundancy: bool,
+    ///Output gain in 1/256 dB
+    gain: i16,
+    ///Samples per channel of the last decoded or concealed packet
+    last_packet_duration: usize,
+    ///Final range of the last frame's range decoder, encoders report the same value
+    final_range: u32,
+}
+
+impl Decoder {
+    ///Creates a decoder with output at `rate` in `channels`. Packets are converted to the output
+    ///channels whatever they were coded with, stereo is downmixed and mono copied to both channels
+    pub fn new(rate: SampleRate, channels: Channels) -> Self {
+        let fs_hz = match rate {
+            SampleRate::Khz8 => 8000,
+            SampleRate::Khz12 => 12000,
+            SampleRate::Khz16 => 16000,
+            SampleRate::Khz24 => 24000,
+            SampleRate::Khz48 => 48000,
+        };
+        Self {
+            silk: silk::Decoder::new(rate, channels),
+            celt: celt::Decoder::new(rate, channels),
+            fs_hz,
+            channels,
+            mode: Mode::Silk,
+            bandwidth: Bandwidth::Full,
+            frame_size: FrameSize::Ms20,
+            stream_channels: channels,
+            prev_mode: None,
+            prev_redundancy: false,
+            gain: 0,
+            last_packet_duration: 0,
+            final_range: 0,
+        }
+    }
+
+    ///Resets the decoder to its state before the first packet, keeping the output gain and phase inversion setting
+    pub fn reset(&mut self) {
+        self.silk.reset();
+        self.celt.reset();
+        self.mode = Mode::Silk;
+        self.bandwidth = Bandwidth::Full;
+        self.frame_size = FrameSize::Ms20;
+        self.stream_channels = self.channels;
+        self.prev_mode = None;
+        self.prev_redundancy = false;
+        self.last_packet_duration = 0;
+        self.final_range = 0;
+    }
+
+    ///Channels of the output
+    pub fn channels(&self) -> Channels {
+        self.channels
+    }
+
+    ///Sets the gain applied to the output in 1/256 dB, such as the output gain of an Ogg Opus header
+    pub fn set_gain(&mut self, gain: i16) {
+        self.gain = gain;
+    }
+
+    pub fn gain(&self) -> i16 {
+        self.gain
+    }
+
+    ///Keeps intensity stereo from inverting the phase of one channel, so a downmix of the output doesn't cancel out
+    pub fn set_phase_inversion_disabled(&mut self, disabled: bool) {
+        self.celt.set_phase_inversion_disabled(disabled);
+    }
+
+    pub fn phase_inversion_disabled(&self) -> bool {
+        self.celt.phase_inversion_disabled()
+    }
+
+    ///Bandwidth of the last packet, `None` until the first one
+    pub fn bandwidth(&self) -> Option<Bandwidth> {
+        self.prev_mode.map(|_| self.bandwidth)
+    }
+
+    ///Samples per channel of the last decoded or concealed packet
+    pub fn last_packet_duration(&self) -> usize {
+        self.last_packet_duration
+    }
+
+    ///Pitch period of the last frame at 48 kHz, 0 if it wasn't voiced
+    pub fn pitch(&self) -> usize {
+        if self.prev_mode == Some(Mode::Celt) {
+            self.celt.pitch()
+        } else {
+            self.silk.prev_pitch_lag()
+        }
+    }
+
+    ///Final range of the range decoder after the last frame, 0 if it was lost.
+    ///Encoders report the same value, so it can check that a stream decoded correctly
+    pub fn final_range(&self) -> u32 {
+        self.final_range
+    }
+
+    ///Samples per channel in a frame at the output rate
+    fn samples(&self, frame_size: FrameSize) -> usize {
+        let fs = self.fs_hz;
+        match frame_size {
+            FrameSize::Ms2_5 => fs / 400,
+            FrameSize::Ms5 => fs / 200,
+            FrameSize::Ms10 => fs / 100,
+            FrameSize::Ms20 => fs / 50,
+            FrameSize::Ms40 => fs / 25,
+            FrameSize::Ms60 => 3 * fs / 50,
+        }
+    }
+
+    ///Decodes all frames of a packet into `pcm` interleaved, and returns the number of samples per channel
+    pub fn decode(&mut self, data: &[u8], pcm: &mut [i16]) -> Result<usize> {
+        self.decode_i16(pcm, |decoder, buf| decoder.decode_float(data, buf))
+    }
+
+    ///Conceals `samples` per channel of lost audio into `pcm` interleaved, see `decode_lost_float`
+    pub fn decode_lost(&mut self, samples: usize, pcm: &mut [i16]) -> Result<usize> {
+        self.check_lost(samples, pcm.len())?;
+        //Losses longer than a packet are concealed a packet's worth at a time
+        let chunk = self.samples(FrameSize::Ms60) * 2;
+        let channels = self.channels as usize;
+        let mut n = 0;
+        while n < samples {
+            let len = (samples - n).min(chunk);
+            n += self.decode_i16(&mut pcm[n * channels..], |decoder, buf| decoder.decode_lost_float(len, buf))?;
+        }
+        self.last_packet_duration = samples;
+        Ok(samples)
+    }
+
+    ///Recovers `samples` per channel of lost audio into `pcm` interleaved, see `decode_fec_float`
+    pub fn decode_fec(&mut self, next_packet: &[u8], samples: usize, pcm: &mut [i16]) -> Result<usize> {
+        let packet = Packet::read(next_packet)?;
+        self.check_lost(samples, pcm.len())?;
+        //Only the last frame is recovered, so a longer loss is concealed up to it in chunks
+        let n = self.samples(packet.frame_size());
+        if samples > self.samples(FrameSize::Ms60) * 2 {
+            let channels = self.channels as usize;
+            self.decode_lost(samples - n, pcm)?;
+            self.decode_i16(&mut pcm[(samples - n) * channels..], |decoder, buf| decoder.decode_fec_packet(&packet, n, buf))?;
+            self.last_packet_duration = samples;
+            return Ok(samples);
+        }
+        self.decode_i16(pcm, |decoder, buf| decoder.decode_fec_packet(&packet, samples, buf))
+    }
+
+    ///Checks that `samples` per channel of lost audio are a multiple of 2.5 ms and fit into `len` samples
+    fn check_lost(&self, samples: usize, len: usize) -> Result<()> {
+        if !samples.is_multiple_of(self.samples(FrameSize::Ms2_5)) {
+            return Err(DecoderErrorKind::InvalidDuration);
+        }
+        if samples * self.channels as usize > len {
+            return Err(DecoderErrorKind::BufferTooSmall);
+        }
+        Ok(())
+    }
+
+    ///Runs `decode` into a float buffer and converts its output to 16 bit samples.
+    ///The buffer holds the longest packet, `decode` mustn't output more than that
+    fn decode_i16<F>(&mut self, pcm: &mut [i16], decode: F) -> Result<usize>
+        where F: FnOnce(&mut Self, &mut [f32]) -> Result<usize> {
+        let mut buf = [0.0; MAX_PACKET_SIZE * 2];
+        let len = pcm.len().min(buf.len());
+        let n = decode(self, &mut buf[..len])?;
+        for (out, &x) in pcm.iter_mut().zip(buf[..n * self.channels as usize].iter()) {
+            *out = (x * 32768.0).clamp(-32768.0, 32767.0).round() as i16;
+        }
+        Ok(n)
+    }
+
+    ///Decodes all frames of a packet into `pcm` interleaved, with samples between -1 and 1.
+    ///Returns the number of samples per channel
+    pub fn decode_float(&mut self, data: &[u8], pcm: &mut [f32]) -> Result<usize> {
+        let packet = Packet::read(data)?;
+        self.decode_packet(&packet, pcm)
+    }
+
+    ///Decodes all frames of an already parsed packet, see `decode_float`
+    fn decode_packet(&mut self, packet: &Packet, pcm: &mut [f32]) -> Result<usize> {
+        let channels = self.channels as usize;
+        let frame_size = packet.frame_size();
+        let n = self.samples(frame_size);
+        let frames = packet.frame_count();
+        if frames * n * channels > pcm.len() {
+            return Err(DecoderErrorKind::BufferTooSmall);
+        }
+
+        self.mode = packet.mode();
+        self.bandwidth = packet.bandwidth();
+        self.frame_size = frame_size;
+        self.stream_channels = packet.channels();
+        //Empty frames at the end, and all of them in a packet of only the table of contents, are concealed
+        let mut data = packet.frames();
+        for i in 0..frames {
+            self.decode_frame(data.next(), frame_size, false, &mut pcm[i * n * channels..]);
+        }
+        self.last_packet_duration = frames * n;
+        Ok(frames * n)
+    }
+
+    ///Conceals `samples` per channel of lost audio into `pcm` interleaved, continuing from the last packet.
+    ///`samples` has to be a multiple of 2.5 ms. Returns the number of samples per channel
+    pub fn decode_lost_float(&mut self, samples: usize, pcm: &mut [f32]) -> Result<usize> {
+        let channels = self.channels as usize;
+        self.check_lost(samples, pcm.len())?;
+        let mut n = 0;
+        while n < samples {
+            //Concealment doesn't run over more than the last packet's frame size at once,
+            //and only in steps of 2.5, 5, 10 or 20 ms
+            let left = (samples - n).min(self.samples(self.frame_size));
+            let frame_size = [FrameSize::Ms60, FrameSize::Ms40, FrameSize::Ms20, FrameSize::Ms10, FrameSize::Ms5, FrameSize::Ms2_5]
+                .iter()
+                .cloned()
+                .find(|&frame_size| self.samples(frame_size) <= left)
+                .unwrap_or(FrameSize::Ms2_5);
+            n += self.decode_frame(None, frame_size, false, &mut pcm[n * channels..]);
+        }
+        self.last_packet_duration = n;
+        Ok(n)
+    }
+
+    ///Recovers `samples` per channel of lost audio into `pcm` interleaved, with the low bitrate redundancy
+    ///SILK and hybrid packets can carry for the packet before them. Only the last frame's worth of audio
+    ///can be recovered from `next_packet`, the rest and packets without redundancy are concealed.
+    ///`samples` has to be a multiple of 2.5 ms. `next_packet` still has to be decoded after this.
+    ///Returns the number of samples per channel
+    pub fn decode_fec_float(&mut self, next_packet: &[u8], samples: usize, pcm: &mut [f32]) -> Result<usize> {
+        let packet = Packet::read(next_packet)?;
+        self.decode_fec_packet(&packet, samples, pcm)
+    }
+
+    ///Recovers lost audio from an already parsed packet, see `decode_fec_float`
+    fn decode_fec_packet(&mut self, packet: &Packet, samples: usize, pcm: &mut [f32]) -> Result<usize> {
+        let channels = self.channels as usize;
+        self.check_lost(samples, pcm.len())?;
+        let frame_size = packet.frame_size();
+        let n = self.samples(frame_size);
+        if samples < n || packet.mode() == Mode::Celt || self.mode == Mode::Celt {
+            return self.decode_lost_float(samples, pcm);
+        }
+        if samples > n {
+            self.decode_lost_float(samples - n, pcm)?;
+        }
+
+        self.mode = packet.mode();
+        self.bandwidth = packet.bandwidth();
+        self.frame_size = frame_size;
+        self.stream_channels = packet.channels();
+        let first = packet.frames().next();
+        self.decode_frame(first, frame_size, true, &mut pcm[(samples - n) * channels..]);
+        self.last_packet_duration = samples;
+        Ok(samples)
+    }
+
+    ///Decodes a frame, or conceals `frame_size` of audio if `data` is `None`. With `fec` only the redundancy
+    ///`data` carries for the frame before it is decoded, and CELT conceals its part.
+    ///Mode switches cross-fade over 2.5 ms, either with the redundant frame the packet carries
+    ///or by concealing the previous mode, see RFC 6716 section 4.5
+    fn decode_frame(&mut self, data: Option<&[u8]>, frame_size: FrameSize, fec: bool, pcm: &mut [f32]) -> usize {
+        let channels = self.channels as usize;
+        //Payloads of one byte or less signal a lost frame or discontinuous transmission
+        let data = data.filter(|data| data.len() > 1);
+        let (mode, audio_size) = match (data, self.prev_mode) {
+            (Some(_), _) => (self.mode, self.frame_size),
+            (None, None) => {
+                let n = self.samples(frame_size);
+                for x in pcm[..n * channels].iter_mut() {
+                    *x = 0.0;
+                }
+                return n;
+            },
+            (None, Some(mode)) => {
+                //Concealment runs in 20 ms steps at most
+                if frame_size > FrameSize::Ms20 {
+                    let mut n = 0;
+                    while n < self.samples(frame_size) {
+                        n += self.decode_frame(None, FrameSize::Ms20, false, &mut pcm[n * channels..]);
+                    }
+                    return n;
+                }
+                (mode, frame_size)
+            },
+        };
+        let n = self.samples(audio_size);
+        let f2_5 = self.fs_hz / 400;
+        let transition_size = if audio_size < FrameSize::Ms5 { audio_size } else { FrameSize::Ms5 };
+
+        //Without redundancy, switching between CELT and the other modes fades over from a concealed frame of the old mode
+        let mut transition = data.is_some() && match self.prev_mode {
+            Some(Mode::Celt) => mode != Mode::Celt,
+            Some(_) => mode == Mode::Celt && !self.prev_redundancy,
+            None => false,
+        };
+        let mut pcm_transition = [0.0; MAX_F5 * 2];
+        if transition && mode == Mode::Celt {
+            self.decode_frame(None, transition_size, false, &mut pcm_transition);
+        }
+
+        let mut rc = range::Decoder::new(data.unwrap_or(&[]));
+        let mut pcm_silk = [0i16; MAX_FRAME_SIZE * 2];
+        if mode != Mode::Celt {
+            if self.prev_mode == Some(Mode::Celt) {
+                self.silk.reset();
+            }
+            if data.is_some() {
+                let bandwidth = if mode == Mode::Hybrid { Bandwidth::Wide } else { self.bandwidth };
+                let kind = if fec { FrameKind::Lbrr } else { FrameKind::Normal };
+                self.silk.decode(&mut rc, kind, self.stream_channels, bandwidth, audio_size, &mut pcm_silk);
+            } else {
+                self.silk.decode_lost(audio_size, &mut pcm_silk);
+            }
+        }
+
+        let mut celt_lost = data.is_none() || fec;
+        let mut redundancy = None;
+        if let Some(data) = data {
+            if mode != Mode::Celt && !fec {
+                let (redundant, valid) = decode_redundancy(&mut rc, mode, data);
+                redundancy = redundant;
+                celt_lost = !valid;
+            }
+        }
+        let start_band = if mode == Mode::Celt { 0 } else { HYBRID_START_BAND };
+
+        if redundancy.is_some() {
+            transition = false;
+        }
+        if transition && mode != Mode::Celt {
+            self.decode_frame(None, transition_size, false, &mut pcm_transition);
+        }
+
+        //A redundant frame from CELT decodes before the SILK frame it leads into
+        let mut redundant_audio = [0.0; MAX_F5 * 2];
+        let mut redundant_rng = 0;
+        let celt_to_silk = redundancy.as_ref().is_some_and(|r| r.celt_to_silk);
+        if let Some(ref r) = redundancy {
+            if r.celt_to_silk {
+                let mut rc = range::Decoder::new(r.data);
+                self.celt.decode(&mut rc, FrameSize::Ms5, self.bandwidth, self.stream_channels, 0, &mut redundant_audio);
+                redundant_rng = rc.final_range();
+            }
+        }
+
+        if mode != Mode::Silk {
+            //CELT starts over, unless a redundant frame already brought it up to date
+            if self.prev_mode.is_some() && self.prev_mode != Some(mode) && !self.prev_redundancy {
+                self.celt.reset();
+            }
+            if celt_lost {
+                self.celt.decode_lost(audio_size, data.map(|_| self.bandwidth), start_band, pcm);
+            } else {
+                self.celt.decode(&mut rc, audio_size, self.bandwidth, self.stream_channels, start_band, pcm);
+            }
+        } else {
+            for x in pcm[..n * channels].iter_mut() {
+                *x = 0.0;
+            }
+            //Leaving hybrid mode, the CELT overlap fades out by decoding a silent frame
+            if self.prev_mode == Some(Mode::Hybrid) && !(celt_to_silk && self.prev_redundancy) {
+                let silence = [0xFF, 0xFF];
+                let mut rc = range::Decoder::new(&silence);
+                self.celt.decode(&mut rc, FrameSize::Ms2_5, self.bandwidth, self.stream_channels, 0, pcm);
+            }
+        }
+
+        if mode != Mode::Celt {
+            for (out, &s) in pcm[..n * channels].iter_mut().zip(pcm_silk.iter()) {
+                *out += f32::from(s) * (1.0 / 32768.0);
+            }
+        }
+
+        if let Some(ref r) = redundancy {
+            if r.celt_to_silk {
+                //After SILK without redundancy there is nothing of CELT to fade out of, libopus skips it too
+                if self.prev_mode != Some(Mode::Silk) || self.prev_redundancy {
+                    //The first 2.5 ms come from the redundant frame, then it fades into the SILK frame
+                    let pcm = &mut pcm[..2 * f2_5 * channels];
+                    pcm[..f2_5 * channels].copy_from_slice(&redundant_audio[..f2_5 * channels]);
+                    smooth_fade(&redundant_audio[f2_5 * channels..], &mut pcm[f2_5 * channels..], channels, self.fs_hz);
+                }
+            } else {
+                //The frame fades into the redundant one at its end, the next CELT frame follows on from that
+                self.celt.reset();
+                let mut rc = range::Decoder::new(r.data);
+                self.celt.decode(&mut rc, FrameSize::Ms5, self.bandwidth, self.stream_channels, 0, &mut redundant_audio);
+                redundant_rng = rc.final_range();
+                let fade = &mut pcm[(n - f2_5) * channels..n * channels];
+                let to = &mut redundant_audio[f2_5 * channels..2 * f2_5 * channels];
+                smooth_fade(fade, to, channels, self.fs_hz);
+                fade.copy_from_slice(to);
+            }
+        }
+
+        if transition {
+            if audio_size >= FrameSize::Ms5 {
+                pcm[..f2_5 * channels].copy_from_slice(&pcm_transition[..f2_5 * channels]);
+                smooth_fade(&pcm_transition[f2_5 * channels..], &mut pcm[f2_5 * channels..2 * f2_5 * channels], channels, self.fs_hz);
+            } else {
+                //Too short for a clean transition, this fades over the first 2.5 ms anyway
+                smooth_fade(&pcm_transition[..f2_5 * channels], &mut pcm[..f2_5 * channels], channels, self.fs_hz);
+            }
+        }
+
+        if self.gain != 0 {
+            //1/256 dB to log2 of the linear gain, log2(10)/20/256
+            let gain = (f64::from(6.488_141e-4 * f32::from(self.gain)) * LN_2).exp() as f32;
+            for x in pcm[..n * channels].iter_mut() {
+                *x *= gain;
+            }
+        }
+
+        self.final_range = if data.is_some() { rc.final_range() ^ redundant_rng } else { 0 };
+        self.prev_mode = Some(mode);
+        self.prev_redundancy = redundancy.is_some_and(|r| !r.celt_to_silk);
+        n
+    }
+}
+
+///Reads the redundancy signalled after the SILK data of a SILK or hybrid frame and shrinks `rc` to the
+///bytes before it. Also returns whether the frame is valid, a redundant frame longer than the packet invalidates it
+fn decode_redundancy<'a>(rc: &mut range::Decoder, mode: Mode, data: &'a [u8]) -> (Option<Redundancy<'a>>, bool) {
+    //SILK frames signal redundancy by leaving enough bits unused, hybrid frames with a flag
+    let len = data.len();
+    let flag_bits = if mode == Mode::Hybrid { 20 } else { 0 };
+    if rc.tell() + 17 + flag_bits > 8 * len || (mode == Mode::Hybrid && !rc.decode_bit_logp(12)) {
+        return (None, true);
+    }
+    let celt_to_silk = rc.decode_bit_logp(1);
+    let bytes = if mode == Mode::Hybrid {
+        rc.decode_uniform(256) as usize + 2
+    } else {
+        len - ((rc.tell() + 7)>>3)
+    };
+    if bytes > len || (len - bytes) * 8 < rc.tell() {
+        return (None, false);
+    }
+    rc.shrink(bytes);
+    (Some(Redundancy { celt_to_silk, data: &data[len - bytes..] }), true)
+}
+
+///Cross-fades from `from` into `to` over 2.5 ms at `fs_hz` with the square of the CELT window, writing over `to`
+fn smooth_fade(from: &[f32], to: &mut [f32], channels: usize, fs_hz: usize) {
+    let inc = MAX_FS_HZ / fs_hz;
+    for (i, &w) in WINDOW.iter().step_by(inc).take(fs_hz / 400).enumerate() {
+        let w = w * w;
+        for c in 0..channels {
+            let j = i * channels + c;
+            to[j] = w * to[j] + (1.0 - w) * from[j];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitstream::{BitPacket, Reader};
+
+    ///Stereo packets of 20 ms with the modes CELT CELT SILK SILK hybrid CELT hybrid hybrid hybrid CELT hybrid hybrid SILK SILK
+    ///hybrid hybrid hybrid CELT SILK SILK SILK hybrid CELT SILK SILK. Every switch from CELT starts with a redundant
+    ///CELT frame and every switch to CELT is led into by one at the end of the packet before
+    static MODE_SWITCH: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/mode_switch.bit"));
+
+    ///Stereo CELT packets of 20 ms with intensity stereo from band 10 up
+    static CELT_STEREO: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/celt_stereo.bit"));
+
+    ///Mono SILK packets of 20 ms at medium band, each carrying low bitrate redundancy for the one before
+    static SILK_FEC: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/silk_fec.bit"));
+
+    fn packets(data: &[u8]) -> Vec<BitPacket> {
+        let mut reader = Reader::new(data);
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.read_packet().unwrap() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    #[test]
+    fn silk_and_hybrid() {
+        let packets = packets(MODE_SWITCH);
+        let modes: Vec<Mode> = packets.iter().map(|packet| Packet::read(&packet.data).unwrap().mode()).collect();
+        assert_eq!(modes.iter().filter(|&&mode| mode == Mode::Silk).count(), 9);
+        assert_eq!(modes.iter().filter(|&&mode| mode == Mode::Hybrid).count(), 10);
+
+        let mut decoder = Decoder::new(SampleRate::Khz48, Channels::Stereo);
+        let mut pcm = vec![0.0; packets.len() * 960 * 2];
+        for (packet, pcm) in packets.iter().zip(pcm.chunks_mut(960 * 2)) {
+            assert_eq!(decoder.decode_float(&packet.data, pcm).unwrap(), 960);
+            assert_eq!(decoder.final_range(), packet.final_range);
+        }
+        //Levels of the reference decoder's output
+        let rms = |c: usize| (pcm.iter().skip(c).step_by(2).map(|x| x * x).sum::<f32>() / (pcm.len() / 2) as f32).sqrt();
+        assert!((rms(0) - 0.095_183).abs() < 1e-4);
+        assert!((rms(1) - 0.105_161).abs() < 1e-4);
+    }
+
+    #[test]
+    fn rates_and_channels() {
+        let packets = packets(MODE_SWITCH);
+        //Levels of the reference decoder's output at each rate, mono and both stereo channels
+        let tests = [
+            (SampleRate::Khz8, 160, [0.071_827, 0.095_117, 0.104_339]),
+            (SampleRate::Khz12, 240, [0.072_058, 0.095_162, 0.104_871]),
+            (SampleRate::Khz16, 320, [0.072_116, 0.095_163, 0.105_023]),
+            (SampleRate::Khz24, 480, [0.072_149, 0.095_175, 0.105_055]),
+        ];
+        for &(rate, n, levels) in &tests {
+            for &channels in &[Channels::Mono, Channels::Stereo] {
+                let c = channels as usize;
+                let mut decoder = Decoder::new(rate, channels);
+                let mut pcm = vec![0; packets.len() * n * c];
+                for (packet, pcm) in packets.iter().zip(pcm.chunks_mut(n * c)) {
+                    assert_eq!(decoder.decode(&packet.data, pcm).unwrap(), n);
+                    assert_eq!(decoder.last_packet_duration(), n);
+                    assert_eq!(decoder.final_range(), packet.final_range);
+                }
+                for k in 0..c {
+                    let sum = pcm.iter().skip(k).step_by(c).map(|&x| (x as f32 / 32768.0).powi(2)).sum::<f32>();
+                    let rms = (sum / (pcm.len() / c) as f32).sqrt();
+                    assert!((rms - levels[c - 1 + k]).abs() < 1e-4, "rate: {:?}, channels: {}, rms: {}", rate, c, rms);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fec() {
+        let packets = packets(SILK_FEC);
+        //Decodes the packets with the sixth one lost, recovering it with `recover`, and returns its audio
+        let decode = |recover: &dyn Fn(&mut Decoder, &mut [i16])| {
+            let mut decoder = Decoder::new(SampleRate::Khz48, Channels::Mono);
+            let mut pcm = vec![0; packets.len() * 960];
+            for (i, (packet, pcm)) in packets.iter().zip(pcm.chunks_mut(960)).enumerate() {
+                if i == 5 {
+                    recover(&mut decoder, pcm);
+                } else {
+                    assert_eq!(decoder.decode(&packet.data, pcm).unwrap(), 960);
+                }
+            }
+            pcm[5 * 960..6 * 960].to_vec()
+        };
+        let decoded = decode(&|decoder, pcm| assert_eq!(decoder.decode(&packets[5].data, pcm).unwrap(), 960));
+        let fec = decode(&|decoder, pcm| assert_eq!(decoder.decode_fec(&packets[6].data, 960, pcm).unwrap(), 960));
+        let lost = decode(&|decoder, pcm| assert_eq!(decoder.decode_lost(960, pcm).unwrap(), 960));
+        assert_ne!(fec, lost);
+        //The redundancy is a coarser coding of the same audio, concealment only continues the frame before
+        let error = |pcm: &[i16]| pcm.iter().zip(decoded.iter()).map(|(&x, &y)| (x as f64 - y as f64).powi(2)).sum::<f64>();
+        assert!(error(&fec) < error(&lost) / 2.0, "fec: {}, lost: {}", error(&fec), error(&lost));
+    }
+
+    #[test]
+    fn long_loss() {
+        let packets = packets(SILK_FEC);
+        let mut decoder = Decoder::new(SampleRate::Khz16, Channels::Stereo);
+        let mut reference = Decoder::new(SampleRate::Khz16, Channels::Stereo);
+        decoder.decode(&packets[0].data, &mut [0; 320 * 2]).unwrap();
+        reference.decode(&packets[0].data, &mut [0; 320 * 2]).unwrap();
+        //A second is longer than the longest packet, so it's concealed in parts, the same as all at once
+        let mut pcm = vec![0; 16000 * 2];
+        let mut expected = vec![0.0; 16000 * 2];
+        assert_eq!(decoder.decode_lost(16000, &mut pcm).unwrap(), 16000);
+        assert_eq!(decoder.last_packet_duration(), 16000);
+        assert_eq!(reference.decode_lost_float(16000, &mut expected).unwrap(), 16000);
+        assert!(pcm.iter().zip(expected.iter()).all(|(&x, &y)| x == (y * 32768.0).clamp(-32768.0, 32767.0).round() as i16));
+        //An odd duration is rejected before any of it is concealed
+        assert!(matches!(decoder.decode_lost(15999, &mut pcm), Err(DecoderErrorKind::InvalidDuration)));
+        assert_eq!(decoder.decode_fec(&packets[1].data, 16000, &mut pcm).unwrap(), 16000);
+        assert_eq!(decoder.last_packet_duration(), 16000);
+        assert_eq!(reference.decode_fec_float(&packets[1].data, 16000, &mut expected).unwrap(), 16000);
+        assert!(pcm.iter().zip(expected.iter()).all(|(&x, &y)| x == (y * 32768.0).clamp(-32768.0, 32767.0).round() as i16));
+    }
+
+    #[test]
+    fn gain() {
+        let packets = packets(MODE_SWITCH);
+        let mut decoder = Decoder::new(SampleRate::Khz48, Channels::Stereo);
+        let mut quieter = Decoder::new(SampleRate::Khz48, Channels::Stereo);
+        //-6 dB, about half the amplitude
+        quieter.set_gain(-6 * 256);
+        assert_eq!(quieter.gain(), -1536);
+        let mut pcm = [0.0; 960 * 2];
+        let mut scaled = [0.0; 960 * 2];
+        for packet in &packets {
+            decoder.decode_float(&packet.data, &mut pcm).unwrap();
+            quieter.decode_float(&packet.data, &mut scaled).unwrap();
+            for (&x, &y) in pcm.iter().zip(scaled.iter()) {
+                assert!((x * 0.501_187 - y).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn phase_inversion() {
+        let packets = packets(CELT_STEREO);
+        let mut decoder = Decoder::new(SampleRate::Khz48, Channels::Stereo);
+        let mut uninverted = Decoder::new(SampleRate::Khz48, Channels::Stereo);
+        uninverted.set_phase_inversion_disabled(true);
+        let mut pcm = vec![0.0; packets.len() * 960 * 2];
+        let mut expected = vec![0.0; packets.len() * 960 * 2];
+        for (packet, (pcm, expected)) in packets.iter().zip(pcm.chunks_mut(960 * 2).zip(expected.chunks_mut(960 * 2))) {
+            decoder.decode_float(&packet.data, expected).unwrap();
+            uninverted.decode_float(&packet.data, pcm).unwrap();
+            assert_eq!(uninverted.final_range(), packet.final_range);
+        }
+        //Only the intensity stereo bands differ, the left channel and the sum of the channels below them are the same
+        assert_ne!(pcm, expected);
+        let left = |pcm: &[f32]| pcm.iter().step_by(2).cloned().collect::<Vec<f32>>();
+        assert!(left(&pcm).iter().zip(left(&expected).iter()).all(|(x, y)| (x - y).abs() < 1e-6));
+    }
+
+    #[test]
+    fn reset() {
+        let packets = packets(MODE_SWITCH);
+        let mut decoder = Decoder::new(SampleRate::Khz48, Channels::Stereo);
+        assert_eq!(decoder.bandwidth(), None);
+        decoder.set_gain(256);
+        decoder.set_phase_inversion_disabled(true);
+        for packet in &packets[..10] {
+            decoder.decode_float(&packet.data, &mut [0.0; 960 * 2]).unwrap();
+        }
+        assert!(decoder.bandwidth().is_some());
+        assert_eq!(decoder.last_packet_duration(), 960);
+
+        //After a reset the decoder continues as a new one with the same settings
+        decoder.reset();
+        assert_eq!(decoder.bandwidth(), None);
+        assert_eq!(decoder.gain(), 256);
+        assert!(decoder.phase_inversion_disabled());
+        let mut fresh = Decoder::new(SampleRate::Khz48, Channels::Stereo);
+        fresh.set_gain(256);
+        fresh.set_phase_inversion_disabled(true);
+        let mut pcm = [0.0; 960 * 2];
+        let mut expected = [0.0; 960 * 2];
+        for packet in &packets {
+            decoder.decode_float(&packet.data, &mut pcm).unwrap();
+            fresh.decode_float(&packet.data, &mut expected).unwrap();
+            assert_eq!(pcm[..], expected[..]);
+            assert_eq!(decoder.final_range(), fresh.final_range());
+            assert_eq!(decoder.pitch(), fresh.pitch());
+        }
+    }
+
+    ///Decodes the packets at 48 kHz in stereo and conceals the ones in `lost`
+    fn decode_all(packets: &[BitPacket], lost: &[usize]) -> Vec<f32> {
+        let mut decoder = Decoder::new(SampleRate::Khz48, Channels::Stereo);
+        let mut pcm = vec![0.0; packets.len() * 960 * 2];
+        for (i, (packet, pcm)) in packets.iter().zip(pcm.chunks_mut(960 * 2)).enumerate() {
+            if lost.contains(&i) {
+                assert_eq!(decoder.decode_lost_float(960, pcm).unwrap(), 960);
+            } else {
+                assert_eq!(decoder.decode_float(&packet.data, pcm).unwrap(), 960);
+                assert_eq!(decoder.final_range(), packet.final_range);
+            }
+        }
+        pcm
+    }
+
+    ///Checks that no channel jumps at a frame boundary by more than its largest step within the frames on either side
+    fn assert_continuous(pcm: &[f32]) {
+        for c in 0..2 {
+            let x: Vec<f32> = pcm.iter().skip(c).step_by(2).cloned().collect();
+            let step = |i: usize| (x[i] - x[i - 1]).abs();
+            for boundary in (960..x.len()).step_by(960) {
+                let inner = (boundary - 959..boundary + 960).filter(|&i| i != boundary).map(step).fold(0.0, f32::max);
+                assert!(step(boundary) <= inner, "channel: {}, sample: {}", c, boundary);
+            }
+        }
+    }
+
+    #[test]
+    fn mode_switches() {
+        assert_continuous(&decode_all(&packets(MODE_SWITCH), &[]));
+    }
+
+    #[test]
+    fn lost_redundancy() {
+        //Without the hybrid packet leading into it, CELT fades in from concealed SILK
+        assert_continuous(&decode_all(&packets(MODE_SWITCH), &[21]));
+        //After concealed SILK there's no CELT to fade out of, so the redundant frame of a switch from CELT is left out
+        assert_continuous(&decode_all(&packets(MODE_SWITCH), &[21, 22]));
+    }
+}
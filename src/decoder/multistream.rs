@@ -0,0 +1,385 @@
+use common::types::{Channels, SampleRate};
+use packet::{Packet, PacketErrorKind};
+use super::{DecoderErrorKind, Result};
+
+///Streams and channel order of the Vorbis layouts for 1 to 8 channels, as libopus codes them
+const VORBIS_MAPPINGS: [(usize, usize, &[u8]); 8] = [
+    (1, 0, &[0]),
+    (1, 1, &[0, 1]),
+    (2, 1, &[0, 2, 1]),
+    (2, 2, &[0, 1, 2, 3]),
+    (3, 2, &[0, 4, 1, 2, 3]),
+    (4, 2, &[0, 4, 1, 2, 3, 5]),
+    (4, 3, &[0, 4, 1, 2, 3, 5, 6]),
+    (5, 3, &[0, 6, 1, 2, 3, 4, 5, 7]),
+];
+
+///Silent output channel in a mapping table
+pub const SILENT_CHANNEL: u8 = 255;
+
+///How the streams of a multistream packet map onto the output channels, see RFC 7845 section 5.1.1
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelMapping {
+    pub family: u8,
+    pub streams: usize,
+    ///The first streams are coupled and decode to two channels, the others to one
+    pub coupled_streams: usize,
+    ///Decoded channel for each output channel, counting both channels of the coupled streams first.
+    ///`SILENT_CHANNEL` leaves an output channel silent
+    pub mapping: Vec<u8>,
+    ///Family 3 only, the Q15 gains from every decoded channel to every output channel, stored column by column
+    ///with a column per decoded channel. It's applied after decoding, the mapping only counts the output channels
+    pub demixing_matrix: Vec<i16>,
+}
+
+impl ChannelMapping {
+    ///Family 0, a single mono or stereo stream
+    pub fn mono_stereo(channels: Channels) -> Self {
+        match channels {
+            Channels::Mono => Self { family: 0, streams: 1, coupled_streams: 0, mapping: vec![0], demixing_matrix: Vec::new() },
+            Channels::Stereo => Self { family: 0, streams: 1, coupled_streams: 1, mapping: vec![0, 1], demixing_matrix: Vec::new() },
+        }
+    }
+
+    ///Family 1 in the Vorbis channel order for 1 to 8 channels, with the streams libopus codes it with
+    pub fn vorbis(channels: usize) -> Option<Self> {
+        let &(streams, coupled_streams, mapping) = VORBIS_MAPPINGS.get(channels.wrapping_sub(1))?;
+        Some(Self { family: 1, streams, coupled_streams, mapping: mapping.to_vec(), demixing_matrix: Vec::new() })
+    }
+
+    ///Family 2, ambisonics in ACN order with SN3D normalization and an optional non-diegetic stereo pair
+    ///at the end, see RFC 8486 section 3.1. Every ambisonic channel gets a mono stream and the stereo
+    ///pair a coupled one, as libopus codes it
+    pub fn ambisonics(channels: usize) -> Option<Self> {
+        let nondiegetic = ambisonic_order(channels)?.1;
+        let coupled_streams = nondiegetic / 2;
+        let streams = channels - nondiegetic + coupled_streams;
+        let mapping = (2 * coupled_streams..channels).chain(0..2 * coupled_streams).map(|m| m as u8).collect();
+        Some(Self { family: 2, streams, coupled_streams, mapping, demixing_matrix: Vec::new() })
+    }
+
+    ///Family 3, ambisonics mixed into the streams, which `demixing_matrix` turns back into the
+    ///`channels` output channels, see RFC 8486 section 3.2. The streams may decode to more or fewer channels
+    pub fn projection(channels: usize, streams: usize, coupled_streams: usize, demixing_matrix: Vec<i16>) -> Self {
+        let mapping = (0..channels).map(|m| m as u8).collect();
+        Self { family: 3, streams, coupled_streams, mapping, demixing_matrix }
+    }
+
+    ///Reads the mapping of a family from the channel mapping table of an OpusHead header,
+    ///see RFC 7845 section 5.1.1. Family 0 has no table, and bytes past the end of the table are ignored
+    pub fn read(family: u8, channels: usize, table: &[u8]) -> Result<Self> {
+        if family == 0 {
+            return match channels {
+                1 => Ok(Self::mono_stereo(Channels::Mono)),
+                2 => Ok(Self::mono_stereo(Channels::Stereo)),
+                _ => Err(DecoderErrorKind::InvalidChannelMapping),
+            };
+        }
+        //Stream counts, then the mapping or the demixing matrix
+        if table.len() < 2 {
+            return Err(DecoderErrorKind::InvalidChannelMapping);
+        }
+        let (streams, coupled_streams) = (usize::from(table[0]), usize::from(table[1]));
+        let table = &table[2..];
+        let mapping = if family == 3 {
+            let size = 2 * channels * (streams + coupled_streams);
+            if table.len() < size {
+                return Err(DecoderErrorKind::InvalidChannelMapping);
+            }
+            let demixing_matrix = table[..size].chunks(2).map(|gain| i16::from_le_bytes([gain[0], gain[1]])).collect();
+            Self::projection(channels, streams, coupled_streams, demixing_matrix)
+        } else {
+            if table.len() < channels {
+                return Err(DecoderErrorKind::InvalidChannelMapping);
+            }
+            Self { family, streams, coupled_streams, mapping: table[..channels].to_vec(), demixing_matrix: Vec::new() }
+        };
+        mapping.validate()?;
+        Ok(mapping)
+    }
+
+    pub fn channels(&self) -> usize {
+        self.mapping.len()
+    }
+
+    ///Checks the streams and the mapping against each other and the rules of the family
+    pub fn validate(&self) -> Result<()> {
+        let channels = self.channels();
+        let decoded = self.streams + self.coupled_streams;
+        let valid = (1..=255).contains(&channels)
+            && self.streams >= 1 && self.coupled_streams <= self.streams && decoded <= 255
+            && (self.family == 3 || self.mapping.iter().all(|&m| m == SILENT_CHANNEL || usize::from(m) < decoded))
+            && match self.family {
+                0 => channels <= 2 && self.streams == 1 && self.coupled_streams == channels - 1
+                    && self.mapping.iter().enumerate().all(|(i, &m)| usize::from(m) == i),
+                1 => channels <= 8,
+                2 => ambisonic_order(channels).is_some(),
+                //Demixing mixes every decoded channel into every output channel, the counts of both are independent
+                3 => ambisonic_order(channels).is_some()
+                    && self.demixing_matrix.len() == channels * decoded
+                    && self.mapping.iter().enumerate().all(|(i, &m)| usize::from(m) == i),
+                255 => true,
+                _ => false,
+            }
+            && (self.family == 3 || self.demixing_matrix.is_empty());
+        if valid { Ok(()) } else { Err(DecoderErrorKind::InvalidChannelMapping) }
+    }
+
+    ///Stream and channel within it of a decoded channel
+    fn source(&self, channel: u8) -> (usize, usize) {
+        let channel = usize::from(channel);
+        if channel < 2 * self.coupled_streams {
+            (channel / 2, channel % 2)
+        } else {
+            (channel - self.coupled_streams, 0)
+        }
+    }
+
+    ///Mixes the first `samples` of the interleaved decoded channels into `pcm` with the demixing matrix
+    fn demix(&self, samples: usize, decoded: &[f32], pcm: &mut [f32]) {
+        let channels = self.channels();
+        let decoded_channels = self.streams + self.coupled_streams;
+        for (frame, decoded) in pcm[..samples * channels].chunks_mut(channels).zip(decoded.chunks(decoded_channels)) {
+            for out in frame.iter_mut() {
+                *out = 0.0;
+            }
+            for (&x, column) in decoded.iter().zip(self.demixing_matrix.chunks(channels)) {
+                for (out, &gain) in frame.iter_mut().zip(column) {
+                    *out += 1.0 / 32768.0 * f32::from(gain) * x;
+                }
+            }
+        }
+    }
+}
+
+///Ambisonic order and number of non-diegetic channels of an ambisonic channel count, which is
+///the square of the order plus one, plus an optional stereo pair
+fn ambisonic_order(channels: usize) -> Option<(usize, usize)> {
+    let order = (0..15).take_while(|order| (order + 1) * (order + 1) <= channels).last()?;
+    match channels - (order + 1) * (order + 1) {
+        nondiegetic @ 0 | nondiegetic @ 2 => Some((order, nondiegetic)),
+        _ => None,
+    }
+}
+
+///Decodes multistream packets, where every stream but the last is self-delimited, see RFC 7845 section 5.1.1.
+///Ambisonics of family 3 are demixed into the output channels
+pub struct Decoder {
+    streams: Vec<super::Decoder>,
+    mapping: ChannelMapping,
+}
+
+impl Decoder {
+    pub fn new(rate: SampleRate, mapping: ChannelMapping) -> Result<Self> {
+        mapping.validate()?;
+        let streams = (0..mapping.streams)
+            .map(|s| super::Decoder::new(rate, if s < mapping.coupled_streams { Channels::Stereo } else { Channels::Mono }))
+            .collect();
+        Ok(Self { streams, mapping })
+    }
+
+    pub fn channels(&self) -> usize {
+        self.mapping.channels()
+    }
+
+    pub fn mapping(&self) -> &ChannelMapping {
+        &self.mapping
+    }
+
+    ///The decoder of a single stream, for its controls and state
+    pub fn stream(&mut self, stream: usize) -> Option<&mut super::Decoder> {
+        self.streams.get_mut(stream)
+    }
+
+    pub fn reset(&mut self) {
+        for stream in &mut self.streams {
+            stream.reset();
+        }
+    }
+
+    ///Decodes a packet into `pcm` interleaved, and returns the number of samples per channel
+    pub fn decode(&mut self, data: &[u8], pcm: &mut [i16]) -> Result<usize> {
+        self.decode_i16(pcm, |decoder, buf| decoder.decode_float(data, buf))
+    }
+
+    ///Conceals `samples` per channel of lost audio into `pcm` interleaved, see `decode_lost_float`
+    pub fn decode_lost(&mut self, samples: usize, pcm: &mut [i16]) -> Result<usize> {
+        self.decode_i16(pcm, |decoder, buf| decoder.decode_lost_float(samples, buf))
+    }
+
+    ///Recovers `samples` per channel of lost audio into `pcm` interleaved, see `decode_fec_float`
+    pub fn decode_fec(&mut self, next_packet: &[u8], samples: usize, pcm: &mut [i16]) -> Result<usize> {
+        self.decode_i16(pcm, |decoder, buf| decoder.decode_fec_float(next_packet, samples, buf))
+    }
+
+    fn decode_i16<F>(&mut self, pcm: &mut [i16], decode: F) -> Result<usize>
+        where F: FnOnce(&mut Self, &mut [f32]) -> Result<usize> {
+        let mut buf = vec![0.0; pcm.len()];
+        let n = decode(self, &mut buf)?;
+        for (out, &x) in pcm.iter_mut().zip(buf[..n * self.channels()].iter()) {
+            *out = (x * 32768.0).clamp(-32768.0, 32767.0).round() as i16;
+        }
+        Ok(n)
+    }
+
+    ///Decodes a packet into `pcm` interleaved, with samples between -1 and 1.
+    ///Returns the number of samples per channel
+    pub fn decode_float(&mut self, data: &[u8], pcm: &mut [f32]) -> Result<usize> {
+        let (packets, n) = self.read(data)?;
+        self.decode_streams(n, pcm, |stream, s, buf| stream.decode_packet(&packets[s], buf))
+    }
+
+    ///Conceals `samples` per channel of lost audio in all streams, see `super::Decoder::decode_lost_float`
+    pub fn decode_lost_float(&mut self, samples: usize, pcm: &mut [f32]) -> Result<usize> {
+        self.decode_streams(samples, pcm, |stream, _, buf| stream.decode_lost_float(samples, buf))
+    }
+
+    ///Recovers `samples` per channel of lost audio in all streams, see `super::Decoder::decode_fec_float`
+    pub fn decode_fec_float(&mut self, next_packet: &[u8], samples: usize, pcm: &mut [f32]) -> Result<usize> {
+        let (packets, _) = self.read(next_packet)?;
+        self.decode_streams(samples, pcm, |stream, s, buf| stream.decode_fec_packet(&packets[s], samples, buf))
+    }
+
+    ///Splits a multistream packet into the packets of its streams, which have to be of the same duration.
+    ///Returns them with their number of samples per channel
+    fn read<'a>(&self, mut data: &'a [u8]) -> Result<(Vec<Packet<'a>>, usize)> {
+        let count = self.streams.len();
+        //Every self-delimited packet takes at least two bytes, the last one at least one
+        if data.len() < 2 * count - 1 {
+            return Err(PacketErrorKind::InvalidLength.into());
+        }
+        let mut packets = Vec::with_capacity(count);
+        for _ in 1..count {
+            let (packet, len) = Packet::read_self_delimited(data)?;
+            data = &data[len..];
+            packets.push(packet);
+        }
+        packets.push(Packet::read(data)?);
+
+        let decoder = &self.streams[0];
+        let duration = |packet: &Packet| packet.frame_count() * decoder.samples(packet.frame_size());
+        let n = duration(&packets[0]);
+        if packets.iter().any(|packet| duration(packet) != n) {
+            return Err(PacketErrorKind::InvalidFormat.into());
+        }
+        Ok((packets, n))
+    }
+
+    ///Runs `decode` on every stream and copies the channels of each to the output channels they map to.
+    ///For family 3 they're copied to the decoded channels in order and demixed from there
+    fn decode_streams<F>(&mut self, samples: usize, pcm: &mut [f32], mut decode: F) -> Result<usize>
+        where F: FnMut(&mut super::Decoder, usize, &mut [f32]) -> Result<usize> {
+        if samples * self.channels() > pcm.len() {
+            return Err(DecoderErrorKind::BufferTooSmall);
+        }
+        let projection = self.mapping.family == 3;
+        let decoded_channels = self.mapping.streams + self.mapping.coupled_streams;
+        let (mut decoded, order) = if projection {
+            (vec![0.0; samples * decoded_channels], (0..decoded_channels).map(|m| m as u8).collect())
+        } else {
+            (Vec::new(), Vec::new())
+        };
+        let (out, mapping) = if projection { (&mut decoded[..], &order[..]) } else { (&mut pcm[..], &self.mapping.mapping[..]) };
+        let channels = mapping.len();
+
+        let mut buf = vec![0.0; 2 * samples];
+        let mut n = 0;
+        for (s, stream) in self.streams.iter_mut().enumerate() {
+            n = decode(stream, s, &mut buf)?;
+            let stream_channels = stream.channels as usize;
+            for (c, &m) in mapping.iter().enumerate() {
+                if m == SILENT_CHANNEL || self.mapping.source(m).0 != s {
+                    continue;
+                }
+                let source = self.mapping.source(m).1;
+                for (out, &x) in out[c..n * channels].iter_mut().step_by(channels).zip(buf[source..].iter().step_by(stream_channels)) {
+                    *out = x;
+                }
+            }
+        }
+        for (c, _) in mapping.iter().enumerate().filter(|&(_, &m)| m == SILENT_CHANNEL) {
+            for out in out[c..n * channels].iter_mut().step_by(channels) {
+                *out = 0.0;
+            }
+        }
+        if projection {
+            self.mapping.demix(n, &decoded, pcm);
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitstream::Reader;
+
+    #[test]
+    fn vorbis() {
+        //5.1 surround, front left, center, front right, rear left, rear right and LFE
+        let mapping = ChannelMapping::read(1, 6, &[4, 2, 0, 4, 1, 2, 3, 5]).unwrap();
+        assert_eq!(mapping, ChannelMapping::vorbis(6).unwrap());
+        assert_eq!(mapping.channels(), 6);
+        assert_eq!(mapping.source(4), (2, 0));
+        assert_eq!(mapping.source(5), (3, 0));
+        assert_eq!(mapping.source(1), (0, 1));
+        //A decoded channel past the streams, and more channels than the family allows
+        assert!(ChannelMapping::read(1, 6, &[4, 2, 0, 4, 1, 2, 3, 6]).is_err());
+        assert!(ChannelMapping::read(1, 9, &[9, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8]).is_err());
+        assert!(ChannelMapping::read(1, 6, &[4, 2, 0, 4, 1, 2, 3]).is_err());
+        assert!(ChannelMapping::vorbis(9).is_none());
+        //Silent channels and mappings family 255 leaves open
+        let mapping = ChannelMapping::read(255, 3, &[1, 0, SILENT_CHANNEL, 0, 0]).unwrap();
+        assert_eq!(mapping.mapping, [SILENT_CHANNEL, 0, 0]);
+    }
+
+    #[test]
+    fn ambisonics() {
+        //First order with a non-diegetic stereo pair in the coupled stream
+        let mapping = ChannelMapping::read(2, 6, &[5, 1, 2, 3, 4, 5, 0, 1]).unwrap();
+        assert_eq!(mapping, ChannelMapping::ambisonics(6).unwrap());
+        assert_eq!(ChannelMapping::ambisonics(9).unwrap().streams, 9);
+        assert_eq!(ambisonic_order(16), Some((3, 0)));
+        assert_eq!(ambisonic_order(11), Some((2, 2)));
+        assert!(ChannelMapping::ambisonics(5).is_none());
+        assert!(ChannelMapping::read(2, 5, &[5, 0, 0, 1, 2, 3, 4]).is_err());
+    }
+
+    #[test]
+    fn projection() {
+        //First order ambisonics from a single coupled stream, two decoded channels for four output channels
+        let matrix: Vec<i16> = vec![16384, 0, 8192, -8192, 0, -32768, 8192, 8192];
+        let table: Vec<u8> = [1, 1].iter().cloned().chain(matrix.iter().flat_map(|gain| gain.to_le_bytes().to_vec())).collect();
+        let mapping = ChannelMapping::read(3, 4, &table).unwrap();
+        assert_eq!(mapping, ChannelMapping::projection(4, 1, 1, matrix.clone()));
+        assert_eq!(mapping.channels(), 4);
+        //The matrix needs a gain for every pair of decoded and output channel, and the output ambisonic channels
+        assert!(ChannelMapping::read(3, 4, &table[..table.len() - 2]).is_err());
+        assert!(ChannelMapping::projection(4, 1, 1, matrix[..6].to_vec()).validate().is_err());
+        assert!(ChannelMapping::projection(5, 1, 1, [&matrix[..], &[0, 0]].concat()).validate().is_err());
+        assert!(ChannelMapping::projection(4, 2, 2, [&matrix[..], &matrix[..]].concat()).validate().is_ok());
+
+        let mut pcm = [0.0; 2 * 4];
+        mapping.demix(2, &[0.5, 0.25, -1.0, 1.0], &mut pcm);
+        assert_eq!(pcm, [0.25, -0.25, 0.1875, -0.0625, -0.5, -1.0, 0.0, 0.5]);
+    }
+
+    #[test]
+    fn demix_stream() {
+        let data = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/celt_stereo.bit"));
+        let mut reader = Reader::new(&data[..]);
+        let matrix = vec![16384, 0, 8192, -8192, 0, -32768, 8192, 8192];
+        let mut decoder = Decoder::new(SampleRate::Khz48, ChannelMapping::projection(4, 1, 1, matrix)).unwrap();
+        let mut stereo = super::super::Decoder::new(SampleRate::Khz48, Channels::Stereo);
+        let mut pcm = [0.0; 960 * 4];
+        let mut expected = [0.0; 960 * 2];
+        while let Some(packet) = reader.read_packet().unwrap() {
+            assert_eq!(decoder.decode_float(&packet.data, &mut pcm).unwrap(), 960);
+            stereo.decode_float(&packet.data, &mut expected).unwrap();
+            for (out, x) in pcm.chunks(4).zip(expected.chunks(2)) {
+                let mixed = [0.5 * x[0], -x[1], 0.25 * (x[0] + x[1]), 0.25 * (x[1] - x[0])];
+                assert!(out.iter().zip(mixed.iter()).all(|(a, b)| (a - b).abs() < 1e-6));
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use super::lpc;
+use super::math::{add_sat16, lshift_sat32, rand, rshift_round, sat16, smulwb, smultt, smulww, sqrt_approx};
+use super::nlsf;
+use super::{ChannelState, Control, SignalType, MAX_FRAME_LENGTH, MAX_LPC_ORDER};
+
+///Largest power of two minus one that fits in a frame
+const BUF_MASK_MAX: i32 = 255;
+///Smoothing of the gain, 0.25^(1/4) in Q16
+const GAIN_SMTH_Q16: i32 = 4634;
+///The smoothed gain follows a subframe gain that is 3 dB lower right away
+const GAIN_SMTH_THRESHOLD_Q16: i32 = 46396;
+///Smoothing of the NLSFs, 0.25 in Q16
+const NLSF_SMTH_Q16: i32 = 16348;
+
+///Comfort noise state, estimated from inactive frames
+#[derive(Clone)]
+pub struct Cng {
+    exc_buf_q14: [i32; MAX_FRAME_LENGTH],
+    smth_nlsf_q15: [i16; MAX_LPC_ORDER],
+    synth_state: [i32; MAX_LPC_ORDER],
+    smth_gain_q16: i32,
+    rand_seed: i32,
+    fs_khz: usize,
+}
+
+impl Default for Cng {
+    fn default() -> Self {
+        Self {
+            exc_buf_q14: [0; MAX_FRAME_LENGTH],
+            smth_nlsf_q15: [0; MAX_LPC_ORDER],
+            synth_state: [0; MAX_LPC_ORDER],
+            smth_gain_q16: 0,
+            rand_seed: 0,
+            fs_khz: 0,
+        }
+    }
+}
+
+impl ChannelState {
+    pub fn cng_reset(&mut self) {
+        let step_q15 = i32::from(i16::MAX) / (self.lpc_order as i32 + 1);
+        let order = self.lpc_order;
+        for (i, nlsf) in self.cng.smth_nlsf_q15[..order].iter_mut().enumerate() {
+            *nlsf = (step_q15 * (i as i32 + 1)) as i16;
+        }
+        self.cng.smth_gain_q16 = 0;
+        self.cng.rand_seed = 3_176_576;
+    }
+
+    ///Updates the noise estimate from inactive frames, and adds comfort noise to concealed ones
+    pub fn cng(&mut self, ctrl: &Control, frame: &mut [i16]) {
+        if self.fs_khz != self.cng.fs_khz {
+            self.cng_reset();
+            self.cng.fs_khz = self.fs_khz;
+        }
+        let order = self.lpc_order;
+        let nb_subfr = self.nb_subfr;
+        let subfr_length = self.subfr_length;
+        let cng = &mut self.cng;
+
+        if self.loss_count == 0 && self.prev_signal_type == SignalType::Inactive {
+            for (smth, &prev) in cng.smth_nlsf_q15[..order].iter_mut().zip(self.prev_nlsf_q15.iter()) {
+                *smth += smulwb(i32::from(prev) - i32::from(*smth), NLSF_SMTH_Q16) as i16;
+            }
+
+            //Keep the excitation of the loudest subframe
+            let mut max_gain_q16 = 0;
+            let mut subfr = 0;
+            for (i, &gain) in ctrl.gains_q16[..nb_subfr].iter().enumerate() {
+                if gain > max_gain_q16 {
+                    max_gain_q16 = gain;
+                    subfr = i;
+                }
+            }
+            cng.exc_buf_q14.copy_within(..(nb_subfr - 1) * subfr_length, subfr_length);
+            cng.exc_buf_q14[..subfr_length].copy_from_slice(&self.exc_q14[subfr * subfr_length..(subfr + 1) * subfr_length]);
+
+            for &gain in ctrl.gains_q16[..nb_subfr].iter() {
+                cng.smth_gain_q16 += smulwb(gain - cng.smth_gain_q16, GAIN_SMTH_Q16);
+                if smulww(cng.smth_gain_q16, GAIN_SMTH_THRESHOLD_Q16) > gain {
+                    cng.smth_gain_q16 = gain;
+                }
+            }
+        }
+
+        if self.loss_count == 0 {
+            cng.synth_state[..order].iter_mut().for_each(|x| *x = 0);
+            return;
+        }
+
+        //The noise fills in the energy the concealment lacks
+        let mut gain_q16 = smulww(i32::from(self.plc.rand_scale_q14), self.plc.prev_gain_q16[1]);
+        if gain_q16 >= 1<<21 || cng.smth_gain_q16 > 1<<23 {
+            gain_q16 = smultt(gain_q16, gain_q16);
+            gain_q16 = smultt(cng.smth_gain_q16, cng.smth_gain_q16).wrapping_sub(gain_q16<<5);
+            gain_q16 = sqrt_approx(gain_q16)<<16;
+        } else {
+            gain_q16 = smulww(gain_q16, gain_q16);
+            gain_q16 = smulww(cng.smth_gain_q16, cng.smth_gain_q16).wrapping_sub(gain_q16<<5);
+            gain_q16 = sqrt_approx(gain_q16)<<8;
+        }
+        let gain_q10 = gain_q16>>6;
+
+        let length = frame.len();
+        let mut sig_q14 = [0i32; MAX_FRAME_LENGTH + MAX_LPC_ORDER];
+        let mut mask = BUF_MASK_MAX;
+        while mask > length as i32 {
+            mask >>= 1;
+        }
+        for x in sig_q14[MAX_LPC_ORDER..MAX_LPC_ORDER + length].iter_mut() {
+            cng.rand_seed = rand(cng.rand_seed);
+            *x = cng.exc_buf_q14[((cng.rand_seed>>24) & mask) as usize];
+        }
+
+        let a_q12 = nlsf::nlsf2a(&cng.smth_nlsf_q15[..order]);
+        sig_q14[..MAX_LPC_ORDER].copy_from_slice(&cng.synth_state);
+        for (i, out) in frame.iter_mut().enumerate() {
+            let pred_q10 = lpc::predict(&sig_q14[i..MAX_LPC_ORDER + i], &a_q12[..order]);
+            let s = sig_q14[MAX_LPC_ORDER + i].saturating_add(lshift_sat32(pred_q10, 4));
+            sig_q14[MAX_LPC_ORDER + i] = s;
+            *out = add_sat16(i32::from(*out), i32::from(sat16(rshift_round(smulww(s, gain_q10), 8)))) as i16;
+        }
+        cng.synth_state.copy_from_slice(&sig_q14[length..length + MAX_LPC_ORDER]);
+    }
+}
@@ -0,0 +1,275 @@
+use range;
+use super::lpc;
+use super::math::{
+    div32_var_q, inverse32_var_q, log2lin, lshift_sat32, rand, rshift_round, sat16, smlawb, smulwb,
+    smulww,
+};
+use super::nlsf;
+use super::pulses::{self, SHELL_CODEC_FRAME_LENGTH};
+use super::tables;
+use super::{
+    ChannelState, Coding, Control, FrameKind, SignalType, LTP_ORDER, MAX_FRAME_LENGTH, MAX_LPC_ORDER,
+    MAX_LTP_MEM_LENGTH, MAX_NB_SUBFR,
+};
+
+///Pulses are moved towards zero by this much, in Q10
+const QUANT_LEVEL_ADJUST_Q10: i32 = 80;
+///Bandwidth expansion of the first frame after a loss, 0.97 in Q16
+const BWE_AFTER_LOSS_Q16: i32 = 63570;
+///Gains are quantized in 1.369 dB steps from 2 dB
+const OFFSET: i32 = 2090;
+const INV_SCALE_Q16: i32 = 1_907_825;
+const MIN_DELTA_GAIN_QUANT: i32 = -4;
+const MAX_DELTA_GAIN_QUANT: i32 = 36;
+const N_LEVELS_QGAIN: i32 = 64;
+///Pitch lag range in ms
+const PE_MIN_LAG_MS: i32 = 2;
+const PE_MAX_LAG_MS: i32 = 18;
+
+impl ChannelState {
+    ///Decodes or conceals a frame into `out` and returns its length
+    pub fn decode_frame(&mut self, rc: &mut range::Decoder, out: &mut [i16], kind: FrameKind, coding: Coding) -> usize {
+        let len = self.frame_length;
+        let out = &mut out[..len];
+        let mut ctrl = Control::default();
+
+        if kind == FrameKind::Normal || (kind == FrameKind::Lbrr && self.lbrr_flags[self.frames_decoded]) {
+            let mut pulses = [0i16; (MAX_FRAME_LENGTH + SHELL_CODEC_FRAME_LENGTH - 1) & !(SHELL_CODEC_FRAME_LENGTH - 1)];
+            let frame_index = self.frames_decoded;
+            self.decode_indices(rc, frame_index, kind == FrameKind::Lbrr, coding);
+            pulses::decode(rc, &mut pulses, self.indices.signal_type, self.indices.quant_offset, len);
+            self.decode_parameters(&mut ctrl, coding);
+            self.decode_core(&mut ctrl, out, &pulses);
+            self.plc(&mut ctrl, out, false);
+            self.loss_count = 0;
+            self.prev_signal_type = self.indices.signal_type;
+            self.first_frame_after_reset = false;
+        } else {
+            self.plc(&mut ctrl, out, true);
+        }
+
+        //Keep the output for the long-term prediction
+        let mv_len = self.ltp_mem_length - len;
+        self.out_buf.copy_within(len..len + mv_len, 0);
+        self.out_buf[mv_len..mv_len + len].copy_from_slice(out);
+
+        self.cng(&ctrl, out);
+        self.glue_frames(out);
+        self.lag_prev = ctrl.pitch_l[self.nb_subfr - 1];
+        len
+    }
+
+    ///Dequantizes the gains, filters and pitch of the decoded indices
+    fn decode_parameters(&mut self, ctrl: &mut Control, coding: Coding) {
+        self.gains_dequant(&mut ctrl.gains_q16, coding == Coding::Conditionally);
+
+        let order = self.lpc_order;
+        let nlsf_q15 = nlsf::decode(&self.indices.nlsf, self.nlsf_cb);
+        ctrl.pred_coef_q12[1] = nlsf::nlsf2a(&nlsf_q15[..order]);
+
+        //No interpolation of the first frame after a reset, the previous NLSFs are invalid
+        if self.first_frame_after_reset {
+            self.indices.nlsf_interp_coef_q2 = 4;
+        }
+        let interp = self.indices.nlsf_interp_coef_q2;
+        if interp < 4 {
+            let mut nlsf0_q15 = [0i16; MAX_LPC_ORDER];
+            for i in 0..order {
+                let prev = i32::from(self.prev_nlsf_q15[i]);
+                nlsf0_q15[i] = (prev + ((interp * (i32::from(nlsf_q15[i]) - prev))>>2)) as i16;
+            }
+            ctrl.pred_coef_q12[0] = nlsf::nlsf2a(&nlsf0_q15[..order]);
+        } else {
+            ctrl.pred_coef_q12[0] = ctrl.pred_coef_q12[1];
+        }
+        self.prev_nlsf_q15 = nlsf_q15;
+
+        //Limit the filter gains after a loss, which would otherwise amplify the concealed signal
+        if self.loss_count != 0 {
+            lpc::bwexpander(&mut ctrl.pred_coef_q12[0][..order], BWE_AFTER_LOSS_Q16);
+            lpc::bwexpander(&mut ctrl.pred_coef_q12[1][..order], BWE_AFTER_LOSS_Q16);
+        }
+
+        if self.indices.signal_type == SignalType::Voiced {
+            self.decode_pitch(&mut ctrl.pitch_l);
+            let cb = tables::LTP_VQ[self.indices.per];
+            for k in 0..self.nb_subfr {
+                let vector = &cb[self.indices.ltp[k]];
+                for (coef, &v) in ctrl.ltp_coef_q14[k * LTP_ORDER..(k + 1) * LTP_ORDER].iter_mut().zip(vector.iter()) {
+                    *coef = i16::from(v)<<7;
+                }
+            }
+            ctrl.ltp_scale_q14 = i32::from(tables::LTP_SCALES_Q14[self.indices.ltp_scale]);
+        } else {
+            ctrl.pitch_l = [0; MAX_NB_SUBFR];
+            ctrl.ltp_coef_q14 = [0; LTP_ORDER * MAX_NB_SUBFR];
+            self.indices.per = 0;
+            ctrl.ltp_scale_q14 = 0;
+        }
+    }
+
+    fn gains_dequant(&mut self, gains_q16: &mut [i32; MAX_NB_SUBFR], conditional: bool) {
+        let prev = &mut self.last_gain_index;
+        for (k, gain) in gains_q16[..self.nb_subfr].iter_mut().enumerate() {
+            let ind = self.indices.gains[k];
+            if k == 0 && !conditional {
+                //The gain can't drop more than 16 steps, about 21.8 dB
+                *prev = ind.max(*prev - 16);
+            } else {
+                //Large increases are coded in double steps
+                let ind_tmp = ind + MIN_DELTA_GAIN_QUANT;
+                let double_step_size_threshold = 2 * MAX_DELTA_GAIN_QUANT - N_LEVELS_QGAIN + *prev;
+                if ind_tmp > double_step_size_threshold {
+                    *prev += (ind_tmp<<1) - double_step_size_threshold;
+                } else {
+                    *prev += ind_tmp;
+                }
+            }
+            *prev = (*prev).clamp(0, N_LEVELS_QGAIN - 1);
+            *gain = log2lin((smulwb(INV_SCALE_Q16, *prev) + OFFSET).min(3967));
+        }
+    }
+
+    ///Pitch lags of the subframes from the lag and its contour
+    fn decode_pitch(&self, pitch_l: &mut [i32; MAX_NB_SUBFR]) {
+        let contour = self.indices.contour;
+        let min_lag = PE_MIN_LAG_MS * self.fs_khz as i32;
+        let max_lag = PE_MAX_LAG_MS * self.fs_khz as i32;
+        let lag = min_lag + self.indices.lag;
+        for (k, pitch) in pitch_l[..self.nb_subfr].iter_mut().enumerate() {
+            let offset = match (self.fs_khz, self.nb_subfr) {
+                (8, MAX_NB_SUBFR) => tables::CB_LAGS_STAGE2[k][contour],
+                (8, _) => tables::CB_LAGS_STAGE2_10_MS[k][contour],
+                (_, MAX_NB_SUBFR) => tables::CB_LAGS_STAGE3[k][contour],
+                _ => tables::CB_LAGS_STAGE3_10_MS[k][contour],
+            };
+            *pitch = (lag + i32::from(offset)).max(min_lag).min(max_lag);
+        }
+    }
+
+    ///Reconstructs the excitation and runs it through the long-term and short-term synthesis filters
+    fn decode_core(&mut self, ctrl: &mut Control, xq: &mut [i16], pulses: &[i16]) {
+        let order = self.lpc_order;
+        let subfr_length = self.subfr_length;
+        let ltp_mem_length = self.ltp_mem_length;
+        let signal_type = self.indices.signal_type;
+        let offset_q10 = i32::from(tables::QUANTIZATION_OFFSETS_Q10[(signal_type as usize)>>1][self.indices.quant_offset]);
+        let nlsf_interpolation = self.indices.nlsf_interp_coef_q2 < 4;
+
+        //Dequantize the excitation, with pseudorandom signs
+        let mut rand_seed = self.indices.seed;
+        for (exc, &pulse) in self.exc_q14[..self.frame_length].iter_mut().zip(pulses.iter()) {
+            rand_seed = rand(rand_seed);
+            *exc = i32::from(pulse)<<14;
+            if *exc > 0 {
+                *exc -= QUANT_LEVEL_ADJUST_Q10<<4;
+            } else if *exc < 0 {
+                *exc += QUANT_LEVEL_ADJUST_Q10<<4;
+            }
+            *exc += offset_q10<<4;
+            if rand_seed < 0 {
+                *exc = -*exc;
+            }
+            rand_seed = rand_seed.wrapping_add(i32::from(pulse));
+        }
+
+        let mut s_lpc_q14 = [0i32; MAX_LPC_ORDER + MAX_FRAME_LENGTH / 2];
+        s_lpc_q14[..MAX_LPC_ORDER].copy_from_slice(&self.s_lpc_q14_buf);
+        let mut s_ltp = [0i16; MAX_LTP_MEM_LENGTH];
+        let mut s_ltp_q15 = [0i32; MAX_LTP_MEM_LENGTH + MAX_FRAME_LENGTH];
+        let mut res_buf = [0i32; MAX_FRAME_LENGTH / 2];
+        let mut s_ltp_buf_idx = ltp_mem_length;
+        let mut lag = 0;
+
+        for k in 0..self.nb_subfr {
+            let a_q12 = ctrl.pred_coef_q12[k>>1];
+            let gain_q16 = ctrl.gains_q16[k];
+            let gain_q10 = gain_q16>>6;
+            let mut inv_gain_q31 = inverse32_var_q(gain_q16, 47);
+            let mut signal_type = signal_type;
+
+            //Rescale the filter state to the gain of this subframe
+            let gain_adj_q16 = if gain_q16 != self.prev_gain_q16 {
+                let adj = div32_var_q(self.prev_gain_q16, gain_q16, 16);
+                for s in s_lpc_q14[..MAX_LPC_ORDER].iter_mut() {
+                    *s = smulww(adj, *s);
+                }
+                adj
+            } else {
+                1<<16
+            };
+            self.prev_gain_q16 = gain_q16;
+
+            //After concealing a voiced frame, the first half of an unvoiced frame continues the pitch
+            //with a weak predictor so the transition is smooth
+            if self.loss_count != 0 && self.prev_signal_type == SignalType::Voiced
+                && signal_type != SignalType::Voiced && k < MAX_NB_SUBFR / 2 {
+                let b = &mut ctrl.ltp_coef_q14[k * LTP_ORDER..(k + 1) * LTP_ORDER];
+                for b in b.iter_mut() {
+                    *b = 0;
+                }
+                b[LTP_ORDER / 2] = 4096;
+                signal_type = SignalType::Voiced;
+                ctrl.pitch_l[k] = self.lag_prev;
+            }
+
+            if signal_type == SignalType::Voiced {
+                lag = ctrl.pitch_l[k] as usize;
+                //Re-whiten the past output with the filter of this subframe
+                if k == 0 || (k == 2 && nlsf_interpolation) {
+                    let start_idx = ltp_mem_length - lag - order - LTP_ORDER / 2;
+                    if k == 2 {
+                        self.out_buf[ltp_mem_length..ltp_mem_length + 2 * subfr_length].copy_from_slice(&xq[..2 * subfr_length]);
+                    }
+                    let input_start = start_idx + k * subfr_length;
+                    lpc::analysis_filter(
+                        &mut s_ltp[start_idx..ltp_mem_length],
+                        &self.out_buf[input_start..input_start + ltp_mem_length - start_idx],
+                        &a_q12[..order],
+                    );
+                    //The long-term prediction scaling only applies to the first subframe
+                    if k == 0 {
+                        inv_gain_q31 = smulwb(inv_gain_q31, ctrl.ltp_scale_q14)<<2;
+                    }
+                    for i in 0..lag + LTP_ORDER / 2 {
+                        s_ltp_q15[s_ltp_buf_idx - i - 1] = smulwb(inv_gain_q31, i32::from(s_ltp[ltp_mem_length - i - 1]));
+                    }
+                } else if gain_adj_q16 != 1<<16 {
+                    for i in 0..lag + LTP_ORDER / 2 {
+                        s_ltp_q15[s_ltp_buf_idx - i - 1] = smulww(gain_adj_q16, s_ltp_q15[s_ltp_buf_idx - i - 1]);
+                    }
+                }
+            }
+
+            let exc_q14 = &self.exc_q14[k * subfr_length..(k + 1) * subfr_length];
+            let res_q14: &[i32] = if signal_type == SignalType::Voiced {
+                //Long-term prediction
+                let b_q14 = &ctrl.ltp_coef_q14[k * LTP_ORDER..(k + 1) * LTP_ORDER];
+                for i in 0..subfr_length {
+                    let pred_lag = s_ltp_buf_idx - lag + LTP_ORDER / 2;
+                    let mut ltp_pred_q13 = 2;
+                    for (j, &b) in b_q14.iter().enumerate() {
+                        ltp_pred_q13 = smlawb(ltp_pred_q13, s_ltp_q15[pred_lag - j], i32::from(b));
+                    }
+                    res_buf[i] = exc_q14[i].wrapping_add(ltp_pred_q13<<1);
+                    s_ltp_q15[s_ltp_buf_idx] = res_buf[i]<<1;
+                    s_ltp_buf_idx += 1;
+                }
+                &res_buf[..subfr_length]
+            } else {
+                exc_q14
+            };
+
+            //Short-term prediction
+            let pxq = &mut xq[k * subfr_length..(k + 1) * subfr_length];
+            for i in 0..subfr_length {
+                let pred_q10 = lpc::predict(&s_lpc_q14[i..MAX_LPC_ORDER + i], &a_q12[..order]);
+                let s = res_q14[i].saturating_add(lshift_sat32(pred_q10, 4));
+                s_lpc_q14[MAX_LPC_ORDER + i] = s;
+                pxq[i] = sat16(rshift_round(smulww(s, gain_q10), 8));
+            }
+            s_lpc_q14.copy_within(subfr_length..subfr_length + MAX_LPC_ORDER, 0);
+        }
+        self.s_lpc_q14_buf.copy_from_slice(&s_lpc_q14[..MAX_LPC_ORDER]);
+    }
+}
@@ -0,0 +1,99 @@
+use range;
+use super::nlsf::{self, NLSF_QUANT_MAX_AMPLITUDE};
+use super::tables::icdf;
+use super::{ChannelState, Coding, SignalType, MAX_LPC_ORDER, MAX_NB_SUBFR};
+
+///Quantization indices of a frame
+#[derive(Clone, Default)]
+pub struct Indices {
+    pub gains: [i32; MAX_NB_SUBFR],
+    pub ltp: [usize; MAX_NB_SUBFR],
+    ///First stage codebook vector followed by the residuals
+    pub nlsf: [i8; MAX_LPC_ORDER + 1],
+    pub lag: i32,
+    pub contour: usize,
+    pub signal_type: SignalType,
+    pub quant_offset: usize,
+    pub nlsf_interp_coef_q2: i32,
+    ///Long-term predictor codebook
+    pub per: usize,
+    pub ltp_scale: usize,
+    pub seed: i32,
+}
+
+impl ChannelState {
+    ///Decodes the side information of frame `frame_index` of the packet
+    pub fn decode_indices(&mut self, rc: &mut range::Decoder, frame_index: usize, lbrr: bool, coding: Coding) {
+        let ix = if lbrr || self.vad_flags[frame_index] {
+            rc.decode_icdf(&icdf::TYPE_OFFSET_VAD, 8) + 2
+        } else {
+            rc.decode_icdf(&icdf::TYPE_OFFSET_NO_VAD, 8)
+        };
+        let indices = &mut self.indices;
+        indices.signal_type = match ix>>1 {
+            0 => SignalType::Inactive,
+            1 => SignalType::Unvoiced,
+            _ => SignalType::Voiced,
+        };
+        indices.quant_offset = ix & 1;
+
+        //The first gain is either relative to the previous frame, or coded in two stages, MSBs then 3 LSBs
+        indices.gains[0] = if coding == Coding::Conditionally {
+            rc.decode_icdf(&icdf::DELTA_GAIN, 8) as i32
+        } else {
+            ((rc.decode_icdf(&icdf::GAIN[indices.signal_type as usize], 8)<<3) + rc.decode_icdf(&icdf::UNIFORM8, 8)) as i32
+        };
+        for gain in indices.gains[1..self.nb_subfr].iter_mut() {
+            *gain = rc.decode_icdf(&icdf::DELTA_GAIN, 8) as i32;
+        }
+
+        let cb = self.nlsf_cb;
+        let cb1_index = rc.decode_icdf(&cb.cb1_icdf[((indices.signal_type as usize)>>1) * cb.vectors..], 8);
+        indices.nlsf[0] = cb1_index as i8;
+        let (ec_ix, _) = nlsf::unpack(cb, cb1_index);
+        for (i, &ec_ix) in ec_ix[..cb.order].iter().enumerate() {
+            let mut ix = rc.decode_icdf(&cb.ec_icdf[ec_ix..], 8) as i32;
+            if ix == 0 {
+                ix -= rc.decode_icdf(&icdf::NLSF_EXT, 8) as i32;
+            } else if ix == 2 * NLSF_QUANT_MAX_AMPLITUDE {
+                ix += rc.decode_icdf(&icdf::NLSF_EXT, 8) as i32;
+            }
+            indices.nlsf[i + 1] = (ix - NLSF_QUANT_MAX_AMPLITUDE) as i8;
+        }
+
+        indices.nlsf_interp_coef_q2 = if self.nb_subfr == MAX_NB_SUBFR {
+            rc.decode_icdf(&icdf::NLSF_INTERPOLATION_FACTOR, 8) as i32
+        } else {
+            4
+        };
+
+        if indices.signal_type == SignalType::Voiced {
+            let mut absolute_lag = true;
+            if coding == Coding::Conditionally && self.ec_prev_signal_type == SignalType::Voiced {
+                let delta = rc.decode_icdf(&icdf::PITCH_DELTA, 8) as i32;
+                if delta > 0 {
+                    indices.lag = self.ec_prev_lag_index + delta - 9;
+                    absolute_lag = false;
+                }
+            }
+            if absolute_lag {
+                indices.lag = (rc.decode_icdf(&icdf::PITCH_LAG, 8) * (self.fs_khz>>1)) as i32;
+                indices.lag += rc.decode_icdf(self.pitch_lag_low_bits_icdf, 8) as i32;
+            }
+            self.ec_prev_lag_index = indices.lag;
+            indices.contour = rc.decode_icdf(self.pitch_contour_icdf, 8);
+
+            indices.per = rc.decode_icdf(&icdf::LTP_PER_INDEX, 8);
+            for ltp in indices.ltp[..self.nb_subfr].iter_mut() {
+                *ltp = rc.decode_icdf(icdf::LTP_GAIN[indices.per], 8);
+            }
+            indices.ltp_scale = if coding == Coding::Independently {
+                rc.decode_icdf(&icdf::LTP_SCALE, 8)
+            } else {
+                0
+            };
+        }
+        self.ec_prev_signal_type = indices.signal_type;
+        indices.seed = rc.decode_icdf(&icdf::UNIFORM4, 8) as i32;
+    }
+}
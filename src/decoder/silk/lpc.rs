@@ -0,0 +1,159 @@
+use super::math::{clz32, inverse32_var_q, rshift_round, rshift_round64, sat16, smlabb, smlawb, smmul, smulww};
+use super::MAX_LPC_ORDER;
+
+///Q domain of the stability check
+const QA: u32 = 24;
+///Reflection coefficients closer to one than this count as unstable
+const A_LIMIT: i32 = 16_773_022;
+///Smallest allowed inverse prediction gain, 1/1e4 in Q30
+const MIN_INV_GAIN_Q30: i32 = 107_374;
+
+///Converts coefficients in Q`q_in` to 16 bits in Q`q_out`, bandwidth expanding them so they don't wrap around
+pub fn fit(a_out: &mut [i16], a_in: &mut [i32], q_out: u32, q_in: u32) {
+    let d = a_in.len();
+    let mut clipped = true;
+    for _ in 0..10 {
+        //Find the largest magnitude
+        let mut maxabs = 0;
+        let mut idx = 0;
+        for (k, &a) in a_in.iter().enumerate() {
+            if a.wrapping_abs() > maxabs {
+                maxabs = a.wrapping_abs();
+                idx = k;
+            }
+        }
+        maxabs = rshift_round(maxabs, q_in - q_out);
+
+        if maxabs > i32::from(i16::MAX) {
+            //Reduce the magnitude of the coefficients, (i32::MAX>>14) + i16::MAX = 163838
+            maxabs = maxabs.min(163_838);
+            let chirp_q16 = 65470 - ((maxabs - i32::from(i16::MAX))<<14) / ((maxabs * (idx as i32 + 1))>>2);
+            bwexpander_32(a_in, chirp_q16);
+        } else {
+            clipped = false;
+            break;
+        }
+    }
+
+    for k in 0..d {
+        a_out[k] = if clipped {
+            sat16(rshift_round(a_in[k], q_in - q_out))
+        } else {
+            rshift_round(a_in[k], q_in - q_out) as i16
+        };
+        if clipped {
+            a_in[k] = i32::from(a_out[k])<<(q_in - q_out);
+        }
+    }
+}
+
+///Inverse of the prediction gain of `a_q12` in Q30, or 0 if the filter is unstable
+pub fn inverse_pred_gain(a_q12: &[i16]) -> i32 {
+    let mut a_qa = [0i32; MAX_LPC_ORDER];
+    let mut dc_resp = 0;
+    for (a_qa, &a) in a_qa.iter_mut().zip(a_q12.iter()) {
+        dc_resp += i32::from(a);
+        *a_qa = i32::from(a)<<(QA - 12);
+    }
+    //If the DC is unstable we don't need the full calculation
+    if dc_resp >= 4096 {
+        return 0;
+    }
+    inverse_pred_gain_qa(&mut a_qa[..a_q12.len()])
+}
+
+fn inverse_pred_gain_qa(a_qa: &mut [i32]) -> i32 {
+    let mut inv_gain_q30 = 1<<30;
+    for k in (1..a_qa.len()).rev() {
+        if a_qa[k] > A_LIMIT || a_qa[k] < -A_LIMIT {
+            return 0;
+        }
+        //Reflection coefficient is the negated AR coefficient
+        let rc_q31 = -(a_qa[k]<<(31 - QA));
+        let rc_mult1_q30 = (1<<30) - smmul(rc_q31, rc_q31);
+        inv_gain_q30 = smmul(inv_gain_q30, rc_mult1_q30)<<2;
+        if inv_gain_q30 < MIN_INV_GAIN_Q30 {
+            return 0;
+        }
+
+        let mult2_q = 32 - clz32(rc_mult1_q30.abs());
+        let rc_mult2 = inverse32_var_q(rc_mult1_q30, mult2_q + 30);
+
+        //Step down to the next lower order
+        let mul_frac_q31 = |a: i32, b: i32| rshift_round64(i64::from(a) * i64::from(b), 31) as i32;
+        for n in 0..(k + 1)>>1 {
+            let tmp1 = a_qa[n];
+            let tmp2 = a_qa[k - n - 1];
+            let tmp64 = rshift_round64(i64::from(tmp1.saturating_sub(mul_frac_q31(tmp2, rc_q31))) * i64::from(rc_mult2), mult2_q as u32);
+            if tmp64 > i64::from(i32::MAX) || tmp64 < i64::from(i32::MIN) {
+                return 0;
+            }
+            a_qa[n] = tmp64 as i32;
+            let tmp64 = rshift_round64(i64::from(tmp2.saturating_sub(mul_frac_q31(tmp1, rc_q31))) * i64::from(rc_mult2), mult2_q as u32);
+            if tmp64 > i64::from(i32::MAX) || tmp64 < i64::from(i32::MIN) {
+                return 0;
+            }
+            a_qa[k - n - 1] = tmp64 as i32;
+        }
+    }
+
+    if a_qa[0] > A_LIMIT || a_qa[0] < -A_LIMIT {
+        return 0;
+    }
+    let rc_q31 = -(a_qa[0]<<(31 - QA));
+    let rc_mult1_q30 = (1<<30) - smmul(rc_q31, rc_q31);
+    inv_gain_q30 = smmul(inv_gain_q30, rc_mult1_q30)<<2;
+    if inv_gain_q30 < MIN_INV_GAIN_Q30 {
+        return 0;
+    }
+    inv_gain_q30
+}
+
+///Chirps the filter `ar` by `chirp_q16`, widening the bandwidth of its poles
+pub fn bwexpander(ar: &mut [i16], mut chirp_q16: i32) {
+    let chirp_minus_one_q16 = chirp_q16 - 65536;
+    let d = ar.len();
+    //Rounding instead of smulwb, whose bias can make the filter unstable
+    for a in ar[..d - 1].iter_mut() {
+        *a = rshift_round(chirp_q16 * i32::from(*a), 16) as i16;
+        chirp_q16 += rshift_round(chirp_q16 * chirp_minus_one_q16, 16);
+    }
+    ar[d - 1] = rshift_round(chirp_q16 * i32::from(ar[d - 1]), 16) as i16;
+}
+
+pub fn bwexpander_32(ar: &mut [i32], mut chirp_q16: i32) {
+    let chirp_minus_one_q16 = chirp_q16 - 65536;
+    let d = ar.len();
+    for a in ar[..d - 1].iter_mut() {
+        *a = smulww(chirp_q16, *a);
+        chirp_q16 += rshift_round(chirp_q16 * chirp_minus_one_q16, 16);
+    }
+    ar[d - 1] = smulww(chirp_q16, ar[d - 1]);
+}
+
+///Filters `input` with the whitening filter `b` in Q12. The first `b.len()` outputs are zero
+pub fn analysis_filter(out: &mut [i16], input: &[i16], b: &[i16]) {
+    let d = b.len();
+    for ix in d..input.len() {
+        //Wrapping, so two wraps of an invalid stream can cancel each other
+        let mut out32_q12 = 0i32;
+        for (j, &b) in b.iter().enumerate() {
+            out32_q12 = smlabb(out32_q12, i32::from(input[ix - 1 - j]), i32::from(b));
+        }
+        let out32_q12 = (i32::from(input[ix])<<12).wrapping_sub(out32_q12);
+        out[ix] = sat16(rshift_round(out32_q12, 12));
+    }
+    for o in out[..d].iter_mut() {
+        *o = 0;
+    }
+}
+
+///Short-term prediction in Q10 of the sample after `hist`, from its last `a_q12.len()` samples in Q14
+#[inline]
+pub fn predict(hist: &[i32], a_q12: &[i16]) -> i32 {
+    let mut pred_q10 = (a_q12.len() as i32)>>1;
+    for (&x, &a) in hist.iter().rev().zip(a_q12.iter()) {
+        pred_q10 = smlawb(pred_q10, x, i32::from(a));
+    }
+    pred_q10
+}
@@ -0,0 +1,196 @@
+//Fixed-point helpers. The decoder has to match the reference bit for bit,
+//so these follow the 64-bit variants of the reference macros exactly
+
+///(a * b[15:0])>>16
+#[inline]
+pub fn smulwb(a: i32, b: i32) -> i32 {
+    ((i64::from(a) * i64::from(b as i16))>>16) as i32
+}
+
+#[inline]
+pub fn smlawb(a: i32, b: i32, c: i32) -> i32 {
+    a.wrapping_add(smulwb(b, c))
+}
+
+///(a * b)>>16
+#[inline]
+pub fn smulww(a: i32, b: i32) -> i32 {
+    ((i64::from(a) * i64::from(b))>>16) as i32
+}
+
+#[inline]
+pub fn smlaww(a: i32, b: i32, c: i32) -> i32 {
+    a.wrapping_add(smulww(b, c))
+}
+
+///a[15:0] * b[15:0]
+#[inline]
+pub fn smulbb(a: i32, b: i32) -> i32 {
+    i32::from(a as i16) * i32::from(b as i16)
+}
+
+#[inline]
+pub fn smlabb(a: i32, b: i32, c: i32) -> i32 {
+    a.wrapping_add(smulbb(b, c))
+}
+
+///a[31:16] * b[31:16]
+#[inline]
+pub fn smultt(a: i32, b: i32) -> i32 {
+    (a>>16) * (b>>16)
+}
+
+///(a * b)>>32
+#[inline]
+pub fn smmul(a: i32, b: i32) -> i32 {
+    ((i64::from(a) * i64::from(b))>>32) as i32
+}
+
+///Shift right with rounding, `shift` must be positive
+#[inline]
+pub fn rshift_round(a: i32, shift: u32) -> i32 {
+    if shift == 1 {
+        (a>>1) + (a & 1)
+    } else {
+        ((a>>(shift - 1)) + 1)>>1
+    }
+}
+
+#[inline]
+pub fn rshift_round64(a: i64, shift: u32) -> i64 {
+    if shift == 1 {
+        (a>>1) + (a & 1)
+    } else {
+        ((a>>(shift - 1)) + 1)>>1
+    }
+}
+
+#[inline]
+pub fn sat16(a: i32) -> i16 {
+    a.max(i32::from(i16::MIN)).min(i32::from(i16::MAX)) as i16
+}
+
+#[inline]
+pub fn add_sat16(a: i32, b: i32) -> i32 {
+    i32::from(sat16(a + b))
+}
+
+///Shift left, saturating to the 32-bit range
+#[inline]
+pub fn lshift_sat32(a: i32, shift: u32) -> i32 {
+    a.max(i32::MIN>>shift).min(i32::MAX>>shift)<<shift
+}
+
+#[inline]
+pub fn clz32(x: i32) -> i32 {
+    (x as u32).leading_zeros() as i32
+}
+
+///Leading zeros and the 7 bits right after the leading one
+#[inline]
+pub fn clz_frac(x: i32) -> (i32, i32) {
+    let lz = clz32(x);
+    let frac_q7 = (x as u32).rotate_right((24 - lz) as u32 & 31) as i32 & 0x7f;
+    (lz, frac_q7)
+}
+
+///Approximates the square root within 10% for values above 15, and 2.5% above 120
+pub fn sqrt_approx(x: i32) -> i32 {
+    if x <= 0 {
+        return 0;
+    }
+    let (lz, frac_q7) = clz_frac(x);
+    let mut y = if lz & 1 != 0 { 32768 } else { 46214 };
+    y >>= lz>>1;
+    smlawb(y, y, smulbb(213, frac_q7))
+}
+
+///Approximates `(a<<q_res) / b`
+pub fn div32_var_q(a: i32, b: i32, q_res: i32) -> i32 {
+    debug_assert!(b != 0 && q_res >= 0);
+    let a_headrm = clz32(a.wrapping_abs()) - 1;
+    let mut a_nrm = a<<a_headrm;
+    let b_headrm = clz32(b.wrapping_abs()) - 1;
+    let b_nrm = b<<b_headrm;
+
+    //Inverse of b with 14 bits of precision
+    let b_inv = (i32::MAX>>2) / (b_nrm>>16);
+    let mut result = smulwb(a_nrm, b_inv);
+    //The residual may overflow, its final value is small
+    a_nrm = a_nrm.wrapping_sub(smmul(b_nrm, result).wrapping_shl(3));
+    result = smlawb(result, a_nrm, b_inv);
+
+    let lshift = 29 + a_headrm - b_headrm - q_res;
+    if lshift < 0 {
+        lshift_sat32(result, -lshift as u32)
+    } else if lshift < 32 {
+        result>>lshift
+    } else {
+        0
+    }
+}
+
+///Approximates `(1<<q_res) / b`
+pub fn inverse32_var_q(b: i32, q_res: i32) -> i32 {
+    debug_assert!(b != 0 && q_res > 0);
+    let b_headrm = clz32(b.wrapping_abs()) - 1;
+    let b_nrm = b<<b_headrm;
+
+    let b_inv = (i32::MAX>>2) / (b_nrm>>16);
+    let mut result = b_inv<<16;
+    let err_q32 = ((1<<29) - smulwb(b_nrm, b_inv))<<3;
+    result = smlaww(result, err_q32, b_inv);
+
+    let lshift = 61 - b_headrm - q_res;
+    if lshift <= 0 {
+        lshift_sat32(result, -lshift as u32)
+    } else if lshift < 32 {
+        result>>lshift
+    } else {
+        0
+    }
+}
+
+///Approximates 2^(x/128)
+pub fn log2lin(in_log_q7: i32) -> i32 {
+    if in_log_q7 < 0 {
+        return 0;
+    } else if in_log_q7 >= 3967 {
+        return i32::MAX;
+    }
+    let out = 1<<(in_log_q7>>7);
+    let frac_q7 = in_log_q7 & 0x7f;
+    let parabola = smlawb(frac_q7, smulbb(frac_q7, 128 - frac_q7), -174);
+    if in_log_q7 < 2048 {
+        out + ((out * parabola)>>7)
+    } else {
+        out + (out>>7) * parabola
+    }
+}
+
+///Linear congruential generator
+#[inline]
+pub fn rand(seed: i32) -> i32 {
+    907_633_515i32.wrapping_add(seed.wrapping_mul(196_314_165))
+}
+
+///Energy of `x`, shifted right so it fits with two bits of headroom. Returns the energy and the shift
+pub fn sum_sqr_shift(x: &[i16]) -> (i32, i32) {
+    fn sum(x: &[i16], shift: i32, init: i32) -> i32 {
+        let mut nrg = init;
+        for pair in x.chunks(2) {
+            let mut tmp = smulbb(i32::from(pair[0]), i32::from(pair[0])) as u32;
+            if pair.len() == 2 {
+                tmp = tmp.wrapping_add(smulbb(i32::from(pair[1]), i32::from(pair[1])) as u32);
+            }
+            nrg = (nrg as u32).wrapping_add(tmp>>shift) as i32;
+        }
+        nrg
+    }
+    let len = x.len() as i32;
+    //First run with the largest shift we could need, conservatively starting at len
+    let shift = 31 - clz32(len);
+    let nrg = sum(x, shift, len);
+    let shift = (shift + 3 - clz32(nrg)).max(0);
+    (sum(x, shift, 0), shift)
+}
@@ -1,192 +1,486 @@
-mod header;
+mod cng;
+mod decode;
+mod indices;
+mod lpc;
+mod math;
+mod nlsf;
+mod plc;
+mod pulses;
+mod resampler;
+mod stereo;
 mod tables;
 
+use common::types::{Bandwidth, Channels, FrameSize, SampleRate};
 use range;
-use common::types::Channels;
-use common::types::FrameSize;
-use self::header::HeaderFlagsStereo;
-use self::header::HeaderFlagsMono;
+use self::cng::Cng;
+use self::indices::Indices;
+use self::plc::Plc;
+use self::resampler::Resampler;
+use self::stereo::Stereo;
+use self::tables::NlsfCodebook;
 
-pub struct Decoder<'a> {
-    rc: range::Decoder<'a>,
-    stereo: Stereo,
+pub const MAX_LPC_ORDER: usize = 16;
+const MIN_LPC_ORDER: usize = 10;
+const MAX_NB_SUBFR: usize = 4;
+const LTP_ORDER: usize = 5;
+const MAX_FRAMES_PER_PACKET: usize = 3;
+const SUB_FRAME_LENGTH_MS: usize = 5;
+///Pitch history kept for the long-term prediction
+const LTP_MEM_LENGTH_MS: usize = 20;
+const MAX_FS_KHZ: usize = 16;
+const MAX_SUB_FRAME_LENGTH: usize = SUB_FRAME_LENGTH_MS * MAX_FS_KHZ;
+const MAX_FRAME_LENGTH: usize = MAX_NB_SUBFR * MAX_SUB_FRAME_LENGTH;
+const MAX_LTP_MEM_LENGTH: usize = LTP_MEM_LENGTH_MS * MAX_FS_KHZ;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SignalType {
+    #[default]
+    Inactive,
+    Unvoiced,
+    Voiced,
 }
 
-struct Stereo {
-    stereo_prediction_weights: (i32, i32),
+///How the parameters of a frame depend on the previous one
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Coding {
+    Independently,
+    ///Independent, but the long-term prediction state is known to be intact
+    IndependentlyNoLtpScaling,
+    Conditionally,
 }
 
-impl<'a> Decoder<'a> {
-    pub fn new(rc: range::Decoder<'a>) -> Self {
-        Self {
-            rc,
-            stereo: Stereo {
-                stereo_prediction_weights: (0, 0),
+///Whether a frame is decoded from the regular data, the redundancy of the next packet, or concealed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrameKind {
+    Normal,
+    Lost,
+    ///Low bitrate redundancy, frames without it are concealed
+    Lbrr,
+}
+
+///Parameters of a frame after dequantization
+#[derive(Default)]
+struct Control {
+    pitch_l: [i32; MAX_NB_SUBFR],
+    gains_q16: [i32; MAX_NB_SUBFR],
+    ///Short-term predictors of the first and second half of the frame
+    pred_coef_q12: [[i16; MAX_LPC_ORDER]; 2],
+    ltp_coef_q14: [i16; LTP_ORDER * MAX_NB_SUBFR],
+    ltp_scale_q14: i32,
+}
+
+///Decoder state of a single mid or side channel
+#[derive(Clone)]
+struct ChannelState {
+    prev_gain_q16: i32,
+    exc_q14: [i32; MAX_FRAME_LENGTH],
+    s_lpc_q14_buf: [i32; MAX_LPC_ORDER],
+    ///Past output the long-term prediction is whitened from
+    out_buf: [i16; MAX_FRAME_LENGTH + 2 * MAX_SUB_FRAME_LENGTH],
+    lag_prev: i32,
+    last_gain_index: i32,
+    fs_khz: usize,
+    fs_api_hz: usize,
+    nb_subfr: usize,
+    frame_length: usize,
+    subfr_length: usize,
+    ltp_mem_length: usize,
+    lpc_order: usize,
+    prev_nlsf_q15: [i16; MAX_LPC_ORDER],
+    first_frame_after_reset: bool,
+    pitch_lag_low_bits_icdf: &'static [u8],
+    pitch_contour_icdf: &'static [u8],
+    nlsf_cb: &'static NlsfCodebook,
+    frames_decoded: usize,
+    frames_per_packet: usize,
+    ///Signal type and pitch lag of the previously decoded indices, which conditional coding is relative to
+    ec_prev_signal_type: SignalType,
+    ec_prev_lag_index: i32,
+    vad_flags: [bool; MAX_FRAMES_PER_PACKET],
+    lbrr_flag: bool,
+    lbrr_flags: [bool; MAX_FRAMES_PER_PACKET],
+    resampler: Resampler,
+    indices: Indices,
+    cng: Cng,
+    ///Number of frames concealed in a row
+    loss_count: usize,
+    prev_signal_type: SignalType,
+    plc: Plc,
+}
+
+impl ChannelState {
+    fn new() -> Self {
+        let mut state = Self {
+            prev_gain_q16: 65536,
+            exc_q14: [0; MAX_FRAME_LENGTH],
+            s_lpc_q14_buf: [0; MAX_LPC_ORDER],
+            out_buf: [0; MAX_FRAME_LENGTH + 2 * MAX_SUB_FRAME_LENGTH],
+            lag_prev: 0,
+            last_gain_index: 0,
+            fs_khz: 0,
+            fs_api_hz: 0,
+            nb_subfr: 0,
+            frame_length: 0,
+            subfr_length: 0,
+            ltp_mem_length: 0,
+            lpc_order: 0,
+            prev_nlsf_q15: [0; MAX_LPC_ORDER],
+            first_frame_after_reset: true,
+            pitch_lag_low_bits_icdf: &tables::icdf::UNIFORM4,
+            pitch_contour_icdf: &tables::icdf::PITCH_CONTOUR_NB,
+            nlsf_cb: &tables::NLSF_CB_NB_MB,
+            frames_decoded: 0,
+            frames_per_packet: 0,
+            ec_prev_signal_type: SignalType::Inactive,
+            ec_prev_lag_index: 0,
+            vad_flags: [false; MAX_FRAMES_PER_PACKET],
+            lbrr_flag: false,
+            lbrr_flags: [false; MAX_FRAMES_PER_PACKET],
+            resampler: Resampler::new(8000, 8000),
+            indices: Indices::default(),
+            cng: Cng::default(),
+            loss_count: 0,
+            prev_signal_type: SignalType::Inactive,
+            plc: Plc::default(),
+        };
+        state.cng_reset();
+        state.plc_reset();
+        state
+    }
+
+    ///Switches the internal sampling rate and frame length. A new rate resets the prediction state
+    fn set_fs(&mut self, fs_khz: usize, fs_api_hz: usize) {
+        self.subfr_length = SUB_FRAME_LENGTH_MS * fs_khz;
+        let frame_length = self.nb_subfr * self.subfr_length;
+
+        if self.fs_khz != fs_khz || self.fs_api_hz != fs_api_hz {
+            self.resampler = Resampler::new(fs_khz * 1000, fs_api_hz);
+            self.fs_api_hz = fs_api_hz;
+        }
+
+        if self.fs_khz != fs_khz || self.frame_length != frame_length {
+            self.pitch_contour_icdf = match (fs_khz, self.nb_subfr) {
+                (8, MAX_NB_SUBFR) => &tables::icdf::PITCH_CONTOUR_NB,
+                (8, _) => &tables::icdf::PITCH_CONTOUR_10_MS_NB,
+                (_, MAX_NB_SUBFR) => &tables::icdf::PITCH_CONTOUR,
+                _ => &tables::icdf::PITCH_CONTOUR_10_MS,
+            };
+            if self.fs_khz != fs_khz {
+                self.ltp_mem_length = LTP_MEM_LENGTH_MS * fs_khz;
+                if fs_khz == 16 {
+                    self.lpc_order = MAX_LPC_ORDER;
+                    self.nlsf_cb = &tables::NLSF_CB_WB;
+                } else {
+                    self.lpc_order = MIN_LPC_ORDER;
+                    self.nlsf_cb = &tables::NLSF_CB_NB_MB;
+                }
+                self.pitch_lag_low_bits_icdf = match fs_khz {
+                    16 => &tables::icdf::UNIFORM8,
+                    12 => &tables::icdf::UNIFORM6,
+                    _ => &tables::icdf::UNIFORM4,
+                };
+                self.first_frame_after_reset = true;
+                self.lag_prev = 100;
+                self.last_gain_index = 10;
+                self.prev_signal_type = SignalType::Inactive;
+                self.out_buf = [0; MAX_FRAME_LENGTH + 2 * MAX_SUB_FRAME_LENGTH];
+                self.s_lpc_q14_buf = [0; MAX_LPC_ORDER];
             }
+            self.fs_khz = fs_khz;
+            self.frame_length = frame_length;
         }
     }
+}
 
-    pub fn decode_frame(&mut self, channels: Channels, frame_size: FrameSize) -> u16 {
-        debug_assert!(frame_size != FrameSize::Ms2_5 && frame_size != FrameSize::Ms5);
-        let (silkframe_count, subframe_count) = match frame_size {
-            FrameSize::Ms10 => (1, 2),
-            FrameSize::Ms20 => (1, 4),
-            FrameSize::Ms40 => (2, 4),
-            FrameSize::Ms60 => (3, 4),
-            _ => unreachable!(),
-        };
-        match channels {
-            Channels::Mono => {
-                let flags = header_flags_mono(&mut self.rc, silkframe_count);
+pub struct Decoder {
+    channel_state: [ChannelState; 2],
+    stereo: Stereo,
+    fs_api_hz: usize,
+    channels: Channels,
+    ///Channel counts of the last call, 0 before the first one
+    prev_channels_api: usize,
+    prev_channels_internal: usize,
+    prev_decode_only_middle: bool,
+    ///Channel count and internal rate of the last packet, concealment keeps using them
+    stream_channels: Channels,
+    fs_internal_hz: usize,
+    prev_pitch_lag: usize,
+}
+
+impl Decoder {
+    pub fn new(rate: SampleRate, channels: Channels) -> Self {
+        Self {
+            channel_state: [ChannelState::new(), ChannelState::new()],
+            stereo: Stereo::default(),
+            fs_api_hz: match rate {
+                SampleRate::Khz8 => 8000,
+                SampleRate::Khz12 => 12000,
+                SampleRate::Khz16 => 16000,
+                SampleRate::Khz24 => 24000,
+                SampleRate::Khz48 => 48000,
             },
-            Channels::Stereo => {
-                let flags = header_flags_stereo(&mut self.rc, silkframe_count);
-                self.decode_silkframe_stereo((flags.vad_mid[0], flags.vad_side[0]), true);
-                for i in 1..silkframe_count as usize {
-                    self.decode_silkframe_stereo((flags.vad_mid[i], flags.vad_side[i]), false);
-                }
-            }
-        };
-        0
+            channels,
+            prev_channels_api: 0,
+            prev_channels_internal: 0,
+            prev_decode_only_middle: false,
+            stream_channels: channels,
+            fs_internal_hz: 16000,
+            prev_pitch_lag: 0,
+        }
     }
 
-    fn decode_silkframe_mono(&mut self, flags: HeaderFlagsMono) {
+    pub fn reset(&mut self) {
+        self.channel_state = [ChannelState::new(), ChannelState::new()];
+        self.stereo = Stereo::default();
+        self.prev_decode_only_middle = false;
+    }
 
+    ///Pitch lag of the last frame at 48 kHz, 0 if it wasn't voiced
+    pub fn prev_pitch_lag(&self) -> usize {
+        self.prev_pitch_lag
     }
 
-    fn decode_silkframe_stereo(&mut self, activity: (bool, bool), first: bool) {
-        let stereo_weights = stereo_prediction_weights(&mut self.rc);
-        let mid_only = if activity.1 {
-            mid_only(&mut self.rc)
-        } else {
-            0
+    ///Decodes the SILK frames of an Opus frame of `frame_size` and writes them interleaved into `pcm` with the
+    ///decoder's rate and channel count. `bandwidth` selects the internal rate, hybrid frames code up to `Wide`.
+    ///Returns the number of samples per channel
+    pub fn decode(&mut self, rc: &mut range::Decoder, kind: FrameKind, stream_channels: Channels, bandwidth: Bandwidth, frame_size: FrameSize, pcm: &mut [i16]) -> usize {
+        if kind != FrameKind::Lost {
+            self.stream_channels = stream_channels;
+            self.fs_internal_hz = match bandwidth {
+                Bandwidth::Narrow => 8000,
+                Bandwidth::Medium => 12000,
+                _ => 16000,
+            };
+        }
+        let payload_ms = match frame_size {
+            FrameSize::Ms40 => 40,
+            FrameSize::Ms60 => 60,
+            FrameSize::Ms20 => 20,
+            _ => 10,
         };
-        let frame_type = frame_type(&mut self.rc, activity.0);
+        let total = payload_ms * self.fs_api_hz / 1000;
+        let channels = self.channels as usize;
+        let mut decoded = 0;
+        while decoded < total {
+            decoded += self.decode_frame(rc, kind, decoded == 0, payload_ms, &mut pcm[decoded * channels..]);
+        }
+        decoded
+    }
 
+    ///Conceals a lost Opus frame of `frame_size`, see `decode`
+    pub fn decode_lost(&mut self, frame_size: FrameSize, pcm: &mut [i16]) -> usize {
+        let mut rc = range::Decoder::new(&[]);
+        let (channels, bandwidth) = (self.stream_channels, Bandwidth::Wide);
+        self.decode(&mut rc, FrameKind::Lost, channels, bandwidth, frame_size, pcm)
     }
-}
 
-fn stereo_prediction_weights(rc: &mut range::Decoder) -> (i32, i32) {
-    use self::tables::STEREO_WEIGHT_TABLE_PRECOMPUTE as w_Q13_precompute;
+    ///Decodes a single 10 or 20 ms SILK frame
+    fn decode_frame(&mut self, rc: &mut range::Decoder, kind: FrameKind, new_packet: bool, payload_ms: usize, pcm: &mut [i16]) -> usize {
+        let channels_internal = self.stream_channels as usize;
+        let channels_api = self.channels as usize;
+        let fs_internal_hz = self.fs_internal_hz;
+        let mut decode_only_middle = false;
+        let mut ms_pred_q13 = [0; 2];
 
-    let n = rc.decode_icdf(&tables::icdf::STEREO_PREDICTION_WEIGHT.0, 8);
-    let (val, rem) = ::common::util::div_rem(n, 5);
-    let wi0 = rc.decode_icdf(&tables::icdf::STEREO_PREDICTION_WEIGHT.1, 8) + 3 * val;
-    let i1 = (rc.decode_icdf(&tables::icdf::STEREO_PREDICTION_WEIGHT.2, 8) * 2 + 1) as i32;
-    let wi1 = rc.decode_icdf(&tables::icdf::STEREO_PREDICTION_WEIGHT.1, 8) + 3 * rem;
-    let i3 = (rc.decode_icdf(&tables::icdf::STEREO_PREDICTION_WEIGHT.2, 8) * 2 + 1) as i32;
+        if new_packet {
+            for state in self.channel_state[..channels_internal].iter_mut() {
+                state.frames_decoded = 0;
+            }
+        }
+        if channels_internal > self.prev_channels_internal {
+            self.channel_state[1] = ChannelState::new();
+        }
+        let stereo_to_mono = channels_internal == 1 && self.prev_channels_internal == 2
+            && fs_internal_hz == 1000 * self.channel_state[0].fs_khz;
 
-    let w1_q13 = w_Q13_precompute[wi1] as i32 * i3;
-    let w0_q13 = w_Q13_precompute[wi0] as i32 * i1 - w1_q13;
-    (w0_q13, w1_q13)
-}
+        if self.channel_state[0].frames_decoded == 0 {
+            let (frames_per_packet, nb_subfr) = match payload_ms {
+                10 => (1, 2),
+                20 => (1, 4),
+                40 => (2, 4),
+                _ => (3, 4),
+            };
+            for state in self.channel_state[..channels_internal].iter_mut() {
+                state.frames_per_packet = frames_per_packet;
+                state.nb_subfr = nb_subfr;
+                state.set_fs(fs_internal_hz / 1000, self.fs_api_hz);
+            }
+        }
 
-fn mid_only(rc: &mut range::Decoder) -> usize {
-    rc.decode_icdf(&tables::icdf::MID_ONLY, 8)
-}
+        if channels_api == 2 && channels_internal == 2 && (self.prev_channels_api == 1 || self.prev_channels_internal == 1) {
+            self.stereo.pred_prev_q13 = [0; 2];
+            self.stereo.s_side = [0; 2];
+            self.channel_state[1].resampler = self.channel_state[0].resampler.clone();
+        }
+        self.prev_channels_api = channels_api;
+        self.prev_channels_internal = channels_internal;
 
-struct FrameType {
-    signal: SignalType,
-    quant_offset: QuantizationOffset,
-}
+        if kind != FrameKind::Lost && self.channel_state[0].frames_decoded == 0 {
+            self.decode_header(rc, kind, channels_internal);
+        }
 
-enum SignalType {
-    Inactive,
-    Unvoiced,
-    Voiced,
-}
+        let frame_index = self.channel_state[0].frames_decoded;
+        if channels_internal == 2 {
+            if kind == FrameKind::Normal || (kind == FrameKind::Lbrr && self.channel_state[0].lbrr_flags[frame_index]) {
+                ms_pred_q13 = stereo::decode_pred(rc);
+                let side_coded = match kind {
+                    FrameKind::Normal => self.channel_state[1].vad_flags[frame_index],
+                    _ => self.channel_state[1].lbrr_flags[frame_index],
+                };
+                if !side_coded {
+                    decode_only_middle = stereo::decode_mid_only(rc);
+                }
+            } else {
+                ms_pred_q13 = self.stereo.pred_prev_q13;
+            }
+        }
 
-enum QuantizationOffset {
-    Low,
-    High
-}
+        //Reset the side channel if it wasn't coded in the previous frame
+        if channels_internal == 2 && !decode_only_middle && self.prev_decode_only_middle {
+            let side = &mut self.channel_state[1];
+            side.out_buf = [0; MAX_FRAME_LENGTH + 2 * MAX_SUB_FRAME_LENGTH];
+            side.s_lpc_q14_buf = [0; MAX_LPC_ORDER];
+            side.lag_prev = 100;
+            side.last_gain_index = 10;
+            side.prev_signal_type = SignalType::Inactive;
+            side.first_frame_after_reset = true;
+        }
 
-fn frame_type(rc: &mut range::Decoder, active: bool) -> FrameType {
-    let val = if active {
-        rc.decode_icdf(&tables::icdf::FRAME_TYPE.1, 8)
-    } else {
-        rc.decode_icdf(&tables::icdf::FRAME_TYPE.0, 8)
-    };
-    FrameType {
-        signal: match val>>1 {
-            0 => SignalType::Inactive,
-            1 => SignalType::Unvoiced,
-            2 => SignalType::Voiced,
-            _ => unreachable!()
-        },
-        quant_offset: if val & 1 == 1 { QuantizationOffset::High } else { QuantizationOffset::Low }
-    }
-}
+        let has_side = if kind == FrameKind::Normal {
+            !decode_only_middle
+        } else {
+            !self.prev_decode_only_middle
+                || (channels_internal == 2 && kind == FrameKind::Lbrr && self.channel_state[1].lbrr_flags[self.channel_state[1].frames_decoded])
+        };
+
+        //Two samples of history in front of every channel for the stereo prediction
+        let mut samples = [[0i16; MAX_FRAME_LENGTH + 2]; 2];
+        let mut n_samples = 0;
+        for (n, channel) in samples[..channels_internal].iter_mut().enumerate() {
+            if n == 0 || has_side {
+                let frame_index = self.channel_state[0].frames_decoded as isize - n as isize;
+                let coding = if frame_index <= 0 {
+                    Coding::Independently
+                } else if kind == FrameKind::Lbrr {
+                    if self.channel_state[n].lbrr_flags[frame_index as usize - 1] {
+                        Coding::Conditionally
+                    } else {
+                        Coding::Independently
+                    }
+                } else if n > 0 && self.prev_decode_only_middle {
+                    Coding::IndependentlyNoLtpScaling
+                } else {
+                    Coding::Conditionally
+                };
+                n_samples = self.channel_state[n].decode_frame(rc, &mut channel[2..], kind, coding);
+            } else {
+                for s in channel[2..2 + n_samples].iter_mut() {
+                    *s = 0;
+                }
+            }
+            self.channel_state[n].frames_decoded += 1;
+        }
+
+        if channels_api == 2 && channels_internal == 2 {
+            let (mid, side) = samples.split_at_mut(1);
+            self.stereo.ms_to_lr(&mut mid[0], &mut side[0], ms_pred_q13, self.channel_state[0].fs_khz, n_samples);
+        } else {
+            samples[0][..2].copy_from_slice(&self.stereo.s_mid);
+            self.stereo.s_mid.copy_from_slice(&samples[0][n_samples..n_samples + 2]);
+        }
+
+        //Resample, the output lags the decoded signal by one sample
+        let n_out = n_samples * self.fs_api_hz / (self.channel_state[0].fs_khz * 1000);
+        let mut resampled = [0i16; 48 * 20];
+        for (n, channel) in samples[..channels_api.min(channels_internal)].iter().enumerate() {
+            self.channel_state[n].resampler.process(&mut resampled[..n_out], &channel[1..n_samples + 1]);
+            for (out, &s) in pcm.iter_mut().skip(n).step_by(channels_api).zip(resampled[..n_out].iter()) {
+                *out = s;
+            }
+        }
+        if channels_api == 2 && channels_internal == 1 {
+            if stereo_to_mono {
+                //Keeps the resampler of the right channel running for a stream that just collapsed to mono
+                self.channel_state[1].resampler.process(&mut resampled[..n_out], &samples[0][1..n_samples + 1]);
+                for (out, &s) in pcm.chunks_mut(2).zip(resampled[..n_out].iter()) {
+                    out[1] = s;
+                }
+            } else {
+                for out in pcm.chunks_mut(2).take(n_out) {
+                    out[1] = out[0];
+                }
+            }
+        }
 
-fn subframe_gain(rc: &mut range::Decoder, signal_type: SignalType, subframe_count: u8, prev_log_gain: Option<u8>, independant: bool) -> [u8; 4] {
-    let mut gain = [0u8; 4];
-    let mut log_gain = 0;
-    if independant {
-        let table = match signal_type {
-            SignalType::Inactive => &tables::icdf::SUBFRAME_GAIN.0,
-            SignalType::Unvoiced => &tables::icdf::SUBFRAME_GAIN.1,
-            SignalType::Voiced => &tables::icdf::SUBFRAME_GAIN.2,
+        let state = &self.channel_state[0];
+        self.prev_pitch_lag = if state.prev_signal_type == SignalType::Voiced {
+            state.lag_prev as usize * [6, 4, 3][(state.fs_khz - 8)>>2]
+        } else {
+            0
         };
-        log_gain = (rc.decode_icdf(table, 8) as u8)<<3;
-        log_gain += rc.decode_icdf(&tables::icdf::SUBFRAME_GAIN.3, 8) as u8;
-        if let Some(prev_log_gain) = prev_log_gain {
-            log_gain = log_gain.max(prev_log_gain - 16);
+
+        if kind == FrameKind::Lost {
+            //Don't clamp the gain of the next frame, so the energy doesn't bounce back after losses
+            for state in self.channel_state[..self.prev_channels_internal].iter_mut() {
+                state.last_gain_index = 10;
+            }
+        } else {
+            self.prev_decode_only_middle = decode_only_middle;
         }
+        n_out
     }
-    for val in &mut gain[1..subframe_count as usize] {
-        let delta_gain = rc.decode_icdf(&tables::icdf::SUBFRAME_GAIN_DELTA, 8) as u8;
-        log_gain = (2*delta_gain-16).max(log_gain + delta_gain - 4).min(63).max(0);
-        *val = unimplemented!()
-    }
-    gain
-}
 
-fn header_flags_mono(rc: &mut range::Decoder, subframe_count: u8) -> HeaderFlagsMono {
-    let mut flags = HeaderFlagsMono::default();
-    for i in 0..subframe_count as usize {
-        flags.vad_mid[i] = rc.decode_bit_logp(1);
-    }
-    if rc.decode_bit_logp(1) {
-        per_frame_lbrr_flags(rc, &mut flags.lbrr_mid, subframe_count);
-    }
-    flags
-}
+    ///Decodes the voice activity and redundancy flags at the start of a packet, and skips the redundancy
+    ///data when decoding normally
+    fn decode_header(&mut self, rc: &mut range::Decoder, kind: FrameKind, channels_internal: usize) {
+        for state in self.channel_state[..channels_internal].iter_mut() {
+            for flag in state.vad_flags[..state.frames_per_packet].iter_mut() {
+                *flag = rc.decode_bit_logp(1);
+            }
+            state.lbrr_flag = rc.decode_bit_logp(1);
+        }
+        for state in self.channel_state[..channels_internal].iter_mut() {
+            state.lbrr_flags = [false; MAX_FRAMES_PER_PACKET];
+            if state.lbrr_flag {
+                if state.frames_per_packet == 1 {
+                    state.lbrr_flags[0] = true;
+                } else {
+                    let symbol = if state.frames_per_packet == 2 {
+                        rc.decode_icdf(&tables::icdf::LBRR_FLAGS.0, 8)
+                    } else {
+                        rc.decode_icdf(&tables::icdf::LBRR_FLAGS.1, 8)
+                    } + 1;
+                    for (i, flag) in state.lbrr_flags[..state.frames_per_packet].iter_mut().enumerate() {
+                        *flag = (symbol>>i) & 1 == 1;
+                    }
+                }
+            }
+        }
 
-fn header_flags_stereo(rc: &mut range::Decoder, subframe_count: u8) -> HeaderFlagsStereo {
-    let mut flags = HeaderFlagsStereo::default();
-    for i in 0..subframe_count as usize {
-        flags.vad_mid[i] = rc.decode_bit_logp(1);
-    }
-    let lbrr_mid_flag = rc.decode_bit_logp(1);
-    for i in 0..subframe_count as usize {
-        flags.vad_side[i] = rc.decode_bit_logp(1);
-    }
-    let lbrr_side_flag = rc.decode_bit_logp(1);
-    if lbrr_mid_flag {
-        per_frame_lbrr_flags(rc, &mut flags.lbrr_mid, subframe_count);
-    }
-    if lbrr_side_flag {
-        per_frame_lbrr_flags(rc, &mut flags.lbrr_side, subframe_count);
+        if kind == FrameKind::Normal {
+            let mut pulses = [0i16; MAX_FRAME_LENGTH];
+            for i in 0..self.channel_state[0].frames_per_packet {
+                for n in 0..channels_internal {
+                    if !self.channel_state[n].lbrr_flags[i] {
+                        continue;
+                    }
+                    if channels_internal == 2 && n == 0 {
+                        stereo::decode_pred(rc);
+                        if !self.channel_state[1].lbrr_flags[i] {
+                            stereo::decode_mid_only(rc);
+                        }
+                    }
+                    let state = &mut self.channel_state[n];
+                    let coding = if i > 0 && state.lbrr_flags[i - 1] {
+                        Coding::Conditionally
+                    } else {
+                        Coding::Independently
+                    };
+                    state.decode_indices(rc, i, true, coding);
+                    let indices = &state.indices;
+                    pulses::decode(rc, &mut pulses, indices.signal_type, indices.quant_offset, state.frame_length);
+                }
+            }
+        }
     }
-    flags
 }
-
-///Decodes per frame low bitrate redundancy flags
-fn per_frame_lbrr_flags(rc: &mut range::Decoder, flags: &mut [bool; 3], subframe_count: u8) {
-    debug_assert!(subframe_count <= 3 && subframe_count > 0);
-    match subframe_count {
-        1 => flags[0] = true,
-        2 => {
-            let lbrr_symbol = rc.decode_icdf(&tables::icdf::LBRR_FLAGS.0, 8);
-            flags[0] = lbrr_symbol & 0b1 > 0;
-            flags[1] = lbrr_symbol & 0b10 > 0;
-        },
-        3 => {
-            let lbrr_symbol = rc.decode_icdf(&tables::icdf::LBRR_FLAGS.1, 8);
-            flags[0] = lbrr_symbol & 0b1 > 0;
-            flags[1] = lbrr_symbol & 0b10 > 0;
-            flags[2] = lbrr_symbol & 0b100 > 0;
-        },
-        _ => unreachable!(),
-    }
-}
\ No newline at end of file
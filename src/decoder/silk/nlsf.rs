@@ -0,0 +1,185 @@
+use super::lpc;
+use super::math::{add_sat16, rshift_round, rshift_round64, smlawb, smulbb};
+use super::tables::{self, NlsfCodebook};
+use super::MAX_LPC_ORDER;
+
+///Largest residual index coded without the extension table
+pub const NLSF_QUANT_MAX_AMPLITUDE: i32 = 4;
+///Residual magnitudes are pulled towards zero by 0.1 steps, in Q10
+const NLSF_QUANT_LEVEL_ADJ_Q10: i32 = 102;
+///Attempts at making the filter stable with bandwidth expansion before it's accepted
+const MAX_LPC_STABILIZE_ITERATIONS: i32 = 16;
+///Attempts at moving the NLSFs apart before falling back to sorting them
+const MAX_STABILIZE_LOOPS: usize = 20;
+///Q domain of the polynomials of the filter conversion
+const QA: u32 = 16;
+
+///Entropy table offsets and backward predictors of the residuals of first stage vector `cb1_index`
+pub fn unpack(cb: &NlsfCodebook, cb1_index: usize) -> ([usize; MAX_LPC_ORDER], [u8; MAX_LPC_ORDER]) {
+    let mut ec_ix = [0; MAX_LPC_ORDER];
+    let mut pred_q8 = [0; MAX_LPC_ORDER];
+    let ec_sel = &cb.ec_sel[cb1_index * cb.order / 2..];
+    for i in (0..cb.order).step_by(2) {
+        let entry = ec_sel[i / 2] as usize;
+        ec_ix[i] = ((entry>>1) & 7) * (2 * NLSF_QUANT_MAX_AMPLITUDE as usize + 1);
+        pred_q8[i] = cb.pred_q8[i + (entry & 1) * (cb.order - 1)];
+        ec_ix[i + 1] = ((entry>>5) & 7) * (2 * NLSF_QUANT_MAX_AMPLITUDE as usize + 1);
+        pred_q8[i + 1] = cb.pred_q8[i + ((entry>>4) & 1) * (cb.order - 1) + 1];
+    }
+    (ec_ix, pred_q8)
+}
+
+///Reconstructs the NLSFs in Q15 from the first stage index in `indices[0]` and the residual indices after it
+pub fn decode(indices: &[i8], cb: &NlsfCodebook) -> [i16; MAX_LPC_ORDER] {
+    let order = cb.order;
+    let cb1_index = indices[0] as usize;
+    let (_, pred_q8) = unpack(cb, cb1_index);
+
+    //Predictive residual dequantizer
+    let mut res_q10 = [0i16; MAX_LPC_ORDER];
+    let mut out_q10 = 0i32;
+    for i in (0..order).rev() {
+        let pred_q10 = smulbb(out_q10, i32::from(pred_q8[i]))>>8;
+        out_q10 = i32::from(indices[i + 1])<<10;
+        if out_q10 > 0 {
+            out_q10 -= NLSF_QUANT_LEVEL_ADJ_Q10;
+        } else if out_q10 < 0 {
+            out_q10 += NLSF_QUANT_LEVEL_ADJ_Q10;
+        }
+        out_q10 = smlawb(pred_q10, out_q10, cb.quant_step_size_q16);
+        res_q10[i] = out_q10 as i16;
+    }
+
+    //Apply the inverse square-rooted weights to the residuals and add the first stage
+    let mut nlsf_q15 = [0; MAX_LPC_ORDER];
+    let cb1 = &cb.cb1_nlsf_q8[cb1_index * order..];
+    let wght = &cb.cb1_wght_q9[cb1_index * order..];
+    for i in 0..order {
+        let nlsf = ((i32::from(res_q10[i])<<14) / i32::from(wght[i])) + (i32::from(cb1[i])<<7);
+        nlsf_q15[i] = nlsf.clamp(0, 32767) as i16;
+    }
+
+    stabilize(&mut nlsf_q15[..order], cb.delta_min_q15);
+    nlsf_q15
+}
+
+///Moves the NLSFs apart and away from the borders until they are at least `delta_min_q15` apart,
+///with the smallest changes that achieve it
+pub fn stabilize(nlsf_q15: &mut [i16], delta_min_q15: &[i16]) {
+    let l = nlsf_q15.len();
+    let delta_min = |i: usize| i32::from(delta_min_q15[i]);
+    for _ in 0..MAX_STABILIZE_LOOPS {
+        //Find the smallest distance
+        let mut min_diff = i32::from(nlsf_q15[0]) - delta_min(0);
+        let mut idx = 0;
+        for i in 1..l {
+            let diff = i32::from(nlsf_q15[i]) - (i32::from(nlsf_q15[i - 1]) + delta_min(i));
+            if diff < min_diff {
+                min_diff = diff;
+                idx = i;
+            }
+        }
+        let diff = (1<<15) - (i32::from(nlsf_q15[l - 1]) + delta_min(l));
+        if diff < min_diff {
+            min_diff = diff;
+            idx = l;
+        }
+
+        if min_diff >= 0 {
+            return;
+        }
+
+        if idx == 0 {
+            nlsf_q15[0] = delta_min_q15[0];
+        } else if idx == l {
+            nlsf_q15[l - 1] = ((1<<15) - delta_min(l)) as i16;
+        } else {
+            //Move both apart around their center, as far as the neighbours allow
+            let min_center = delta_min_q15[..idx].iter().map(|&d| i32::from(d)).sum::<i32>() + (delta_min(idx)>>1);
+            let max_center = (1<<15) - delta_min_q15[idx + 1..=l].iter().map(|&d| i32::from(d)).sum::<i32>() - (delta_min(idx)>>1);
+            let center = rshift_round(i32::from(nlsf_q15[idx - 1]) + i32::from(nlsf_q15[idx]), 1);
+            let center = if min_center > max_center {
+                center.max(max_center).min(min_center)
+            } else {
+                center.max(min_center).min(max_center)
+            };
+            nlsf_q15[idx - 1] = (center - (delta_min(idx)>>1)) as i16;
+            nlsf_q15[idx] = (i32::from(nlsf_q15[idx - 1]) + delta_min(idx)) as i16;
+        }
+    }
+
+    //Fall back to sorting and pushing the values apart from both ends
+    nlsf_q15.sort();
+    nlsf_q15[0] = nlsf_q15[0].max(delta_min_q15[0]);
+    for i in 1..l {
+        nlsf_q15[i] = i32::from(nlsf_q15[i]).max(add_sat16(i32::from(nlsf_q15[i - 1]), delta_min(i))) as i16;
+    }
+    nlsf_q15[l - 1] = i32::from(nlsf_q15[l - 1]).min((1<<15) - delta_min(l)) as i16;
+    for i in (0..l - 1).rev() {
+        nlsf_q15[i] = i32::from(nlsf_q15[i]).min(i32::from(nlsf_q15[i + 1]) - delta_min(i + 1)) as i16;
+    }
+}
+
+///Polynomial of the even or odd NLSFs, from their interleaved 2*cos in QA
+fn find_poly(out: &mut [i32], c_lsf: &[i32], dd: usize) {
+    out[0] = 1<<QA;
+    out[1] = -c_lsf[0];
+    for k in 1..dd {
+        let ftmp = i64::from(c_lsf[2 * k]);
+        out[k + 1] = (out[k - 1]<<1) - rshift_round64(ftmp * i64::from(out[k]), QA) as i32;
+        for n in (2..=k).rev() {
+            out[n] += out[n - 2] - rshift_round64(ftmp * i64::from(out[n - 1]), QA) as i32;
+        }
+        out[1] -= ftmp as i32;
+    }
+}
+
+///Converts NLSFs in Q15 to the coefficients of a stable whitening filter in Q12
+pub fn nlsf2a(nlsf_q15: &[i16]) -> [i16; MAX_LPC_ORDER] {
+    //This ordering improves the numerical accuracy of the polynomial
+    const ORDERING16: [usize; 16] = [0, 15, 8, 7, 4, 11, 12, 3, 2, 13, 10, 5, 6, 9, 14, 1];
+    const ORDERING10: [usize; 10] = [0, 9, 6, 3, 4, 5, 8, 1, 2, 7];
+    let d = nlsf_q15.len();
+    debug_assert!(d == 10 || d == 16);
+    let ordering: &[usize] = if d == 16 { &ORDERING16 } else { &ORDERING10 };
+
+    //2*cos(LSF) from a piecewise linear approximation
+    let mut cos_lsf_qa = [0i32; MAX_LPC_ORDER];
+    for (&nlsf, &o) in nlsf_q15.iter().zip(ordering.iter()) {
+        let nlsf = i32::from(nlsf);
+        let f_int = nlsf>>(15 - 7);
+        let f_frac = nlsf - (f_int<<(15 - 7));
+        let cos_val = i32::from(tables::LSF_COS_Q12[f_int as usize]);
+        let delta = i32::from(tables::LSF_COS_Q12[f_int as usize + 1]) - cos_val;
+        cos_lsf_qa[o] = rshift_round((cos_val<<8) + delta * f_frac, 20 - QA);
+    }
+
+    //Even and odd polynomials
+    let dd = d / 2;
+    let mut p = [0i32; MAX_LPC_ORDER / 2 + 1];
+    let mut q = [0i32; MAX_LPC_ORDER / 2 + 1];
+    find_poly(&mut p, &cos_lsf_qa[..d], dd);
+    find_poly(&mut q, &cos_lsf_qa[1..d], dd);
+
+    let mut a32_qa1 = [0i32; MAX_LPC_ORDER];
+    for k in 0..dd {
+        let ptmp = p[k + 1] + p[k];
+        let qtmp = q[k + 1] - q[k];
+        a32_qa1[k] = -qtmp - ptmp;
+        a32_qa1[d - k - 1] = qtmp - ptmp;
+    }
+
+    let mut a_q12 = [0i16; MAX_LPC_ORDER];
+    lpc::fit(&mut a_q12[..d], &mut a32_qa1[..d], 12, QA + 1);
+
+    //Bandwidth expand the unscaled coefficients until the filter is stable
+    let mut i = 0;
+    while lpc::inverse_pred_gain(&a_q12[..d]) == 0 && i < MAX_LPC_STABILIZE_ITERATIONS {
+        lpc::bwexpander_32(&mut a32_qa1[..d], 65536 - (2<<i));
+        for (a, &a32) in a_q12.iter_mut().zip(a32_qa1[..d].iter()) {
+            *a = rshift_round(a32, QA + 1 - 12) as i16;
+        }
+        i += 1;
+    }
+    a_q12
+}
@@ -0,0 +1,273 @@
+use super::lpc;
+use super::math::{
+    clz32, inverse32_var_q, lshift_sat32, rand, rshift_round, sat16, smlawb, smulbb, smulwb, smulww,
+    sqrt_approx, sum_sqr_shift,
+};
+use super::{ChannelState, Control, SignalType, LTP_ORDER, MAX_FRAME_LENGTH, MAX_LPC_ORDER, MAX_LTP_MEM_LENGTH, MAX_NB_SUBFR};
+
+///Bandwidth expansion of the concealment filter per frame, 0.99 in Q16
+const BWE_COEF_Q16: i32 = 64881;
+///Range the pitch gain of a concealed voiced frame starts in
+const V_PITCH_GAIN_START_MIN_Q14: i32 = 11469;
+const V_PITCH_GAIN_START_MAX_Q14: i32 = 15565;
+const MAX_PITCH_LAG_MS: i32 = 18;
+///The pitch lag drifts up by 1% per subframe
+const PITCH_DRIFT_FAC_Q16: i32 = 655;
+///The noise is drawn from the last 128 excitation samples
+const RAND_BUF_SIZE: usize = 128;
+const RAND_BUF_MASK: i32 = RAND_BUF_SIZE as i32 - 1;
+///Clamps the inverse prediction gain of unvoiced concealment between 2^-3 and 2^-8
+const LOG2_INV_LPC_GAIN_HIGH_THRES: u32 = 3;
+const LOG2_INV_LPC_GAIN_LOW_THRES: u32 = 8;
+
+///Attenuation of the harmonic and noise parts, for the first and following lost frames
+static HARM_ATT_Q15: [i32; 2] = [32440, 31130];
+static RAND_ATTENUATE_V_Q15: [i32; 2] = [31130, 26214];
+static RAND_ATTENUATE_UV_Q15: [i32; 2] = [32440, 29491];
+
+///Concealment state, updated from every decoded frame
+#[derive(Clone, Default)]
+pub struct Plc {
+    pub pitch_l_q8: i32,
+    pub ltp_coef_q14: [i16; LTP_ORDER],
+    pub prev_lpc_q12: [i16; MAX_LPC_ORDER],
+    pub last_frame_lost: bool,
+    pub rand_seed: i32,
+    pub rand_scale_q14: i16,
+    ///Energy of the last concealed frame, the next decoded frame fades in from it
+    pub conc_energy: i32,
+    pub conc_energy_shift: i32,
+    pub prev_ltp_scale_q14: i32,
+    ///Gains of the last two subframes
+    pub prev_gain_q16: [i32; 2],
+    pub fs_khz: usize,
+    pub nb_subfr: usize,
+    pub subfr_length: usize,
+}
+
+impl ChannelState {
+    pub fn plc_reset(&mut self) {
+        self.plc.pitch_l_q8 = (self.frame_length as i32)<<(8 - 1);
+        self.plc.prev_gain_q16 = [1<<16, 1<<16];
+        self.plc.subfr_length = 20;
+        self.plc.nb_subfr = 2;
+    }
+
+    ///Updates the concealment state from a decoded frame, or conceals a lost one into `frame`
+    pub fn plc(&mut self, ctrl: &mut Control, frame: &mut [i16], lost: bool) {
+        if self.fs_khz != self.plc.fs_khz {
+            self.plc_reset();
+            self.plc.fs_khz = self.fs_khz;
+        }
+        if lost {
+            self.plc_conceal(ctrl, frame);
+            self.loss_count += 1;
+        } else {
+            self.plc_update(ctrl);
+        }
+    }
+
+    fn plc_update(&mut self, ctrl: &Control) {
+        let nb_subfr = self.nb_subfr;
+        let plc = &mut self.plc;
+        self.prev_signal_type = self.indices.signal_type;
+
+        if self.indices.signal_type == SignalType::Voiced {
+            //Find the strongest long-term predictor within one pitch period of the end of the frame
+            let mut ltp_gain_q14 = 0;
+            let mut j = 0;
+            while j * self.subfr_length < ctrl.pitch_l[nb_subfr - 1] as usize && j < nb_subfr {
+                let coef = &ctrl.ltp_coef_q14[(nb_subfr - 1 - j) * LTP_ORDER..(nb_subfr - j) * LTP_ORDER];
+                let gain = coef.iter().map(|&c| i32::from(c)).sum::<i32>();
+                if gain > ltp_gain_q14 {
+                    ltp_gain_q14 = gain;
+                    plc.ltp_coef_q14.copy_from_slice(coef);
+                    plc.pitch_l_q8 = ctrl.pitch_l[nb_subfr - 1 - j]<<8;
+                }
+                j += 1;
+            }
+
+            //Concealment uses a single tap, with its gain limited to a sensible range
+            plc.ltp_coef_q14 = [0; LTP_ORDER];
+            plc.ltp_coef_q14[LTP_ORDER / 2] = ltp_gain_q14 as i16;
+            if ltp_gain_q14 < V_PITCH_GAIN_START_MIN_Q14 {
+                let scale_q10 = (V_PITCH_GAIN_START_MIN_Q14<<10) / ltp_gain_q14.max(1);
+                for c in plc.ltp_coef_q14.iter_mut() {
+                    *c = (smulbb(i32::from(*c), scale_q10)>>10) as i16;
+                }
+            } else if ltp_gain_q14 > V_PITCH_GAIN_START_MAX_Q14 {
+                let scale_q14 = (V_PITCH_GAIN_START_MAX_Q14<<14) / ltp_gain_q14.max(1);
+                for c in plc.ltp_coef_q14.iter_mut() {
+                    *c = (smulbb(i32::from(*c), scale_q14)>>14) as i16;
+                }
+            }
+        } else {
+            plc.pitch_l_q8 = (self.fs_khz as i32 * MAX_PITCH_LAG_MS)<<8;
+            plc.ltp_coef_q14 = [0; LTP_ORDER];
+        }
+
+        plc.prev_lpc_q12 = ctrl.pred_coef_q12[1];
+        plc.prev_ltp_scale_q14 = ctrl.ltp_scale_q14;
+        plc.prev_gain_q16.copy_from_slice(&ctrl.gains_q16[nb_subfr - 2..nb_subfr]);
+        plc.subfr_length = self.subfr_length;
+        plc.nb_subfr = nb_subfr;
+    }
+
+    ///Energies and shifts of the excitation of the last two subframes, scaled with their gains
+    fn plc_energy(&self, prev_gain_q10: [i32; 2]) -> [(i32, i32); 2] {
+        let mut exc_buf = [0i16; MAX_FRAME_LENGTH / 2];
+        let subfr_length = self.subfr_length;
+        for k in 0..2 {
+            let start = (k + self.nb_subfr - 2) * subfr_length;
+            for (buf, &exc) in exc_buf[k * subfr_length..(k + 1) * subfr_length].iter_mut().zip(self.exc_q14[start..].iter()) {
+                *buf = sat16(smulww(exc, prev_gain_q10[k])>>8);
+            }
+        }
+        [
+            sum_sqr_shift(&exc_buf[..subfr_length]),
+            sum_sqr_shift(&exc_buf[subfr_length..2 * subfr_length]),
+        ]
+    }
+
+    ///Extrapolates the last frame with its pitch and filter, mixing in noise that grows with every lost frame
+    fn plc_conceal(&mut self, ctrl: &mut Control, frame: &mut [i16]) {
+        let order = self.lpc_order;
+        let ltp_mem_length = self.ltp_mem_length;
+        let prev_gain_q10 = [self.plc.prev_gain_q16[0]>>6, self.plc.prev_gain_q16[1]>>6];
+
+        if self.first_frame_after_reset {
+            self.plc.prev_lpc_q12 = [0; MAX_LPC_ORDER];
+        }
+
+        //Take the noise from the quieter of the last two subframes
+        let [(energy1, shift1), (energy2, shift2)] = self.plc_energy(prev_gain_q10);
+        let rand_start = if energy1>>shift2 < energy2>>shift1 {
+            ((self.plc.nb_subfr - 1) * self.plc.subfr_length).saturating_sub(RAND_BUF_SIZE)
+        } else {
+            (self.plc.nb_subfr * self.plc.subfr_length).saturating_sub(RAND_BUF_SIZE)
+        };
+
+        let loss = self.loss_count.min(1);
+        let harm_gain_q15 = HARM_ATT_Q15[loss];
+        let mut rand_gain_q15 = if self.prev_signal_type == SignalType::Voiced {
+            RAND_ATTENUATE_V_Q15[loss]
+        } else {
+            RAND_ATTENUATE_UV_Q15[loss]
+        };
+
+        lpc::bwexpander(&mut self.plc.prev_lpc_q12[..order], BWE_COEF_Q16);
+        let a_q12 = self.plc.prev_lpc_q12;
+
+        let mut rand_scale_q14 = self.plc.rand_scale_q14;
+        if self.loss_count == 0 {
+            rand_scale_q14 = 1<<14;
+            if self.prev_signal_type == SignalType::Voiced {
+                //Reduce the noise by the strength of the pitch prediction
+                for &b in self.plc.ltp_coef_q14.iter() {
+                    rand_scale_q14 -= b;
+                }
+                rand_scale_q14 = rand_scale_q14.max(3277);
+                rand_scale_q14 = (smulbb(i32::from(rand_scale_q14), self.plc.prev_ltp_scale_q14)>>14) as i16;
+            } else {
+                //Reduce the noise for filters with a high prediction gain
+                let inv_gain_q30 = lpc::inverse_pred_gain(&self.plc.prev_lpc_q12[..order]);
+                let down_scale_q30 = inv_gain_q30
+                    .clamp((1<<30)>>LOG2_INV_LPC_GAIN_LOW_THRES, (1<<30)>>LOG2_INV_LPC_GAIN_HIGH_THRES)<<LOG2_INV_LPC_GAIN_HIGH_THRES;
+                rand_gain_q15 = smulwb(down_scale_q30, rand_gain_q15)>>14;
+            }
+        }
+
+        let mut rand_seed = self.plc.rand_seed;
+        let mut lag = rshift_round(self.plc.pitch_l_q8, 8) as usize;
+        let mut s_ltp_buf_idx = ltp_mem_length;
+
+        //Whiten the past output, and scale it with the inverse of the last gain
+        let mut s_ltp = [0i16; MAX_LTP_MEM_LENGTH];
+        let mut s_ltp_q14 = [0i32; MAX_LTP_MEM_LENGTH + MAX_FRAME_LENGTH];
+        let idx = ltp_mem_length - lag - order - LTP_ORDER / 2;
+        lpc::analysis_filter(&mut s_ltp[idx..ltp_mem_length], &self.out_buf[idx..ltp_mem_length], &a_q12[..order]);
+        let inv_gain_q30 = inverse32_var_q(self.plc.prev_gain_q16[1], 46).min(i32::MAX>>1);
+        for i in idx + order..ltp_mem_length {
+            s_ltp_q14[i] = smulwb(inv_gain_q30, i32::from(s_ltp[i]));
+        }
+
+        //Long-term prediction of the residual, plus noise
+        let b_q14 = &mut self.plc.ltp_coef_q14;
+        let rand_buf = &self.exc_q14[rand_start..];
+        for _ in 0..self.nb_subfr {
+            for _ in 0..self.subfr_length {
+                let pred_lag = s_ltp_buf_idx - lag + LTP_ORDER / 2;
+                let mut ltp_pred_q12 = 2;
+                for (j, &b) in b_q14.iter().enumerate() {
+                    ltp_pred_q12 = smlawb(ltp_pred_q12, s_ltp_q14[pred_lag - j], i32::from(b));
+                }
+                rand_seed = rand(rand_seed);
+                let idx = ((rand_seed>>25) & RAND_BUF_MASK) as usize;
+                s_ltp_q14[s_ltp_buf_idx] = smlawb(ltp_pred_q12, rand_buf[idx], i32::from(rand_scale_q14))<<2;
+                s_ltp_buf_idx += 1;
+            }
+
+            //Attenuate both parts and let the pitch drift up
+            for b in b_q14.iter_mut() {
+                *b = (smulbb(harm_gain_q15, i32::from(*b))>>15) as i16;
+            }
+            rand_scale_q14 = (smulbb(i32::from(rand_scale_q14), rand_gain_q15)>>15) as i16;
+            self.plc.pitch_l_q8 = smlawb(self.plc.pitch_l_q8, self.plc.pitch_l_q8, PITCH_DRIFT_FAC_Q16);
+            self.plc.pitch_l_q8 = self.plc.pitch_l_q8.min((MAX_PITCH_LAG_MS * self.fs_khz as i32)<<8);
+            lag = rshift_round(self.plc.pitch_l_q8, 8) as usize;
+        }
+
+        //Short-term synthesis over the whole frame, in place of the residual
+        let s_lpc_q14 = &mut s_ltp_q14[ltp_mem_length - MAX_LPC_ORDER..];
+        s_lpc_q14[..MAX_LPC_ORDER].copy_from_slice(&self.s_lpc_q14_buf);
+        for (i, out) in frame[..self.frame_length].iter_mut().enumerate() {
+            let pred_q10 = lpc::predict(&s_lpc_q14[i..MAX_LPC_ORDER + i], &a_q12[..order]);
+            let s = s_lpc_q14[MAX_LPC_ORDER + i].saturating_add(lshift_sat32(pred_q10, 4));
+            s_lpc_q14[MAX_LPC_ORDER + i] = s;
+            *out = sat16(rshift_round(smulww(s, prev_gain_q10[1]), 8));
+        }
+        self.s_lpc_q14_buf.copy_from_slice(&s_lpc_q14[self.frame_length..self.frame_length + MAX_LPC_ORDER]);
+
+        self.plc.rand_seed = rand_seed;
+        self.plc.rand_scale_q14 = rand_scale_q14;
+        ctrl.pitch_l = [lag as i32; MAX_NB_SUBFR];
+    }
+
+    ///Fades a decoded frame in from the energy of the concealed frame before it
+    pub fn glue_frames(&mut self, frame: &mut [i16]) {
+        let plc = &mut self.plc;
+        if self.loss_count != 0 {
+            let (energy, shift) = sum_sqr_shift(frame);
+            plc.conc_energy = energy;
+            plc.conc_energy_shift = shift;
+            plc.last_frame_lost = true;
+            return;
+        }
+
+        if plc.last_frame_lost {
+            let (mut energy, energy_shift) = sum_sqr_shift(frame);
+            if energy_shift > plc.conc_energy_shift {
+                plc.conc_energy >>= energy_shift - plc.conc_energy_shift;
+            } else if energy_shift < plc.conc_energy_shift {
+                energy >>= plc.conc_energy_shift - energy_shift;
+            }
+
+            if energy > plc.conc_energy {
+                let lz = clz32(plc.conc_energy) - 1;
+                plc.conc_energy <<= lz;
+                energy >>= (24 - lz).max(0);
+                let frac_q24 = plc.conc_energy / energy.max(1);
+                let mut gain_q16 = sqrt_approx(frac_q24)<<4;
+                let slope_q16 = (((1<<16) - gain_q16) / frame.len() as i32)<<2;
+                for x in frame.iter_mut() {
+                    *x = smulwb(gain_q16, i32::from(*x)) as i16;
+                    gain_q16 += slope_q16;
+                    if gain_q16 > 1<<16 {
+                        break;
+                    }
+                }
+            }
+        }
+        plc.last_frame_lost = false;
+    }
+}
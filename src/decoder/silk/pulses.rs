@@ -0,0 +1,106 @@
+use range;
+use super::tables::{self, icdf};
+use super::SignalType;
+
+///Pulses are coded in blocks of 16 samples
+pub const SHELL_CODEC_FRAME_LENGTH: usize = 16;
+///Most blocks in a frame, 20 ms at 16 kHz
+const MAX_NB_SHELL_BLOCKS: usize = 20;
+///Pulse count symbol that signals an extra LSB
+const MAX_PULSES: usize = 16;
+
+///Decodes the quantized excitation of a frame into `pulses`, which is rounded up to whole shell blocks
+pub fn decode(rc: &mut range::Decoder, pulses: &mut [i16], signal_type: SignalType, quant_offset: usize, frame_length: usize) {
+    let rate_level = rc.decode_icdf(&icdf::RATE_LEVELS[(signal_type as usize)>>1], 8);
+
+    //120 samples of a 10 ms frame at 12 kHz take an incomplete last block
+    let iter = frame_length.div_ceil(SHELL_CODEC_FRAME_LENGTH);
+
+    //Pulse counts per block, each extra LSB is signalled with a count past the maximum
+    let mut sum_pulses = [0usize; MAX_NB_SHELL_BLOCKS];
+    let mut n_lshifts = [0usize; MAX_NB_SHELL_BLOCKS];
+    for i in 0..iter {
+        sum_pulses[i] = rc.decode_icdf(&icdf::PULSES_PER_BLOCK[rate_level], 8);
+        while sum_pulses[i] == MAX_PULSES + 1 {
+            n_lshifts[i] += 1;
+            //After 10 LSBs the table is shifted so it can't signal another one
+            let table = &icdf::PULSES_PER_BLOCK[icdf::PULSES_PER_BLOCK.len() - 1][(n_lshifts[i] == 10) as usize..];
+            sum_pulses[i] = rc.decode_icdf(table, 8);
+        }
+    }
+
+    for (i, block) in pulses.chunks_mut(SHELL_CODEC_FRAME_LENGTH).take(iter).enumerate() {
+        if sum_pulses[i] > 0 {
+            shell_decode(rc, block, sum_pulses[i]);
+        } else {
+            for p in block.iter_mut() {
+                *p = 0;
+            }
+        }
+    }
+
+    for (i, block) in pulses.chunks_mut(SHELL_CODEC_FRAME_LENGTH).take(iter).enumerate() {
+        let n_ls = n_lshifts[i];
+        if n_ls > 0 {
+            for p in block.iter_mut() {
+                let mut abs_q = i32::from(*p);
+                for _ in 0..n_ls {
+                    abs_q = (abs_q<<1) + rc.decode_icdf(&icdf::LSB, 8) as i32;
+                }
+                *p = abs_q as i16;
+            }
+            //Makes sure the signs are read even if the shell coded count was zero
+            sum_pulses[i] |= n_ls<<5;
+        }
+    }
+
+    decode_signs(rc, pulses, frame_length, signal_type, quant_offset, &sum_pulses);
+}
+
+fn decode_signs(rc: &mut range::Decoder, pulses: &mut [i16], length: usize, signal_type: SignalType, quant_offset: usize, sum_pulses: &[usize]) {
+    let sign_icdf = &icdf::SIGN[7 * (quant_offset + ((signal_type as usize)<<1))..];
+    let blocks = (length + SHELL_CODEC_FRAME_LENGTH / 2) / SHELL_CODEC_FRAME_LENGTH;
+    for (block, &p) in pulses.chunks_mut(SHELL_CODEC_FRAME_LENGTH).zip(sum_pulses.iter()).take(blocks) {
+        if p > 0 {
+            let table = [sign_icdf[(p & 0x1f).min(6)], 0];
+            for q in block.iter_mut().filter(|q| **q > 0) {
+                *q *= ((rc.decode_icdf(&table, 8) as i16)<<1) - 1;
+            }
+        }
+    }
+}
+
+///Splits `p` pulses between the two halves of a tree node
+fn decode_split(rc: &mut range::Decoder, children: &mut [i16], p: i16, table: &[u8]) {
+    if p > 0 {
+        children[0] = rc.decode_icdf(&table[tables::SHELL_CODE_TABLE_OFFSETS[p as usize] as usize..], 8) as i16;
+        children[1] = p - children[0];
+    } else {
+        children[0] = 0;
+        children[1] = 0;
+    }
+}
+
+///Distributes `pulses4` pulses over a block of 16 samples, splitting them in halves recursively
+fn shell_decode(rc: &mut range::Decoder, pulses0: &mut [i16], pulses4: usize) {
+    let mut pulses3 = [0i16; 2];
+    let mut pulses2 = [0i16; 4];
+    let mut pulses1 = [0i16; 8];
+    let (t0, t1, t2, t3) = (&icdf::SHELL_CODE_TABLE0, &icdf::SHELL_CODE_TABLE1, &icdf::SHELL_CODE_TABLE2, &icdf::SHELL_CODE_TABLE3);
+
+    decode_split(rc, &mut pulses3[0..2], pulses4 as i16, t3);
+    decode_split(rc, &mut pulses2[0..2], pulses3[0], t2);
+    decode_split(rc, &mut pulses1[0..2], pulses2[0], t1);
+    decode_split(rc, &mut pulses0[0..2], pulses1[0], t0);
+    decode_split(rc, &mut pulses0[2..4], pulses1[1], t0);
+    decode_split(rc, &mut pulses1[2..4], pulses2[1], t1);
+    decode_split(rc, &mut pulses0[4..6], pulses1[2], t0);
+    decode_split(rc, &mut pulses0[6..8], pulses1[3], t0);
+    decode_split(rc, &mut pulses2[2..4], pulses3[1], t2);
+    decode_split(rc, &mut pulses1[4..6], pulses2[2], t1);
+    decode_split(rc, &mut pulses0[8..10], pulses1[4], t0);
+    decode_split(rc, &mut pulses0[10..12], pulses1[5], t0);
+    decode_split(rc, &mut pulses1[6..8], pulses2[3], t1);
+    decode_split(rc, &mut pulses0[12..14], pulses1[6], t0);
+    decode_split(rc, &mut pulses0[14..16], pulses1[7], t0);
+}
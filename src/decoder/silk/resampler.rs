@@ -0,0 +1,253 @@
+use super::math::{rshift_round, sat16, smlabb, smlawb, smulbb, smulwb, smulww};
+use super::tables;
+
+///Most input samples processed in one go, in ms
+const MAX_BATCH_SIZE_MS: usize = 10;
+const ORDER_FIR_12: usize = 8;
+const DOWN_ORDER_FIR0: usize = 18;
+const DOWN_ORDER_FIR1: usize = 24;
+const MAX_FIR_ORDER: usize = 36;
+
+///Input delay per internal rate (8, 12, 16 kHz) and output rate (8, 12, 16, 24, 48 kHz), in samples
+static DELAY_MATRIX: [[usize; 5]; 3] = [
+    [4, 0, 2, 0, 0],
+    [0, 9, 4, 7, 4],
+    [0, 3, 12, 7, 7],
+];
+
+fn rate_id(khz: usize) -> usize {
+    match khz {
+        8 => 0,
+        12 => 1,
+        16 => 2,
+        24 => 3,
+        48 => 4,
+        _ => unreachable!(),
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Method {
+    Copy,
+    ///Exactly twice the rate
+    Up2Hq,
+    ///2x upsampling followed by fractional interpolation
+    IirFir,
+    ///Second order AR filter followed by fractional interpolation
+    DownFir {
+        fracs: usize,
+        order: usize,
+        coefs: &'static [i16],
+    },
+}
+
+///Converts the internal rate of the SILK decoder to the output rate
+#[derive(Clone)]
+pub struct Resampler {
+    method: Method,
+    s_iir: [i32; 6],
+    s_fir_i16: [i16; ORDER_FIR_12],
+    s_fir_i32: [i32; MAX_FIR_ORDER],
+    delay_buf: [i16; 48],
+    input_delay: usize,
+    batch_size: usize,
+    inv_ratio_q16: i32,
+    fs_in_khz: usize,
+    fs_out_khz: usize,
+}
+
+impl Resampler {
+    ///Creates a resampler from 8, 12 or 16 kHz to 8, 12, 16, 24 or 48 kHz
+    pub fn new(fs_in_hz: usize, fs_out_hz: usize) -> Self {
+        let fs_in_khz = fs_in_hz / 1000;
+        let fs_out_khz = fs_out_hz / 1000;
+        let mut up2x = 0;
+        let method = if fs_out_hz > fs_in_hz {
+            if fs_out_hz == 2 * fs_in_hz {
+                Method::Up2Hq
+            } else {
+                up2x = 1;
+                Method::IirFir
+            }
+        } else if fs_out_hz < fs_in_hz {
+            if 4 * fs_out_hz == 3 * fs_in_hz {
+                Method::DownFir { fracs: 3, order: DOWN_ORDER_FIR0, coefs: &tables::RESAMPLER_3_4_COEFS }
+            } else if 3 * fs_out_hz == 2 * fs_in_hz {
+                Method::DownFir { fracs: 2, order: DOWN_ORDER_FIR0, coefs: &tables::RESAMPLER_2_3_COEFS }
+            } else if 2 * fs_out_hz == fs_in_hz {
+                Method::DownFir { fracs: 1, order: DOWN_ORDER_FIR1, coefs: &tables::RESAMPLER_1_2_COEFS }
+            } else {
+                unreachable!()
+            }
+        } else {
+            Method::Copy
+        };
+
+        //Ratio of input to output samples, rounded up
+        let (fs_in, fs_out) = (fs_in_hz as i32, fs_out_hz as i32);
+        let mut inv_ratio_q16 = ((fs_in<<(14 + up2x)) / fs_out)<<2;
+        while smulww(inv_ratio_q16, fs_out) < fs_in<<up2x {
+            inv_ratio_q16 += 1;
+        }
+
+        Self {
+            method,
+            s_iir: [0; 6],
+            s_fir_i16: [0; ORDER_FIR_12],
+            s_fir_i32: [0; MAX_FIR_ORDER],
+            delay_buf: [0; 48],
+            input_delay: DELAY_MATRIX[rate_id(fs_in_khz)][rate_id(fs_out_khz)],
+            batch_size: fs_in_khz * MAX_BATCH_SIZE_MS,
+            inv_ratio_q16,
+            fs_in_khz,
+            fs_out_khz,
+        }
+    }
+
+    ///Resamples `input`, at least 1 ms long, into `out`
+    pub fn process(&mut self, out: &mut [i16], input: &[i16]) {
+        debug_assert!(input.len() >= self.fs_in_khz);
+        let n_samples = self.fs_in_khz - self.input_delay;
+        let delay = self.input_delay;
+        self.delay_buf[delay..self.fs_in_khz].copy_from_slice(&input[..n_samples]);
+
+        //The first ms comes from the delay buffer
+        let delay_buf = self.delay_buf;
+        let (head, tail) = out.split_at_mut(self.fs_out_khz);
+        self.run(head, &delay_buf[..self.fs_in_khz]);
+        self.run(tail, &input[n_samples..input.len() - delay]);
+
+        self.delay_buf[..delay].copy_from_slice(&input[input.len() - delay..]);
+    }
+
+    fn run(&mut self, out: &mut [i16], input: &[i16]) {
+        match self.method {
+            Method::Copy => out[..input.len()].copy_from_slice(input),
+            Method::Up2Hq => up2_hq(&mut self.s_iir, out, input),
+            Method::IirFir => self.iir_fir(out, input),
+            Method::DownFir { fracs, order, coefs } => self.down_fir(out, input, fracs, order, coefs),
+        }
+    }
+
+    fn iir_fir(&mut self, out: &mut [i16], mut input: &[i16]) {
+        let mut buf = [0i16; 2 * 16 * MAX_BATCH_SIZE_MS + ORDER_FIR_12];
+        buf[..ORDER_FIR_12].copy_from_slice(&self.s_fir_i16);
+
+        let mut out_idx = 0;
+        loop {
+            let n = input.len().min(self.batch_size);
+            up2_hq(&mut self.s_iir, &mut buf[ORDER_FIR_12..], &input[..n]);
+
+            //Interpolate the upsampled signal
+            let max_index_q16 = (n as i32)<<(16 + 1);
+            let mut index_q16 = 0;
+            while index_q16 < max_index_q16 {
+                let table_index = smulwb(index_q16 & 0xffff, 12) as usize;
+                let p = &buf[(index_q16>>16) as usize..];
+                let fir = &tables::RESAMPLER_FRAC_FIR_12[table_index];
+                let fir_rev = &tables::RESAMPLER_FRAC_FIR_12[11 - table_index];
+                let mut res_q15 = smulbb(i32::from(p[0]), i32::from(fir[0]));
+                for j in 1..4 {
+                    res_q15 = smlabb(res_q15, i32::from(p[j]), i32::from(fir[j]));
+                }
+                for j in 0..4 {
+                    res_q15 = smlabb(res_q15, i32::from(p[4 + j]), i32::from(fir_rev[3 - j]));
+                }
+                out[out_idx] = sat16(rshift_round(res_q15, 15));
+                out_idx += 1;
+                index_q16 += self.inv_ratio_q16;
+            }
+
+            input = &input[n..];
+            if input.is_empty() {
+                self.s_fir_i16.copy_from_slice(&buf[n<<1..(n<<1) + ORDER_FIR_12]);
+                return;
+            }
+            buf.copy_within(n<<1..(n<<1) + ORDER_FIR_12, 0);
+        }
+    }
+
+    fn down_fir(&mut self, out: &mut [i16], mut input: &[i16], fracs: usize, order: usize, coefs: &[i16]) {
+        let mut buf = [0i32; 16 * MAX_BATCH_SIZE_MS + MAX_FIR_ORDER];
+        buf[..order].copy_from_slice(&self.s_fir_i32[..order]);
+        let fir = &coefs[2..];
+
+        let mut out_idx = 0;
+        let mut n;
+        loop {
+            n = input.len().min(self.batch_size);
+
+            //Second order AR filter, output in Q8
+            let (s, a) = (&mut self.s_iir, coefs);
+            for (b, &x) in buf[order..order + n].iter_mut().zip(input[..n].iter()) {
+                let out32 = s[0].wrapping_add(i32::from(x)<<8);
+                *b = out32;
+                let out32 = out32<<2;
+                s[0] = smlawb(s[1], out32, i32::from(a[0]));
+                s[1] = smulwb(out32, i32::from(a[1]));
+            }
+
+            //Interpolate the filtered signal
+            let max_index_q16 = (n as i32)<<16;
+            let mut index_q16 = 0;
+            while index_q16 < max_index_q16 {
+                let p = &buf[(index_q16>>16) as usize..];
+                let mut res_q6 = 0;
+                if order == DOWN_ORDER_FIR0 {
+                    let half = DOWN_ORDER_FIR0 / 2;
+                    let ind = smulwb(index_q16 & 0xffff, fracs as i32) as usize;
+                    let c = &fir[half * ind..];
+                    for j in 0..half {
+                        res_q6 = smlawb(res_q6, p[j], i32::from(c[j]));
+                    }
+                    let c = &fir[half * (fracs - 1 - ind)..];
+                    for j in 0..half {
+                        res_q6 = smlawb(res_q6, p[order - 1 - j], i32::from(c[j]));
+                    }
+                } else {
+                    for j in 0..order / 2 {
+                        res_q6 = smlawb(res_q6, p[j].wrapping_add(p[order - 1 - j]), i32::from(fir[j]));
+                    }
+                }
+                out[out_idx] = sat16(rshift_round(res_q6, 6));
+                out_idx += 1;
+                index_q16 += self.inv_ratio_q16;
+            }
+
+            input = &input[n..];
+            if input.len() <= 1 {
+                break;
+            }
+            buf.copy_within(n..n + order, 0);
+        }
+        self.s_fir_i32[..order].copy_from_slice(&buf[n..n + order]);
+    }
+}
+
+///Upsamples by two with a pair of allpass filter chains, writing `2*input.len()` samples
+fn up2_hq(s: &mut [i32; 6], out: &mut [i16], input: &[i16]) {
+    fn allpass(s: &mut [i32], coefs: &[i16; 3], in32: i32) -> i16 {
+        let y = in32.wrapping_sub(s[0]);
+        let x = smulwb(y, i32::from(coefs[0]));
+        let out1 = s[0].wrapping_add(x);
+        s[0] = in32.wrapping_add(x);
+
+        let y = out1.wrapping_sub(s[1]);
+        let x = smulwb(y, i32::from(coefs[1]));
+        let out2 = s[1].wrapping_add(x);
+        s[1] = out1.wrapping_add(x);
+
+        let y = out2.wrapping_sub(s[2]);
+        let x = smlawb(y, y, i32::from(coefs[2]));
+        let out1 = s[2].wrapping_add(x);
+        s[2] = out2.wrapping_add(x);
+        sat16(rshift_round(out1, 10))
+    }
+
+    let (even, odd) = s.split_at_mut(3);
+    for (k, &x) in input.iter().enumerate() {
+        let in32 = i32::from(x)<<10;
+        out[2 * k] = allpass(even, &tables::RESAMPLER_UP2_HQ_0, in32);
+        out[2 * k + 1] = allpass(odd, &tables::RESAMPLER_UP2_HQ_1, in32);
+    }
+}
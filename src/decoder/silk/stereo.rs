@@ -0,0 +1,82 @@
+use range;
+use super::math::{rshift_round, sat16, smlabb, smlawb, smulbb, smulwb};
+use super::tables::{self, icdf};
+
+///The predictors are interpolated over the first 8 ms of a frame
+const INTERP_LEN_MS: usize = 8;
+
+///Mid/side state carried between frames
+#[derive(Clone, Default)]
+pub struct Stereo {
+    pub pred_prev_q13: [i32; 2],
+    ///Last two samples of the previous frame, the side prediction looks one sample ahead
+    pub s_mid: [i16; 2],
+    pub s_side: [i16; 2],
+}
+
+///Decodes the predictors of the side channel from the mid channel, in Q13
+pub fn decode_pred(rc: &mut range::Decoder) -> [i32; 2] {
+    let n = rc.decode_icdf(&icdf::STEREO_PRED_JOINT, 8);
+    let mut ix = [[0; 3]; 2];
+    ix[0][2] = n / 5;
+    ix[1][2] = n - 5 * ix[0][2];
+    for ix in ix.iter_mut() {
+        ix[0] = rc.decode_icdf(&icdf::UNIFORM3, 8);
+        ix[1] = rc.decode_icdf(&icdf::UNIFORM5, 8);
+    }
+
+    let mut pred_q13 = [0; 2];
+    for (pred, ix) in pred_q13.iter_mut().zip(ix.iter()) {
+        let i = ix[0] + 3 * ix[2];
+        let low_q13 = i32::from(tables::STEREO_PRED_QUANT_Q13[i]);
+        //Each quantization step is split in 5 sub-steps, 0.5/5 in Q16
+        let step_q13 = smulwb(i32::from(tables::STEREO_PRED_QUANT_Q13[i + 1]) - low_q13, 6554);
+        *pred = smlabb(low_q13, step_q13, 2 * ix[1] as i32 + 1);
+    }
+    //Subtracting the second from the first predictor makes applying them cheaper
+    pred_q13[0] -= pred_q13[1];
+    pred_q13
+}
+
+///Whether only the mid channel is coded in this frame
+pub fn decode_mid_only(rc: &mut range::Decoder) -> bool {
+    rc.decode_icdf(&icdf::STEREO_ONLY_CODE_MID, 8) == 1
+}
+
+impl Stereo {
+    ///Converts mid/side to left/right in place. `x1` and `x2` hold two samples of history followed by `len` samples
+    pub fn ms_to_lr(&mut self, x1: &mut [i16], x2: &mut [i16], pred_q13: [i32; 2], fs_khz: usize, len: usize) {
+        x1[..2].copy_from_slice(&self.s_mid);
+        x2[..2].copy_from_slice(&self.s_side);
+        self.s_mid.copy_from_slice(&x1[len..len + 2]);
+        self.s_side.copy_from_slice(&x2[len..len + 2]);
+
+        //Interpolate the predictors and add the prediction to the side channel
+        let mut pred0_q13 = self.pred_prev_q13[0];
+        let mut pred1_q13 = self.pred_prev_q13[1];
+        let interp_len = INTERP_LEN_MS * fs_khz;
+        let denom_q16 = (1<<16) / interp_len as i32;
+        let delta0_q13 = rshift_round(smulbb(pred_q13[0] - pred0_q13, denom_q16), 16);
+        let delta1_q13 = rshift_round(smulbb(pred_q13[1] - pred1_q13, denom_q16), 16);
+        for n in 0..len {
+            if n < interp_len {
+                pred0_q13 += delta0_q13;
+                pred1_q13 += delta1_q13;
+            } else {
+                pred0_q13 = pred_q13[0];
+                pred1_q13 = pred_q13[1];
+            }
+            let sum = (i32::from(x1[n]) + i32::from(x1[n + 2]) + (i32::from(x1[n + 1])<<1))<<9;
+            let sum = smlawb(i32::from(x2[n + 1])<<8, sum, pred0_q13);
+            let sum = smlawb(sum, i32::from(x1[n + 1])<<11, pred1_q13);
+            x2[n + 1] = sat16(rshift_round(sum, 8));
+        }
+        self.pred_prev_q13 = pred_q13;
+
+        for n in 1..len + 1 {
+            let (mid, side) = (i32::from(x1[n]), i32::from(x2[n]));
+            x1[n] = sat16(mid + side);
+            x2[n] = sat16(mid - side);
+        }
+    }
+}
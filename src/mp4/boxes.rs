@@ -0,0 +1,63 @@
+use super::{be_u32, be_u64, Mp4ErrorKind, Result};
+
+///Splits the data of a container box into its children's types and data, see ISO/IEC 14496-12 section 4.2.
+///A size of 0 makes a box run to the end of the data
+pub fn children(mut data: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    let mut children = Vec::new();
+    while !data.is_empty() {
+        let (kind, header, size) = parse_header(data)?;
+        let size = size.unwrap_or(data.len() as u64);
+        if size < header as u64 || size > data.len() as u64 {
+            return Err(Mp4ErrorKind::InvalidBox);
+        }
+        children.push((kind, &data[header..size as usize]));
+        data = &data[size as usize..];
+    }
+    Ok(children)
+}
+
+///The data of the first child of type `kind`
+pub fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Result<Option<&'a [u8]>> {
+    Ok(children(data)?.into_iter().find(|&(k, _)| &k == kind).map(|(_, data)| data))
+}
+
+///Reads the type of a box, the length of its header and its size with the header, `None` if it runs to the end
+pub fn parse_header(data: &[u8]) -> Result<([u8; 4], usize, Option<u64>)> {
+    if data.len() < 8 {
+        return Err(Mp4ErrorKind::InvalidBox);
+    }
+    let kind = [data[4], data[5], data[6], data[7]];
+    match be_u32(data) {
+        0 => Ok((kind, 8, None)),
+        //The size follows the type in 64 bits
+        1 if data.len() >= 16 => Ok((kind, 16, Some(be_u64(&data[8..])))),
+        1 => Err(Mp4ErrorKind::InvalidBox),
+        size => Ok((kind, 8, Some(u64::from(size)))),
+    }
+}
+
+///Splits the version and flags off the data of a full box
+pub fn full_box(data: &[u8]) -> Result<(u8, u32, &[u8])> {
+    if data.len() < 4 {
+        return Err(Mp4ErrorKind::InvalidBox);
+    }
+    Ok((data[0], be_u32(data) & 0xff_ffff, &data[4..]))
+}
+
+///Writes a box of type `kind` with the data `content` writes
+pub fn write_box<F: FnOnce(&mut Vec<u8>)>(out: &mut Vec<u8>, kind: &[u8; 4], content: F) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(kind);
+    content(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+///Writes a full box of type `kind` with its version and flags and the data `content` writes
+pub fn write_full_box<F: FnOnce(&mut Vec<u8>)>(out: &mut Vec<u8>, kind: &[u8; 4], version: u8, flags: u32, content: F) {
+    write_box(out, kind, |out| {
+        out.extend_from_slice(&(u32::from(version)<<24 | flags).to_be_bytes());
+        content(out);
+    });
+}
@@ -0,0 +1,74 @@
+use decoder::multistream::ChannelMapping;
+use ogg::OpusHead;
+use super::{be_u16, be_u32, Mp4ErrorKind, Result, SAMPLE_RATE};
+use super::boxes::{self, write_box};
+
+///Bytes of a dOps box's data before the channel mapping table
+const DOPS_SIZE: usize = 11;
+///Bytes of an audio sample entry before its child boxes
+const AUDIO_SAMPLE_ENTRY_SIZE: usize = 28;
+
+///Reads the data of a dOps box, the Opus specific box of the `Opus` sample entry, into the OpusHead it stands for,
+///see the Encapsulation of Opus in ISO Base Media File Format section 4.3.2. Its fields are big-endian,
+///but otherwise those of the OpusHead with version 0 in place of 1
+pub fn read_dops(data: &[u8]) -> Result<OpusHead> {
+    if data.len() < DOPS_SIZE || data[0] != 0 {
+        return Err(Mp4ErrorKind::InvalidOpusSpecificBox);
+    }
+    let mapping = ChannelMapping::read(data[10], usize::from(data[1]), &data[DOPS_SIZE..])
+        .map_err(|_| Mp4ErrorKind::InvalidOpusSpecificBox)?;
+    Ok(OpusHead {
+        version: 1,
+        pre_skip: be_u16(&data[2..]),
+        input_sample_rate: be_u32(&data[4..]),
+        output_gain: be_u16(&data[8..]) as i16,
+        mapping,
+    })
+}
+
+///Writes the data of a dOps box for an OpusHead
+pub fn write_dops(head: &OpusHead, out: &mut Vec<u8>) {
+    let mapping = &head.mapping;
+    out.push(0);
+    out.push(mapping.channels() as u8);
+    out.extend_from_slice(&head.pre_skip.to_be_bytes());
+    out.extend_from_slice(&head.input_sample_rate.to_be_bytes());
+    out.extend_from_slice(&head.output_gain.to_be_bytes());
+    out.push(mapping.family);
+    if mapping.family != 0 {
+        out.push(mapping.streams as u8);
+        out.push(mapping.coupled_streams as u8);
+        if mapping.family == 3 {
+            for gain in &mapping.demixing_matrix {
+                out.extend_from_slice(&gain.to_le_bytes());
+            }
+        } else {
+            out.extend_from_slice(&mapping.mapping);
+        }
+    }
+}
+
+///Reads the data of an `Opus` sample entry, an audio sample entry whose dOps child box holds the header
+pub fn read_sample_entry(data: &[u8]) -> Result<OpusHead> {
+    if data.len() < AUDIO_SAMPLE_ENTRY_SIZE {
+        return Err(Mp4ErrorKind::InvalidBox);
+    }
+    let dops = boxes::child(&data[AUDIO_SAMPLE_ENTRY_SIZE..], b"dOps")?.ok_or(Mp4ErrorKind::InvalidOpusSpecificBox)?;
+    read_dops(dops)
+}
+
+///Writes an `Opus` sample entry box for an OpusHead, see section 4.3.1. The channel count is that of the output,
+///and the sample rate is always 48 kHz
+pub fn write_sample_entry(head: &OpusHead, out: &mut Vec<u8>) {
+    write_box(out, b"Opus", |out| {
+        //Reserved, then the data reference index
+        out.extend_from_slice(&[0; 6]);
+        out.extend_from_slice(&1u16.to_be_bytes());
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(&(head.channels() as u16).to_be_bytes());
+        out.extend_from_slice(&16u16.to_be_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&((SAMPLE_RATE as u32)<<16).to_be_bytes());
+        write_box(out, b"dOps", |out| write_dops(head, out));
+    });
+}
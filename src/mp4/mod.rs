@@ -0,0 +1,57 @@
+mod boxes;
+mod dops;
+mod reader;
+mod writer;
+
+pub use self::dops::{read_dops, write_dops};
+pub use self::reader::{Mp4Packet, Reader};
+pub use self::writer::Writer;
+
+use std::io;
+use std::result;
+use packet::PacketErrorKind;
+
+///Rate of the packet durations and the pre-skip, and the timescale of the tracks the writer makes
+const SAMPLE_RATE: u64 = 48000;
+
+#[derive(Debug)]
+pub enum Mp4ErrorKind {
+    Io(io::Error),
+    ///A box is cut off, or has fields that don't fit its size
+    InvalidBox,
+    ///There's no movie box, or a table of the sample table box is missing or doesn't match the others
+    InvalidMovie,
+    ///A dOps box is malformed, or has a version or channel mapping that isn't supported
+    InvalidOpusSpecificBox,
+    ///No track has an `Opus` sample entry
+    NoOpusTrack,
+    ///A sample's data lies outside the file
+    InvalidSample,
+    InvalidPacket(PacketErrorKind),
+}
+
+impl From<io::Error> for Mp4ErrorKind {
+    fn from(err: io::Error) -> Self {
+        Mp4ErrorKind::Io(err)
+    }
+}
+
+impl From<PacketErrorKind> for Mp4ErrorKind {
+    fn from(err: PacketErrorKind) -> Self {
+        Mp4ErrorKind::InvalidPacket(err)
+    }
+}
+
+pub type Result<T> = result::Result<T, Mp4ErrorKind>;
+
+fn be_u16(data: &[u8]) -> u16 {
+    u16::from_be_bytes([data[0], data[1]])
+}
+
+fn be_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn be_u64(data: &[u8]) -> u64 {
+    u64::from(be_u32(data))<<32 | u64::from(be_u32(&data[4..]))
+}
@@ -0,0 +1,693 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::iter;
+use ogg::OpusHead;
+use packet::Packet;
+use super::{be_u32, be_u64, Mp4ErrorKind, Result, SAMPLE_RATE};
+use super::boxes::{self, full_box};
+use super::dops::read_sample_entry;
+
+///Flags of a track fragment header that say which fields it has
+const BASE_DATA_OFFSET: u32 = 0x1;
+const SAMPLE_DESCRIPTION_INDEX: u32 = 0x2;
+const DEFAULT_SAMPLE_DURATION: u32 = 0x8;
+const DEFAULT_SAMPLE_SIZE: u32 = 0x10;
+///Flags of a track run that say which fields it and its samples have
+const DATA_OFFSET: u32 = 0x1;
+const FIRST_SAMPLE_FLAGS: u32 = 0x4;
+const SAMPLE_DURATION: u32 = 0x100;
+const SAMPLE_SIZE: u32 = 0x200;
+const SAMPLE_FLAGS: u32 = 0x400;
+const SAMPLE_COMPOSITION_TIME_OFFSET: u32 = 0x800;
+
+///An Opus packet of an MP4 track, with the part of its decoded samples that the edit list keeps.
+///Samples are counted per channel at 48 kHz
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mp4Packet {
+    pub data: Vec<u8>,
+    ///Decode time of the packet's first sample
+    pub timestamp: u64,
+    ///Decoded samples to drop from the start, for the pre-skip
+    pub skip: usize,
+    ///Decoded samples to keep after the dropped ones, fewer than the packet holds where the end is trimmed
+    pub samples: usize,
+}
+
+impl Mp4Packet {
+    ///Parses the data as the packet of a single stream, multistream packets are split up by `multistream::Decoder`
+    pub fn packet<'a>(&'a self) -> Result<Packet<'a>> {
+        Ok(Packet::read(&self.data)?)
+    }
+}
+
+///Where a sample's data is and when it plays, in the timescale of its track
+#[derive(Copy, Clone, Debug)]
+struct Sample {
+    offset: u64,
+    size: u32,
+    time: u64,
+    duration: u32,
+}
+
+///Defaults of a track's samples in movie fragments, from the track extends box
+#[derive(Copy, Clone, Debug, Default)]
+struct TrackDefaults {
+    duration: u32,
+    size: u32,
+}
+
+///Reads the first track with an `Opus` sample entry of an MP4 file, see the Encapsulation of Opus in ISO Base Media
+///File Format. Samples are found through the sample table of the movie box, followed by those of any movie fragments.
+///The start and end are trimmed by the first edit of the edit list, or by the pre-skip without one
+pub struct Reader<R> {
+    reader: R,
+    track_id: u32,
+    head: OpusHead,
+    timescale: u32,
+    samples: Vec<Sample>,
+    ///Index of the next sample to return
+    next: usize,
+    ///Media time of the first sample to keep and the end of the last, in the track's timescale
+    start: u64,
+    end: Option<u64>,
+    fragmented: bool,
+}
+
+impl<R: Read + Seek> Reader<R> {
+    ///Reads the movie box and any movie fragment boxes, from where the reader is to the end of the file
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut moov = None;
+        let mut moofs = Vec::new();
+        //Counts in the boxes are checked against the size of the file before anything is allocated by them
+        let start = reader.stream_position()?;
+        let file_size = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(start))?;
+        loop {
+            let offset = reader.stream_position()?;
+            let mut header = [0; 16];
+            match read_full(&mut reader, &mut header[..8])? {
+                0 => break,
+                8 => {},
+                _ => return Err(Mp4ErrorKind::InvalidBox),
+            }
+            if header[..4] == [0, 0, 0, 1] && read_full(&mut reader, &mut header[8..])? < 8 {
+                return Err(Mp4ErrorKind::InvalidBox);
+            }
+            let (kind, header_size, size) = boxes::parse_header(&header)?;
+            let size = match size {
+                Some(size) => size,
+                None => reader.seek(SeekFrom::End(0))? - offset,
+            };
+            if size < header_size as u64 {
+                return Err(Mp4ErrorKind::InvalidBox);
+            }
+            reader.seek(SeekFrom::Start(offset + header_size as u64))?;
+            match &kind {
+                b"moov" | b"moof" => {
+                    let mut data = Vec::new();
+                    (&mut reader).take(size - header_size as u64).read_to_end(&mut data)?;
+                    if (data.len() as u64) < size - header_size as u64 {
+                        return Err(Mp4ErrorKind::InvalidBox);
+                    }
+                    if &kind == b"moov" {
+                        moov = Some(data);
+                    } else {
+                        moofs.push((offset, data));
+                    }
+                },
+                _ => {
+                    reader.seek(SeekFrom::Start(offset + size))?;
+                },
+            }
+        }
+
+        let moov = moov.ok_or(Mp4ErrorKind::InvalidMovie)?;
+        let children = boxes::children(&moov)?;
+        let movie_timescale = match children.iter().find(|&&(kind, _)| &kind == b"mvhd") {
+            Some(&(_, mvhd)) => read_timescale(mvhd)?,
+            None => return Err(Mp4ErrorKind::InvalidMovie),
+        };
+        for &(kind, trak) in &children {
+            if &kind != b"trak" {
+                continue;
+            }
+            let mut track = match read_track(trak, movie_timescale, file_size)? {
+                Some(track) => track,
+                None => continue,
+            };
+            if let Some(mvex) = children.iter().find(|&&(kind, _)| &kind == b"mvex").map(|&(_, data)| data) {
+                let defaults = read_track_defaults(mvex, track.track_id)?;
+                for &(offset, ref moof) in &moofs {
+                    track.read_fragment(offset, moof, defaults, file_size)?;
+                    track.fragmented = true;
+                }
+            }
+            return Ok(Self {
+                reader,
+                track_id: track.track_id,
+                head: track.head,
+                timescale: track.timescale,
+                samples: track.samples,
+                next: 0,
+                start: track.start,
+                end: track.end,
+                fragmented: track.fragmented,
+            });
+        }
+        Err(Mp4ErrorKind::NoOpusTrack)
+    }
+
+    pub fn head(&self) -> &OpusHead {
+        &self.head
+    }
+
+    ///ID of the track the packets come from
+    pub fn track_id(&self) -> u32 {
+        self.track_id
+    }
+
+    ///Whether any of the samples came from movie fragments
+    pub fn fragmented(&self) -> bool {
+        self.fragmented
+    }
+
+    ///Samples per channel at 48 kHz that are kept after trimming
+    pub fn duration(&self) -> u64 {
+        let end = self.samples.last().map_or(0, |sample| sample.time + u64::from(sample.duration));
+        let end = self.end.map_or(end, |trim| trim.min(end));
+        end.saturating_sub(self.start).saturating_mul(SAMPLE_RATE) / u64::from(self.timescale)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    ///Returns the next packet, or `None` once the samples are read or the rest is trimmed
+    pub fn read_packet(&mut self) -> Result<Option<Mp4Packet>> {
+        let sample = match self.samples.get(self.next) {
+            Some(&sample) => sample,
+            None => return Ok(None),
+        };
+        let end = sample.time + u64::from(sample.duration);
+        if self.end.is_some_and(|trim| sample.time >= trim) {
+            self.next = self.samples.len();
+            return Ok(None);
+        }
+        self.next += 1;
+        //Samples before the start are still decoded, and dropped whole
+        let kept_start = sample.time.max(self.start).min(end);
+        let kept_end = self.end.map_or(end, |trim| trim.min(end)).max(kept_start);
+        let timestamp = to_samples(sample.time, self.timescale)?;
+        let skip = to_samples(kept_start - sample.time, self.timescale)? as usize;
+        let samples = to_samples(kept_end - sample.time, self.timescale)? as usize - skip;
+
+        //The data is only as large as what's actually there, whatever size the sample claims
+        self.reader.seek(SeekFrom::Start(sample.offset))?;
+        let mut data = Vec::new();
+        (&mut self.reader).take(u64::from(sample.size)).read_to_end(&mut data)?;
+        if data.len() < sample.size as usize {
+            return Err(Mp4ErrorKind::InvalidSample);
+        }
+        Ok(Some(Mp4Packet { data, timestamp, skip, samples }))
+    }
+}
+
+///An Opus track as it's read from the movie box, before its samples are read
+struct Track {
+    track_id: u32,
+    head: OpusHead,
+    timescale: u32,
+    samples: Vec<Sample>,
+    start: u64,
+    end: Option<u64>,
+    fragmented: bool,
+}
+
+impl Track {
+    ///Adds the samples of the track's fragments in a movie fragment box at `offset`
+    fn read_fragment(&mut self, offset: u64, moof: &[u8], defaults: TrackDefaults, file_size: u64) -> Result<()> {
+        for (kind, traf) in boxes::children(moof)? {
+            if &kind != b"traf" {
+                continue;
+            }
+            let children = boxes::children(traf)?;
+            let tfhd = children.iter().find(|&&(kind, _)| &kind == b"tfhd").map(|&(_, data)| data).ok_or(Mp4ErrorKind::InvalidBox)?;
+            let (_, flags, mut data) = full_box(tfhd)?;
+            let mut field = |size: usize| -> Result<u64> {
+                let value = data.get(..size).ok_or(Mp4ErrorKind::InvalidBox)?;
+                data = &data[size..];
+                Ok(if size == 8 { be_u64(value) } else { u64::from(be_u32(value)) })
+            };
+            if field(4)? as u32 != self.track_id {
+                continue;
+            }
+            //Without a base data offset, data offsets count from the start of the movie fragment box
+            let base = if flags & BASE_DATA_OFFSET != 0 { field(8)? } else { offset };
+            if flags & SAMPLE_DESCRIPTION_INDEX != 0 {
+                field(4)?;
+            }
+            let duration = if flags & DEFAULT_SAMPLE_DURATION != 0 { field(4)? as u32 } else { defaults.duration };
+            let size = if flags & DEFAULT_SAMPLE_SIZE != 0 { field(4)? as u32 } else { defaults.size };
+
+            let mut time = self.samples.last().map_or(0, |sample| sample.time + u64::from(sample.duration));
+            if let Some(&(_, tfdt)) = children.iter().find(|&&(kind, _)| &kind == b"tfdt") {
+                time = match full_box(tfdt)? {
+                    (1, _, data) if data.len() >= 8 => be_u64(data),
+                    (0, _, data) if data.len() >= 4 => u64::from(be_u32(data)),
+                    _ => return Err(Mp4ErrorKind::InvalidBox),
+                };
+            }
+            let mut position = base;
+            for &(kind, trun) in &children {
+                if &kind != b"trun" {
+                    continue;
+                }
+                let (_, flags, data) = full_box(trun)?;
+                let count = data.get(..4).map(be_u32).ok_or(Mp4ErrorKind::InvalidBox)? as usize;
+                let mut data = &data[4..];
+                if flags & DATA_OFFSET != 0 {
+                    let data_offset = data.get(..4).map(be_u32).ok_or(Mp4ErrorKind::InvalidBox)? as i32;
+                    position = (base as i64 + i64::from(data_offset)) as u64;
+                    data = &data[4..];
+                }
+                if flags & FIRST_SAMPLE_FLAGS != 0 {
+                    data = data.get(4..).ok_or(Mp4ErrorKind::InvalidBox)?;
+                }
+                let fields = [SAMPLE_DURATION, SAMPLE_SIZE, SAMPLE_FLAGS, SAMPLE_COMPOSITION_TIME_OFFSET].iter()
+                    .filter(|&&field| flags & field != 0)
+                    .count();
+                if data.len() < count * fields * 4 {
+                    return Err(Mp4ErrorKind::InvalidBox);
+                }
+                //Without fields for its samples, a run's count is only bounded by the data its samples take up
+                if fields == 0 && count as u64 > file_size.saturating_sub(position) / u64::from(size.max(1)) {
+                    return Err(Mp4ErrorKind::InvalidBox);
+                }
+                for i in 0..count {
+                    //Durations and sizes come first of a sample's fields, the defaults stand in for missing ones
+                    let entry = &data[i * fields * 4..];
+                    let duration = if flags & SAMPLE_DURATION != 0 { be_u32(entry) } else { duration };
+                    let entry = if flags & SAMPLE_DURATION != 0 { &entry[4..] } else { entry };
+                    let size = if flags & SAMPLE_SIZE != 0 { be_u32(entry) } else { size };
+                    self.samples.push(Sample { offset: position, size, time, duration });
+                    position = position.checked_add(u64::from(size)).ok_or(Mp4ErrorKind::InvalidBox)?;
+                    time = time.checked_add(u64::from(duration)).ok_or(Mp4ErrorKind::InvalidBox)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+///Reads a track box, returns `None` if the track has no `Opus` sample entry
+fn read_track(trak: &[u8], movie_timescale: u32, file_size: u64) -> Result<Option<Track>> {
+    let tkhd = boxes::child(trak, b"tkhd")?.ok_or(Mp4ErrorKind::InvalidMovie)?;
+    let track_id = match full_box(tkhd)? {
+        (1, _, data) if data.len() >= 20 => be_u32(&data[16..]),
+        (0, _, data) if data.len() >= 12 => be_u32(&data[8..]),
+        _ => return Err(Mp4ErrorKind::InvalidBox),
+    };
+    let mdia = boxes::child(trak, b"mdia")?.ok_or(Mp4ErrorKind::InvalidMovie)?;
+    let timescale = read_timescale(boxes::child(mdia, b"mdhd")?.ok_or(Mp4ErrorKind::InvalidMovie)?)?;
+    let stbl = match boxes::child(mdia, b"minf")? {
+        Some(minf) => boxes::child(minf, b"stbl")?.ok_or(Mp4ErrorKind::InvalidMovie)?,
+        None => return Err(Mp4ErrorKind::InvalidMovie),
+    };
+
+    //Only the first sample entry is used
+    let (_, _, stsd) = full_box(boxes::child(stbl, b"stsd")?.ok_or(Mp4ErrorKind::InvalidMovie)?)?;
+    let entries = boxes::children(stsd.get(4..).ok_or(Mp4ErrorKind::InvalidBox)?)?;
+    let head = match entries.first() {
+        Some(&(kind, entry)) if &kind == b"Opus" => read_sample_entry(entry)?,
+        _ => return Ok(None),
+    };
+    let samples = read_sample_table(stbl, file_size)?;
+
+    //Empty edits only delay the presentation, the first edit with media sets the trimming
+    let edit = match boxes::child(trak, b"edts")? {
+        Some(edts) => match boxes::child(edts, b"elst")? {
+            Some(elst) => read_edit_list(elst)?.into_iter().find(|&(_, media_time)| media_time >= 0),
+            None => None,
+        },
+        None => None,
+    };
+    let (start, end) = match edit {
+        //A segment duration of 0, as fragmented files have, runs to the end of the media
+        Some((segment_duration, media_time)) => {
+            let start = media_time as u64;
+            let end = segment_duration.checked_mul(u64::from(timescale))
+                .and_then(|duration| start.checked_add(duration / u64::from(movie_timescale)))
+                .ok_or(Mp4ErrorKind::InvalidMovie)?;
+            (start, Some(end).filter(|_| segment_duration > 0))
+        },
+        None => (u64::from(head.pre_skip) * u64::from(timescale) / SAMPLE_RATE, None),
+    };
+    Ok(Some(Track { track_id, head, timescale, samples, start, end, fragmented: false }))
+}
+
+///Works out the offset, size and time of every sample from the sample table box's tables
+fn read_sample_table(stbl: &[u8], file_size: u64) -> Result<Vec<Sample>> {
+    let table = |kind: &[u8; 4]| -> Result<Option<&[u8]>> {
+        match boxes::child(stbl, kind)? {
+            Some(data) => Ok(Some(full_box(data)?.2)),
+            None => Ok(None),
+        }
+    };
+    let stts = entries(table(b"stts")?.ok_or(Mp4ErrorKind::InvalidMovie)?, 0, 8)?;
+    let stsz = table(b"stsz")?.ok_or(Mp4ErrorKind::InvalidMovie)?;
+    let sample_size = stsz.get(..4).map(be_u32).ok_or(Mp4ErrorKind::InvalidBox)?;
+    let sizes = if sample_size == 0 {
+        entries(stsz, 4, 4)?.into_iter().map(be_u32).collect()
+    } else {
+        //Samples of the same size have to fit in the file
+        let count = stsz.get(4..8).map(be_u32).ok_or(Mp4ErrorKind::InvalidBox)?;
+        if u64::from(count) > file_size / u64::from(sample_size) {
+            return Err(Mp4ErrorKind::InvalidMovie);
+        }
+        vec![sample_size; count as usize]
+    };
+    let stsc = entries(table(b"stsc")?.ok_or(Mp4ErrorKind::InvalidMovie)?, 0, 12)?;
+    let chunks = match (table(b"stco")?, table(b"co64")?) {
+        (Some(stco), _) => entries(stco, 0, 4)?.into_iter().map(|entry| u64::from(be_u32(entry))).collect::<Vec<_>>(),
+        (None, Some(co64)) => entries(co64, 0, 8)?.into_iter().map(be_u64).collect(),
+        (None, None) => return Err(Mp4ErrorKind::InvalidMovie),
+    };
+
+    //The runs of durations are expanded as the samples are, once they're known to match the sizes
+    if stts.iter().map(|entry| u64::from(be_u32(entry))).sum::<u64>() != sizes.len() as u64 {
+        return Err(Mp4ErrorKind::InvalidMovie);
+    }
+    let mut durations = stts.iter()
+        .flat_map(|entry| iter::repeat_n(be_u32(&entry[4..]), be_u32(entry) as usize));
+    let mut samples = Vec::with_capacity(sizes.len());
+    let mut time = 0;
+    //Every run of chunks from the first chunk of an stsc entry to that of the next has the same number of samples
+    for (i, entry) in stsc.iter().enumerate() {
+        let first = be_u32(entry) as usize;
+        let last = stsc.get(i + 1).map_or(chunks.len() + 1, |next| be_u32(next) as usize);
+        if first == 0 || first > last {
+            return Err(Mp4ErrorKind::InvalidMovie);
+        }
+        for &chunk in chunks.get(first - 1..last - 1).ok_or(Mp4ErrorKind::InvalidMovie)? {
+            let mut offset = chunk;
+            for _ in 0..be_u32(&entry[4..]) {
+                let (&size, duration) = sizes.get(samples.len()).zip(durations.next()).ok_or(Mp4ErrorKind::InvalidMovie)?;
+                samples.push(Sample { offset, size, time, duration });
+                offset = offset.checked_add(u64::from(size)).ok_or(Mp4ErrorKind::InvalidMovie)?;
+                time = time.checked_add(u64::from(duration)).ok_or(Mp4ErrorKind::InvalidMovie)?;
+            }
+        }
+    }
+    if samples.len() != sizes.len() {
+        return Err(Mp4ErrorKind::InvalidMovie);
+    }
+    Ok(samples)
+}
+
+///Splits the data of a table box after its version and flags into its entries of `size` bytes,
+///going by the entry count after the first `skip` bytes
+fn entries(data: &[u8], skip: usize, size: usize) -> Result<Vec<&[u8]>> {
+    let count = data.get(skip..skip + 4).map(be_u32).ok_or(Mp4ErrorKind::InvalidBox)? as usize;
+    let data = &data[skip + 4..];
+    if data.len() / size < count {
+        return Err(Mp4ErrorKind::InvalidBox);
+    }
+    Ok(data.chunks(size).take(count).collect())
+}
+
+///Reads the entries of an edit list box as their segment duration in the movie's timescale and media time in the track's,
+///which is -1 for an empty edit
+fn read_edit_list(elst: &[u8]) -> Result<Vec<(u64, i64)>> {
+    let (version, _, data) = full_box(elst)?;
+    let size = if version == 1 { 20 } else { 12 };
+    Ok(entries(data, 0, size)?.into_iter().map(|entry| if version == 1 {
+        (be_u64(entry), be_u64(&entry[8..]) as i64)
+    } else {
+        (u64::from(be_u32(entry)), i64::from(be_u32(&entry[4..]) as i32))
+    }).collect())
+}
+
+///Reads the timescale of a movie or media header box
+fn read_timescale(data: &[u8]) -> Result<u32> {
+    let timescale = match full_box(data)? {
+        (1, _, data) if data.len() >= 20 => be_u32(&data[16..]),
+        (0, _, data) if data.len() >= 12 => be_u32(&data[8..]),
+        _ => return Err(Mp4ErrorKind::InvalidBox),
+    };
+    if timescale == 0 {
+        return Err(Mp4ErrorKind::InvalidMovie);
+    }
+    Ok(timescale)
+}
+
+///Converts a time in a track's timescale to samples at 48 kHz
+fn to_samples(time: u64, timescale: u32) -> Result<u64> {
+    time.checked_mul(SAMPLE_RATE).map(|time| time / u64::from(timescale)).ok_or(Mp4ErrorKind::InvalidMovie)
+}
+
+///Reads the defaults of a track's samples in movie fragments from the movie extends box
+fn read_track_defaults(mvex: &[u8], track_id: u32) -> Result<TrackDefaults> {
+    for (kind, trex) in boxes::children(mvex)? {
+        if &kind != b"trex" {
+            continue;
+        }
+        //Track ID, sample description index, duration, size and flags
+        let (_, _, data) = full_box(trex)?;
+        if data.len() < 20 {
+            return Err(Mp4ErrorKind::InvalidBox);
+        }
+        if be_u32(data) == track_id {
+            return Ok(TrackDefaults { duration: be_u32(&data[8..]), size: be_u32(&data[12..]) });
+        }
+    }
+    Ok(TrackDefaults::default())
+}
+
+///Reads until `buf` is full or the data ends, returns the number of bytes read
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use common::types::Channels;
+    use decoder::multistream::ChannelMapping;
+    use mp4::Writer;
+    use super::super::boxes::{write_box, write_full_box};
+    use super::super::dops::write_sample_entry;
+
+    fn head() -> OpusHead {
+        OpusHead { version: 1, pre_skip: 312, input_sample_rate: 48000, output_gain: 0, mapping: ChannelMapping::mono_stereo(Channels::Mono) }
+    }
+
+    ///A 20 ms CELT packet of `len` bytes that holds its index
+    fn packet(i: usize, len: usize) -> Vec<u8> {
+        let mut data = vec![0xf8, i as u8];
+        data.resize(len, 0x55);
+        data
+    }
+
+    fn be_u32s(out: &mut Vec<u8>, values: &[u32]) {
+        for value in values {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    ///Writes a movie box of one track with a timescale of 48 kHz in a movie with one of 1 kHz, and an edit list
+    ///of the segment duration and media time in `edit`. `stbl` writes the sample table after the sample description,
+    ///a movie extends box is added with `trex` for the duration and size of samples in fragments
+    fn moov<F: FnOnce(&mut Vec<u8>)>(edit: Option<(u32, i32)>, stbl: F, trex: Option<(u32, u32)>) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_box(&mut out, b"moov", |out| {
+            write_full_box(out, b"mvhd", 0, 0, |out| be_u32s(out, &[0, 0, 1000, 0]));
+            write_box(out, b"trak", |out| {
+                write_full_box(out, b"tkhd", 0, 0, |out| be_u32s(out, &[0, 0, 1, 0, 0]));
+                if let Some((segment_duration, media_time)) = edit {
+                    write_box(out, b"edts", |out| {
+                        write_full_box(out, b"elst", 0, 0, |out| be_u32s(out, &[1, segment_duration, media_time as u32, 0x1_0000]));
+                    });
+                }
+                write_box(out, b"mdia", |out| {
+                    write_full_box(out, b"mdhd", 0, 0, |out| be_u32s(out, &[0, 0, 48000, 0, 0]));
+                    write_box(out, b"minf", |out| {
+                        write_box(out, b"stbl", |out| {
+                            write_full_box(out, b"stsd", 0, 0, |out| {
+                                be_u32s(out, &[1]);
+                                write_sample_entry(&head(), out);
+                            });
+                            stbl(out);
+                        });
+                    });
+                });
+            });
+            if let Some((duration, size)) = trex {
+                write_box(out, b"mvex", |out| write_full_box(out, b"trex", 0, 0, |out| be_u32s(out, &[1, 1, duration, size, 0])));
+            }
+        });
+        out
+    }
+
+    ///A media data box of 4 packets with a gap after the first two, and a movie box with a chunk for each pair
+    ///and the edit list `edit`. Chunk offsets go in a co64 box with `co64`
+    fn regular(edit: Option<(u32, i32)>, co64: bool) -> Vec<u8> {
+        let packets: Vec<Vec<u8>> = [10, 20, 30, 40].iter().enumerate().map(|(i, &len)| packet(i, len)).collect();
+        let mut file = Vec::new();
+        write_box(&mut file, b"mdat", |out| {
+            out.extend(packets[0].iter().chain(&packets[1]));
+            out.extend_from_slice(&[0; 5]);
+            out.extend(packets[2].iter().chain(&packets[3]));
+        });
+        let chunks = [8, 8 + 30 + 5];
+        file.extend(moov(edit, |out| {
+            //Three samples of 20 ms and one of 10 ms
+            write_full_box(out, b"stts", 0, 0, |out| be_u32s(out, &[2, 3, 960, 1, 480]));
+            write_full_box(out, b"stsc", 0, 0, |out| be_u32s(out, &[1, 1, 2, 1]));
+            write_full_box(out, b"stsz", 0, 0, |out| be_u32s(out, &[0, 4, 10, 20, 30, 40]));
+            if co64 {
+                write_full_box(out, b"co64", 0, 0, |out| {
+                    be_u32s(out, &[2]);
+                    for &chunk in &chunks {
+                        out.extend_from_slice(&u64::from(chunk).to_be_bytes());
+                    }
+                });
+            } else {
+                write_full_box(out, b"stco", 0, 0, |out| {
+                    be_u32s(out, &[2]);
+                    be_u32s(out, &chunks);
+                });
+            }
+        }, None));
+        file
+    }
+
+    fn read_all<R: Read + Seek>(reader: &mut Reader<R>) -> Vec<Mp4Packet> {
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.read_packet().unwrap() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    #[test]
+    fn sample_table() {
+        //Starting after the pre-skip and running 55 ms, which ends 1.5 ms into the last packet
+        for &co64 in &[false, true] {
+            let mut reader = Reader::new(Cursor::new(regular(Some((55, 312)), co64))).unwrap();
+            assert_eq!((reader.track_id(), reader.fragmented(), reader.head()), (1, false, &head()));
+            assert_eq!(reader.duration(), 2640);
+            let packets = read_all(&mut reader);
+            assert_eq!(packets.len(), 4);
+            for (i, (read, &len)) in packets.iter().zip(&[10, 20, 30, 40]).enumerate() {
+                assert_eq!(read.data, packet(i, len));
+                assert_eq!(read.timestamp, 960 * i as u64);
+            }
+            let kept: Vec<_> = packets.iter().map(|packet| (packet.skip, packet.samples)).collect();
+            assert_eq!(kept, [(312, 648), (0, 960), (0, 960), (0, 72)]);
+        }
+    }
+
+    #[test]
+    fn pre_skip_without_edit_list() {
+        let mut reader = Reader::new(Cursor::new(regular(None, false))).unwrap();
+        assert_eq!(reader.duration(), 3360 - 312);
+        let kept: Vec<_> = read_all(&mut reader).iter().map(|packet| (packet.skip, packet.samples)).collect();
+        assert_eq!(kept, [(312, 648), (0, 960), (0, 960), (0, 480)]);
+        //Samples that start after the trimmed end aren't returned at all
+        let mut reader = Reader::new(Cursor::new(regular(Some((30, 312)), false))).unwrap();
+        let kept: Vec<_> = read_all(&mut reader).iter().map(|packet| (packet.skip, packet.samples)).collect();
+        assert_eq!(kept, [(312, 648), (0, 792)]);
+    }
+
+    #[test]
+    fn fragments() {
+        let empty = |out: &mut Vec<u8>| {
+            write_full_box(out, b"stts", 0, 0, |out| be_u32s(out, &[0]));
+            write_full_box(out, b"stsc", 0, 0, |out| be_u32s(out, &[0]));
+            write_full_box(out, b"stsz", 0, 0, |out| be_u32s(out, &[0, 0]));
+            write_full_box(out, b"stco", 0, 0, |out| be_u32s(out, &[0]));
+        };
+        //An edit list that runs to the end, samples 20 ms long by default
+        let mut file = moov(Some((0, 312)), empty, Some((960, 0)));
+
+        //Two samples with sizes in the run, its data offset counting from the movie fragment box,
+        //past its 60 bytes and the media data box's header
+        write_box(&mut file, b"moof", |out| {
+            write_box(out, b"traf", |out| {
+                write_full_box(out, b"tfhd", 0, 0, |out| be_u32s(out, &[1]));
+                write_full_box(out, b"trun", 0, DATA_OFFSET | SAMPLE_SIZE, |out| be_u32s(out, &[2, 60 + 8, 10, 20]));
+            });
+        });
+        write_box(&mut file, b"mdat", |out| out.extend(packet(0, 10).iter().chain(&packet(1, 20))));
+
+        //Two samples of 10 ms and 30 bytes at an explicit base and decode time, after a gap of 480
+        let base = file.len() as u32 + 200;
+        write_box(&mut file, b"moof", |out| {
+            write_box(out, b"traf", |out| {
+                write_full_box(out, b"tfhd", 0, BASE_DATA_OFFSET | DEFAULT_SAMPLE_DURATION | DEFAULT_SAMPLE_SIZE, |out| {
+                    be_u32s(out, &[1, 0, base, 480, 30]);
+                });
+                write_full_box(out, b"tfdt", 0, 0, |out| be_u32s(out, &[2400]));
+                write_full_box(out, b"trun", 0, 0, |out| be_u32s(out, &[2]));
+            });
+        });
+        let len = base as usize - file.len() - 8;
+        write_box(&mut file, b"mdat", |out| {
+            out.resize(out.len() + len, 0);
+            out.extend(packet(2, 30).iter().chain(&packet(3, 30)));
+        });
+
+        let mut reader = Reader::new(Cursor::new(file)).unwrap();
+        assert!(reader.fragmented());
+        assert_eq!(reader.duration(), 3360 - 312);
+        let packets = read_all(&mut reader);
+        let read: Vec<_> = packets.iter().map(|packet| (packet.data.clone(), packet.timestamp, packet.skip, packet.samples)).collect();
+        assert_eq!(read, [
+            (packet(0, 10), 0, 312, 648),
+            (packet(1, 20), 960, 0, 960),
+            (packet(2, 30), 2400, 0, 480),
+            (packet(3, 30), 2880, 0, 480),
+        ]);
+    }
+
+    #[test]
+    fn writer_round_trip() {
+        let packets: Vec<Vec<u8>> = (0..10).map(|i| packet(i, 50 + i)).collect();
+        let mut writer = Writer::new(Vec::new(), &head());
+        for data in &packets {
+            writer.write_packet(data).unwrap();
+        }
+        assert_eq!(writer.duration(), 9600);
+        let file = writer.finish(Some(9000)).unwrap();
+        let mut reader = Reader::new(Cursor::new(file)).unwrap();
+        assert_eq!((reader.head(), reader.fragmented(), reader.duration()), (&head(), false, 9000));
+        let read = read_all(&mut reader);
+        assert_eq!(read.iter().map(|packet| packet.data.clone()).collect::<Vec<_>>(), packets);
+        assert_eq!((read[0].skip, read[0].samples), (312, 648));
+        //312 + 9000 samples end 672 samples into the last packet
+        assert_eq!((read[9].timestamp, read[9].skip, read[9].samples), (8640, 0, 672));
+        assert_eq!(read.iter().map(|packet| packet.samples).sum::<usize>(), 9000);
+
+        //Fragments of 60 ms, the last one short
+        let mut writer = Writer::fragmented(Vec::new(), &head(), 2880).unwrap();
+        for data in &packets {
+            writer.write_packet(data).unwrap();
+        }
+        let file = writer.finish(None).unwrap();
+        let mut reader = Reader::new(Cursor::new(file)).unwrap();
+        assert_eq!((reader.fragmented(), reader.duration()), (true, 9600 - 312));
+        let read = read_all(&mut reader);
+        assert_eq!(read.iter().map(|packet| packet.data.clone()).collect::<Vec<_>>(), packets);
+        assert!(read.iter().enumerate().all(|(i, packet)| packet.timestamp == 960 * i as u64));
+        assert_eq!(read.iter().map(|packet| packet.samples).sum::<usize>(), 9600 - 312);
+    }
+}
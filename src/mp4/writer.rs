@@ -0,0 +1,322 @@
+use std::io::Write;
+use std::mem;
+use ogg::OpusHead;
+use packet::Packet;
+use super::{Result, SAMPLE_RATE};
+use super::boxes::{write_box, write_full_box};
+use super::dops::write_sample_entry;
+
+const TRACK_ID: u32 = 1;
+///Matrix of the movie and track headers that leaves the picture as it is, in 16.16 and 2.30 fixed point
+const UNITY_MATRIX: [u32; 9] = [0x1_0000, 0, 0, 0, 0x1_0000, 0, 0, 0, 0x4000_0000];
+///Flags of the track header for an enabled track that's in the movie
+const TRACK_ENABLED: u32 = 0x3;
+///Flags of a track fragment header and run the writer uses
+const DEFAULT_BASE_IS_MOOF: u32 = 0x2_0000;
+const DATA_OFFSET: u32 = 0x1;
+const SAMPLE_DURATION: u32 = 0x100;
+const SAMPLE_SIZE: u32 = 0x200;
+
+///Writes packets of an Opus stream as a single track MP4 file with a timescale of 48 kHz, see the Encapsulation
+///of Opus in ISO Base Media File Format. The pre-skip goes in the dOps box and into the edit list, which also
+///trims the end. A regular file is written whole by `finish`, with the movie box before the media data so
+///playback can start before it's all downloaded. A fragmented file gets its movie box at once, and a movie
+///fragment whenever the packets written add up to the fragment duration
+pub struct Writer<W> {
+    writer: W,
+    head: OpusHead,
+    ///Size and duration of the packets not written yet
+    samples: Vec<(u32, u32)>,
+    data: Vec<u8>,
+    ///Samples per channel at 48 kHz of a fragment, `None` for a regular file
+    fragment_duration: Option<u64>,
+    ///Sequence number of the next fragment
+    sequence: u32,
+    ///Samples per channel at 48 kHz of the packets written so far
+    duration: u64,
+    ///Decode time of the first packet not written yet
+    decode_time: u64,
+    multistream: bool,
+}
+
+impl<W: Write> Writer<W> {
+    ///Starts a regular file, which is written out by `finish`
+    pub fn new(writer: W, head: &OpusHead) -> Self {
+        Self {
+            writer,
+            head: head.clone(),
+            samples: Vec::new(),
+            data: Vec::new(),
+            fragment_duration: None,
+            sequence: 1,
+            duration: 0,
+            decode_time: 0,
+            multistream: head.mapping.streams > 1,
+        }
+    }
+
+    ///Starts a fragmented file with fragments of at least `fragment_duration` samples per channel at 48 kHz,
+    ///and writes its file type and movie box
+    pub fn fragmented(writer: W, head: &OpusHead, fragment_duration: u64) -> Result<Self> {
+        let mut writer = Self::new(writer, head);
+        writer.fragment_duration = Some(fragment_duration.max(1));
+        let mut out = Vec::new();
+        write_ftyp(&mut out);
+        writer.write_moov(&mut out, None);
+        writer.writer.write_all(&out)?;
+        Ok(writer)
+    }
+
+    ///Samples per channel at 48 kHz of the packets written so far, including the pre-skip
+    pub fn duration(&self) -> u64 {
+        self.duration
+    }
+
+    ///Adds a packet as the next sample, the packets of a fragmented file are written once they fill a fragment
+    pub fn write_packet(&mut self, data: &[u8]) -> Result<()> {
+        //All streams of a multistream packet have the same duration, the first one is self-delimited
+        let duration = if self.multistream { Packet::read_self_delimited(data)?.0 } else { Packet::read(data)? }.duration();
+        self.samples.push((data.len() as u32, duration as u32));
+        self.data.extend_from_slice(data);
+        self.duration += duration as u64;
+        if let Some(fragment_duration) = self.fragment_duration {
+            if self.duration - self.decode_time >= fragment_duration {
+                self.write_fragment()?;
+            }
+        }
+        Ok(())
+    }
+
+    ///Writes what's left of the file and returns the underlying writer. `samples` is the length of the encoder's input
+    ///per channel at 48 kHz, the edit list of a regular file trims the end to it. A fragmented file's movie box
+    ///is already written, so its end isn't trimmed
+    pub fn finish(mut self, samples: Option<u64>) -> Result<W> {
+        if self.fragment_duration.is_some() {
+            if !self.samples.is_empty() {
+                self.write_fragment()?;
+            }
+        } else {
+            let pre_skip = u64::from(self.head.pre_skip);
+            let kept = self.duration.saturating_sub(pre_skip);
+            let kept = samples.map_or(kept, |samples| samples.min(kept));
+            let mut out = Vec::new();
+            write_ftyp(&mut out);
+            //The movie box is written once to find where the data starts. An offset past 4 GiB takes a co64 box,
+            //which is larger than an stco box, so the box is measured again with one
+            let mut moov = Vec::new();
+            self.write_moov(&mut moov, Some((kept, 0)));
+            let mut offset = (out.len() + moov.len()) as u64 + mdat_header_size(self.data.len());
+            if offset > u64::from(u32::MAX) {
+                moov.clear();
+                self.write_moov(&mut moov, Some((kept, offset)));
+                offset = (out.len() + moov.len()) as u64 + mdat_header_size(self.data.len());
+            }
+            self.write_moov(&mut out, Some((kept, offset)));
+            write_mdat_header(&mut out, self.data.len());
+            self.writer.write_all(&out)?;
+            self.writer.write_all(&self.data)?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    ///Writes the packets not written yet as a movie fragment
+    fn write_fragment(&mut self) -> Result<()> {
+        let samples = mem::take(&mut self.samples);
+        let data = mem::take(&mut self.data);
+        let mut moof = Vec::new();
+        //The data offset is from the start of the movie fragment box to the data after the media data box's header,
+        //and doesn't change the box's size
+        for pass in 0..2 {
+            let data_offset = if pass == 0 { 0 } else { moof.len() as u32 + mdat_header_size(data.len()) as u32 };
+            moof.clear();
+            write_box(&mut moof, b"moof", |out| {
+                write_full_box(out, b"mfhd", 0, 0, |out| out.extend_from_slice(&self.sequence.to_be_bytes()));
+                write_box(out, b"traf", |out| {
+                    write_full_box(out, b"tfhd", 0, DEFAULT_BASE_IS_MOOF, |out| out.extend_from_slice(&TRACK_ID.to_be_bytes()));
+                    write_full_box(out, b"tfdt", 1, 0, |out| out.extend_from_slice(&self.decode_time.to_be_bytes()));
+                    write_full_box(out, b"trun", 0, DATA_OFFSET | SAMPLE_DURATION | SAMPLE_SIZE, |out| {
+                        out.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+                        out.extend_from_slice(&data_offset.to_be_bytes());
+                        for &(size, duration) in &samples {
+                            out.extend_from_slice(&duration.to_be_bytes());
+                            out.extend_from_slice(&size.to_be_bytes());
+                        }
+                    });
+                });
+            });
+        }
+        write_mdat_header(&mut moof, data.len());
+        self.writer.write_all(&moof)?;
+        self.writer.write_all(&data)?;
+        self.sequence += 1;
+        self.decode_time += samples.iter().map(|&(_, duration)| u64::from(duration)).sum::<u64>();
+        Ok(())
+    }
+
+    ///Writes the movie box. A regular file gets the samples' tables with their data in one chunk at `offset`,
+    ///and an edit list that keeps `kept` samples after the pre-skip. A fragmented file gets empty tables, and
+    ///an edit list that runs to the end
+    fn write_moov(&self, out: &mut Vec<u8>, regular: Option<(u64, u64)>) {
+        let duration = regular.map_or(0, |(kept, _)| kept);
+        let media_duration = if regular.is_some() { self.duration } else { 0 };
+        write_box(out, b"moov", |out| {
+            write_full_box(out, b"mvhd", 1, 0, |out| {
+                out.extend_from_slice(&[0; 16]);
+                out.extend_from_slice(&(SAMPLE_RATE as u32).to_be_bytes());
+                out.extend_from_slice(&duration.to_be_bytes());
+                //Rate 1.0, volume 1.0, reserved
+                out.extend_from_slice(&0x1_0000u32.to_be_bytes());
+                out.extend_from_slice(&0x100u16.to_be_bytes());
+                out.extend_from_slice(&[0; 10]);
+                write_matrix(out);
+                out.extend_from_slice(&[0; 24]);
+                out.extend_from_slice(&(TRACK_ID + 1).to_be_bytes());
+            });
+            write_box(out, b"trak", |out| {
+                write_full_box(out, b"tkhd", 1, TRACK_ENABLED, |out| {
+                    out.extend_from_slice(&[0; 16]);
+                    out.extend_from_slice(&TRACK_ID.to_be_bytes());
+                    out.extend_from_slice(&[0; 4]);
+                    out.extend_from_slice(&duration.to_be_bytes());
+                    //Reserved, layer, alternate group, volume 1.0, reserved
+                    out.extend_from_slice(&[0; 12]);
+                    out.extend_from_slice(&0x100u16.to_be_bytes());
+                    out.extend_from_slice(&[0; 2]);
+                    write_matrix(out);
+                    //Width and height
+                    out.extend_from_slice(&[0; 8]);
+                });
+                write_box(out, b"edts", |out| {
+                    write_full_box(out, b"elst", 1, 0, |out| {
+                        out.extend_from_slice(&1u32.to_be_bytes());
+                        out.extend_from_slice(&duration.to_be_bytes());
+                        out.extend_from_slice(&u64::from(self.head.pre_skip).to_be_bytes());
+                        out.extend_from_slice(&0x1_0000u32.to_be_bytes());
+                    });
+                });
+                write_box(out, b"mdia", |out| {
+                    write_full_box(out, b"mdhd", 1, 0, |out| {
+                        out.extend_from_slice(&[0; 16]);
+                        out.extend_from_slice(&(SAMPLE_RATE as u32).to_be_bytes());
+                        out.extend_from_slice(&media_duration.to_be_bytes());
+                        //Undetermined language, pre-defined
+                        out.extend_from_slice(&0x55c4u16.to_be_bytes());
+                        out.extend_from_slice(&[0; 2]);
+                    });
+                    write_full_box(out, b"hdlr", 0, 0, |out| {
+                        out.extend_from_slice(&[0; 4]);
+                        out.extend_from_slice(b"soun");
+                        out.extend_from_slice(&[0; 12]);
+                        out.extend_from_slice(b"SoundHandler\0");
+                    });
+                    write_box(out, b"minf", |out| {
+                        write_full_box(out, b"smhd", 0, 0, |out| out.extend_from_slice(&[0; 4]));
+                        write_box(out, b"dinf", |out| {
+                            write_full_box(out, b"dref", 0, 0, |out| {
+                                out.extend_from_slice(&1u32.to_be_bytes());
+                                //The media data is in the same file
+                                write_full_box(out, b"url ", 0, 1, |_| {});
+                            });
+                        });
+                        write_box(out, b"stbl", |out| self.write_sample_table(out, regular.map(|(_, offset)| offset)));
+                    });
+                });
+            });
+            if regular.is_none() {
+                write_box(out, b"mvex", |out| {
+                    write_full_box(out, b"trex", 0, 0, |out| {
+                        out.extend_from_slice(&TRACK_ID.to_be_bytes());
+                        out.extend_from_slice(&1u32.to_be_bytes());
+                        out.extend_from_slice(&[0; 12]);
+                    });
+                });
+            }
+        });
+    }
+
+    ///Writes the sample table box, with all samples in a chunk at `offset`, or empty tables without one
+    fn write_sample_table(&self, out: &mut Vec<u8>, offset: Option<u64>) {
+        write_full_box(out, b"stsd", 0, 0, |out| {
+            out.extend_from_slice(&1u32.to_be_bytes());
+            write_sample_entry(&self.head, out);
+        });
+        let samples = if offset.is_some() { &self.samples[..] } else { &[] };
+        //Runs of samples with the same duration
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        for &(_, duration) in samples {
+            match runs.last_mut() {
+                Some(&mut (ref mut count, last)) if last == duration => *count += 1,
+                _ => runs.push((1, duration)),
+            }
+        }
+        write_full_box(out, b"stts", 0, 0, |out| {
+            out.extend_from_slice(&(runs.len() as u32).to_be_bytes());
+            for &(count, duration) in &runs {
+                out.extend_from_slice(&count.to_be_bytes());
+                out.extend_from_slice(&duration.to_be_bytes());
+            }
+        });
+        write_full_box(out, b"stsc", 0, 0, |out| match offset {
+            Some(_) if !samples.is_empty() => {
+                out.extend_from_slice(&1u32.to_be_bytes());
+                out.extend_from_slice(&1u32.to_be_bytes());
+                out.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+                out.extend_from_slice(&1u32.to_be_bytes());
+            },
+            _ => out.extend_from_slice(&0u32.to_be_bytes()),
+        });
+        write_full_box(out, b"stsz", 0, 0, |out| {
+            out.extend_from_slice(&0u32.to_be_bytes());
+            out.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+            for &(size, _) in samples {
+                out.extend_from_slice(&size.to_be_bytes());
+            }
+        });
+        //Chunk offsets past 4 GiB need 64 bits
+        match offset {
+            Some(offset) if !samples.is_empty() && offset > u64::from(u32::MAX) => write_full_box(out, b"co64", 0, 0, |out| {
+                out.extend_from_slice(&1u32.to_be_bytes());
+                out.extend_from_slice(&offset.to_be_bytes());
+            }),
+            Some(offset) if !samples.is_empty() => write_full_box(out, b"stco", 0, 0, |out| {
+                out.extend_from_slice(&1u32.to_be_bytes());
+                out.extend_from_slice(&(offset as u32).to_be_bytes());
+            }),
+            _ => write_full_box(out, b"stco", 0, 0, |out| out.extend_from_slice(&0u32.to_be_bytes())),
+        }
+    }
+}
+
+///Writes the file type box, with the brands that the Opus encapsulation and fragments need
+fn write_ftyp(out: &mut Vec<u8>) {
+    write_box(out, b"ftyp", |out| {
+        out.extend_from_slice(b"isom");
+        out.extend_from_slice(&0x200u32.to_be_bytes());
+        for brand in &[b"isom", b"iso6", b"mp41", b"Opus"] {
+            out.extend_from_slice(*brand);
+        }
+    });
+}
+
+fn write_matrix(out: &mut Vec<u8>) {
+    for value in &UNITY_MATRIX {
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+///Bytes of the header of a media data box for `size` bytes of data, which need a 64 bit size past 4 GiB
+fn mdat_header_size(size: usize) -> u64 {
+    if size as u64 + 8 > u64::from(u32::MAX) { 16 } else { 8 }
+}
+
+fn write_mdat_header(out: &mut Vec<u8>, size: usize) {
+    if mdat_header_size(size) == 16 {
+        out.extend_from_slice(&1u32.to_be_bytes());
+        out.extend_from_slice(b"mdat");
+        out.extend_from_slice(&(size as u64 + 16).to_be_bytes());
+    } else {
+        out.extend_from_slice(&(size as u32 + 8).to_be_bytes());
+        out.extend_from_slice(b"mdat");
+    }
+}
@@ -0,0 +1,114 @@
+use std::io::{Read, Seek};
+use common::types::SampleRate;
+use decoder::multistream;
+use decoder::DecoderErrorKind;
+use super::{Reader, Result, GRANULE_RATE};
+
+///Samples per channel of the longest packet at 48 kHz, 120 ms
+const MAX_PACKET_SIZE: usize = 5760;
+///Samples at 48 kHz decoded before a seek target so the decoder converges, see RFC 7845 section 4.6
+const PREROLL: u64 = 3840;
+///Samples at 48 kHz of the shortest frame, 2.5 ms
+const CONCEAL_STEP: usize = 120;
+
+///Decodes an Ogg Opus stream, dropping the pre-skip and the trimmed end so that the output
+///lines up with the encoder's input sample for sample. The links of a chained file are decoded one after another
+pub struct Decoder<R> {
+    reader: Reader<R>,
+    decoder: multistream::Decoder,
+    rate: SampleRate,
+    fs_hz: usize,
+    ///Link the decoder was set up for
+    link: usize,
+    ///Output of the last decoded packet
+    buf: Vec<f32>,
+    ///Granule position of the next sample to return, everything before is decoded and dropped after a seek
+    discard: u64,
+}
+
+impl<R: Read> Decoder<R> {
+    ///Reads the headers of the first Opus stream and creates a decoder with output at `rate`
+    pub fn new(reader: R, rate: SampleRate) -> Result<Self> {
+        let reader = Reader::new(reader)?;
+        let decoder = reader.head().decoder(rate)?;
+        let fs_hz = match rate {
+            SampleRate::Khz8 => 8000,
+            SampleRate::Khz12 => 12000,
+            SampleRate::Khz16 => 16000,
+            SampleRate::Khz24 => 24000,
+            SampleRate::Khz48 => 48000,
+        };
+        let buf = vec![0.0; MAX_PACKET_SIZE * fs_hz / GRANULE_RATE * decoder.channels()];
+        Ok(Self { reader, decoder, rate, fs_hz, link: 0, buf, discard: 0 })
+    }
+
+    pub fn reader(&self) -> &Reader<R> {
+        &self.reader
+    }
+
+    ///Channels of the link the last samples came from, which may change from one link to the next
+    pub fn channels(&self) -> usize {
+        self.decoder.channels()
+    }
+
+    ///Decodes the next packet that has samples left after trimming into `pcm` interleaved, with samples
+    ///between -1 and 1, with the channels of the packet's link. Audio lost to corrupt pages is concealed. Returns the number of samples per channel, 0 at the end of the file
+    pub fn read_float(&mut self, pcm: &mut [f32]) -> Result<usize> {
+        while let Some(packet) = self.reader.read_packet()? {
+            if self.reader.link() != self.link {
+                self.start_link()?;
+            }
+            let channels = self.channels();
+            let n = if packet.data.is_empty() {
+                //Lost audio is concealed in steps of 2.5 ms
+                let duration = (packet.skip + packet.samples).div_ceil(CONCEAL_STEP) * CONCEAL_STEP;
+                self.decoder.decode_lost_float(duration * self.fs_hz / GRANULE_RATE, &mut self.buf)?
+            } else {
+                self.decoder.decode_float(&packet.data, &mut self.buf)?
+            };
+            let seeking = self.discard.saturating_sub(packet.granule_position - packet.samples as u64);
+            let skip = packet.skip + (seeking as usize).min(packet.samples);
+            let start = skip * self.fs_hz / GRANULE_RATE;
+            let end = ((packet.skip + packet.samples) * self.fs_hz / GRANULE_RATE).min(n);
+            if start >= end {
+                continue;
+            }
+            let out = pcm.get_mut(..(end - start) * channels).ok_or(DecoderErrorKind::BufferTooSmall)?;
+            out.copy_from_slice(&self.buf[start * channels..end * channels]);
+            return Ok(end - start);
+        }
+        Ok(0)
+    }
+
+    ///Decodes the next packet that has samples left after trimming into `pcm` interleaved.
+    ///Returns the number of samples per channel, 0 at the end of the stream
+    pub fn read(&mut self, pcm: &mut [i16]) -> Result<usize> {
+        let mut buf = vec![0.0; pcm.len()];
+        let n = self.read_float(&mut buf)?;
+        for (out, &x) in pcm.iter_mut().zip(buf[..n * self.channels()].iter()) {
+            *out = (x * 32768.0).clamp(-32768.0, 32767.0).round() as i16;
+        }
+        Ok(n)
+    }
+
+    ///Sets up the decoder for the headers of a new link
+    fn start_link(&mut self) -> Result<()> {
+        self.decoder = self.reader.head().decoder(self.rate)?;
+        self.buf.resize(MAX_PACKET_SIZE * self.fs_hz / GRANULE_RATE * self.decoder.channels(), 0.0);
+        self.link = self.reader.link();
+        self.discard = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Decoder<R> {
+    ///Moves to sample `sample` per channel at the output rate within the current link, counted from its first
+    ///sample after the pre-skip. Decoding starts at least 80 ms earlier, and the samples before `sample` are dropped
+    pub fn seek(&mut self, sample: u64) -> Result<()> {
+        let target = self.reader.start()? + u64::from(self.reader.head().pre_skip) + sample * GRANULE_RATE as u64 / self.fs_hz as u64;
+        self.reader.seek(target.saturating_sub(PREROLL))?;
+        self.decoder.reset();
+        self.discard = target;
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+use common::types::SampleRate;
+use decoder::multistream::{self, ChannelMapping};
+use super::{le_u16, le_u32, OggErrorKind, Result};
+
+///Bytes of an OpusHead header before the channel mapping table
+const HEAD_SIZE: usize = 19;
+
+///Identification header of an Ogg Opus stream, see RFC 7845 section 5.1
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpusHead {
+    ///Minor versions in the low 4 bits are compatible with each other
+    pub version: u8,
+    ///Samples at 48 kHz to drop from the start of the decoded stream
+    pub pre_skip: u16,
+    ///Rate of the encoder's input, for information only
+    pub input_sample_rate: u32,
+    ///Gain to apply to the output in 1/256 dB
+    pub output_gain: i16,
+    pub mapping: ChannelMapping,
+}
+
+impl OpusHead {
+    pub fn read(data: &[u8]) -> Result<Self> {
+        if data.len() < HEAD_SIZE || &data[..8] != b"OpusHead" || data[8]>>4 != 0 {
+            return Err(OggErrorKind::InvalidHeader);
+        }
+        let mapping = ChannelMapping::read(data[18], usize::from(data[9]), &data[HEAD_SIZE..])
+            .map_err(|_| OggErrorKind::InvalidHeader)?;
+        Ok(Self {
+            version: data[8],
+            pre_skip: le_u16(&data[10..]),
+            input_sample_rate: le_u32(&data[12..]),
+            output_gain: le_u16(&data[16..]) as i16,
+            mapping,
+        })
+    }
+
+    pub fn channels(&self) -> usize {
+        self.mapping.channels()
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let mapping = &self.mapping;
+        out.extend_from_slice(b"OpusHead");
+        out.push(self.version);
+        out.push(mapping.channels() as u8);
+        out.extend_from_slice(&self.pre_skip.to_le_bytes());
+        out.extend_from_slice(&self.input_sample_rate.to_le_bytes());
+        out.extend_from_slice(&self.output_gain.to_le_bytes());
+        out.push(mapping.family);
+        if mapping.family != 0 {
+            out.push(mapping.streams as u8);
+            out.push(mapping.coupled_streams as u8);
+            if mapping.family == 3 {
+                for gain in &mapping.demixing_matrix {
+                    out.extend_from_slice(&gain.to_le_bytes());
+                }
+            } else {
+                out.extend_from_slice(&mapping.mapping);
+            }
+        }
+    }
+
+    ///Creates a decoder for the streams with output at `rate`, applying the output gain
+    pub fn decoder(&self, rate: SampleRate) -> Result<multistream::Decoder> {
+        let mut decoder = multistream::Decoder::new(rate, self.mapping.clone())?;
+        for s in 0..self.mapping.streams {
+            if let Some(stream) = decoder.stream(s) {
+                stream.set_gain(self.output_gain);
+            }
+        }
+        Ok(decoder)
+    }
+}
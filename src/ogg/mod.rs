@@ -0,0 +1,132 @@
+mod decoder;
+mod header;
+mod page;
+mod reader;
+mod tags;
+mod writer;
+
+pub use self::decoder::Decoder;
+pub use self::header::OpusHead;
+pub use self::page::Page;
+pub use self::reader::{Link, OggPacket, Reader};
+pub use self::tags::{OpusTags, Picture};
+pub use self::writer::{FlushPolicy, Writer};
+
+use std::io;
+use std::result;
+use decoder::DecoderErrorKind;
+use packet::PacketErrorKind;
+
+///Rate of the granule positions and the pre-skip, whatever rate the stream is decoded at
+const GRANULE_RATE: usize = 48000;
+
+#[derive(Debug)]
+pub enum OggErrorKind {
+    Io(io::Error),
+    ///The data doesn't start with an Ogg page, or the page is cut off
+    InvalidPage,
+    ///An OpusHead or OpusTags header is malformed or missing
+    InvalidHeader,
+    ///A comment of the OpusTags header holds a malformed picture
+    InvalidTag,
+    ///No logical stream starts with an OpusHead header
+    NoOpusStream,
+    ///The granule position of a page is missing or leaves its packets a negative start
+    InvalidGranulePosition,
+    InvalidPacket(PacketErrorKind),
+    Decoder(DecoderErrorKind),
+}
+
+impl From<io::Error> for OggErrorKind {
+    fn from(err: io::Error) -> Self {
+        OggErrorKind::Io(err)
+    }
+}
+
+impl From<PacketErrorKind> for OggErrorKind {
+    fn from(err: PacketErrorKind) -> Self {
+        OggErrorKind::InvalidPacket(err)
+    }
+}
+
+impl From<DecoderErrorKind> for OggErrorKind {
+    fn from(err: DecoderErrorKind) -> Self {
+        OggErrorKind::Decoder(err)
+    }
+}
+
+pub type Result<T> = result::Result<T, OggErrorKind>;
+
+fn le_u16(data: &[u8]) -> u16 {
+    u16::from_le_bytes([data[0], data[1]])
+}
+
+fn le_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn le_u64(data: &[u8]) -> u64 {
+    u64::from(le_u32(data)) | u64::from(le_u32(&data[4..]))<<32
+}
+
+///Splits packets into pages as their lacing values need, starting a fresh page with sequence number `sequence`.
+///Packets come with the granule position at their end, which goes to the page they end on
+pub fn paginate(serial: u32, sequence: u32, packets: &[(Vec<u8>, u64)]) -> Vec<Page> {
+    let mut pages = vec![Page::new(serial, sequence)];
+    for &(ref data, granule_position) in packets {
+        let mut rest = &data[..];
+        let mut continued = false;
+        loop {
+            if pages[pages.len() - 1].segments.len() == 255 {
+                let mut page = Page::new(serial, sequence + pages.len() as u32);
+                page.continued = continued;
+                pages.push(page);
+            }
+            let page = pages.last_mut().unwrap();
+            let len = rest.len().min(255);
+            page.segments.push(len as u8);
+            page.data.extend_from_slice(&rest[..len]);
+            rest = &rest[len..];
+            continued = true;
+            //A packet ends with a lacing value below 255, a multiple of 255 bytes with a 0
+            if len < 255 {
+                page.granule_position = granule_position as i64;
+                break;
+            }
+        }
+    }
+    pages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paginate_multiple_of_255() {
+        let pages = paginate(1, 5, &[(vec![1; 510], 960), (vec![2; 10], 1920)]);
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].segments, [255, 255, 0, 10]);
+        assert_eq!((pages[0].sequence, pages[0].granule_position), (5, 1920));
+        assert_eq!(pages[0].packets(), [(&[1; 510][..], true), (&[2; 10][..], true)]);
+    }
+
+    #[test]
+    fn paginate_segment_limit() {
+        //A packet of 255 full segments needs a 0 on the next page
+        let pages = paginate(1, 0, &[(vec![1; 255 * 255], 960)]);
+        assert_eq!(pages.len(), 2);
+        assert_eq!((pages[0].segments.len(), pages[0].granule_position), (255, -1));
+        assert_eq!(pages[0].packets(), [(&[1; 255 * 255][..], false)]);
+        assert!(pages[1].continued);
+        assert_eq!((pages[1].segments.clone(), pages[1].sequence, pages[1].granule_position), (vec![0], 1, 960));
+
+        let pages = paginate(1, 0, &[(vec![1; 300], 960), (vec![2; 255 * 255], 1920)]);
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].segments[..2], [255, 45]);
+        assert_eq!((pages[0].segments.len(), pages[0].granule_position), (255, 960));
+        assert_eq!(pages[1].segments, [255, 255, 0]);
+        assert!(pages[1].continued);
+        assert_eq!(pages[1].granule_position, 1920);
+    }
+}
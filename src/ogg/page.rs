@@ -0,0 +1,352 @@
+use std::io::{self, Read, Write};
+use super::{le_u32, le_u64, OggErrorKind, Result};
+
+///Bits of the header type
+const CONTINUED: u8 = 1;
+const FIRST: u8 = 2;
+const LAST: u8 = 4;
+
+///Bytes of the page header before the segment table
+const HEADER_SIZE: usize = 27;
+///Bytes a `PageReader` reads from the underlying reader at once
+const CHUNK_SIZE: usize = 4096;
+
+///CRC-32 with the polynomial 0x04c11db7, no reflection and no final xor, byte by byte
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut r = (i as u32)<<24;
+        let mut j = 0;
+        while j < 8 {
+            r = if r & 0x8000_0000 != 0 { r<<1 ^ 0x04c1_1db7 } else { r<<1 };
+            j += 1;
+        }
+        table[i] = r;
+        i += 1;
+    }
+    table
+}
+
+///A page of an Ogg stream, see RFC 3533 section 6
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Page {
+    ///The first packet continues the last one of the previous page
+    pub continued: bool,
+    ///First page of its logical stream
+    pub first: bool,
+    ///Last page of its logical stream
+    pub last: bool,
+    ///Position at the end of the last packet that ends on the page, -1 if none does
+    pub granule_position: i64,
+    pub serial: u32,
+    pub sequence: u32,
+    pub checksum: u32,
+    ///Lacing values, every packet takes a run of 255s ending with a smaller value
+    pub segments: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+impl Page {
+    ///Reads the next page, or returns `None` at the end of the data
+    pub fn read<R: Read>(reader: &mut R) -> Result<Option<Self>> {
+        let mut header = [0; HEADER_SIZE];
+        let mut filled = 0;
+        while filled < HEADER_SIZE {
+            match reader.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(OggErrorKind::InvalidPage),
+                Ok(n) => filled += n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if &header[..4] != b"OggS" || header[4] != 0 {
+            return Err(OggErrorKind::InvalidPage);
+        }
+
+        let mut segments = vec![0; usize::from(header[26])];
+        read_exact(reader, &mut segments)?;
+        let mut data = vec![0; segments.iter().map(|&s| usize::from(s)).sum()];
+        read_exact(reader, &mut data)?;
+        Ok(Some(Self::from_parts(&header, segments, data)))
+    }
+
+    ///Parses the page at the start of `data`, or returns `None` if the data ends before the page does.
+    ///The capture pattern and version have to be checked already
+    fn parse(data: &[u8]) -> Option<Self> {
+        let header = data.get(..HEADER_SIZE)?;
+        let segments = data.get(HEADER_SIZE..HEADER_SIZE + usize::from(header[26]))?;
+        let start = HEADER_SIZE + segments.len();
+        let len = segments.iter().map(|&s| usize::from(s)).sum::<usize>();
+        let body = data.get(start..start + len)?;
+        Some(Self::from_parts(header, segments.to_vec(), body.to_vec()))
+    }
+
+    fn from_parts(header: &[u8], segments: Vec<u8>, data: Vec<u8>) -> Self {
+        Self {
+            continued: header[5] & CONTINUED != 0,
+            first: header[5] & FIRST != 0,
+            last: header[5] & LAST != 0,
+            granule_position: le_u64(&header[6..]) as i64,
+            serial: le_u32(&header[14..]),
+            sequence: le_u32(&header[18..]),
+            checksum: le_u32(&header[22..]),
+            segments,
+            data,
+        }
+    }
+
+    ///Creates an empty page, with no packet ending on it yet
+    pub fn new(serial: u32, sequence: u32) -> Self {
+        Self {
+            continued: false,
+            first: false,
+            last: false,
+            granule_position: -1,
+            serial,
+            sequence,
+            checksum: 0,
+            segments: Vec::new(),
+            data: Vec::new(),
+        }
+    }
+
+    ///Bytes the page takes in the stream
+    pub fn size(&self) -> usize {
+        HEADER_SIZE + self.segments.len() + self.data.len()
+    }
+
+    ///Writes the page with the checksum of its contents, whatever `checksum` holds
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut header = self.header(self.crc());
+        header.extend_from_slice(&self.data);
+        writer.write_all(&header)?;
+        Ok(())
+    }
+
+    ///Whether the checksum matches the contents of the page
+    pub fn is_valid(&self) -> bool {
+        self.crc() == self.checksum
+    }
+
+    ///Checksum of the page, computed over all of it with the checksum field set to 0
+    pub fn crc(&self) -> u32 {
+        let update = |crc: u32, &byte: &u8| crc<<8 ^ CRC_TABLE[(crc>>24) as usize ^ usize::from(byte)];
+        let crc = self.header(0).iter().fold(0, update);
+        self.data.iter().fold(crc, update)
+    }
+
+    ///Header and segment table with `checksum` filled in
+    fn header(&self, checksum: u32) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_SIZE + self.segments.len());
+        header.extend_from_slice(b"OggS");
+        header.push(0);
+        header.push(
+            if self.continued { CONTINUED } else { 0 }
+            | if self.first { FIRST } else { 0 }
+            | if self.last { LAST } else { 0 });
+        header.extend_from_slice(&self.granule_position.to_le_bytes());
+        header.extend_from_slice(&self.serial.to_le_bytes());
+        header.extend_from_slice(&self.sequence.to_le_bytes());
+        header.extend_from_slice(&checksum.to_le_bytes());
+        header.push(self.segments.len() as u8);
+        header.extend_from_slice(&self.segments);
+        header
+    }
+
+    ///Splits the data into packets, each with whether it ends on this page.
+    ///Only the last one can be continued on the next page
+    pub fn packets(&self) -> Vec<(&[u8], bool)> {
+        let mut packets = Vec::new();
+        let mut start = 0;
+        let mut end = 0;
+        for &segment in &self.segments {
+            end += usize::from(segment);
+            if segment < 255 {
+                packets.push((&self.data[start..end], true));
+                start = end;
+            }
+        }
+        if self.segments.last() == Some(&255) {
+            packets.push((&self.data[start..end], false));
+        }
+        packets
+    }
+}
+
+///Reads the pages of data that may be corrupt. Anything that isn't a page with a valid checksum is skipped,
+///and reading goes on from the next capture pattern
+pub struct PageReader<R> {
+    reader: R,
+    ///Data read from the underlying reader, from `pos` on it wasn't returned or skipped yet
+    buf: Vec<u8>,
+    ///Start of the data that wasn't returned or skipped yet. What's before is only dropped when
+    ///more data is read, so that skipping corrupt data a byte at a time doesn't move the rest every time
+    pos: usize,
+    ///Bytes returned or skipped since the reader was created
+    offset: u64,
+}
+
+impl<R: Read> PageReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, buf: Vec::new(), pos: 0, offset: 0 }
+    }
+
+    ///Reads the next valid page, or returns `None` at the end of the data
+    pub fn read(&mut self) -> Result<Option<Page>> {
+        loop {
+            let start = match self.buf[self.pos..].windows(4).position(|capture| capture == b"OggS") {
+                Some(start) => start,
+                //The capture pattern may start in the last 3 bytes
+                None => {
+                    let len = self.buffered().saturating_sub(3);
+                    self.consume(len);
+                    if !self.fill()? {
+                        let len = self.buffered();
+                        self.consume(len);
+                        return Ok(None);
+                    }
+                    continue;
+                },
+            };
+            self.consume(start);
+            if self.buffered() <= 4 {
+                if !self.fill()? {
+                    self.consume(1);
+                }
+                continue;
+            }
+            if self.buf[self.pos + 4] != 0 {
+                self.consume(1);
+                continue;
+            }
+            match Page::parse(&self.buf[self.pos..]) {
+                Some(page) => {
+                    if page.is_valid() {
+                        self.consume(page.size());
+                        return Ok(Some(page));
+                    }
+                    self.consume(1);
+                },
+                //A page cut off by the end of the data is skipped like a corrupt one
+                None => if !self.fill()? {
+                    self.consume(1);
+                },
+            }
+        }
+    }
+
+    ///Bytes returned or skipped since the reader was created, or since the offset was last set
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    ///Bytes read from the underlying reader that weren't returned or skipped yet
+    pub fn buffered(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    ///Drops the buffered data after the underlying reader moved to `offset`
+    pub fn set_offset(&mut self, offset: u64) {
+        self.buf.clear();
+        self.pos = 0;
+        self.offset = offset;
+    }
+
+    ///Reads another chunk of data, returns whether there was any left
+    fn fill(&mut self) -> Result<bool> {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let len = self.buf.len();
+        self.buf.resize(len + CHUNK_SIZE, 0);
+        loop {
+            match self.reader.read(&mut self.buf[len..]) {
+                Ok(n) => {
+                    self.buf.truncate(len + n);
+                    return Ok(n > 0);
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+                Err(err) => {
+                    self.buf.truncate(len);
+                    return Err(err.into());
+                },
+            }
+        }
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.pos += len;
+        self.offset += len as u64;
+    }
+}
+
+///Fills `buf`, where the data ending early means a cut off page
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => OggErrorKind::InvalidPage,
+        _ => err.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    ///First page of a stereo Ogg Opus stream, with the checksum libogg computes
+    const PAGE: [u8; 47] = [
+        0x4f, 0x67, 0x67, 0x53, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x78, 0x56,
+        0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x23, 0xec, 0xb0, 0x3e, 0x01, 0x13, 0x4f, 0x70, 0x75, 0x73,
+        0x48, 0x65, 0x61, 0x64, 0x01, 0x02, 0x38, 0x01, 0x80, 0xbb, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn crc() {
+        let page = Page::read(&mut Cursor::new(&PAGE[..])).unwrap().unwrap();
+        assert_eq!(page.checksum, 0x3eb0_ec23);
+        assert_eq!(page.crc(), page.checksum);
+        assert!(page.first && page.is_valid());
+        let mut data = Vec::new();
+        page.write(&mut data).unwrap();
+        assert_eq!(data, &PAGE[..]);
+    }
+
+    #[test]
+    fn resync() {
+        let mut corrupt = PAGE;
+        corrupt[30] ^= 1;
+        let mut data = b"OggSOgg".to_vec();
+        data.extend_from_slice(&corrupt);
+        data.extend_from_slice(&PAGE[..10]);
+        data.extend_from_slice(&PAGE);
+        data.extend_from_slice(&PAGE[..30]);
+        let mut reader = PageReader::new(Cursor::new(data));
+        let page = reader.read().unwrap().unwrap();
+        assert_eq!(page.crc(), 0x3eb0_ec23);
+        assert_eq!(reader.offset(), (7 + 47 + 10 + 47) as u64);
+        assert!(reader.read().unwrap().is_none());
+    }
+
+    #[test]
+    fn resync_many() {
+        //Capture patterns of an unknown version, each skipped by a byte without moving the rest of the data
+        let mut data = b"OggS\x01".repeat(100_000);
+        data.extend_from_slice(&PAGE);
+        let mut reader = PageReader::new(Cursor::new(data));
+        assert_eq!(reader.read().unwrap().unwrap().crc(), 0x3eb0_ec23);
+        assert_eq!(reader.offset(), 500_000 + 47);
+        assert_eq!(reader.buffered(), 0);
+        assert!(reader.read().unwrap().is_none());
+    }
+}
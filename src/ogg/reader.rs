@@ -0,0 +1,643 @@
+use std::collections::VecDeque;
+use std::io::{Read, Seek, SeekFrom};
+use std::mem;
+use std::ops::Range;
+use packet::Packet;
+use super::{OggErrorKind, OpusHead, OpusTags, Page, Result};
+use super::page::PageReader;
+
+///Bytes left to search when the bisection of a seek turns into reading page by page
+const BISECT_WINDOW: u64 = 1<<16;
+///Samples at 48 kHz that a packet reporting lost audio covers at most, as much as the longest packet
+const MAX_LOST_DURATION: u64 = 5760;
+
+///An audio packet of an Ogg Opus stream, with the part of its decoded samples that belongs to the stream.
+///Samples are counted per channel at 48 kHz
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OggPacket {
+    ///Empty for audio lost to corrupt or missing pages, which the decoder conceals
+    pub data: Vec<u8>,
+    ///Granule position at the end of the packet
+    pub granule_position: u64,
+    ///Decoded samples to drop from the start, for the pre-skip
+    pub skip: usize,
+    ///Decoded samples to keep after the dropped ones, fewer than the packet holds where the end of the stream is trimmed
+    pub samples: usize,
+    ///Last packet of the stream
+    pub last: bool,
+}
+
+impl OggPacket {
+    ///Parses the data as the packet of a single stream, multistream packets are split up by `multistream::Decoder`
+    pub fn packet<'a>(&'a self) -> Result<Packet<'a>> {
+        Ok(Packet::read(&self.data)?)
+    }
+}
+
+///A link of a chained Ogg file, a group of logical streams that another group follows once they all end
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Link {
+    ///Serial number of the Opus stream of the link
+    pub serial: u32,
+    pub head: OpusHead,
+    pub tags: OpusTags,
+    ///Byte range of the link's pages and those of any links without an Opus stream after it, relative to where reading started
+    pub offsets: Range<u64>,
+    ///Samples per channel at 48 kHz after the pre-skip and end trimming
+    pub samples: u64,
+}
+
+///Reads the Opus streams of an Ogg file, see RFC 7845. Every link of a chained file is read in turn,
+///using the first Opus stream of it, and pages of other logical streams are skipped.
+///Corrupt data is skipped up to the next page with a valid checksum, and the audio of missing pages is reported as lost
+pub struct Reader<R> {
+    pages: PageReader<R>,
+    head: OpusHead,
+    tags: OpusTags,
+    serial: u32,
+    ///Packets of the last page that weren't returned yet
+    packets: VecDeque<OggPacket>,
+    ///Start of a packet that continues on the next page
+    partial: Vec<u8>,
+    ///Granule position at the end of the packets so far, `None` until the first page with audio
+    granule_position: Option<u64>,
+    ///Decoded samples still to drop for the pre-skip
+    pre_skip: usize,
+    ///Whether the last page of the stream was read
+    ended: bool,
+    ///Offset of the first page after the headers
+    data_start: u64,
+    ///Granule position the stream starts at, known once the first page with audio is read
+    start: Option<u64>,
+    ///Index of the current link and the offset of its first page
+    link: usize,
+    link_start: u64,
+    ///First page of the next link, read while looking for the end of the current one
+    pending: Option<Page>,
+    ///Whether the headers of the current link were read, after which a first page starts a new link
+    headers_read: bool,
+    ///Sequence number the next page of the stream should have
+    sequence: Option<u32>,
+    ///Whether pages of the stream went missing since the last packet
+    lost: bool,
+}
+
+impl<R: Read> Reader<R> {
+    ///Finds the first Opus stream and reads its headers
+    pub fn new(reader: R) -> Result<Self> {
+        let mut pages = PageReader::new(reader);
+        let (link_start, serial, head) = find_stream(&mut pages, None)?.ok_or(OggErrorKind::NoOpusStream)?;
+        let mut reader = Self {
+            pages,
+            tags: OpusTags::new(""),
+            serial,
+            packets: VecDeque::new(),
+            partial: Vec::new(),
+            granule_position: None,
+            pre_skip: usize::from(head.pre_skip),
+            ended: false,
+            data_start: 0,
+            start: None,
+            link: 0,
+            link_start,
+            pending: None,
+            headers_read: false,
+            sequence: None,
+            lost: false,
+            head,
+        };
+        reader.read_tags()?;
+        Ok(reader)
+    }
+
+    ///Lists the links of a chained file, reading all of its pages from the start of `reader`
+    pub fn links(reader: R) -> Result<Vec<Link>> {
+        let mut reader = Self::new(reader)?;
+        let mut links = Vec::new();
+        loop {
+            let mut samples = 0;
+            loop {
+                reader.fill()?;
+                match reader.packets.pop_front() {
+                    Some(packet) => samples += packet.samples as u64,
+                    None => break,
+                }
+            }
+            let mut link = Link {
+                serial: reader.serial,
+                head: reader.head.clone(),
+                tags: reader.tags.clone(),
+                offsets: reader.link_start..reader.pages.offset(),
+                samples,
+            };
+            let last = !reader.next_link()?;
+            if !last {
+                link.offsets.end = reader.link_start;
+            }
+            links.push(link);
+            if last {
+                return Ok(links);
+            }
+        }
+    }
+
+    pub fn head(&self) -> &OpusHead {
+        &self.head
+    }
+
+    pub fn tags(&self) -> &OpusTags {
+        &self.tags
+    }
+
+    ///Serial number of the logical stream
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    ///Index of the link in a chained file, the headers are those of the link the last packet came from
+    pub fn link(&self) -> usize {
+        self.link
+    }
+
+    pub fn into_inner(self) -> R {
+        self.pages.into_inner()
+    }
+
+    ///Returns the next audio packet, moving on to the next link at the end of one, or `None` at the end of the file
+    pub fn read_packet(&mut self) -> Result<Option<OggPacket>> {
+        loop {
+            self.fill()?;
+            if let Some(packet) = self.packets.pop_front() {
+                return Ok(Some(packet));
+            }
+            if !self.next_link()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    ///Granule position the stream starts at, 0 for a stream without audio
+    pub fn start(&mut self) -> Result<u64> {
+        self.fill()?;
+        Ok(self.start.unwrap_or(0))
+    }
+
+    ///Finds the next link with an Opus stream and reads its headers, returns whether there is one
+    fn next_link(&mut self) -> Result<bool> {
+        let pending = self.pending.take();
+        let (link_start, serial, head) = match find_stream(&mut self.pages, pending)? {
+            Some(stream) => stream,
+            None => return Ok(false),
+        };
+        self.serial = serial;
+        self.packets.clear();
+        self.partial.clear();
+        self.granule_position = None;
+        self.pre_skip = usize::from(head.pre_skip);
+        self.ended = false;
+        self.start = None;
+        self.link += 1;
+        self.link_start = link_start;
+        self.headers_read = false;
+        self.sequence = None;
+        self.lost = false;
+        self.head = head;
+        self.read_tags()?;
+        Ok(true)
+    }
+
+    ///Reads the comment header, which ends a page of its own so audio starts on the next one
+    fn read_tags(&mut self) -> Result<()> {
+        loop {
+            let (page, packets) = self.read_page()?.ok_or(OggErrorKind::InvalidHeader)?;
+            if let Some(data) = packets.first() {
+                if packets.len() > 1 || page.last {
+                    return Err(OggErrorKind::InvalidHeader);
+                }
+                self.tags = OpusTags::read(data)?;
+                break;
+            }
+        }
+        self.data_start = self.pages.offset();
+        self.headers_read = true;
+        Ok(())
+    }
+
+    ///Reads pages until there's a packet to return or the stream ends
+    fn fill(&mut self) -> Result<()> {
+        while self.packets.is_empty() && !self.ended {
+            match self.read_page()? {
+                Some((page, packets)) => {
+                    self.ended = page.last;
+                    self.queue(&page, packets)?;
+                },
+                //A stream cut off before its last page just ends
+                None => self.ended = true,
+            }
+        }
+        Ok(())
+    }
+
+    ///Reads up to the next page of the stream, and returns it with the packets that end on it
+    fn read_page(&mut self) -> Result<Option<(Page, Vec<Vec<u8>>)>> {
+        let page = loop {
+            match self.pages.read()? {
+                Some(page) => {
+                    //A stream whose last page is missing ends where the next link starts
+                    if page.first && self.headers_read {
+                        self.pending = Some(page);
+                        return Ok(None);
+                    }
+                    if page.serial == self.serial {
+                        break page;
+                    }
+                },
+                None => return Ok(None),
+            }
+        };
+
+        //A packet is dropped if its start or the rest of it is missing
+        if self.sequence.is_some_and(|sequence| sequence != page.sequence) {
+            self.lost = true;
+            self.partial.clear();
+        }
+        self.sequence = Some(page.sequence.wrapping_add(1));
+        if !page.continued {
+            self.partial.clear();
+        }
+        let mut packets = Vec::new();
+        for (i, (data, complete)) in page.packets().into_iter().enumerate() {
+            let mut packet = Vec::new();
+            if i == 0 && page.continued {
+                if self.partial.is_empty() {
+                    continue;
+                }
+                packet = mem::take(&mut self.partial);
+            }
+            packet.extend_from_slice(data);
+            if complete {
+                packets.push(packet);
+            } else {
+                self.partial = packet;
+            }
+        }
+        Ok(Some((page, packets)))
+    }
+
+    ///Works out the positions of the packets that end on a page and the samples of them that belong to the stream.
+    ///The first page's granule position gives the start of the stream, the last one's where it's trimmed.
+    ///Packets that can't be parsed are reported as lost for the part of the page's duration the others leave
+    fn queue(&mut self, page: &Page, packets: Vec<Vec<u8>>) -> Result<()> {
+        if packets.is_empty() {
+            return Ok(());
+        }
+        if page.granule_position < 0 {
+            return Err(OggErrorKind::InvalidGranulePosition);
+        }
+        let granule_position = page.granule_position as u64;
+        //All streams of a multistream packet have the same duration, the first one is self-delimited
+        let multistream = self.head.mapping.streams > 1;
+        let durations: Vec<Option<usize>> = packets.iter()
+            .map(|data| if multistream { Packet::read_self_delimited(data).map(|(packet, _)| packet) } else { Packet::read(data) })
+            .map(|packet| packet.ok().map(|packet| packet.duration()))
+            .collect();
+        let total = durations.iter().flatten().sum::<usize>() as u64;
+
+        let start = granule_position.checked_sub(total);
+        let mut position = match (self.granule_position, start) {
+            //After missing pages the packets start where the granule position says, and the gap is reported as lost
+            (Some(position), Some(start)) if self.lost && start > position => {
+                self.queue_lost(position, start);
+                start
+            },
+            (Some(position), _) => position,
+            (None, Some(start)) => start,
+            //Only the last page may end before its packets, a stream of a single page then starts at 0
+            (None, None) if page.last => 0,
+            (None, None) => return Err(OggErrorKind::InvalidGranulePosition),
+        };
+        self.lost = false;
+        if self.start.is_none() {
+            self.start = Some(position);
+        }
+        //Broken packets take up what the others leave of the page, which is nothing on the first page,
+        //where the others are all there is to go by
+        let mut broken = granule_position.saturating_sub(position + total);
+        for (data, duration) in packets.into_iter().zip(durations) {
+            let duration = match duration {
+                Some(duration) => duration,
+                None => {
+                    self.queue_lost(position, position + broken);
+                    position += broken;
+                    broken = 0;
+                    continue;
+                },
+            };
+            let mut end = position + duration as u64;
+            let mut samples = duration;
+            if page.last && end > granule_position {
+                samples = granule_position.saturating_sub(position) as usize;
+                end = position + samples as u64;
+            }
+            let skip = self.pre_skip.min(samples);
+            self.pre_skip -= skip;
+            self.packets.push_back(OggPacket {
+                data,
+                granule_position: end,
+                skip,
+                samples: samples - skip,
+                last: false,
+            });
+            position = end;
+        }
+        if page.last {
+            if let Some(packet) = self.packets.back_mut() {
+                packet.last = true;
+            }
+        }
+        self.granule_position = Some(position);
+        Ok(())
+    }
+
+    ///Queues packets without data for the audio lost between granule positions `position` and `end`
+    fn queue_lost(&mut self, mut position: u64, end: u64) {
+        while position < end {
+            let samples = (end - position).min(MAX_LOST_DURATION) as usize;
+            let skip = self.pre_skip.min(samples);
+            self.pre_skip -= skip;
+            position += samples as u64;
+            self.packets.push_back(OggPacket {
+                data: Vec::new(),
+                granule_position: position,
+                skip,
+                samples: samples - skip,
+                last: false,
+            });
+        }
+    }
+}
+
+///Reads up to the identification header of the next Opus stream, starting with `pending` if given.
+///Returns the offset of the first page of the stream's link with the stream's serial number and header
+fn find_stream<R: Read>(pages: &mut PageReader<R>, mut pending: Option<Page>) -> Result<Option<(u64, u32, OpusHead)>> {
+    let mut link_start = None;
+    loop {
+        let page = match pending.take() {
+            Some(page) => page,
+            None => match pages.read()? {
+                Some(page) => page,
+                None => return Ok(None),
+            },
+        };
+        //A link starts with the first pages of all its streams
+        if !page.first {
+            link_start = None;
+            continue;
+        }
+        let start = *link_start.get_or_insert(pages.offset() - page.size() as u64);
+        if page.data.starts_with(b"OpusHead") {
+            //The identification header fills the first page on its own
+            return match page.packets().as_slice() {
+                &[(data, true)] => Ok(Some((start, page.serial, OpusHead::read(data)?))),
+                _ => Err(OggErrorKind::InvalidHeader),
+            };
+        }
+    }
+}
+
+impl<R: Read + Seek> Reader<R> {
+    ///Moves to the page boundary where the stream can be read from to get all packets that end after
+    ///`granule_position`. It's found by bisecting over the granule positions of the pages of the current link.
+    ///The pre-skip only applies again when that's the start of the stream
+    pub fn seek(&mut self, granule_position: u64) -> Result<()> {
+        //Offsets are relative to where the reader was created
+        let base = self.pages.get_mut().stream_position()? - self.pages.buffered() as u64 - self.pages.offset();
+        let end = self.pages.get_mut().seek(SeekFrom::End(0))?;
+
+        //Packets after a page start where its granule position is, unless the page ends inside a packet
+        let mut best = base + self.data_start;
+        let mut low = best;
+        let mut high = end;
+        while high - low > BISECT_WINDOW {
+            let mid = low + (high - low) / 2;
+            match self.find_granule_page(mid)? {
+                Some((offset, page)) if offset < high && page.granule_position as u64 <= granule_position => {
+                    low = offset + page.size() as u64;
+                    if page.segments.last() != Some(&255) {
+                        best = low;
+                    }
+                },
+                _ => high = mid,
+            }
+        }
+        let mut offset = low;
+        while let Some((start, page)) = self.find_granule_page(offset)? {
+            if page.granule_position as u64 > granule_position {
+                break;
+            }
+            offset = start + page.size() as u64;
+            if page.segments.last() != Some(&255) {
+                best = offset;
+            }
+        }
+
+        self.pages.get_mut().seek(SeekFrom::Start(best))?;
+        self.pages.set_offset(best - base);
+        self.packets.clear();
+        self.partial.clear();
+        self.granule_position = None;
+        self.pre_skip = if best == base + self.data_start { usize::from(self.head.pre_skip) } else { 0 };
+        self.ended = false;
+        self.pending = None;
+        self.sequence = None;
+        self.lost = false;
+        Ok(())
+    }
+
+    ///Finds the first page of the stream at or after byte `from` that a packet ends on, before the next link
+    fn find_granule_page(&mut self, mut from: u64) -> Result<Option<(u64, Page)>> {
+        while let Some((offset, page)) = self.find_page(from)? {
+            if page.first {
+                return Ok(None);
+            }
+            if page.serial == self.serial && page.granule_position != -1 {
+                return Ok(Some((offset, page)));
+            }
+            from = offset + page.size() as u64;
+        }
+        Ok(None)
+    }
+
+    ///Finds the first page at or after byte `from` of the underlying reader, and returns it with its offset
+    fn find_page(&mut self, mut from: u64) -> Result<Option<(u64, Page)>> {
+        loop {
+            let reader = self.pages.get_mut();
+            reader.seek(SeekFrom::Start(from))?;
+            let mut buf = Vec::new();
+            reader.take(4096).read_to_end(&mut buf)?;
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            match buf.windows(4).position(|capture| capture == b"OggS") {
+                Some(i) => {
+                    let offset = from + i as u64;
+                    reader.seek(SeekFrom::Start(offset))?;
+                    match Page::read(reader) {
+                        Ok(Some(ref page)) if !page.is_valid() => from = offset + 1,
+                        Ok(Some(page)) => return Ok(Some((offset, page))),
+                        Ok(None) | Err(OggErrorKind::InvalidPage) => from = offset + 1,
+                        Err(err) => return Err(err),
+                    }
+                },
+                //The capture pattern may start in the last 3 bytes
+                None => from += buf.len() as u64 - 3,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use common::types::Channels;
+    use decoder::multistream::ChannelMapping;
+    use ogg::{FlushPolicy, Writer};
+
+    fn head() -> OpusHead {
+        OpusHead { version: 1, pre_skip: 312, input_sample_rate: 48000, output_gain: 0, mapping: ChannelMapping::mono_stereo(Channels::Mono) }
+    }
+
+    ///A 20 ms CELT packet that holds its index
+    fn packet(i: usize) -> Vec<u8> {
+        let mut data = vec![0xf8, i as u8, (i>>8) as u8];
+        data.resize(100, 0x55);
+        data
+    }
+
+    ///Writes a stream of `count` packets, with the input trimmed to `samples`
+    fn stream(out: Vec<u8>, serial: u32, count: usize, flush: FlushPolicy, samples: Option<u64>) -> Vec<u8> {
+        let mut writer = Writer::new(out, serial, &head(), &OpusTags::new("poppy"), flush).unwrap();
+        for i in 0..count {
+            writer.write_packet(&packet(i)).unwrap();
+        }
+        writer.finish(samples).unwrap()
+    }
+
+    fn read_all<R: Read>(reader: &mut Reader<R>) -> Vec<OggPacket> {
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.read_packet().unwrap() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    #[test]
+    fn pre_skip_and_end_trimming() {
+        let file = stream(Vec::new(), 1, 10, FlushPolicy::Latency(2880), Some(9000));
+        let mut reader = Reader::new(Cursor::new(file)).unwrap();
+        assert_eq!(reader.head(), &head());
+        assert_eq!(reader.start().unwrap(), 0);
+        let packets = read_all(&mut reader);
+        assert_eq!(packets.len(), 10);
+        for (i, read) in packets.iter().enumerate() {
+            assert_eq!(read.data, packet(i));
+            assert_eq!(read.last, i == 9);
+        }
+        assert_eq!((packets[0].skip, packets[0].samples), (312, 648));
+        assert!(packets[1..9].iter().all(|packet| (packet.skip, packet.samples) == (0, 960)));
+        //312 + 9000 samples end 672 samples into the last packet
+        assert_eq!((packets[9].skip, packets[9].samples, packets[9].granule_position), (0, 672, 9312));
+        assert_eq!(packets.iter().map(|packet| packet.samples).sum::<usize>(), 9000);
+    }
+
+    #[test]
+    fn seek() {
+        //Pages of 5 packets, over enough data for the seek to bisect
+        let file = stream(Vec::new(), 1, 2000, FlushPolicy::Latency(4800), None);
+        assert!(file.len() as u64 > 2 * BISECT_WINDOW);
+        let mut reader = Reader::new(Cursor::new(file)).unwrap();
+        for &target in &[0, 4799, 4800, 1_000_000, 1_234_567, 1_919_999] {
+            reader.seek(target).unwrap();
+            let first = reader.read_packet().unwrap().unwrap();
+            //Reading starts on the page after the last one that ends by the target
+            let page = target / 4800;
+            assert_eq!(first.data, packet(page as usize * 5), "target {}", target);
+            assert_eq!(first.granule_position, page * 4800 + 960);
+            assert_eq!(first.skip, if page == 0 { 312 } else { 0 });
+        }
+        reader.seek(1_920_000).unwrap();
+        assert!(reader.read_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn chained() {
+        let first = stream(Vec::new(), 1, 10, FlushPolicy::Latency(4800), Some(8000));
+        let len = first.len() as u64;
+        let file = stream(first, 2, 5, FlushPolicy::Latency(4800), None);
+        let links = Reader::links(Cursor::new(&file)).unwrap();
+        assert_eq!(links.len(), 2);
+        assert_eq!((links[0].serial, links[0].offsets.clone(), links[0].samples), (1, 0..len, 8000));
+        assert_eq!((links[1].serial, links[1].offsets.clone(), links[1].samples), (2, len..file.len() as u64, 5 * 960 - 312));
+
+        let mut reader = Reader::new(Cursor::new(&file)).unwrap();
+        let packets = read_all(&mut reader);
+        assert_eq!(packets.len(), 15);
+        assert_eq!(reader.link(), 1);
+        assert_eq!((packets[10].data.clone(), packets[10].skip), (packet(0), 312));
+    }
+
+    #[test]
+    fn corrupt_page() {
+        //A packet a page, with the sixth page of audio corrupted
+        let mut file = stream(Vec::new(), 1, 10, FlushPolicy::Latency(960), None);
+        let mut offset = 0;
+        for _ in 0..2 + 5 {
+            let page = Page::read(&mut Cursor::new(&file[offset..])).unwrap().unwrap();
+            offset += page.size();
+        }
+        file[offset + 40] ^= 1;
+
+        let packets = read_all(&mut Reader::new(Cursor::new(file)).unwrap());
+        assert_eq!(packets.len(), 10);
+        for (i, read) in packets.iter().enumerate() {
+            if i == 5 {
+                assert!(read.data.is_empty());
+            } else {
+                assert_eq!(read.data, packet(i));
+            }
+            assert_eq!(read.granule_position, 960 * (i as u64 + 1));
+        }
+        assert_eq!(packets.iter().map(|packet| packet.samples).sum::<usize>(), 9600 - 312);
+    }
+
+    #[test]
+    fn broken_packet() {
+        //A packet a page, with the fourth packet of audio and the last one made unparseable, a code 3 packet of no frames
+        let mut file = stream(Vec::new(), 1, 10, FlushPolicy::Latency(960), None);
+        let mut offset = 0;
+        for i in 0..2 + 10 {
+            let mut page = Page::read(&mut Cursor::new(&file[offset..])).unwrap().unwrap();
+            if i == 2 + 3 || i == 2 + 9 {
+                page.data[..2].copy_from_slice(&[0xfb, 0]);
+                let mut data = Vec::new();
+                page.write(&mut data).unwrap();
+                file[offset..offset + page.size()].copy_from_slice(&data);
+            }
+            offset += page.size();
+        }
+
+        let packets = read_all(&mut Reader::new(Cursor::new(file)).unwrap());
+        assert_eq!(packets.len(), 10);
+        for (i, read) in packets.iter().enumerate() {
+            if i == 3 || i == 9 {
+                assert!(read.data.is_empty());
+            } else {
+                assert_eq!(read.data, packet(i));
+            }
+            assert_eq!(read.granule_position, 960 * (i as u64 + 1));
+            assert_eq!(read.last, i == 9);
+        }
+        assert_eq!(packets.iter().map(|packet| packet.samples).sum::<usize>(), 9600 - 312);
+    }
+}
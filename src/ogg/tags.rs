@@ -0,0 +1,345 @@
+use std::io::{Read, Write};
+use super::{le_u32, paginate, OggErrorKind, Page, Result};
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+///Comment header of an Ogg Opus stream, see RFC 7845 section 5.2
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpusTags {
+    pub vendor: String,
+    ///Comments of the form `NAME=value`, where names are compared ignoring ASCII case
+    pub comments: Vec<String>,
+    ///Binary data after the comments, only kept if the lowest bit of its first byte is set
+    pub extra: Vec<u8>,
+}
+
+impl OpusTags {
+    pub fn new(vendor: &str) -> Self {
+        Self { vendor: vendor.to_string(), comments: Vec::new(), extra: Vec::new() }
+    }
+
+    pub fn read(data: &[u8]) -> Result<Self> {
+        if data.len() < 8 || &data[..8] != b"OpusTags" {
+            return Err(OggErrorKind::InvalidHeader);
+        }
+        let mut data = &data[8..];
+        let vendor = read_string(&mut data)?;
+        if data.len() < 4 {
+            return Err(OggErrorKind::InvalidHeader);
+        }
+        let count = le_u32(data) as usize;
+        data = &data[4..];
+        //Every comment takes at least its length
+        if count > data.len() / 4 {
+            return Err(OggErrorKind::InvalidHeader);
+        }
+        let comments = (0..count).map(|_| read_string(&mut data)).collect::<Result<_>>()?;
+        //Data with the lowest bit clear is padding
+        let extra = if data.first().is_some_and(|&b| b & 1 != 0) { data.to_vec() } else { Vec::new() };
+        Ok(Self { vendor, comments, extra })
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(b"OpusTags");
+        write_string(&self.vendor, out);
+        out.extend_from_slice(&(self.comments.len() as u32).to_le_bytes());
+        for comment in &self.comments {
+            write_string(comment, out);
+        }
+        out.extend_from_slice(&self.extra);
+    }
+
+    ///Names and values of the comments, skipping any without a `=`
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.comments.iter().filter_map(|comment| {
+            let i = comment.find('=')?;
+            Some((&comment[..i], &comment[i + 1..]))
+        })
+    }
+
+    ///Value of the first comment called `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.iter().find(|&(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, value)| value)
+    }
+
+    ///Values of all comments called `name`, in order
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.iter().filter(|&(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, value)| value).collect()
+    }
+
+    pub fn add(&mut self, name: &str, value: &str) {
+        self.comments.push(format!("{}={}", name, value));
+    }
+
+    ///Replaces all comments called `name` with a single one
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.add(name, value);
+    }
+
+    ///Removes all comments called `name`
+    pub fn remove(&mut self, name: &str) {
+        self.comments.retain(|comment| {
+            let n = comment.split('=').next().unwrap_or("");
+            !(comment.contains('=') && n.eq_ignore_ascii_case(name))
+        });
+    }
+
+    ///Pictures of the `METADATA_BLOCK_PICTURE` comments
+    pub fn pictures(&self) -> Result<Vec<Picture>> {
+        self.get_all("METADATA_BLOCK_PICTURE").into_iter()
+            .map(|value| Picture::read(&base64_decode(value).ok_or(OggErrorKind::InvalidTag)?))
+            .collect()
+    }
+
+    pub fn add_picture(&mut self, picture: &Picture) {
+        let mut data = Vec::new();
+        picture.write(&mut data);
+        self.add("METADATA_BLOCK_PICTURE", &base64_encode(&data));
+    }
+
+    ///Gain in 1/256 dB that brings the track to the loudness of EBU R 128, on top of the output gain
+    pub fn track_gain(&self) -> Option<i16> {
+        self.get("R128_TRACK_GAIN").and_then(|gain| gain.parse().ok())
+    }
+
+    ///Gain in 1/256 dB that brings the album to the loudness of EBU R 128, on top of the output gain
+    pub fn album_gain(&self) -> Option<i16> {
+        self.get("R128_ALBUM_GAIN").and_then(|gain| gain.parse().ok())
+    }
+
+    ///Copies an Ogg file from `input` to `output` with this as the comment header of its first Opus stream.
+    ///The pages of the stream after the header are only renumbered when the header takes a different
+    ///number of pages, everything else is copied as is
+    pub fn rewrite<R: Read, W: Write>(&self, mut input: R, mut output: W) -> Result<()> {
+        let mut serial = None;
+        //Sequence number of the first page of the old header and the change in the number of pages
+        let mut header_sequence = None;
+        let mut shift = 0i64;
+        let mut done = false;
+        while let Some(mut page) = Page::read(&mut input)? {
+            match serial {
+                Some(serial) if page.serial == serial && !done => {
+                    let sequence = *header_sequence.get_or_insert(page.sequence);
+                    //The header ends on the first page a packet ends on, and audio starts on the next one
+                    if page.segments.iter().any(|&s| s < 255) {
+                        if page.packets().len() > 1 {
+                            return Err(OggErrorKind::InvalidHeader);
+                        }
+                        let mut data = Vec::new();
+                        self.write(&mut data);
+                        let pages = paginate(serial, sequence, &[(data, 0)]);
+                        for new in &pages {
+                            new.write(&mut output)?;
+                        }
+                        shift = i64::from(sequence) + pages.len() as i64 - i64::from(page.sequence) - 1;
+                        done = true;
+                    }
+                },
+                Some(serial) if page.serial == serial => {
+                    page.sequence = (i64::from(page.sequence) + shift) as u32;
+                    page.write(&mut output)?;
+                },
+                _ => {
+                    if serial.is_none() && page.first && page.data.starts_with(b"OpusHead") {
+                        serial = Some(page.serial);
+                    }
+                    page.write(&mut output)?;
+                },
+            }
+        }
+        if !done {
+            return Err(OggErrorKind::InvalidHeader);
+        }
+        output.flush()?;
+        Ok(())
+    }
+}
+
+///A picture as in a FLAC PICTURE metadata block, which `METADATA_BLOCK_PICTURE` comments hold in base64
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Picture {
+    ///Picture type of an ID3v2 APIC frame, 3 is the front cover
+    pub picture_type: u32,
+    ///MIME type of the data, or `-->` if the data is a URL
+    pub mime_type: String,
+    pub description: String,
+    pub width: u32,
+    pub height: u32,
+    ///Bits per pixel
+    pub depth: u32,
+    ///Number of colors of an indexed picture, 0 otherwise
+    pub colors: u32,
+    pub data: Vec<u8>,
+}
+
+impl Picture {
+    pub fn read(mut data: &[u8]) -> Result<Self> {
+        let picture_type = read_be_u32(&mut data)?;
+        let len = read_be_u32(&mut data)? as usize;
+        let mime_type = String::from_utf8_lossy(take(&mut data, len)?).into_owned();
+        let len = read_be_u32(&mut data)? as usize;
+        let description = String::from_utf8_lossy(take(&mut data, len)?).into_owned();
+        let width = read_be_u32(&mut data)?;
+        let height = read_be_u32(&mut data)?;
+        let depth = read_be_u32(&mut data)?;
+        let colors = read_be_u32(&mut data)?;
+        let len = read_be_u32(&mut data)? as usize;
+        let data = take(&mut data, len)?.to_vec();
+        Ok(Self { picture_type, mime_type, description, width, height, depth, colors, data })
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.picture_type.to_be_bytes());
+        out.extend_from_slice(&(self.mime_type.len() as u32).to_be_bytes());
+        out.extend_from_slice(self.mime_type.as_bytes());
+        out.extend_from_slice(&(self.description.len() as u32).to_be_bytes());
+        out.extend_from_slice(self.description.as_bytes());
+        for value in &[self.width, self.height, self.depth, self.colors, self.data.len() as u32] {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+        out.extend_from_slice(&self.data);
+    }
+}
+
+fn read_be_u32(data: &mut &[u8]) -> Result<u32> {
+    let value = take(data, 4)?;
+    Ok(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+}
+
+///Splits off the first `len` bytes of a picture
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if data.len() < len {
+        return Err(OggErrorKind::InvalidTag);
+    }
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+    Ok(taken)
+}
+
+///Reads a string prefixed with its length, invalid UTF-8 is replaced
+fn read_string(data: &mut &[u8]) -> Result<String> {
+    if data.len() < 4 || data.len() - 4 < le_u32(data) as usize {
+        return Err(OggErrorKind::InvalidHeader);
+    }
+    let (string, rest) = data[4..].split_at(le_u32(data) as usize);
+    *data = rest;
+    Ok(String::from_utf8_lossy(string).into_owned())
+}
+
+fn write_string(string: &str, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(string.len() as u32).to_le_bytes());
+    out.extend_from_slice(string.as_bytes());
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &b)| bits | u32::from(b)<<(16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(char::from(BASE64[(bits>>(18 - 6 * i)) as usize & 63]));
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+///Decodes base64 with or without padding, `None` if there are other characters
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=').as_bytes();
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut bits = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let value = BASE64.iter().position(|&b| b == c)? as u32;
+            bits |= value<<(18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            out.push((bits>>(16 - 8 * i)) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use common::types::Channels;
+    use decoder::multistream::ChannelMapping;
+    use ogg::{FlushPolicy, OpusHead, Reader, Writer};
+
+    #[test]
+    fn base64() {
+        for &(data, text) in &[(&b""[..], ""), (b"f", "Zg=="), (b"fo", "Zm8="), (b"foo", "Zm9v"), (b"foob", "Zm9vYg==")] {
+            assert_eq!(base64_encode(data), text);
+            assert_eq!(base64_decode(text).unwrap(), data);
+            assert_eq!(base64_decode(text.trim_end_matches('=')).unwrap(), data);
+        }
+        assert!(base64_decode("Zm9v!").is_none());
+        assert!(base64_decode("Zm9vY").is_none());
+    }
+
+    #[test]
+    fn pictures() {
+        let mut tags = OpusTags::new("poppy");
+        tags.add("TITLE", "Song");
+        tags.add("R128_TRACK_GAIN", "-512");
+        let pictures: Vec<_> = (1..4).map(|len| Picture {
+            picture_type: 3,
+            mime_type: "image/png".to_string(),
+            description: "Cover".to_string(),
+            width: 1,
+            height: 1,
+            depth: 24,
+            colors: 0,
+            data: vec![0x89; len],
+        }).collect();
+        for picture in &pictures {
+            tags.add_picture(picture);
+        }
+        let mut data = Vec::new();
+        tags.write(&mut data);
+        let read = OpusTags::read(&data).unwrap();
+        assert_eq!(read, tags);
+        assert_eq!(read.pictures().unwrap(), pictures);
+        assert_eq!((read.get("title"), read.track_gain()), (Some("Song"), Some(-512)));
+    }
+
+    #[test]
+    fn rewrite() {
+        let head = OpusHead { version: 1, pre_skip: 312, input_sample_rate: 48000, output_gain: 0, mapping: ChannelMapping::mono_stereo(Channels::Mono) };
+        let mut writer = Writer::new(Vec::new(), 1, &head, &OpusTags::new("poppy"), FlushPolicy::Latency(960)).unwrap();
+        for i in 0..10 {
+            writer.write_packet(&[0xf8, i]).unwrap();
+        }
+        let file = writer.finish(None).unwrap();
+
+        //A comment header of three pages instead of one moves the audio pages two on
+        let mut tags = OpusTags::new("poppy");
+        tags.add("COMMENT", &"x".repeat(2 * 255 * 255));
+        let mut output = Vec::new();
+        tags.rewrite(Cursor::new(&file), &mut output).unwrap();
+        let mut input = Cursor::new(&output);
+        let mut sequence = 0;
+        while let Some(page) = Page::read(&mut input).unwrap() {
+            assert_eq!(page.crc(), page.checksum);
+            assert_eq!(page.sequence, sequence);
+            sequence += 1;
+        }
+        assert_eq!(sequence, 1 + 3 + 10);
+
+        let mut reader = Reader::new(Cursor::new(&output)).unwrap();
+        assert_eq!(reader.tags(), &tags);
+        for i in 0..10 {
+            assert_eq!(reader.read_packet().unwrap().unwrap().data, [0xf8, i]);
+        }
+        assert!(reader.read_packet().unwrap().is_none());
+    }
+}
@@ -0,0 +1,110 @@
+use std::io::Write;
+use std::mem;
+use packet::Packet;
+use super::{paginate, OpusHead, OpusTags, Result};
+
+///When the writer ends a page and starts the next one
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FlushPolicy {
+    ///Ends a page before its packets would span more than this many samples at 48 kHz,
+    ///which bounds the delay a streaming reader sees
+    Latency(usize),
+    ///Ends a page before its data would grow past this many bytes. A larger packet gets pages of its own
+    Size(usize),
+}
+
+///Writes packets of a single stream as an Ogg Opus stream, see RFC 7845
+pub struct Writer<W> {
+    writer: W,
+    serial: u32,
+    ///Sequence number of the next page
+    sequence: u32,
+    flush: FlushPolicy,
+    ///Packets of the page being filled, with the granule position at the end of each
+    packets: Vec<(Vec<u8>, u64)>,
+    ///Samples per channel at 48 kHz of those packets
+    page_duration: usize,
+    ///Granule position at the end of the packets written so far
+    granule_position: u64,
+    pre_skip: u16,
+    multistream: bool,
+}
+
+impl<W: Write> Writer<W> {
+    ///Starts a logical stream with serial number `serial` and writes its headers,
+    ///the identification header on the first page and the comment header on the following ones
+    pub fn new(writer: W, serial: u32, head: &OpusHead, tags: &OpusTags, flush: FlushPolicy) -> Result<Self> {
+        let mut writer = Self {
+            writer,
+            serial,
+            sequence: 0,
+            flush,
+            packets: Vec::new(),
+            page_duration: 0,
+            granule_position: 0,
+            pre_skip: head.pre_skip,
+            multistream: head.mapping.streams > 1,
+        };
+        let mut data = Vec::new();
+        head.write(&mut data);
+        writer.write_pages(&[(data, 0)], false, None)?;
+        let mut data = Vec::new();
+        tags.write(&mut data);
+        writer.write_pages(&[(data, 0)], false, None)?;
+        Ok(writer)
+    }
+
+    ///Granule position at the end of the packets written so far, including the pre-skip
+    pub fn granule_position(&self) -> u64 {
+        self.granule_position
+    }
+
+    ///Adds a packet to the stream, ending the page before it if the flush policy asks for it.
+    ///The last page isn't written until the next packet or `finish`
+    pub fn write_packet(&mut self, data: &[u8]) -> Result<()> {
+        //All streams of a multistream packet have the same duration, the first one is self-delimited
+        let duration = if self.multistream { Packet::read_self_delimited(data)?.0 } else { Packet::read(data)? }.duration();
+        if !self.packets.is_empty() {
+            let full = match self.flush {
+                FlushPolicy::Latency(samples) => self.page_duration + duration > samples,
+                FlushPolicy::Size(size) => self.packets.iter().map(|p| p.0.len()).sum::<usize>() + data.len() > size,
+            };
+            if full {
+                let packets = mem::take(&mut self.packets);
+                self.write_pages(&packets, false, None)?;
+                self.page_duration = 0;
+            }
+        }
+        self.granule_position += duration as u64;
+        self.page_duration += duration;
+        self.packets.push((data.to_vec(), self.granule_position));
+        Ok(())
+    }
+
+    ///Writes the last page and returns the underlying writer. `samples` is the length of the encoder's input
+    ///per channel at 48 kHz, the stream's end is trimmed to it if the packets hold more
+    pub fn finish(mut self, samples: Option<u64>) -> Result<W> {
+        let end = samples.map(|samples| (samples + u64::from(self.pre_skip)).min(self.granule_position));
+        let packets = mem::take(&mut self.packets);
+        self.write_pages(&packets, true, end)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    ///Writes packets on as many pages as their lacing values need, starting a fresh page.
+    ///The last page ends the stream if `last` is set, with the granule position `end` if given
+    fn write_pages(&mut self, packets: &[(Vec<u8>, u64)], last: bool, end: Option<u64>) -> Result<()> {
+        let mut pages = paginate(self.serial, self.sequence, packets);
+        pages[0].first = self.sequence == 0;
+        let page = pages.last_mut().unwrap();
+        page.last = last;
+        if let Some(end) = end {
+            page.granule_position = end as i64;
+        }
+        for page in &pages {
+            page.write(&mut self.writer)?;
+        }
+        self.sequence += pages.len() as u32;
+        Ok(())
+    }
+}
@@ -7,7 +7,12 @@ use ::common::types::{
     Channels,
 };
 use ::common::util::div_rem;
+use ::range;
 
+///Longest frame in bytes, RFC 6716 section 3.4 R2
+const MAX_FRAME_LEN: usize = 1275;
+///Longest packet in samples per channel at 48 kHz, 120 ms, RFC 6716 section 3.4 R5
+const MAX_DURATION: usize = 5760;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Mode {
@@ -20,7 +25,8 @@ pub enum Mode {
 pub enum PacketLength<'a> {
     Single,
     DoubleEq,
-    Double(NonZeroU16),
+    ///Length of the first frame, which may be empty
+    Double(u16),
     VariableCbr(NonZeroU16),
     VariableVbr(&'a [u8]),
 }
@@ -28,11 +34,16 @@ pub enum PacketLength<'a> {
 #[derive(Debug)]
 pub struct Packet<'a> {
     //header: header::PacketHeader,
+    toc: u8,
     mode: Mode,
     bandwidth: Bandwidth,
     frame_size: FrameSize,
     channels: Channels,
     length: PacketLength<'a>,
+    ///Number of frames signalled in the header, including empty ones
+    frame_count: usize,
+    ///Bytes the packet takes up, with its framing and padding
+    size: usize,
     data: &'a [u8],
 }
 
@@ -47,11 +58,11 @@ impl<'a> Display for Packet<'a> {
 pub enum PacketErrorKind {
     InvalidLength,
     InvalidFormat,
-    Dtx,
 }
 
 impl<'a> Packet<'a> {
     pub fn read(data: &'a [u8]) -> Result<Self, PacketErrorKind> {
+        let size = data.len();
         let (&toc, mut data) = data.split_first().ok_or(PacketErrorKind::InvalidLength)?;
         let channels = match toc & 4 {
             0 => Channels::Mono,
@@ -59,9 +70,10 @@ impl<'a> Packet<'a> {
             _ => unreachable!(),
         };
 
+        let mut frame_count = 1;
         let length = match toc & 3 {
             0 => {
-                if data.len() <= 1275 {
+                if data.len() <= MAX_FRAME_LEN {
                     PacketLength::Single
                 } else {
                     return Err(PacketErrorKind::InvalidLength);
@@ -69,19 +81,21 @@ impl<'a> Packet<'a> {
             },
             1 => {
                 let len = data.len();
-                if len & 1 == 0 && len <= 2550 {
+                frame_count = 2;
+                if len & 1 == 0 && len <= 2 * MAX_FRAME_LEN {
                     PacketLength::DoubleEq
                 } else {
                     return Err(PacketErrorKind::InvalidLength);
                 }
             },
             2 => {
-                let res = length(data)?;
-                if res.0.get() as usize > res.1.len() {
+                let res = self_delimited_length(data)?;
+                if res.0 > res.1.len() || res.1.len() - res.0 > MAX_FRAME_LEN {
                     return Err(PacketErrorKind::InvalidLength);
                 }
                 data = res.1;
-                PacketLength::Double(res.0)
+                frame_count = 2;
+                PacketLength::Double(res.0 as u16)
             },
             3 => {
                 let (&config, split_data) = data.split_first().ok_or(PacketErrorKind::InvalidLength)?;
@@ -94,20 +108,8 @@ impl<'a> Packet<'a> {
                     }
                     data = &striped_data[..striped_data_len-padding_len];
                 }
-                let frame_count = config & 63;
+                frame_count = usize::from(config & 63);
                 if frame_count == 0 { return Err(PacketErrorKind::InvalidFormat) }
-                
-                fn length_internal(data: &[u8]) -> Result<(usize, bool), PacketErrorKind> {
-                    let frame_size_one = usize::from(*data.get(0).ok_or(PacketErrorKind::InvalidLength)?);
-                    if frame_size_one == 0 {
-                        Err(PacketErrorKind::Dtx)
-                    } else if frame_size_one <= 251 {
-                        Ok((frame_size_one, false))
-                    } else {
-                        let frame_size_two = usize::from(*data.get(1).ok_or(PacketErrorKind::InvalidLength)?);
-                        Ok((frame_size_two * 4 + frame_size_one, true))
-                    }
-                }
 
                 if config & 128 == 128 {
                     let mut sum = 0;
@@ -115,52 +117,120 @@ impl<'a> Packet<'a> {
                     for _ in 0..frame_count-1 {
                         let (val, double) = length_internal(&data[cursor..])?;
                         if double { cursor += 2 } else { cursor += 1 }
-                        sum += val as usize;
+                        sum += val;
                     }
                     let (lengths, split_data) = data.split_at(cursor);
                     data = split_data;
-                    if data.len() < sum { return Err(PacketErrorKind::InvalidLength) }
+                    if data.len() < sum || data.len() - sum > MAX_FRAME_LEN { return Err(PacketErrorKind::InvalidLength) }
                     PacketLength::VariableVbr(lengths)
                 } else {
-                    let (res, rem) = div_rem(data.len(), frame_count as usize);
-                    if rem != 0 || res > 1275{ return Err(PacketErrorKind::InvalidLength) }
-                    PacketLength::VariableCbr(unsafe { NonZeroU16::new_unchecked(res as u16) })
+                    let (res, rem) = div_rem(data.len(), frame_count);
+                    if rem != 0 || res > MAX_FRAME_LEN { return Err(PacketErrorKind::InvalidLength) }
+                    cbr_length(res)
                 }
 
             },
             _ => unreachable!(),
         };
 
-        let config = toc >> 3;
-        let (mode, bandwidth, frame_size) = match config {
-            x if x & 0x10 == 0x10 => (
-                Mode::Celt,
-                celt_bandwidth(config),
-                celt_frame_size(config),
-            ),
-            x if x & 0xC == 0xC => (
-                Mode::Hybrid,
-                hybrid_bandwidth(config),
-                hybrid_frame_size(config),
-            ),
-            _ => (
-                Mode::Silk,
-                silk_bandwidth(config),
-                silk_frame_size(config),
-            )
+        let (mode, bandwidth, frame_size) = configuration(toc);
+        let packet = Self {
+            toc,
+            mode,
+            bandwidth,
+            frame_size,
+            channels,
+            length,
+            frame_count,
+            size,
+            data,
+        };
+        if packet.duration() > MAX_DURATION {
+            return Err(PacketErrorKind::InvalidFormat);
+        }
+        Ok(packet)
+    }
+
+    ///Reads a packet in the self-delimiting framing of RFC 6716 appendix B, which also codes the length
+    ///of the last frame, so that the packet can be followed by others. Returns the packet and the number
+    ///of bytes it takes up in `data`
+    pub fn read_self_delimited(data: &'a [u8]) -> Result<(Self, usize), PacketErrorKind> {
+        let (&toc, mut rest) = data.split_first().ok_or(PacketErrorKind::InvalidLength)?;
+        let channels = if toc & 4 == 4 { Channels::Stereo } else { Channels::Mono };
+
+        let mut padding = 0;
+        let (length, frame_count, frames_len) = match toc & 3 {
+            0 => {
+                let (len, split_data) = self_delimited_length(rest)?;
+                rest = split_data;
+                (PacketLength::Single, 1, len)
+            },
+            1 => {
+                let (len, split_data) = self_delimited_length(rest)?;
+                rest = split_data;
+                (PacketLength::DoubleEq, 2, 2 * len)
+            },
+            2 => {
+                let (first, split_data) = self_delimited_length(rest)?;
+                let (second, split_data) = self_delimited_length(split_data)?;
+                rest = split_data;
+                (PacketLength::Double(first as u16), 2, first + second)
+            },
+            3 => {
+                let (&config, split_data) = rest.split_first().ok_or(PacketErrorKind::InvalidLength)?;
+                rest = split_data;
+                if config & 64 == 64 {
+                    let (padding_len, split_data) = padding_length(rest).ok_or(PacketErrorKind::InvalidLength)?;
+                    padding = padding_len;
+                    rest = split_data;
+                }
+                let frame_count = usize::from(config & 63);
+                if frame_count == 0 { return Err(PacketErrorKind::InvalidFormat) }
+
+                if config & 128 == 128 {
+                    let mut sum = 0;
+                    let mut cursor = 0;
+                    for _ in 0..frame_count-1 {
+                        let (val, double) = length_internal(&rest[cursor..])?;
+                        if double { cursor += 2 } else { cursor += 1 }
+                        sum += val;
+                    }
+                    let (lengths, split_data) = rest.split_at(cursor);
+                    let (last, split_data) = self_delimited_length(split_data)?;
+                    rest = split_data;
+                    (PacketLength::VariableVbr(lengths), frame_count, sum + last)
+                } else {
+                    let (len, split_data) = self_delimited_length(rest)?;
+                    rest = split_data;
+                    (cbr_length(len), frame_count, frame_count * len)
+                }
+            },
+            _ => unreachable!(),
         };
+        if frames_len + padding > rest.len() {
+            return Err(PacketErrorKind::InvalidLength);
+        }
 
-        Ok(Self {
+        let (mode, bandwidth, frame_size) = configuration(toc);
+        let size = data.len() - rest.len() + frames_len + padding;
+        let packet = Self {
+            toc,
             mode,
             bandwidth,
             frame_size,
             channels,
             length,
-            data,
-        })
+            frame_count,
+            size,
+            data: &rest[..frames_len],
+        };
+        if packet.duration() > MAX_DURATION {
+            return Err(PacketErrorKind::InvalidFormat);
+        }
+        Ok((packet, size))
     }
 
-    pub fn frames(&self) -> Frames {
+    pub fn frames(&self) -> Frames<'_> {
         Frames {
             length: self.length,
             second: false,
@@ -183,17 +253,195 @@ impl<'a> Packet<'a> {
     pub fn frame_size(&self) -> FrameSize {
         self.frame_size
     }
+
+    ///Number of frames in the packet, including empty ones that signal discontinuous transmission
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    ///Whether all frames are at most a byte long, which an encoder sends during silence with
+    ///discontinuous transmission and the decoder conceals
+    pub fn is_dtx(&self) -> bool {
+        self.frames().all(|frame| frame.len() <= 1)
+    }
+
+    ///Whether each frame's SILK layer carries low bitrate redundancy for the packet before, going by the
+    ///voice activity and redundancy flags the range coded data of a frame starts with, one set per coded channel.
+    ///Empty frames and CELT frames carry none
+    pub fn per_frame_lbrr_flags(&self) -> Vec<bool> {
+        let silk_frames = match self.frame_size {
+            FrameSize::Ms40 => 2,
+            FrameSize::Ms60 => 3,
+            _ => 1,
+        };
+        let mut frames: Vec<bool> = self.frames().map(|frame| {
+            if self.mode == Mode::Celt || frame.len() <= 1 {
+                return false;
+            }
+            let mut rc = range::Decoder::new(frame);
+            let mut lbrr = false;
+            for _ in 0..self.channels as usize {
+                for _ in 0..silk_frames {
+                    rc.decode_bit_logp(1);
+                }
+                lbrr |= rc.decode_bit_logp(1);
+            }
+            lbrr
+        }).collect();
+        frames.resize(self.frame_count, false);
+        frames
+    }
+
+    ///Bytes the packet takes up as it was read, with its framing and padding
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    ///Samples per channel of all frames at 48 kHz
+    pub fn duration(&self) -> usize {
+        let samples = match self.frame_size {
+            FrameSize::Ms2_5 => 120,
+            FrameSize::Ms5 => 240,
+            FrameSize::Ms10 => 480,
+            FrameSize::Ms20 => 960,
+            FrameSize::Ms40 => 1920,
+            FrameSize::Ms60 => 2880,
+        };
+        self.frame_count * samples
+    }
+
+    ///Writes the packet to `out` without padding, and returns the number of bytes written
+    pub fn write(&self, out: &mut Vec<u8>) -> usize {
+        self.write_framing(false, out)
+    }
+
+    ///Writes the packet to `out` in the self-delimiting framing of RFC 6716 appendix B,
+    ///see `read_self_delimited`. Returns the number of bytes written
+    pub fn write_self_delimited(&self, out: &mut Vec<u8>) -> usize {
+        self.write_framing(true, out)
+    }
+
+    fn write_framing(&self, self_delimited: bool, out: &mut Vec<u8>) -> usize {
+        let start = out.len();
+        //Trailing empty frames end the iterator early
+        let mut frames: Vec<&[u8]> = self.frames().collect();
+        frames.resize(self.frame_count, &[]);
+        let toc = self.toc & !3;
+        let (last, rest) = frames.split_last().expect("packets have at least one frame");
+        let cbr = rest.iter().all(|frame| frame.len() == last.len());
+        match frames.len() {
+            1 => out.push(toc),
+            2 if cbr => out.push(toc | 1),
+            2 => {
+                out.push(toc | 2);
+                write_length(rest[0].len(), out);
+            },
+            count => {
+                out.push(toc | 3);
+                out.push(count as u8 | if cbr { 0 } else { 128 });
+                if !cbr {
+                    for frame in rest {
+                        write_length(frame.len(), out);
+                    }
+                }
+            },
+        }
+        if self_delimited {
+            write_length(last.len(), out);
+        }
+        for frame in &frames {
+            out.extend_from_slice(frame);
+        }
+        out.len() - start
+    }
+}
+
+///Reads self-delimited packets back to back until the end of `data`
+pub fn read_self_delimited_packets<'a>(data: &'a [u8]) -> SelfDelimitedPackets<'a> {
+    SelfDelimitedPackets { data }
 }
 
-fn length(data: &[u8]) -> Result<(NonZeroU16, &[u8]), PacketErrorKind> {
-    let frame_size_one = u16::from(*data.get(0).ok_or(PacketErrorKind::InvalidLength)?);
-    if frame_size_one == 0 {
-        Err(PacketErrorKind::Dtx)
-    } else if frame_size_one <= 251 {
-        Ok((unsafe { NonZeroU16::new_unchecked(frame_size_one) }, &data[1..]))
+pub struct SelfDelimitedPackets<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for SelfDelimitedPackets<'a> {
+    type Item = Result<Packet<'a>, PacketErrorKind>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        match Packet::read_self_delimited(self.data) {
+            Ok((packet, len)) => {
+                self.data = &self.data[len..];
+                Some(Ok(packet))
+            },
+            Err(err) => {
+                //The rest can't be told apart from the broken packet
+                self.data = &[];
+                Some(Err(err))
+            },
+        }
+    }
+}
+
+fn write_length(len: usize, out: &mut Vec<u8>) {
+    debug_assert!(len <= 1275);
+    if len < 252 {
+        out.push(len as u8);
     } else {
-        let frame_size_two = u16::from(*data.get(1).ok_or(PacketErrorKind::InvalidLength)?);
-        Ok((unsafe { NonZeroU16::new_unchecked(frame_size_two * 4 + frame_size_one) }, &data[2..]))
+        let first = 252 + (len & 3);
+        out.push(first as u8);
+        out.push(((len - first)>>2) as u8);
+    }
+}
+
+fn configuration(toc: u8) -> (Mode, Bandwidth, FrameSize) {
+    let config = toc >> 3;
+    match config {
+        x if x & 0x10 == 0x10 => (
+            Mode::Celt,
+            celt_bandwidth(config),
+            celt_frame_size(config),
+        ),
+        x if x & 0xC == 0xC => (
+            Mode::Hybrid,
+            hybrid_bandwidth(config),
+            hybrid_frame_size(config),
+        ),
+        _ => (
+            Mode::Silk,
+            silk_bandwidth(config),
+            silk_frame_size(config),
+        )
+    }
+}
+
+fn length_internal(data: &[u8]) -> Result<(usize, bool), PacketErrorKind> {
+    let frame_size_one = usize::from(*data.first().ok_or(PacketErrorKind::InvalidLength)?);
+    if frame_size_one <= 251 {
+        Ok((frame_size_one, false))
+    } else {
+        let frame_size_two = usize::from(*data.get(1).ok_or(PacketErrorKind::InvalidLength)?);
+        Ok((frame_size_two * 4 + frame_size_one, true))
+    }
+}
+
+///Length of a frame coded in one or two bytes, which may be empty, and the data after it
+fn self_delimited_length(data: &[u8]) -> Result<(usize, &[u8]), PacketErrorKind> {
+    let (len, double) = length_internal(data)?;
+    if len > MAX_FRAME_LEN {
+        return Err(PacketErrorKind::InvalidLength);
+    }
+    Ok((len, &data[if double { 2 } else { 1 }..]))
+}
+
+///Framing of frames of equal length, where frames of discontinuous transmission are all empty
+fn cbr_length<'a>(len: usize) -> PacketLength<'a> {
+    match NonZeroU16::new(len as u16) {
+        Some(len) => PacketLength::VariableCbr(len),
+        None => PacketLength::VariableVbr(&[]),
     }
 }
 
@@ -208,16 +456,6 @@ fn padding_length(data: &[u8]) -> Option<(usize, &[u8])> {
     None
 }
 
-fn length_unchecked(data: &[u8]) -> (usize, &[u8]) {
-    let frame_size_one = unsafe { *data.get_unchecked(0) as usize };
-    if frame_size_one <= 251 {
-        (frame_size_one, &data[1..])
-    } else {
-        let frame_size_two = unsafe { *data.get_unchecked(1) as usize};
-        (frame_size_two * 4 + frame_size_one, &data[2..])
-    }
-}
-
 pub struct Frames<'a> {
     length: PacketLength<'a>,
     data: &'a [u8],
@@ -238,8 +476,7 @@ impl<'a> Iterator for Frames<'a> {
                     Some(ret)
                 }
                 PacketLength::DoubleEq |
-                PacketLength::Double(_) |
-                PacketLength::VariableVbr(_)
+                PacketLength::Double(_)
                 if self.second => { 
                     let ret = self.data;
                     self.data = &[0; 0];
@@ -252,7 +489,7 @@ impl<'a> Iterator for Frames<'a> {
                     Some(ret)
                 },
                 PacketLength::Double(len) => {
-                    let (ret, data) = self.data.split_at(len.get() as usize);
+                    let (ret, data) = self.data.split_at(usize::from(len));
                     self.data = data;
                     self.second = true;
                     Some(ret)
@@ -263,11 +500,16 @@ impl<'a> Iterator for Frames<'a> {
                     Some(res)
                 },
                 PacketLength::VariableVbr(ref mut lengths) => {
-                    let (len, split_lengths) = length_unchecked(lengths);
+                    //Once the lengths run out the rest of the data is the last frame
+                    let len = match self_delimited_length(lengths) {
+                        Ok((len, split_lengths)) => {
+                            *lengths = split_lengths;
+                            len
+                        },
+                        Err(_) => self.data.len(),
+                    };
                     let (ret, data) = self.data.split_at(len);
                     self.data = data;
-                    if split_lengths.is_empty() { self.second = true }
-                    *lengths = split_lengths;
                     Some(ret)
                 }
             }
@@ -330,4 +572,81 @@ fn silk_bandwidth(val: u8) -> Bandwidth {
         8 => Bandwidth::Wide,
         _ => unreachable!(),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vbr_single_frame() {
+        let packet = Packet::read(&[0x03, 0x81, 1, 2, 3]).unwrap();
+        assert_eq!(packet.frame_count(), 1);
+        let frames: Vec<_> = packet.frames().collect();
+        assert_eq!(frames, [&[1, 2, 3][..]]);
+    }
+
+    #[test]
+    fn vbr_frames() {
+        let packet = Packet::read(&[0x03, 0x83, 1, 0, 1, 2, 3]).unwrap();
+        assert_eq!(packet.frame_count(), 3);
+        let frames: Vec<_> = packet.frames().collect();
+        assert_eq!(frames, [&[1][..], &[][..], &[2, 3][..]]);
+    }
+
+    #[test]
+    fn cbr_empty_frames() {
+        let packet = Packet::read(&[0x03, 0x03]).unwrap();
+        assert_eq!(packet.frame_count(), 3);
+        assert_eq!(packet.frames().count(), 0);
+
+        let mut data = vec![];
+        packet.write_self_delimited(&mut data);
+        let (packet, len) = Packet::read_self_delimited(&data).unwrap();
+        assert_eq!(len, data.len());
+        assert_eq!(packet.frame_count(), 3);
+        assert_eq!(packet.frames().count(), 0);
+    }
+
+    #[test]
+    fn empty_first_frame() {
+        let packet = Packet::read(&[0x02, 0, 1, 2]).unwrap();
+        assert_eq!(packet.frame_count(), 2);
+        let frames: Vec<_> = packet.frames().collect();
+        assert_eq!(frames, [&[][..], &[1, 2][..]]);
+
+        let mut data = vec![];
+        packet.write_self_delimited(&mut data);
+        assert_eq!(data, [0x02, 0, 2, 1, 2]);
+        let (packet, len) = Packet::read_self_delimited(&data).unwrap();
+        assert_eq!(len, 5);
+        let frames: Vec<_> = packet.frames().collect();
+        assert_eq!(frames, [&[][..], &[1, 2][..]]);
+    }
+
+    #[test]
+    fn frame_length_limit() {
+        let data = vec![0; 2 + 2 * 1276];
+        //Frames without a coded length are held to the same limit
+        assert!(Packet::read(&data[..1 + 1275]).is_ok());
+        assert!(Packet::read(&data[..1 + 1276]).is_err());
+        assert!(Packet::read(&[&[0x02, 1][..], &data[..1 + 1276]].concat()).is_err());
+        assert!(Packet::read(&[&[0x03, 0x82, 1][..], &data[..1 + 1276]].concat()).is_err());
+        assert!(Packet::read(&[&[0x03, 0x82, 1][..], &data[..1 + 1275]].concat()).is_ok());
+        //The longest length two bytes code
+        let packet = [&[0x00, 255, 255][..], &data[..1275]].concat();
+        assert_eq!(Packet::read_self_delimited(&packet).unwrap().1, 3 + 1275);
+    }
+
+    #[test]
+    fn duration_limit() {
+        //Six 20 ms CELT frames make up 120 ms, a seventh is too many
+        assert_eq!(Packet::read(&[0xfb, 0x06]).unwrap().duration(), 5760);
+        assert!(Packet::read(&[0xfb, 0x07]).is_err());
+        //Two 60 ms SILK frames, then three
+        assert_eq!(Packet::read(&[0x1b, 0x02]).unwrap().duration(), 5760);
+        assert!(Packet::read(&[0x1b, 0x03]).is_err());
+        assert!(Packet::read_self_delimited(&[0x1b, 0x03, 0]).is_err());
+        assert_eq!(Packet::read_self_delimited(&[0x1b, 0x02, 0]).unwrap().1, 3);
+    }
+}
@@ -2,6 +2,7 @@
 use std::cmp::min;
 
 pub struct Decoder<'a> {
+    storage: usize,
     buffer: &'a [u8],
     current: u8,
     bits_read: usize,
@@ -15,12 +16,15 @@ pub struct Decoder<'a> {
 
 impl<'a> Decoder<'a> {
     pub fn new(mut buffer: &'a [u8]) -> Self {
+        let storage = buffer.len();
+        let buffer_raw = buffer;
         let current = split_first(&mut buffer);
         let mut val = Self {
+            storage,
             buffer,
             current,
             bits_read: 9,
-            buffer_raw: buffer,
+            buffer_raw,
             cache_raw: 0,
             cache_raw_len: 0,
             value: (127 - (current>>1)).into(),
@@ -38,11 +42,7 @@ impl<'a> Decoder<'a> {
 
     pub fn decode_bin(&mut self, total_bits: u8) -> u32 {
         self.scale_cache = self.range>>total_bits;
-        if 1<<total_bits >= self.value / self.scale_cache + 1 {
-            (1<<total_bits) - (self.value / self.scale_cache + 1)
-        } else {
-            0
-        }
+        (1u32<<total_bits).saturating_sub(self.value / self.scale_cache + 1)
     }
 
     pub fn decode_bit_logp(&mut self, logp: u16) -> bool {
@@ -78,11 +78,11 @@ impl<'a> Decoder<'a> {
     pub fn decode_icdf(&mut self, table: &[u8], total_bits: u8) -> usize {
         let scale = self.range>>total_bits;
         let mut new_range = scale * table[0] as u32;
-        let mut res = 1;
+        let mut res = 0;
         while self.value < new_range {
             self.range = new_range;
-            new_range = scale * table[res] as u32;
             res += 1;
+            new_range = scale * table[res] as u32;
         }
         self.value -= new_range;
         self.range -= new_range;
@@ -93,11 +93,13 @@ impl<'a> Decoder<'a> {
     pub fn decode_bits(&mut self, bits: usize) -> u32 {
         debug_assert!(bits <= 25);
         self.bits_read += bits;
-        let amount_bytes = bits / 8 + 1;
-        self.cache_raw |= split_last(&mut self.buffer_raw, amount_bytes)<<self.cache_raw_len;
-        self.cache_raw_len += bits;
+        while self.cache_raw_len < bits {
+            self.cache_raw |= split_last(&mut self.buffer_raw, 1)<<self.cache_raw_len;
+            self.cache_raw_len += 8;
+        }
         let ret = self.cache_raw & ((1<<bits) - 1);
         self.cache_raw>>=bits;
+        self.cache_raw_len -= bits;
         ret
     }
 
@@ -107,7 +109,7 @@ impl<'a> Decoder<'a> {
         if total_bits <= 8 {
             let dec = self.decode(total);
             self.update(dec as u16, (dec + 1) as u16, total as u16);
-            dec as u32
+            dec
         } else {
             let upper = ((total - 1)>>(total_bits - 8)) + 1;
             let dec = self.decode(upper);
@@ -138,6 +140,28 @@ impl<'a> Decoder<'a> {
         }
     }
 
+    ///Size of the coded data in bits
+    pub fn storage_bits(&self) -> usize {
+        self.storage * 8
+    }
+
+    ///Drops `bytes` from the end of the coded data, before any raw bits were read
+    pub fn shrink(&mut self, bytes: usize) {
+        self.storage -= bytes;
+        self.buffer = &self.buffer[..self.buffer.len().saturating_sub(bytes)];
+        self.buffer_raw = &self.buffer_raw[..self.storage];
+    }
+
+    ///Marks all of the coded data as read
+    pub fn skip_to_end(&mut self) {
+        self.bits_read = self.storage * 8 + ilog(self.range) as usize;
+    }
+
+    ///Range after the last decoded symbol, encoders report the same value
+    pub fn final_range(&self) -> u32 {
+        self.range
+    }
+
     pub fn tell(&self) -> usize {
         self.bits_read - ilog(self.range) as usize
     }
@@ -0,0 +1,173 @@
+use super::{RtpErrorKind, Result};
+
+///Bytes of the fixed part of the header
+const HEADER_SIZE: usize = 12;
+const VERSION: u8 = 2;
+
+///An RTP packet, see RFC 3550 section 5.1
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RtpPacket {
+    ///Set on the first packet of a talkspurt
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    ///Contributing sources, at most 15
+    pub csrcs: Vec<u32>,
+    ///Profile specific identifier and data of a header extension, the data taking a multiple of 4 bytes
+    pub extension: Option<(u16, Vec<u8>)>,
+    ///Payload without any padding
+    pub payload: Vec<u8>,
+}
+
+impl RtpPacket {
+    pub fn new(payload_type: u8, sequence: u16, timestamp: u32, ssrc: u32) -> Self {
+        Self {
+            marker: false,
+            payload_type,
+            sequence,
+            timestamp,
+            ssrc,
+            csrcs: Vec::new(),
+            extension: None,
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn read(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_SIZE || data[0]>>6 != VERSION {
+            return Err(RtpErrorKind::InvalidHeader);
+        }
+        let mut end = data.len();
+        //The last byte of padding counts all of it
+        if data[0] & 0x20 != 0 {
+            let padding = usize::from(data[end - 1]);
+            if padding == 0 || padding > end - HEADER_SIZE {
+                return Err(RtpErrorKind::InvalidHeader);
+            }
+            end -= padding;
+        }
+        let mut start = HEADER_SIZE + 4 * usize::from(data[0] & 15);
+        if start > end {
+            return Err(RtpErrorKind::InvalidHeader);
+        }
+        let csrcs = data[HEADER_SIZE..start].chunks(4).map(be_u32).collect();
+        let extension = if data[0] & 0x10 != 0 {
+            if start + 4 > end {
+                return Err(RtpErrorKind::InvalidHeader);
+            }
+            let profile = be_u16(&data[start..]);
+            let len = 4 * usize::from(be_u16(&data[start + 2..]));
+            start += 4;
+            if start + len > end {
+                return Err(RtpErrorKind::InvalidHeader);
+            }
+            start += len;
+            Some((profile, data[start - len..start].to_vec()))
+        } else {
+            None
+        };
+        Ok(Self {
+            marker: data[1] & 0x80 != 0,
+            payload_type: data[1] & 0x7f,
+            sequence: be_u16(&data[2..]),
+            timestamp: be_u32(&data[4..]),
+            ssrc: be_u32(&data[8..]),
+            csrcs,
+            extension,
+            payload: data[start..end].to_vec(),
+        })
+    }
+
+    ///Writes the packet without padding, an extension's data is padded with zeros to a multiple of 4 bytes
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.push(VERSION<<6 | if self.extension.is_some() { 0x10 } else { 0 } | self.csrcs.len().min(15) as u8);
+        out.push(if self.marker { 0x80 } else { 0 } | self.payload_type & 0x7f);
+        out.extend_from_slice(&self.sequence.to_be_bytes());
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out.extend_from_slice(&self.ssrc.to_be_bytes());
+        for csrc in self.csrcs.iter().take(15) {
+            out.extend_from_slice(&csrc.to_be_bytes());
+        }
+        if let Some((profile, ref data)) = self.extension {
+            let words = data.len().div_ceil(4);
+            out.extend_from_slice(&profile.to_be_bytes());
+            out.extend_from_slice(&(words as u16).to_be_bytes());
+            out.extend_from_slice(data);
+            out.resize(out.len() + 4 * words - data.len(), 0);
+        }
+        out.extend_from_slice(&self.payload);
+    }
+}
+
+fn be_u16(data: &[u8]) -> u16 {
+    u16::from_be_bytes([data[0], data[1]])
+}
+
+fn be_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet() -> RtpPacket {
+        let mut packet = RtpPacket::new(111, 65535, 4_294_967_000, 0xdead_beef);
+        packet.marker = true;
+        packet.csrcs = vec![1, 2, 3];
+        packet.extension = Some((0xbede, vec![1, 2, 3, 4, 5, 0, 0, 0]));
+        packet.payload = vec![0xf8, 1, 2, 3];
+        packet
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut data = Vec::new();
+        packet().write(&mut data);
+        assert_eq!(data.len(), 12 + 3 * 4 + 4 + 8 + 4);
+        assert_eq!(RtpPacket::read(&data).unwrap(), packet());
+
+        //Extension data is padded to whole words
+        let mut short = packet();
+        short.extension = Some((0xbede, vec![1, 2, 3, 4, 5]));
+        let mut padded = Vec::new();
+        short.write(&mut padded);
+        assert_eq!(padded, data);
+    }
+
+    #[test]
+    fn padding() {
+        let mut data = Vec::new();
+        packet().write(&mut data);
+        data[0] |= 0x20;
+        data.extend_from_slice(&[0, 0, 3]);
+        assert_eq!(RtpPacket::read(&data).unwrap(), packet());
+
+        //Padding of nothing, or of more than the header leaves
+        let last = data.len() - 1;
+        data[last] = 0;
+        assert!(RtpPacket::read(&data).is_err());
+        data[last] = (data.len() - 11) as u8;
+        assert!(RtpPacket::read(&data).is_err());
+        //Padding that eats into the extension
+        data[last] = 4 + 3 + 1;
+        assert!(RtpPacket::read(&data).is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        let mut data = Vec::new();
+        packet().write(&mut data);
+        for &version in &[0, 1, 3] {
+            let mut data = data.clone();
+            data[0] = data[0] & 0x3f | version<<6;
+            assert!(RtpPacket::read(&data).is_err());
+        }
+        //Cut off in the fixed header, the contributing sources and the extension
+        for &len in &[11, 20, 30] {
+            assert!(RtpPacket::read(&data[..len]).is_err());
+        }
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::{BTreeMap, VecDeque};
+use common::types::{Channels, SampleRate};
+use decoder::Decoder;
+use packet::Packet;
+use super::{Payload, Result, CLOCK_RATE};
+
+///Samples at 48 kHz of the shortest frame, lost audio is concealed in multiples of it
+const FRAME_STEP: usize = 120;
+///Samples at 48 kHz concealed at once while no packet is there to play
+const UNDERRUN_STEP: usize = 960;
+///Samples at 48 kHz the delay may stray from its target before playout is stretched
+const HYSTERESIS: usize = 480;
+///Samples at 48 kHz to play between two stretches, so that playout only changes speed by a few percent
+const STRETCH_INTERVAL: usize = 4800;
+
+///Counts of what happened to the packets pushed into a jitter buffer
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct JitterStats {
+    pub received: usize,
+    ///Packets that arrived after their time to play had passed
+    pub late: usize,
+    pub duplicates: usize,
+    ///Packets that were missing when their time came and were concealed
+    pub concealed: usize,
+    ///Packets that were missing when their time came and were recovered from the redundancy of the next one
+    pub recovered: usize,
+    ///Times playout ran out of packets and concealed audio to wait for more
+    pub underruns: usize,
+    ///Samples per channel at the output rate removed and added by stretching playout
+    pub compressed: usize,
+    pub expanded: usize,
+}
+
+///Orders Opus packets received over RTP and plays them out at a steady pace, see RFC 7587.
+///The delay follows the interarrival jitter of RFC 3550, and playout is stretched a pitch period at a time
+///to get there. Missing packets are recovered from the in-band redundancy of the packet after them if it
+///has any, and concealed otherwise. Times are counted in samples at 48 kHz on the receiver's clock
+pub struct JitterBuffer {
+    decoder: Decoder,
+    fs_hz: usize,
+    channels: usize,
+    ///Packets waiting to be played, by extended sequence number, with their extended timestamps
+    packets: BTreeMap<u64, (u64, Payload)>,
+    ///Highest extended sequence number and timestamp received
+    highest: Option<(u64, u64)>,
+    ///Extended sequence number and timestamp of the next packet to play, `None` until playout starts
+    next: Option<(u64, u64)>,
+    ///Decoded samples not returned yet, interleaved at the output rate
+    pcm: VecDeque<f32>,
+    buf: Vec<f32>,
+    ///Interarrival jitter and the transit time of the last packet
+    jitter: f64,
+    transit: Option<i64>,
+    ///Duration of the last packet received
+    packet_duration: usize,
+    min_delay: usize,
+    max_delay: usize,
+    ///Output samples per channel since the last stretch
+    since_stretch: usize,
+    ///Delay averaged over the last pulls, which evens out its rise and fall with every packet
+    average_delay: f64,
+    stats: JitterStats,
+}
+
+impl JitterBuffer {
+    pub fn new(rate: SampleRate, channels: Channels) -> Self {
+        let fs_hz = match rate {
+            SampleRate::Khz8 => 8000,
+            SampleRate::Khz12 => 12000,
+            SampleRate::Khz16 => 16000,
+            SampleRate::Khz24 => 24000,
+            SampleRate::Khz48 => 48000,
+        };
+        Self {
+            decoder: Decoder::new(rate, channels),
+            fs_hz,
+            channels: channels as usize,
+            packets: BTreeMap::new(),
+            highest: None,
+            next: None,
+            pcm: VecDeque::new(),
+            buf: vec![0.0; 5760 * fs_hz / CLOCK_RATE as usize * channels as usize],
+            jitter: 0.0,
+            transit: None,
+            packet_duration: 960,
+            min_delay: 960,
+            max_delay: 24000,
+            since_stretch: 0,
+            average_delay: 0.0,
+            stats: JitterStats::default(),
+        }
+    }
+
+    ///The decoder, for its controls
+    pub fn decoder(&mut self) -> &mut Decoder {
+        &mut self.decoder
+    }
+
+    ///Sets the range the delay is kept in, in samples at 48 kHz, by default 20 to 500 ms
+    pub fn set_delay_range(&mut self, min: usize, max: usize) {
+        self.min_delay = min;
+        self.max_delay = max.max(min);
+    }
+
+    pub fn stats(&self) -> &JitterStats {
+        &self.stats
+    }
+
+    ///Interarrival jitter in samples at 48 kHz, see RFC 3550 section 6.4.1
+    pub fn jitter(&self) -> usize {
+        self.jitter as usize
+    }
+
+    ///Delay playout aims for, enough for a packet and three times the jitter
+    pub fn target_delay(&self) -> usize {
+        (self.packet_duration + 3 * self.jitter() + HYSTERESIS).clamp(self.min_delay, self.max_delay)
+    }
+
+    ///Audio buffered ahead of playout, from the next sample to play to the end of the last packet received
+    pub fn delay(&self) -> usize {
+        let pending = self.pcm.len() / self.channels * CLOCK_RATE as usize / self.fs_hz;
+        let start = match self.next.or_else(|| self.packets.values().next().map(|&(timestamp, _)| (0, timestamp))) {
+            Some((_, timestamp)) => timestamp,
+            None => return pending,
+        };
+        let end = self.packets.values().next_back().map_or(start, |&(timestamp, ref payload)| timestamp + u64::from(payload.duration));
+        pending + end.saturating_sub(start) as usize
+    }
+
+    ///Adds a packet that arrived at time `arrival`
+    pub fn push(&mut self, payload: Payload, arrival: u64) {
+        let (sequence, timestamp) = match self.highest {
+            Some((sequence, timestamp)) => (extend(u64::from(payload.sequence), 16, sequence), extend(u64::from(payload.timestamp), 32, timestamp)),
+            //Extended values start a wrap in, so that packets from before the first one stay positive
+            None => (u64::from(payload.sequence) + (1<<16), u64::from(payload.timestamp) + (1<<32)),
+        };
+        self.stats.received += 1;
+
+        let transit = arrival as i64 - timestamp as i64;
+        if let Some(last) = self.transit {
+            self.jitter += (((transit - last).abs()) as f64 - self.jitter) / 16.0;
+        }
+        self.transit = Some(transit);
+
+        if self.next.is_some_and(|(next, _)| sequence < next) {
+            self.stats.late += 1;
+            return;
+        }
+        if self.packets.contains_key(&sequence) {
+            self.stats.duplicates += 1;
+            return;
+        }
+        if self.highest.is_none_or(|(highest, _)| sequence > highest) {
+            self.highest = Some((sequence, timestamp));
+        }
+        self.packet_duration = payload.duration as usize;
+        self.packets.insert(sequence, (timestamp, payload));
+    }
+
+    ///Fills `pcm` with the next interleaved samples to play, between -1 and 1.
+    ///Until enough packets arrived to start playout it gets silence
+    pub fn pull(&mut self, pcm: &mut [f32]) -> Result<()> {
+        if self.next.is_none() {
+            match self.packets.iter().next() {
+                Some((&sequence, &(timestamp, _))) if self.delay() >= self.target_delay() => {
+                    self.next = Some((sequence, timestamp));
+                    self.average_delay = self.delay() as f64;
+                },
+                _ => {
+                    for x in pcm.iter_mut() {
+                        *x = 0.0;
+                    }
+                    return Ok(());
+                },
+            }
+        }
+        while self.pcm.len() < pcm.len() {
+            self.decode_next()?;
+        }
+        //Stretching needs some audio after what's played now, but isn't worth concealing for
+        let stretch = pcm.len() + 2 * self.stretch_size() * self.channels;
+        while self.pcm.len() < stretch && self.next.is_some_and(|(sequence, _)| self.packets.contains_key(&sequence)) {
+            self.decode_next()?;
+        }
+        self.average_delay += (self.delay() as f64 - self.average_delay) / 16.0;
+        if self.pcm.len() >= stretch {
+            self.stretch();
+        }
+        let len = pcm.len();
+        for (out, x) in pcm.iter_mut().zip(self.pcm.drain(..len)) {
+            *out = x;
+        }
+        self.since_stretch += len / self.channels;
+        Ok(())
+    }
+
+    ///Decodes or conceals the audio at the next timestamp to play
+    fn decode_next(&mut self) -> Result<()> {
+        let (sequence, timestamp) = self.next.expect("playout started");
+        let n = match self.packets.get(&sequence) {
+            //Nothing was sent for the time before the packet, as with discontinuous transmission
+            Some(&(start, _)) if start > timestamp => {
+                let samples = ((start - timestamp) as usize).min(UNDERRUN_STEP) / FRAME_STEP * FRAME_STEP;
+                self.next = Some((sequence, timestamp + samples.max(FRAME_STEP) as u64));
+                self.decoder.decode_lost_float(samples.max(FRAME_STEP) * self.fs_hz / CLOCK_RATE as usize, &mut self.buf)?
+            },
+            Some(_) => {
+                let (start, payload) = self.packets.remove(&sequence).expect("packet is there");
+                self.next = Some((sequence + 1, start + u64::from(payload.duration)));
+                match self.decoder.decode_float(&payload.data, &mut self.buf) {
+                    Ok(n) => n,
+                    //A packet that can't be decoded is concealed for its duration, the same as a lost one
+                    Err(_) => {
+                        self.stats.concealed += 1;
+                        let samples = (payload.duration as usize / FRAME_STEP).max(1) * FRAME_STEP;
+                        self.decoder.decode_lost_float(samples * self.fs_hz / CLOCK_RATE as usize, &mut self.buf)?
+                    },
+                }
+            },
+            None => match self.packets.range(sequence + 1..).next().map(|(&next, &(start, ref payload))| (next, start, payload)) {
+                //A later packet arrived, so this one is lost rather than late
+                Some((next, start, payload)) => {
+                    //The gap in timestamps gives the duration of the lost packet when the next one follows it
+                    let gap = start.saturating_sub(timestamp) as usize;
+                    let samples = if next == sequence + 1 && gap > 0 && gap <= 5760 { gap } else { self.packet_duration };
+                    let samples = (samples / FRAME_STEP).max(1) * FRAME_STEP;
+                    self.next = Some((sequence + 1, timestamp + samples as u64));
+                    let out_samples = samples * self.fs_hz / CLOCK_RATE as usize;
+                    let redundancy = next == sequence + 1 && Packet::read(&payload.data)
+                        .is_ok_and(|packet| packet.per_frame_lbrr_flags().first() == Some(&true));
+                    if redundancy {
+                        self.stats.recovered += 1;
+                        self.decoder.decode_fec_float(&payload.data, out_samples, &mut self.buf)?
+                    } else {
+                        self.stats.concealed += 1;
+                        self.decoder.decode_lost_float(out_samples, &mut self.buf)?
+                    }
+                },
+                //Playout ran dry, so it waits for the packet while concealing, which adds to the delay
+                None => {
+                    self.stats.underruns += 1;
+                    self.decoder.decode_lost_float(UNDERRUN_STEP * self.fs_hz / CLOCK_RATE as usize, &mut self.buf)?
+                },
+            },
+        };
+        self.pcm.extend(&self.buf[..n * self.channels]);
+        Ok(())
+    }
+
+    ///Samples per channel at the output rate that playout is stretched by at once, a pitch period of
+    ///the last frame if it was voiced and 5 ms otherwise
+    fn stretch_size(&self) -> usize {
+        let pitch = self.decoder.pitch() * self.fs_hz / CLOCK_RATE as usize;
+        if pitch >= self.fs_hz / 400 && pitch <= self.fs_hz / 66 { pitch } else { self.fs_hz / 200 }
+    }
+
+    ///Removes or repeats a stretch of the decoded audio when the delay strays from its target,
+    ///cross-fading so that the waveform stays continuous
+    fn stretch(&mut self) {
+        if self.since_stretch < STRETCH_INTERVAL * self.fs_hz / CLOCK_RATE as usize {
+            return;
+        }
+        let delay = self.average_delay as usize;
+        let target = self.target_delay();
+        let n = self.stretch_size();
+        let channels = self.channels;
+        let data = self.pcm.make_contiguous();
+        //The stretch [n, 2n) is faded against [0, n) and takes its place, or goes in after it
+        let fade = |data: &[f32], from: usize, to: usize| (0..n * channels)
+            .map(|i| {
+                let w = ((i / channels) as f32 + 0.5) / n as f32;
+                data[from + i] * (1.0 - w) + data[to + i] * w
+            })
+            .collect::<Vec<_>>();
+        if delay > target + HYSTERESIS {
+            let faded = fade(data, 0, n * channels);
+            data[..n * channels].copy_from_slice(&faded);
+            self.pcm.drain(n * channels..2 * n * channels);
+            self.stats.compressed += n;
+        } else if delay + HYSTERESIS < target {
+            let faded = fade(data, n * channels, 0);
+            let tail = self.pcm.split_off(n * channels);
+            self.pcm.extend(faded);
+            self.pcm.extend(tail);
+            self.stats.expanded += n;
+        } else {
+            return;
+        }
+        self.since_stretch = 0;
+    }
+}
+
+///Extends a sequence number or timestamp of `bits` bits to the value closest to the extended `reference`
+fn extend(value: u64, bits: u32, reference: u64) -> u64 {
+    let modulus = 1<<bits;
+    let value = reference & !(modulus - 1) | value;
+    if value + modulus / 2 < reference {
+        value + modulus
+    } else if value > reference + modulus / 2 && value >= modulus {
+        value - modulus
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rtp::{Depacketizer, NetworkSimulator, Packetizer};
+
+    ///A 20 ms CELT packet of only the table of contents, which the decoder conceals
+    const PACKET: [u8; 1] = [0xf8];
+    const FRAME_SIZE: usize = 960;
+
+    ///Sends `count` packets through the network a frame apart, pushing what arrives and pulling a frame each time.
+    ///Returns the sequence numbers in the order they arrived, the extended one playout started from, and the
+    ///most audio that was buffered after a pull
+    fn run(network: &mut NetworkSimulator, buffer: &mut JitterBuffer, packetizer: &mut Packetizer, count: u64) -> (Vec<u16>, u64, usize) {
+        let depacketizer = Depacketizer::new(111);
+        let mut output = [0.0; FRAME_SIZE];
+        let mut rtp = Vec::new();
+        let mut arrived = Vec::new();
+        let mut start = None;
+        let mut max_delay = 0;
+        for i in 0..count {
+            let time = i * FRAME_SIZE as u64;
+            rtp.clear();
+            packetizer.packetize(&PACKET).unwrap().write(&mut rtp);
+            network.send(&rtp, time);
+            while let Some((arrival, data)) = network.receive(time) {
+                let payload = depacketizer.read(&data).unwrap();
+                arrived.push(payload.sequence);
+                buffer.push(payload, arrival);
+            }
+            let next = buffer.next.map(|(sequence, _)| sequence);
+            //Playout starts from the first packet buffered
+            let first = buffer.packets.keys().next().cloned();
+            buffer.pull(&mut output).unwrap();
+            assert!(output.iter().all(|x| x.is_finite() && x.abs() <= 1.0));
+            //Playout only moves forward
+            assert!(next.is_none_or(|next| buffer.next.is_some_and(|(sequence, _)| sequence >= next)));
+            if next.is_none() && buffer.next.is_some() {
+                start = first;
+            }
+            max_delay = max_delay.max(buffer.delay());
+        }
+        (arrived, start.expect("playout started"), max_delay)
+    }
+
+    #[test]
+    fn reorders_across_wraps() {
+        let mut network = NetworkSimulator::new(7);
+        network.delay = 2400;
+        network.jitter = 3840;
+        network.duplication = 0.1;
+        let mut buffer = JitterBuffer::new(SampleRate::Khz48, Channels::Mono);
+        //More than the delay and jitter together, so nothing arrives too late
+        buffer.set_delay_range(9600, 24000);
+        let mut packetizer = Packetizer::new(111, 1, 65400, 4294800000);
+        let (arrived, start, max_delay) = run(&mut network, &mut buffer, &mut packetizer, 400);
+
+        assert!(arrived.windows(2).any(|pair| pair[1] == pair[0].wrapping_sub(1)), "no packets were reordered");
+        //Extended as the buffer does, from a wrap in
+        let mut sequences: Vec<_> = arrived.iter()
+            .map(|&sequence| u64::from(sequence) + if sequence < 65400 { 2<<16 } else { 1<<16 })
+            .collect();
+        sequences.sort_unstable();
+        sequences.dedup();
+        let stats = buffer.stats();
+        assert_eq!(stats.received, arrived.len());
+        assert_eq!(stats.duplicates, arrived.len() - sequences.len());
+        assert!(stats.duplicates > 0);
+        assert_eq!((stats.late, stats.concealed, stats.recovered, stats.underruns), (0, 0, 0, 0));
+        //All packets that arrived were played in order from the first one sent, or are still buffered
+        let (next, _) = buffer.next.unwrap();
+        assert_eq!(start, (1<<16) + 65400);
+        assert!(sequences.windows(2).all(|pair| pair[1] == pair[0] + 1));
+        assert_eq!(next + buffer.packets.len() as u64, sequences[0] + sequences.len() as u64);
+        //Playout is stretched towards the least delay, and only jitter and a frame add to it
+        assert!((buffer.average_delay - 9600.0).abs() <= 2.0 * HYSTERESIS as f64);
+        assert!(max_delay <= 9600 + 3840 + FRAME_SIZE + HYSTERESIS);
+    }
+
+    #[test]
+    fn conceals_lost_and_late_packets() {
+        let mut network = NetworkSimulator::new(3);
+        network.loss = 0.1;
+        network.burst_loss = 0.3;
+        network.delay = 480;
+        network.jitter = 4800;
+        let mut buffer = JitterBuffer::new(SampleRate::Khz48, Channels::Mono);
+        //Less than the jitter, so that some packets come after their time to play
+        buffer.set_delay_range(960, 1920);
+        let mut packetizer = Packetizer::new(111, 1, 0, 0);
+        let (arrived, start, max_delay) = run(&mut network, &mut buffer, &mut packetizer, 400);
+
+        let stats = buffer.stats().clone();
+        assert_eq!(stats.received, arrived.len());
+        assert_eq!(stats.duplicates, 0);
+        assert!(stats.late > 0);
+        //Every packet from the first one played to the next one to play was decoded, concealed or came late
+        let (next, _) = buffer.next.unwrap();
+        let played = next - start;
+        let on_time = arrived.iter().filter(|&&sequence| u64::from(sequence) + (1<<16) < next).count() - stats.late;
+        assert_eq!(stats.recovered, 0);
+        assert!(stats.concealed > 0);
+        assert_eq!(on_time as u64 + stats.concealed as u64, played);
+        assert!(max_delay <= 1920 + 4800 + FRAME_SIZE + HYSTERESIS);
+    }
+
+    #[test]
+    fn conceals_undecodable_packets() {
+        let mut buffer = JitterBuffer::new(SampleRate::Khz48, Channels::Mono);
+        //The second packet is a code 3 packet of no frames, which can't be decoded
+        for (i, data) in [&PACKET[..], &[0xfb, 0], &PACKET[..]].iter().enumerate() {
+            let timestamp = (i * FRAME_SIZE) as u32;
+            let payload = Payload { ssrc: 1, sequence: i as u16, timestamp, marker: i == 0, data: data.to_vec(), duration: FRAME_SIZE as u32 };
+            buffer.push(payload, u64::from(timestamp));
+        }
+        let mut output = [1.0; FRAME_SIZE];
+        for _ in 0..3 {
+            buffer.pull(&mut output).unwrap();
+        }
+        assert_eq!(buffer.stats().concealed, 1);
+        assert_eq!(buffer.next, Some(((1<<16) + 3, (1<<32) + 3 * FRAME_SIZE as u64)));
+        assert!(buffer.packets.is_empty());
+    }
+}
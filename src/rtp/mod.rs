@@ -0,0 +1,44 @@
+mod header;
+mod jitter;
+mod payload;
+mod sdp;
+mod simulator;
+
+pub use self::header::RtpPacket;
+pub use self::jitter::{JitterBuffer, JitterStats};
+pub use self::payload::{Depacketizer, Packetizer, Payload};
+pub use self::sdp::Fmtp;
+pub use self::simulator::NetworkSimulator;
+
+use std::result;
+use decoder::DecoderErrorKind;
+use packet::PacketErrorKind;
+
+///Rate of RTP timestamps for Opus, whatever rate the audio is coded or decoded at, see RFC 7587 section 4.1
+const CLOCK_RATE: u32 = 48000;
+
+#[derive(Debug)]
+pub enum RtpErrorKind {
+    ///The data is too short for its header, or the version isn't 2
+    InvalidHeader,
+    ///The packet is of another payload type than the one Opus was negotiated for
+    UnexpectedPayloadType(u8),
+    ///A parameter of an fmtp line has a value out of its range
+    InvalidParameter(String),
+    InvalidPacket(PacketErrorKind),
+    Decoder(DecoderErrorKind),
+}
+
+impl From<PacketErrorKind> for RtpErrorKind {
+    fn from(err: PacketErrorKind) -> Self {
+        RtpErrorKind::InvalidPacket(err)
+    }
+}
+
+impl From<DecoderErrorKind> for RtpErrorKind {
+    fn from(err: DecoderErrorKind) -> Self {
+        RtpErrorKind::Decoder(err)
+    }
+}
+
+pub type Result<T> = result::Result<T, RtpErrorKind>;
@@ -0,0 +1,122 @@
+use packet::Packet;
+use super::{RtpErrorKind, RtpPacket, Result};
+
+///Puts Opus packets into RTP packets, one each, see RFC 7587 section 4.2
+pub struct Packetizer {
+    payload_type: u8,
+    ssrc: u32,
+    ///Sequence number and timestamp of the next packet
+    sequence: u16,
+    timestamp: u32,
+    ///Whether the next packet with audio starts a talkspurt
+    talkspurt: bool,
+}
+
+impl Packetizer {
+    ///Starts a stream whose first packet gets sequence number `sequence` and timestamp `timestamp`,
+    ///which RFC 3550 asks to be random
+    pub fn new(payload_type: u8, ssrc: u32, sequence: u16, timestamp: u32) -> Self {
+        Self { payload_type, ssrc, sequence, timestamp, talkspurt: true }
+    }
+
+    ///Sequence number of the next packet
+    pub fn sequence(&self) -> u16 {
+        self.sequence
+    }
+
+    ///Timestamp of the next packet
+    pub fn timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    ///Makes the RTP packet for an Opus packet and moves the timestamp on by the packet's duration at 48 kHz.
+    ///The marker is set on the first packet after discontinuous transmission
+    pub fn packetize(&mut self, data: &[u8]) -> Result<RtpPacket> {
+        let packet = Packet::read(data)?;
+        let mut rtp = RtpPacket::new(self.payload_type, self.sequence, self.timestamp, self.ssrc);
+        if packet.is_dtx() {
+            self.talkspurt = true;
+        } else {
+            rtp.marker = self.talkspurt;
+            self.talkspurt = false;
+        }
+        rtp.payload = data.to_vec();
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(packet.duration() as u32);
+        Ok(rtp)
+    }
+
+    ///Moves the timestamp on by `samples` at 48 kHz that aren't sent, like packets dropped during
+    ///discontinuous transmission, so the next packet starts a talkspurt
+    pub fn skip(&mut self, samples: u32) {
+        self.timestamp = self.timestamp.wrapping_add(samples);
+        self.talkspurt = true;
+    }
+}
+
+///An Opus packet taken out of an RTP packet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Payload {
+    pub ssrc: u32,
+    pub sequence: u16,
+    ///Timestamp of the first sample at 48 kHz
+    pub timestamp: u32,
+    pub marker: bool,
+    pub data: Vec<u8>,
+    ///Samples per channel at 48 kHz
+    pub duration: u32,
+}
+
+///Takes Opus packets out of RTP packets of the payload type negotiated for Opus, see RFC 7587 section 4.2
+pub struct Depacketizer {
+    payload_type: u8,
+}
+
+impl Depacketizer {
+    pub fn new(payload_type: u8) -> Self {
+        Self { payload_type }
+    }
+
+    ///Parses an RTP packet and the Opus packet it carries
+    pub fn read(&self, data: &[u8]) -> Result<Payload> {
+        self.depacketize(RtpPacket::read(data)?)
+    }
+
+    pub fn depacketize(&self, rtp: RtpPacket) -> Result<Payload> {
+        if rtp.payload_type != self.payload_type {
+            return Err(RtpErrorKind::UnexpectedPayloadType(rtp.payload_type));
+        }
+        let duration = Packet::read(&rtp.payload)?.duration() as u32;
+        Ok(Payload {
+            ssrc: rtp.ssrc,
+            sequence: rtp.sequence,
+            timestamp: rtp.timestamp,
+            marker: rtp.marker,
+            data: rtp.payload,
+            duration,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps() {
+        let mut packetizer = Packetizer::new(111, 1, 65534, 4_294_966_000);
+        let depacketizer = Depacketizer::new(111);
+        //20 ms, then a 60 ms packet of three frames
+        let rtp: Vec<_> = [&[0xf8, 1, 2][..], &[0xfb, 3, 1, 2, 3], &[0xf8, 3, 4]].iter()
+            .map(|data| packetizer.packetize(data).unwrap())
+            .collect();
+        assert_eq!(rtp.iter().map(|rtp| (rtp.sequence, rtp.timestamp)).collect::<Vec<_>>(),
+            [(65534, 4_294_966_000), (65535, 4_294_966_960), (0, 2_544)]);
+        assert_eq!((packetizer.sequence(), packetizer.timestamp()), (1, 3_504));
+        assert!(rtp[0].marker && !rtp[1].marker);
+
+        let payload = depacketizer.depacketize(rtp[1].clone()).unwrap();
+        assert_eq!((payload.sequence, payload.duration, payload.data), (65535, 2880, vec![0xfb, 3, 1, 2, 3]));
+        assert!(depacketizer.depacketize(RtpPacket::new(96, 0, 0, 1)).is_err());
+    }
+}
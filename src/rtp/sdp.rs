@@ -0,0 +1,152 @@
+use std::fmt::{self, Display};
+use common::types::{Channels, SampleRate};
+use super::{RtpErrorKind, Result, CLOCK_RATE};
+
+///Parameters of the fmtp attribute for Opus in SDP, see RFC 7587 section 6.1.
+///Parameters left out take the defaults of the RFC, and unknown ones are ignored
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Fmtp {
+    ///Highest rate in Hz the receiver plays audio at, so the sender can code less bandwidth
+    pub max_playback_rate: Option<u32>,
+    ///Highest rate in Hz the sender captures audio at
+    pub sprop_max_capture_rate: Option<u32>,
+    ///Highest average bitrate in bits per second the receiver wants to get
+    pub max_average_bitrate: Option<u32>,
+    ///Whether the receiver prefers stereo to mono
+    pub stereo: Option<bool>,
+    ///Whether the sender is likely to send stereo
+    pub sprop_stereo: Option<bool>,
+    ///Whether the receiver prefers constant bitrate
+    pub cbr: Option<bool>,
+    ///Whether the receiver can make use of in-band forward error correction
+    pub use_inband_fec: Option<bool>,
+    ///Whether the receiver prefers the sender to use discontinuous transmission
+    pub use_dtx: Option<bool>,
+}
+
+impl Fmtp {
+    ///Parses the parameters of an fmtp attribute, the part after the payload type
+    pub fn read(params: &str) -> Result<Self> {
+        let mut fmtp = Self::default();
+        for param in params.split(';') {
+            let (name, value) = match param.find('=') {
+                Some(i) => (param[..i].trim(), param[i + 1..].trim()),
+                None => continue,
+            };
+            let invalid = || RtpErrorKind::InvalidParameter(name.to_string());
+            let rate = || value.parse().ok().filter(|rate| (8000..=48000).contains(rate)).ok_or_else(invalid);
+            let flag = || match value {
+                "0" => Ok(false),
+                "1" => Ok(true),
+                _ => Err(invalid()),
+            };
+            match &name.to_ascii_lowercase()[..] {
+                "maxplaybackrate" => fmtp.max_playback_rate = Some(rate()?),
+                "sprop-maxcapturerate" => fmtp.sprop_max_capture_rate = Some(rate()?),
+                "maxaveragebitrate" => fmtp.max_average_bitrate = Some(value.parse().ok()
+                    .filter(|bitrate| (6000..=510000).contains(bitrate))
+                    .ok_or_else(invalid)?),
+                "stereo" => fmtp.stereo = Some(flag()?),
+                "sprop-stereo" => fmtp.sprop_stereo = Some(flag()?),
+                "cbr" => fmtp.cbr = Some(flag()?),
+                "useinbandfec" => fmtp.use_inband_fec = Some(flag()?),
+                "usedtx" => fmtp.use_dtx = Some(flag()?),
+                _ => {},
+            }
+        }
+        Ok(fmtp)
+    }
+
+    ///Lowest decoder rate that covers the highest playback rate, 48 kHz if there's none
+    pub fn playback_rate(&self) -> SampleRate {
+        match self.max_playback_rate.unwrap_or(48000) {
+            0..=8000 => SampleRate::Khz8,
+            8001..=12000 => SampleRate::Khz12,
+            12001..=16000 => SampleRate::Khz16,
+            16001..=24000 => SampleRate::Khz24,
+            _ => SampleRate::Khz48,
+        }
+    }
+
+    ///Channels the receiver prefers, mono unless it asks for stereo
+    pub fn channels(&self) -> Channels {
+        if self.stereo == Some(true) { Channels::Stereo } else { Channels::Mono }
+    }
+
+    ///The rtpmap and fmtp attributes for payload type `payload_type`, each ending with CRLF.
+    ///The rtpmap always names 2 channels, see RFC 7587 section 7
+    pub fn attributes(&self, payload_type: u8) -> String {
+        let mut attributes = format!("a=rtpmap:{} opus/{}/2\r\n", payload_type, CLOCK_RATE);
+        let params = self.to_string();
+        if !params.is_empty() {
+            attributes += &format!("a=fmtp:{} {}\r\n", payload_type, params);
+        }
+        attributes
+    }
+}
+
+impl Display for Fmtp {
+    ///Writes the parameters that are set, separated by `; `
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let numbers = [
+            ("maxplaybackrate", self.max_playback_rate),
+            ("sprop-maxcapturerate", self.sprop_max_capture_rate),
+            ("maxaveragebitrate", self.max_average_bitrate),
+        ];
+        let flags = [
+            ("stereo", self.stereo),
+            ("sprop-stereo", self.sprop_stereo),
+            ("cbr", self.cbr),
+            ("useinbandfec", self.use_inband_fec),
+            ("usedtx", self.use_dtx),
+        ];
+        let params = numbers.iter().filter_map(|&(name, value)| value.map(|value| (name, value)))
+            .chain(flags.iter().filter_map(|&(name, value)| value.map(|value| (name, u32::from(value)))));
+        for (i, (name, value)) in params.enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let fmtp = Fmtp::read("maxplaybackrate=16000; sprop-maxcapturerate=48000;maxaveragebitrate=20000;stereo=1;sprop-stereo=0;cbr=0;useinbandfec=1;usedtx=1").unwrap();
+        assert_eq!(fmtp, Fmtp {
+            max_playback_rate: Some(16000),
+            sprop_max_capture_rate: Some(48000),
+            max_average_bitrate: Some(20000),
+            stereo: Some(true),
+            sprop_stereo: Some(false),
+            cbr: Some(false),
+            use_inband_fec: Some(true),
+            use_dtx: Some(true),
+        });
+        assert_eq!(Fmtp::read(&fmtp.to_string()).unwrap(), fmtp);
+        assert_eq!((fmtp.playback_rate(), fmtp.channels()), (SampleRate::Khz16, Channels::Stereo));
+        assert_eq!(fmtp.attributes(111), format!("a=rtpmap:111 opus/48000/2\r\na=fmtp:111 {}\r\n", fmtp));
+
+        //Names are compared ignoring case, unknown parameters are skipped
+        let fmtp = Fmtp::read("MaxPlaybackRate=8000;ptime=20;minptime").unwrap();
+        assert_eq!(fmtp.to_string(), "maxplaybackrate=8000");
+        assert_eq!(Fmtp::default().attributes(96), "a=rtpmap:96 opus/48000/2\r\n");
+    }
+
+    #[test]
+    fn out_of_range() {
+        for params in &["maxplaybackrate=7999", "sprop-maxcapturerate=48001", "maxaveragebitrate=5999",
+                        "maxaveragebitrate=510001", "stereo=2", "usedtx=yes", "cbr="] {
+            match Fmtp::read(params) {
+                Err(RtpErrorKind::InvalidParameter(_)) => {},
+                other => panic!("{}: {:?}", params, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::collections::BTreeMap;
+
+///A deterministic model of a network path for testing the receiving end, which delays, drops, reorders
+///and duplicates packets using a seeded generator. Times are counted in samples at 48 kHz
+pub struct NetworkSimulator {
+    state: u64,
+    ///Probability of dropping a packet after one that got through, and after one that was dropped
+    pub loss: f64,
+    pub burst_loss: f64,
+    ///Delay every packet takes, and the most that's added at random on top, which reorders packets
+    ///sent closer together than that
+    pub delay: u64,
+    pub jitter: u64,
+    pub duplication: f64,
+    last_lost: bool,
+    ///Packets on their way, by arrival time and the order they were sent in
+    queue: BTreeMap<(u64, u64), Vec<u8>>,
+    sent: u64,
+}
+
+impl NetworkSimulator {
+    ///Creates a path without loss, delay or duplication, with the generator seeded by `seed`
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: seed,
+            loss: 0.0,
+            burst_loss: 0.0,
+            delay: 0,
+            jitter: 0,
+            duplication: 0.0,
+            last_lost: false,
+            queue: BTreeMap::new(),
+            sent: 0,
+        }
+    }
+
+    ///Sends a packet at time `time`
+    pub fn send(&mut self, data: &[u8], time: u64) {
+        let loss = if self.last_lost { self.burst_loss } else { self.loss };
+        self.last_lost = self.random() < loss;
+        if self.last_lost {
+            return;
+        }
+        let copies = if self.random() < self.duplication { 2 } else { 1 };
+        for _ in 0..copies {
+            let arrival = time + self.delay + (self.random() * self.jitter as f64) as u64;
+            self.queue.insert((arrival, self.sent), data.to_vec());
+            self.sent += 1;
+        }
+    }
+
+    ///Returns the next packet that arrived by time `time` with its arrival time
+    pub fn receive(&mut self, time: u64) -> Option<(u64, Vec<u8>)> {
+        let key = *self.queue.keys().next().filter(|&&(arrival, _)| arrival <= time)?;
+        self.queue.remove(&key).map(|data| (key.0, data))
+    }
+
+    ///Uniform in [0, 1), from a 64 bit linear congruential generator
+    fn random(&mut self) -> f64 {
+        self.state = self.state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.state>>11) as f64 / (1u64<<53) as f64
+    }
+}
@@ -0,0 +1,199 @@
+use std::io::Read;
+use common::types::{Bandwidth, Channels, FrameSize};
+use packet::{Mode, Packet, PacketErrorKind};
+use ogg;
+
+///The coding parameters a packet's table of contents signals
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Configuration {
+    pub mode: Mode,
+    pub bandwidth: Bandwidth,
+    pub frame_size: FrameSize,
+    pub channels: Channels,
+}
+
+///Duration and bitrate of a stream, gathered from the packets' headers and the flags at the start of
+///their SILK frames without decoding any audio
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub packets: usize,
+    ///Samples per channel at 48 kHz, without the pre-skip and the trimmed end of an Ogg Opus stream
+    pub duration: u64,
+    pub bytes: u64,
+    ///Bitrate of the smallest and the largest packet for their duration, in bits per second
+    pub min_bitrate: Option<u32>,
+    pub max_bitrate: Option<u32>,
+    ///Packets of each configuration, in the order the configurations first appeared.
+    ///A multistream packet counts with the configuration of its first stream
+    pub configurations: Vec<(Configuration, usize)>,
+    ///Packets of only empty frames, which an encoder sends during silence with discontinuous transmission
+    pub dtx_packets: usize,
+    ///Packets with SILK frames that carry low bitrate redundancy for the packet before them
+    pub lbrr_packets: usize,
+    ///Packets an Ogg Opus stream lost to corrupt pages, their audio counts towards the duration
+    pub lost_packets: usize,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///Scans all links of an Ogg Opus file
+    pub fn read_ogg<R: Read>(reader: R) -> ogg::Result<Self> {
+        let mut reader = ogg::Reader::new(reader)?;
+        let mut stats = Self::new();
+        while let Some(packet) = reader.read_packet()? {
+            if packet.data.is_empty() {
+                stats.lost_packets += 1;
+                stats.duration += packet.samples as u64;
+                continue;
+            }
+            let mut streams = Vec::with_capacity(reader.head().mapping.streams);
+            let mut data = &packet.data[..];
+            //Every stream but the last of a multistream packet is self-delimited
+            for _ in 1..reader.head().mapping.streams {
+                let (stream, size) = Packet::read_self_delimited(data)?;
+                data = &data[size..];
+                streams.push(stream);
+            }
+            streams.push(Packet::read(data)?);
+            stats.add_streams(&streams.iter().collect::<Vec<_>>(), packet.data.len(), packet.samples);
+        }
+        Ok(stats)
+    }
+
+    ///Scans packets of a single stream, given in full
+    pub fn read_packets<'a, I: IntoIterator<Item = &'a [u8]>>(packets: I) -> Result<Self, PacketErrorKind> {
+        let mut stats = Self::new();
+        for data in packets {
+            stats.add(&Packet::read(data)?);
+        }
+        Ok(stats)
+    }
+
+    ///Adds a packet of a single stream
+    pub fn add(&mut self, packet: &Packet) {
+        self.add_streams(&[packet], packet.size(), packet.duration());
+    }
+
+    ///Average bitrate over the duration in bits per second, `None` without any audio
+    pub fn average_bitrate(&self) -> Option<u32> {
+        if self.duration == 0 {
+            return None;
+        }
+        Some((self.bytes * 8 * 48000 / self.duration) as u32)
+    }
+
+    ///Adds a packet made of `streams`, which takes up `size` bytes and keeps `samples` of its audio
+    fn add_streams(&mut self, streams: &[&Packet], size: usize, samples: usize) {
+        let first = streams[0];
+        self.packets += 1;
+        self.duration += samples as u64;
+        self.bytes += size as u64;
+        let bitrate = (size * 8 * 48000 / first.duration()) as u32;
+        self.min_bitrate = Some(self.min_bitrate.map_or(bitrate, |min| min.min(bitrate)));
+        self.max_bitrate = Some(self.max_bitrate.map_or(bitrate, |max| max.max(bitrate)));
+
+        let configuration = Configuration {
+            mode: first.mode(),
+            bandwidth: first.bandwidth(),
+            frame_size: first.frame_size(),
+            channels: first.channels(),
+        };
+        match self.configurations.iter_mut().find(|&&mut (c, _)| c == configuration) {
+            Some(&mut (_, ref mut count)) => *count += 1,
+            None => self.configurations.push((configuration, 1)),
+        }
+
+        if streams.iter().all(|stream| stream.is_dtx()) {
+            self.dtx_packets += 1;
+        }
+        if streams.iter().any(|stream| stream.per_frame_lbrr_flags().contains(&true)) {
+            self.lbrr_packets += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use bitstream;
+    use decoder::multistream::ChannelMapping;
+    use ogg::{paginate, OpusHead, OpusTags};
+
+    ///A 20 ms fullband CELT packet of 100 bytes
+    fn celt(i: usize) -> Vec<u8> {
+        let mut data = vec![0xf8, i as u8];
+        data.resize(100, 0x55);
+        data
+    }
+
+    #[test]
+    fn packets() {
+        //Two CELT packets, a SILK packet of only its table of contents and three 20 ms CELT frames of 50 bytes
+        let mut cbr = vec![0xfb, 0x03];
+        cbr.resize(2 + 3 * 50, 0x55);
+        let packets = [celt(0), celt(1), vec![0x08], cbr];
+        let stats = Stats::read_packets(packets.iter().map(|data| &data[..])).unwrap();
+        assert_eq!((stats.packets, stats.duration, stats.bytes), (4, 6 * 960, 100 + 100 + 1 + 152));
+        //One byte in 20 ms is the lowest, 100 bytes in 20 ms the highest
+        assert_eq!((stats.min_bitrate, stats.max_bitrate), (Some(400), Some(40000)));
+        assert_eq!(stats.average_bitrate(), Some(353 * 8 * 48000 / 5760));
+        let celt = Configuration { mode: Mode::Celt, bandwidth: Bandwidth::Full, frame_size: FrameSize::Ms20, channels: Channels::Mono };
+        let silk = Configuration { mode: Mode::Silk, bandwidth: Bandwidth::Narrow, frame_size: FrameSize::Ms20, channels: Channels::Mono };
+        assert_eq!(stats.configurations, [(celt, 3), (silk, 1)]);
+        assert_eq!((stats.dtx_packets, stats.lbrr_packets, stats.lost_packets), (1, 0, 0));
+
+        let mut added = Stats::new();
+        for data in &packets {
+            added.add(&Packet::read(data).unwrap());
+        }
+        assert_eq!(added, stats);
+        assert!(Stats::read_packets(vec![&[][..]]).is_err());
+        assert_eq!(Stats::new().average_bitrate(), None);
+    }
+
+    #[test]
+    fn lbrr() {
+        let data = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/silk_fec.bit"));
+        let mut reader = bitstream::Reader::new(&data[..]);
+        let mut stats = Stats::new();
+        while let Some(packet) = reader.read_packet().unwrap() {
+            stats.add(&Packet::read(&packet.data).unwrap());
+        }
+        //The first packet has nothing before it to carry redundancy for
+        assert_eq!((stats.packets, stats.lbrr_packets, stats.dtx_packets), (10, 9, 0));
+    }
+
+    #[test]
+    fn ogg() {
+        let head = OpusHead { version: 1, pre_skip: 312, input_sample_rate: 48000, output_gain: 0, mapping: ChannelMapping::mono_stereo(Channels::Mono) };
+        let mut data = Vec::new();
+        head.write(&mut data);
+        let mut pages = paginate(1, 0, &[(data, 0)]);
+        pages[0].first = true;
+        let mut data = Vec::new();
+        OpusTags::new("poppy").write(&mut data);
+        pages.extend(paginate(1, 1, &[(data, 0)]));
+        //A packet a page, with the fourth page lost
+        for i in 0..6 {
+            if i != 3 {
+                pages.extend(paginate(1, 2 + i as u32, &[(celt(i), 960 * (i as u64 + 1))]));
+            }
+        }
+        pages.last_mut().unwrap().last = true;
+        let mut file = Vec::new();
+        for page in &pages {
+            page.write(&mut file).unwrap();
+        }
+
+        let stats = Stats::read_ogg(Cursor::new(file)).unwrap();
+        assert_eq!((stats.packets, stats.lost_packets), (5, 1));
+        //The lost packet's audio counts, the pre-skip doesn't
+        assert_eq!((stats.duration, stats.bytes), (6 * 960 - 312, 500));
+        assert_eq!((stats.min_bitrate, stats.max_bitrate), (Some(40000), Some(40000)));
+        assert_eq!(stats.average_bitrate(), Some(35242));
+    }
+}
@@ -0,0 +1,180 @@
+use std::io::{self, Read};
+use super::{Result, WebmErrorKind};
+
+///Reads the headers and data of EBML elements one after another, see RFC 8794, keeping count of the bytes read
+pub struct ElementReader<R> {
+    reader: R,
+    offset: u64,
+}
+
+impl<R: Read> ElementReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, offset: 0 }
+    }
+
+    ///Bytes read since the reader was created
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn set_offset(&mut self, offset: u64) {
+        self.offset = offset;
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    ///Reads the ID and size of the next element, or returns `None` at the end of the data.
+    ///The size is `None` for a master element of unknown size, which ends where an element that can't be its child starts
+    pub fn read_header(&mut self) -> Result<Option<(u32, Option<u64>)>> {
+        let mut first = [0];
+        loop {
+            match self.reader.read(&mut first) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+                Err(err) => return Err(err.into()),
+            }
+        }
+        self.offset += 1;
+        //IDs keep their length marker, and take at most 4 bytes
+        let len = first[0].leading_zeros() as usize + 1;
+        if len > 4 {
+            return Err(WebmErrorKind::InvalidElement);
+        }
+        let mut id = u32::from(first[0]);
+        for b in self.read_bytes(len - 1)? {
+            id = id<<8 | u32::from(b);
+        }
+
+        self.read_exact(&mut first)?;
+        let len = first[0].leading_zeros() as usize + 1;
+        if len > 8 {
+            return Err(WebmErrorKind::InvalidElement);
+        }
+        let mut size = u64::from(first[0]) & (0xff>>len);
+        let mut unknown = size == 0xff>>len;
+        for b in self.read_bytes(len - 1)? {
+            size = size<<8 | u64::from(b);
+            unknown &= b == 0xff;
+        }
+        Ok(Some((id, if unknown { None } else { Some(size) })))
+    }
+
+    ///Reads the data of an element of `size` bytes
+    pub fn read_data(&mut self, size: u64) -> Result<Vec<u8>> {
+        //A size beyond what's there shouldn't allocate it all up front
+        let mut data = Vec::with_capacity(size.min(1<<16) as usize);
+        let read = (&mut self.reader).take(size).read_to_end(&mut data)?;
+        self.offset += read as u64;
+        if (read as u64) < size {
+            return Err(WebmErrorKind::InvalidElement);
+        }
+        Ok(data)
+    }
+
+    ///Skips the data of an element of `size` bytes
+    pub fn skip(&mut self, size: u64) -> Result<()> {
+        let skipped = io::copy(&mut (&mut self.reader).take(size), &mut io::sink())?;
+        self.offset += skipped;
+        if skipped < size {
+            return Err(WebmErrorKind::InvalidElement);
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0; len];
+        self.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.reader.read_exact(buf).map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => WebmErrorKind::InvalidElement,
+            _ => err.into(),
+        })?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+}
+
+///Splits the data of a master element into its children's IDs and data
+pub fn children(mut data: &[u8]) -> Result<Vec<(u32, &[u8])>> {
+    let mut children = Vec::new();
+    while !data.is_empty() {
+        let (id, len) = read_id(data)?;
+        data = &data[len..];
+        let (size, len) = read_vint(data)?;
+        data = &data[len..];
+        if size > data.len() as u64 {
+            return Err(WebmErrorKind::InvalidElement);
+        }
+        let size = size as usize;
+        children.push((id, &data[..size]));
+        data = &data[size..];
+    }
+    Ok(children)
+}
+
+///Reads an element ID at the start of `data`, returns it with its length
+pub fn read_id(data: &[u8]) -> Result<(u32, usize)> {
+    let len = data.first().ok_or(WebmErrorKind::InvalidElement)?.leading_zeros() as usize + 1;
+    if len > 4 || data.len() < len {
+        return Err(WebmErrorKind::InvalidElement);
+    }
+    Ok((data[..len].iter().fold(0, |id, &b| id<<8 | u32::from(b)), len))
+}
+
+///Reads a variable length integer at the start of `data` without its length marker, returns it with its length
+pub fn read_vint(data: &[u8]) -> Result<(u64, usize)> {
+    let len = data.first().ok_or(WebmErrorKind::InvalidElement)?.leading_zeros() as usize + 1;
+    if len > 8 || data.len() < len {
+        return Err(WebmErrorKind::InvalidElement);
+    }
+    let first = u64::from(data[0]) & (0xff>>len);
+    Ok((data[1..len].iter().fold(first, |value, &b| value<<8 | u64::from(b)), len))
+}
+
+///Reads a signed variable length integer, as the lace sizes after the first one, which are stored with a bias
+pub fn read_signed_vint(data: &[u8]) -> Result<(i64, usize)> {
+    let (value, len) = read_vint(data)?;
+    Ok((value as i64 - ((1i64<<(7 * len - 1)) - 1), len))
+}
+
+///Reads the data of an unsigned integer element, big-endian in up to 8 bytes
+pub fn read_uint(data: &[u8]) -> Result<u64> {
+    if data.len() > 8 {
+        return Err(WebmErrorKind::InvalidElement);
+    }
+    Ok(data.iter().fold(0, |value, &b| value<<8 | u64::from(b)))
+}
+
+///Reads the data of a signed integer element, big-endian two's complement in up to 8 bytes
+pub fn read_int(data: &[u8]) -> Result<i64> {
+    let value = read_uint(data)?;
+    Ok(match data.len() {
+        0 => 0,
+        len => (value<<(64 - 8 * len)) as i64>>(64 - 8 * len),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_vint() {
+        assert_eq!(read_signed_vint(&[0xbf]).unwrap(), (0, 1));
+        assert_eq!(read_signed_vint(&[0x80]).unwrap(), (-63, 1));
+        assert_eq!(read_signed_vint(&[0xfe]).unwrap(), (63, 1));
+        assert_eq!(read_signed_vint(&[0x5f, 0xff]).unwrap(), (0, 2));
+        assert_eq!(read_signed_vint(&[0x40, 0x00]).unwrap(), (-8191, 2));
+        assert!(read_signed_vint(&[0x40]).is_err());
+    }
+}
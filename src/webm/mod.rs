@@ -0,0 +1,52 @@
+mod ebml;
+mod reader;
+
+pub use self::reader::{Reader, Track, WebmPacket};
+
+use std::io;
+use std::result;
+use packet::PacketErrorKind;
+
+///Rate of the samples that packet durations, the pre-skip and trimming are counted in
+const SAMPLE_RATE: u64 = 48000;
+const NANOSECONDS: u64 = 1_000_000_000;
+
+#[derive(Debug)]
+pub enum WebmErrorKind {
+    Io(io::Error),
+    ///An element is cut off, has an ID or size that can't be read, or has an unknown size where that isn't allowed
+    InvalidElement,
+    ///The data doesn't start with an EBML header of a Matroska or WebM document, or has no segment
+    InvalidHeader,
+    ///The CodecPrivate of an Opus track isn't an OpusHead header
+    InvalidCodecPrivate,
+    ///No track has the codec ID `A_OPUS`
+    NoOpusTrack,
+    ///A block's lacing doesn't add up to its size
+    InvalidBlock,
+    InvalidPacket(PacketErrorKind),
+}
+
+impl From<io::Error> for WebmErrorKind {
+    fn from(err: io::Error) -> Self {
+        WebmErrorKind::Io(err)
+    }
+}
+
+impl From<PacketErrorKind> for WebmErrorKind {
+    fn from(err: PacketErrorKind) -> Self {
+        WebmErrorKind::InvalidPacket(err)
+    }
+}
+
+pub type Result<T> = result::Result<T, WebmErrorKind>;
+
+///Converts nanoseconds to samples at 48 kHz, rounding to the closest
+fn to_samples(ns: i64) -> i64 {
+    ((i128::from(ns) * i128::from(SAMPLE_RATE) + i128::from(NANOSECONDS / 2)).div_euclid(i128::from(NANOSECONDS))) as i64
+}
+
+///Converts samples at 48 kHz to nanoseconds, rounding down
+fn to_nanoseconds(samples: i64) -> i64 {
+    (i128::from(samples) * i128::from(NANOSECONDS)).div_euclid(i128::from(SAMPLE_RATE)) as i64
+}
@@ -0,0 +1,492 @@
+use std::collections::VecDeque;
+use std::io::{Read, Seek, SeekFrom};
+use ogg::OpusHead;
+use packet::Packet;
+use super::{to_nanoseconds, to_samples, Result, WebmErrorKind};
+use super::ebml::{self, ElementReader};
+
+const EBML_HEADER: u32 = 0x1a45_dfa3;
+const DOC_TYPE: u32 = 0x4282;
+const SEGMENT: u32 = 0x1853_8067;
+const INFO: u32 = 0x1549_a966;
+const TIMESTAMP_SCALE: u32 = 0x2a_d7b1;
+const TRACKS: u32 = 0x1654_ae6b;
+const TRACK_ENTRY: u32 = 0xae;
+const TRACK_NUMBER: u32 = 0xd7;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63a2;
+const CODEC_DELAY: u32 = 0x56aa;
+const SEEK_PRE_ROLL: u32 = 0x56bb;
+const CLUSTER: u32 = 0x1f43_b675;
+const CLUSTER_TIMESTAMP: u32 = 0xe7;
+const SIMPLE_BLOCK: u32 = 0xa3;
+const BLOCK_GROUP: u32 = 0xa0;
+const BLOCK: u32 = 0xa1;
+const DISCARD_PADDING: u32 = 0x75a2;
+
+///Bits of the flags of a block that give its lacing
+const LACING: u8 = 0x06;
+const XIPH_LACING: u8 = 0x02;
+const FIXED_LACING: u8 = 0x04;
+const EBML_LACING: u8 = 0x06;
+
+///Nanoseconds per tick of the timestamps when the segment info doesn't say
+const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
+
+///An Opus track of a Matroska or WebM file, see the codec mapping at https://wiki.xiph.org/MatroskaOpus
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Track {
+    pub number: u64,
+    ///Header from the CodecPrivate
+    pub head: OpusHead,
+    ///Nanoseconds to drop from the start of the decoded track, the pre-skip
+    pub codec_delay: u64,
+    ///Nanoseconds to decode before a seek target so the decoder converges
+    pub seek_pre_roll: u64,
+}
+
+impl Track {
+    ///Samples at 48 kHz to drop from the start of the decoded track, from the CodecDelay if the track has one
+    pub fn pre_skip(&self) -> usize {
+        if self.codec_delay > 0 {
+            to_samples(self.codec_delay as i64) as usize
+        } else {
+            usize::from(self.head.pre_skip)
+        }
+    }
+}
+
+///An Opus packet of a block, with the part of its decoded samples that belongs to the track.
+///Samples are counted per channel at 48 kHz
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WebmPacket {
+    ///Number of the track the packet belongs to
+    pub track: u64,
+    pub data: Vec<u8>,
+    ///Nanoseconds from the start of the segment to the first decoded sample of the packet, before the codec delay is dropped
+    pub timestamp: i64,
+    ///Decoded samples to drop from the start, for the codec delay or a seek
+    pub skip: usize,
+    ///Decoded samples to keep after the dropped ones, fewer than the packet holds where DiscardPadding trims the end
+    pub samples: usize,
+}
+
+impl WebmPacket {
+    ///Parses the data as the packet of a single stream, multistream packets are split up by `multistream::Decoder`
+    pub fn packet<'a>(&'a self) -> Result<Packet<'a>> {
+        Ok(Packet::read(&self.data)?)
+    }
+}
+
+///Reads the Opus tracks of a Matroska or WebM file. Packets of all Opus tracks are returned in the order of
+///their blocks, and blocks of other tracks are skipped. Segments and clusters may have an unknown size, as
+///live streams from browsers do
+pub struct Reader<R> {
+    elements: ElementReader<R>,
+    tracks: Vec<Track>,
+    ///Nanoseconds per tick of the cluster and block timestamps
+    timestamp_scale: u64,
+    ///Timestamp of the current cluster in ticks
+    cluster_timestamp: u64,
+    ///Packets of the last block that weren't returned yet
+    packets: VecDeque<WebmPacket>,
+    ///Offset of the first cluster
+    first_cluster: u64,
+    ///Nanoseconds a seek went to, samples before are dropped
+    target: Option<i64>,
+}
+
+impl<R: Read> Reader<R> {
+    ///Reads the EBML header and the segment's info and tracks, up to the first cluster
+    pub fn new(reader: R) -> Result<Self> {
+        let mut elements = ElementReader::new(reader);
+        match elements.read_header()? {
+            Some((EBML_HEADER, Some(size))) => {
+                let data = elements.read_data(size)?;
+                let doc_type = ebml::children(&data)?.into_iter().find(|&(id, _)| id == DOC_TYPE).map(|(_, data)| data);
+                if doc_type != Some(&b"webm"[..]) && doc_type != Some(&b"matroska"[..]) {
+                    return Err(WebmErrorKind::InvalidHeader);
+                }
+            },
+            _ => return Err(WebmErrorKind::InvalidHeader),
+        }
+        loop {
+            match elements.read_header()? {
+                Some((SEGMENT, _)) => break,
+                Some((_, Some(size))) => elements.skip(size)?,
+                _ => return Err(WebmErrorKind::InvalidHeader),
+            }
+        }
+
+        let mut timestamp_scale = DEFAULT_TIMESTAMP_SCALE;
+        let mut tracks = Vec::new();
+        let first_cluster = loop {
+            let start = elements.offset();
+            match elements.read_header()? {
+                Some((INFO, Some(size))) => {
+                    let data = elements.read_data(size)?;
+                    for (id, data) in ebml::children(&data)? {
+                        if id == TIMESTAMP_SCALE {
+                            timestamp_scale = ebml::read_uint(data)?;
+                        }
+                    }
+                },
+                Some((TRACKS, Some(size))) => tracks = read_tracks(&elements.read_data(size)?)?,
+                Some((CLUSTER, _)) => break start,
+                //A segment without clusters has no audio
+                None => break start,
+                Some((_, Some(size))) => elements.skip(size)?,
+                Some((_, None)) => return Err(WebmErrorKind::InvalidElement),
+            }
+        };
+        if tracks.is_empty() {
+            return Err(WebmErrorKind::NoOpusTrack);
+        }
+        Ok(Self {
+            elements,
+            tracks,
+            timestamp_scale,
+            cluster_timestamp: 0,
+            packets: VecDeque::new(),
+            first_cluster,
+            target: None,
+        })
+    }
+
+    ///Opus tracks of the segment, in the order they're listed
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    pub fn into_inner(self) -> R {
+        self.elements.into_inner()
+    }
+
+    ///Returns the next packet of an Opus track, or `None` at the end of the file
+    pub fn read_packet(&mut self) -> Result<Option<WebmPacket>> {
+        loop {
+            if let Some(packet) = self.packets.pop_front() {
+                return Ok(Some(packet));
+            }
+            let (id, size) = match self.elements.read_header()? {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+            match (id, size) {
+                //The children of clusters are read as they come, so their end doesn't need to be known
+                (SEGMENT, _) | (CLUSTER, _) => {},
+                (_, None) => return Err(WebmErrorKind::InvalidElement),
+                (CLUSTER_TIMESTAMP, Some(size)) => self.cluster_timestamp = ebml::read_uint(&self.elements.read_data(size)?)?,
+                (SIMPLE_BLOCK, Some(size)) => {
+                    let data = self.elements.read_data(size)?;
+                    self.queue(&data, 0)?;
+                },
+                (BLOCK_GROUP, Some(size)) => {
+                    let data = self.elements.read_data(size)?;
+                    let children = ebml::children(&data)?;
+                    let mut discard_padding = 0;
+                    for &(id, data) in &children {
+                        if id == DISCARD_PADDING {
+                            discard_padding = ebml::read_int(data)?;
+                        }
+                    }
+                    if let Some(&(_, block)) = children.iter().find(|&&(id, _)| id == BLOCK) {
+                        self.queue(block, discard_padding)?;
+                    }
+                },
+                (_, Some(size)) => self.elements.skip(size)?,
+            }
+        }
+    }
+
+    ///Splits a block of an Opus track into its packets and works out the samples of each that belong to the track.
+    ///A positive `discard_padding` in nanoseconds trims the end of the block
+    fn queue(&mut self, block: &[u8], discard_padding: i64) -> Result<()> {
+        let (number, len) = ebml::read_vint(block)?;
+        let track = match self.tracks.iter().find(|track| track.number == number) {
+            Some(track) => track,
+            None => return Ok(()),
+        };
+        let header = block.get(len..len + 3).ok_or(WebmErrorKind::InvalidBlock)?;
+        let relative = i64::from(i16::from_be_bytes([header[0], header[1]]));
+        let frames = split_laces(header[2] & LACING, &block[len + 3..])?;
+
+        //All streams of a multistream packet have the same duration, the first one is self-delimited
+        let multistream = track.head.mapping.streams > 1;
+        let durations = frames.iter()
+            .map(|data| Ok(if multistream { Packet::read_self_delimited(data)?.0 } else { Packet::read(data)? }.duration() as i64))
+            .collect::<Result<Vec<_>>>()?;
+        let timestamp = (i128::from(self.cluster_timestamp) + i128::from(relative)).checked_mul(i128::from(self.timestamp_scale))
+            .filter(|&ns| ns >= i128::from(i64::MIN) && ns <= i128::from(i64::MAX))
+            .ok_or(WebmErrorKind::InvalidElement)?;
+        let start = to_samples(timestamp as i64);
+        let end = start + durations.iter().sum::<i64>() - to_samples(discard_padding).max(0);
+        let pre_skip = track.pre_skip() as i64;
+
+        let mut position = start;
+        for (data, duration) in frames.into_iter().zip(durations) {
+            let samples = (position + duration).min(end) - position;
+            let mut skip = pre_skip - position;
+            if let Some(target) = self.target {
+                //Packets before the pre-roll aren't needed to decode from the target
+                if position + duration <= to_samples(target - track.seek_pre_roll as i64) {
+                    position += duration;
+                    continue;
+                }
+                skip = skip.max(to_samples(target) - position);
+            }
+            let samples = samples.max(0);
+            let skip = skip.clamp(0, samples);
+            self.packets.push_back(WebmPacket {
+                track: number,
+                data: data.to_vec(),
+                timestamp: to_nanoseconds(position),
+                skip: skip as usize,
+                samples: (samples - skip) as usize,
+            });
+            position += duration;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Reader<R> {
+    ///Moves to the last cluster that starts before nanosecond `timestamp` less the longest SeekPreRoll of the tracks,
+    ///going through the clusters from the first one. Packets from there to the target are returned for decoding with
+    ///all their samples to drop, so the decoder converges. Clusters of unknown size can't be skipped, reading then
+    ///starts from the first cluster
+    pub fn seek(&mut self, timestamp: u64) -> Result<()> {
+        //Offsets are relative to where the reader was created
+        let base = self.elements.get_mut().stream_position()? - self.elements.offset();
+        let preroll = self.tracks.iter().map(|track| track.seek_pre_roll).max().unwrap_or(0);
+        let from = timestamp.saturating_sub(preroll);
+
+        let mut best = self.first_cluster;
+        self.move_to(base, best)?;
+        loop {
+            let start = self.elements.offset();
+            match self.elements.read_header()? {
+                Some((CLUSTER, Some(size))) => {
+                    let end = self.elements.offset() + size;
+                    let timestamp = self.read_cluster_timestamp(end)?.checked_mul(self.timestamp_scale).ok_or(WebmErrorKind::InvalidElement)?;
+                    if timestamp > from {
+                        break;
+                    }
+                    best = start;
+                    self.move_to(base, end)?;
+                },
+                Some((_, Some(size))) => self.elements.skip(size)?,
+                _ => break,
+            }
+        }
+
+        self.move_to(base, best)?;
+        self.packets.clear();
+        self.cluster_timestamp = 0;
+        self.target = Some(timestamp as i64);
+        Ok(())
+    }
+
+    ///Reads the children of a cluster up to its timestamp, which comes before its blocks
+    fn read_cluster_timestamp(&mut self, end: u64) -> Result<u64> {
+        while self.elements.offset() < end {
+            match self.elements.read_header()? {
+                Some((CLUSTER_TIMESTAMP, Some(size))) => return ebml::read_uint(&self.elements.read_data(size)?),
+                Some((_, Some(size))) => self.elements.skip(size)?,
+                _ => break,
+            }
+        }
+        Err(WebmErrorKind::InvalidElement)
+    }
+
+    fn move_to(&mut self, base: u64, offset: u64) -> Result<()> {
+        self.elements.get_mut().seek(SeekFrom::Start(base + offset))?;
+        self.elements.set_offset(offset);
+        Ok(())
+    }
+}
+
+///Reads the Opus tracks from the data of the Tracks element
+fn read_tracks(data: &[u8]) -> Result<Vec<Track>> {
+    let mut tracks = Vec::new();
+    for (id, entry) in ebml::children(data)? {
+        if id != TRACK_ENTRY {
+            continue;
+        }
+        let mut number = None;
+        let mut opus = false;
+        let mut head = None;
+        let mut codec_delay = 0;
+        let mut seek_pre_roll = 0;
+        for (id, data) in ebml::children(entry)? {
+            match id {
+                TRACK_NUMBER => number = Some(ebml::read_uint(data)?),
+                //Strings may be padded with zeros
+                CODEC_ID => opus = data.split(|&b| b == 0).next() == Some(&b"A_OPUS"[..]),
+                CODEC_PRIVATE => head = Some(data),
+                CODEC_DELAY => codec_delay = ebml::read_uint(data)?,
+                SEEK_PRE_ROLL => seek_pre_roll = ebml::read_uint(data)?,
+                _ => {},
+            }
+        }
+        if !opus {
+            continue;
+        }
+        tracks.push(Track {
+            number: number.ok_or(WebmErrorKind::InvalidElement)?,
+            head: OpusHead::read(head.ok_or(WebmErrorKind::InvalidCodecPrivate)?).map_err(|_| WebmErrorKind::InvalidCodecPrivate)?,
+            codec_delay,
+            seek_pre_roll,
+        });
+    }
+    Ok(tracks)
+}
+
+///Splits the data of a block into its frames, going by the block's lacing bits
+fn split_laces(lacing: u8, data: &[u8]) -> Result<Vec<&[u8]>> {
+    if lacing == 0 {
+        return Ok(vec![data]);
+    }
+    let count = usize::from(*data.first().ok_or(WebmErrorKind::InvalidBlock)?) + 1;
+    let mut data = &data[1..];
+    let mut sizes = Vec::with_capacity(count);
+    match lacing {
+        //Sizes of all frames but the last as runs of 255s ending with a smaller value
+        XIPH_LACING => for _ in 1..count {
+            let mut size = 0;
+            loop {
+                let (&b, rest) = data.split_first().ok_or(WebmErrorKind::InvalidBlock)?;
+                data = rest;
+                size += usize::from(b);
+                if b < 255 {
+                    break;
+                }
+            }
+            sizes.push(size);
+        },
+        //The size of the first frame, then the difference of each to the one before
+        EBML_LACING if count > 1 => {
+            let (first, len) = ebml::read_vint(data).map_err(|_| WebmErrorKind::InvalidBlock)?;
+            data = &data[len..];
+            let mut size = first as i64;
+            sizes.push(first as usize);
+            for _ in 2..count {
+                let (difference, len) = ebml::read_signed_vint(data).map_err(|_| WebmErrorKind::InvalidBlock)?;
+                data = &data[len..];
+                size += difference;
+                if size < 0 {
+                    return Err(WebmErrorKind::InvalidBlock);
+                }
+                sizes.push(size as usize);
+            }
+        },
+        FIXED_LACING => {
+            if !data.len().is_multiple_of(count) {
+                return Err(WebmErrorKind::InvalidBlock);
+            }
+            sizes.resize(count - 1, data.len() / count);
+        },
+        _ => {},
+    }
+    let mut frames = Vec::with_capacity(count);
+    for size in sizes {
+        if size > data.len() {
+            return Err(WebmErrorKind::InvalidBlock);
+        }
+        let (frame, rest) = data.split_at(size);
+        frames.push(frame);
+        data = rest;
+    }
+    frames.push(data);
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use common::types::Channels;
+    use decoder::multistream::ChannelMapping;
+
+    ///Writes an element with its ID and an 8 byte size
+    fn element(id: u32, data: &[u8]) -> Vec<u8> {
+        let mut out: Vec<u8> = id.to_be_bytes().iter().cloned().skip_while(|&b| b == 0).collect();
+        out.push(1);
+        out.extend_from_slice(&(data.len() as u64).to_be_bytes()[1..]);
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn block(relative: i16, flags: u8, packet: &[u8]) -> Vec<u8> {
+        let mut out = vec![0x81];
+        out.extend_from_slice(&relative.to_be_bytes());
+        out.push(flags);
+        out.extend_from_slice(packet);
+        out
+    }
+
+    #[test]
+    fn xiph_lacing() {
+        let mut data = vec![2, 255, 1, 2];
+        data.extend((0..261).map(|i| i as u8));
+        let frames = split_laces(XIPH_LACING, &data).unwrap();
+        assert_eq!(frames.iter().map(|frame| frame.len()).collect::<Vec<_>>(), [256, 2, 3]);
+        assert_eq!(frames[1], [0, 1]);
+        assert!(split_laces(XIPH_LACING, &[2, 255]).is_err());
+    }
+
+    #[test]
+    fn ebml_lacing() {
+        let data = [2, 0x81, 0xc0, 1, 2, 3, 4, 5, 6];
+        let frames = split_laces(EBML_LACING, &data).unwrap();
+        assert_eq!(frames, [&[1][..], &[2, 3][..], &[4, 5, 6][..]]);
+        assert!(split_laces(EBML_LACING, &[2, 0x81, 0x80, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn fixed_lacing() {
+        let data = [2, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let frames = split_laces(FIXED_LACING, &data).unwrap();
+        assert_eq!(frames, [&[1, 2, 3][..], &[4, 5, 6][..], &[7, 8, 9][..]]);
+        assert!(split_laces(FIXED_LACING, &data[..9]).is_err());
+    }
+
+    #[test]
+    fn codec_delay_and_discard_padding() {
+        let head = OpusHead {
+            version: 1,
+            pre_skip: 312,
+            input_sample_rate: 48000,
+            output_gain: 0,
+            mapping: ChannelMapping::mono_stereo(Channels::Mono),
+        };
+        let mut private = Vec::new();
+        head.write(&mut private);
+        let mut track = element(TRACK_NUMBER, &[1]);
+        track.extend(element(CODEC_ID, b"A_OPUS"));
+        track.extend(element(CODEC_PRIVATE, &private));
+        track.extend(element(CODEC_DELAY, &6_500_000u32.to_be_bytes()));
+
+        //A 20 ms CELT frame, then one with 5 ms of padding
+        let mut cluster = element(CLUSTER_TIMESTAMP, &[0]);
+        cluster.extend(element(SIMPLE_BLOCK, &block(0, 0x80, &[0xf8, 1, 2])));
+        let mut group = element(BLOCK, &block(20, 0, &[0xf8, 3]));
+        group.extend(element(DISCARD_PADDING, &5_000_000u32.to_be_bytes()));
+        cluster.extend(element(BLOCK_GROUP, &group));
+
+        let mut segment = element(INFO, &element(TIMESTAMP_SCALE, &1_000_000u32.to_be_bytes()));
+        segment.extend(element(TRACKS, &element(TRACK_ENTRY, &track)));
+        segment.extend(element(CLUSTER, &cluster));
+        let mut file = element(EBML_HEADER, &element(DOC_TYPE, b"webm"));
+        file.extend(element(SEGMENT, &segment));
+
+        let mut reader = Reader::new(Cursor::new(file)).unwrap();
+        assert_eq!(reader.tracks()[0].pre_skip(), 312);
+        let first = reader.read_packet().unwrap().unwrap();
+        assert_eq!((first.timestamp, first.skip, first.samples), (0, 312, 648));
+        assert_eq!(first.data, [0xf8, 1, 2]);
+        let second = reader.read_packet().unwrap().unwrap();
+        assert_eq!((second.timestamp, second.skip, second.samples), (20_000_000, 0, 720));
+        assert!(reader.read_packet().unwrap().is_none());
+    }
+}